@@ -0,0 +1,280 @@
+//! One line per client request, appended to the file configured as `access_log`.
+//!
+//! Lines are space separated: time, connection id, client address, username, command,
+//! requested destination, the address the proxy actually connected to, and the outcome.
+//! Fields without a value are written as `-`. Destinations requested by name show the address
+//! they resolved to, which is what an audit usually needs to know. Usernames and requested
+//! destinations are escaped, as clients could otherwise forge fields or whole lines with
+//! spaces and newlines in them.
+//!
+//! Connections the server aborts when it is terminated get a second line with the same
+//! connection id and `terminated` as the outcome, their other fields written as `-`.
+//!
+//! With `access_log_rotation` configured, the file is renamed to `<path>.1` once it grows
+//! too large or too old, older files move up by one, and the oldest beyond the retention
+//! count are deleted.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{LineWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use jiff::Timestamp;
+use tokio::io;
+
+use crate::{config::LogRotationConfig, proto, server::ConnectionId};
+
+pub struct AccessLog {
+    path: PathBuf,
+    rotation: Option<LogRotationConfig>,
+    out: Mutex<Output>,
+}
+
+/// The file currently written to.
+struct Output {
+    file: LineWriter<File>,
+    size: u64,
+    opened: Instant,
+}
+
+impl Output {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            size: file.metadata()?.len(),
+            file: LineWriter::new(file),
+            opened: Instant::now(),
+        })
+    }
+}
+
+/// What happened to a single client request.
+pub struct AccessRecord<'a> {
+    pub id: ConnectionId,
+    pub client: SocketAddr,
+    pub username: Option<&'a str>,
+    pub request: &'a proto::ClientConnectionRequest,
+    /// the address dialed for the request, if it got that far
+    pub connected: Option<SocketAddr>,
+    pub result: Result<(), &'a io::Error>,
+}
+
+impl AccessLog {
+    pub fn open(path: &Path, rotation: Option<&LogRotationConfig>) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_owned(),
+            rotation: rotation.cloned(),
+            out: Mutex::new(Output::open(path)?),
+        })
+    }
+
+    pub fn record(&self, record: &AccessRecord) {
+        let line = format_record(Timestamp::now(), record);
+        let mut out = self.out.lock().unwrap();
+        if self.rotation_due(&out, line.len()) {
+            // keep logging to the current file if it can't be rotated, a full disk is
+            // better than a gap in the log
+            if let Err(err) = self.rotate(&mut out) {
+                eprintln!("access log: rotating {}: {err}", self.path.display());
+            }
+        }
+        match out.file.write_all(line.as_bytes()) {
+            Ok(()) => out.size += line.len() as u64,
+            Err(err) => eprintln!("access log: {err}"),
+        }
+    }
+
+    /// Records that connection `id` was aborted by the server terminating, whatever it had
+    /// been doing.
+    pub fn record_terminated(&self, id: ConnectionId, client: SocketAddr) {
+        let line = format!("{} {}\n", Timestamp::now(), terminated_fields(id, client));
+        let mut out = self.out.lock().unwrap();
+        match out.file.write_all(line.as_bytes()) {
+            Ok(()) => out.size += line.len() as u64,
+            Err(err) => eprintln!("access log: {err}"),
+        }
+    }
+
+    /// Makes sure every line recorded so far reached the disk.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut out = self.out.lock().unwrap();
+        out.file.flush()?;
+        out.file.get_ref().sync_data()
+    }
+
+    fn rotation_due(&self, out: &Output, line_len: usize) -> bool {
+        let Some(rotation) = &self.rotation else {
+            return false;
+        };
+        let too_large = rotation
+            .max_size_bytes
+            .is_some_and(|max| out.size > 0 && out.size + line_len as u64 > max);
+        let too_old = rotation
+            .interval_secs
+            .is_some_and(|secs| out.opened.elapsed() >= Duration::from_secs(secs));
+        too_large || too_old
+    }
+
+    /// Moves `<path>.N` to `<path>.N+1` for every kept file, the current file to
+    /// `<path>.1`, and starts a new current file.
+    fn rotate(&self, out: &mut Output) -> io::Result<()> {
+        let keep = self.rotation.as_ref().map_or(0, |rotation| rotation.keep);
+        out.file.flush()?;
+        if keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            remove_if_exists(&self.rotated_path(keep))?;
+            for n in (1..keep).rev() {
+                rename_if_exists(&self.rotated_path(n), &self.rotated_path(n + 1))?;
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        *out = Output::open(&self.path)?;
+        Ok(())
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{n}"));
+        name.into()
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn format_record(now: Timestamp, record: &AccessRecord) -> String {
+    format!("{now} {}\n", format_fields(record))
+}
+
+/// The fields of a `terminated` line, after the time.
+pub(crate) fn terminated_fields(id: ConnectionId, client: SocketAddr) -> String {
+    format!("{id} {client} - - - - terminated")
+}
+
+/// The fields of a record's line after the time, which syslog messages carry in their header
+/// instead.
+pub(crate) fn format_fields(record: &AccessRecord) -> String {
+    let request = record.request;
+    let cmd = match request.cmd {
+        proto::ClientCommand::EstablishConnection => "CONNECT",
+        proto::ClientCommand::EstablishPortBinding => "BIND",
+        proto::ClientCommand::AssociateUdpPort => "UDP_ASSOCIATE",
+    };
+    let connected = record
+        .connected
+        .map_or_else(|| "-".to_owned(), |addr| addr.to_string());
+    let result = match record.result {
+        Ok(()) => "ok".to_owned(),
+        Err(err) => format!("{:?}", err.kind()),
+    };
+    format!(
+        "{} {} {} {cmd} {} {connected} {result}",
+        record.id,
+        record.client,
+        record.username.map_or_else(|| "-".to_owned(), escape_field),
+        escape_field(&format!("{}:{}", request.dest_addr, request.dest_port)),
+    )
+}
+
+/// Escapes a field the client controls, such as a username or a requested domain name, so
+/// that it can't split the line or its fields.
+pub(crate) fn escape_field(field: &str) -> String {
+    field.escape_default().to_string().replace(' ', "\\x20")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn records_resolved_address_of_domain_requests() {
+        let request = proto::ClientConnectionRequest {
+            cmd: proto::ClientCommand::EstablishConnection,
+            dest_addr: proto::Address::DomainName("example.com".to_owned()),
+            dest_port: 443,
+        };
+        let record = AccessRecord {
+            id: ConnectionId(7),
+            client: "192.0.2.1:50000".parse().unwrap(),
+            username: Some("al ice\n"),
+            request: &request,
+            connected: Some("93.184.216.34:443".parse().unwrap()),
+            result: Ok(()),
+        };
+        assert_eq!(
+            format_record("2024-01-03T10:00:00Z".parse().unwrap(), &record),
+            "2024-01-03T10:00:00Z conn-7 192.0.2.1:50000 al\\x20ice\\n \
+             CONNECT example.com:443 93.184.216.34:443 ok\n"
+        );
+
+        let request = proto::ClientConnectionRequest {
+            dest_addr: proto::Address::DomainName("a.com:1 ok\nforged".to_owned()),
+            ..request
+        };
+        let record = AccessRecord {
+            username: None,
+            request: &request,
+            ..record
+        };
+        assert_eq!(
+            format_fields(&record),
+            "conn-7 192.0.2.1:50000 - CONNECT a.com:1\\x20ok\\nforged:443 93.184.216.34:443 ok"
+        );
+    }
+
+    #[test]
+    fn rotates_by_size_keeping_the_configured_number_of_files() {
+        let dir = env::temp_dir().join(format!("socks5-access-log-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let rotation = LogRotationConfig {
+            max_size_bytes: Some(150),
+            interval_secs: None,
+            keep: 2,
+        };
+        let log = AccessLog::open(&path, Some(&rotation)).unwrap();
+
+        let request = proto::ClientConnectionRequest {
+            cmd: proto::ClientCommand::EstablishConnection,
+            dest_addr: proto::Address::DomainName("example.com".to_owned()),
+            dest_port: 443,
+        };
+        for id in 1..=4 {
+            log.record(&AccessRecord {
+                id: ConnectionId(id),
+                client: "192.0.2.1:50000".parse().unwrap(),
+                username: None,
+                request: &request,
+                connected: None,
+                result: Ok(()),
+            });
+        }
+
+        // lines are around 80 bytes, so each file holds one
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert!(read("access.log").contains(" conn-4 "));
+        assert!(read("access.log.1").contains(" conn-3 "));
+        assert!(read("access.log.2").contains(" conn-2 "));
+        assert!(!dir.join("access.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
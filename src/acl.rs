@@ -0,0 +1,549 @@
+//! Allow/deny rules for client requests, evaluated in order before the request is served.
+//!
+//! The first rule matching a request decides whether it is allowed. Requests matching no rule
+//! get the configured default action. Rules can carry a schedule, in which case they only
+//! match during the configured days and hours.
+//!
+//! Clients are matched by address or network, destinations by name, by domain along with the
+//! names under it, or by address or network, optionally on a single port. The rules are
+//! compiled into lookups when the acl is loaded, so evaluating a request doesn't slow down
+//! with the number of rules.
+//!
+//! The destination port policy is checked first, and denies requests regardless of the rules.
+//!
+//! In dry run, requests the acl denies are logged and served anyway, so that a new policy can
+//! be tried on real traffic before it is enforced. At most one denial is logged a second, along
+//! with how many went unlogged before it.
+//!
+//! Each rule, and the default action, counts the requests it decided and the bytes relayed
+//! for those it allowed, which the server's metrics break down by rule.
+
+mod matcher;
+
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use jiff::{civil::Time, civil::Weekday, tz::TimeZone, Timestamp};
+use tokio::io;
+
+use crate::{
+    access_log::escape_field,
+    config::{AclAction, AclConfig, AclRuleConfig, PortPolicyConfig, ScheduleConfig},
+    dial::check_dscp,
+    proto,
+};
+use matcher::Matcher;
+
+pub struct Acl {
+    default: AclAction,
+    default_metrics: RuleMetrics,
+    rules: Vec<Rule>,
+    matcher: Matcher,
+    timezone: TimeZone,
+    dry_run: bool,
+    dry_run_log: Mutex<DryRunLog>,
+}
+
+/// How often at most dry run logs a request the acl would have denied.
+const DRY_RUN_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Throttles what dry run logs, so that a busy client can't flood the log.
+#[derive(Default)]
+struct DryRunLog {
+    /// when the last denial was logged
+    logged_at: Option<Instant>,
+    /// the denials not logged since
+    unlogged: u64,
+}
+
+impl DryRunLog {
+    /// Whether to log a denial at `now`, returning how many went unlogged before it if so.
+    fn admit(&mut self, now: Instant) -> Option<u64> {
+        if self
+            .logged_at
+            .is_some_and(|at| now.duration_since(at) < DRY_RUN_LOG_INTERVAL)
+        {
+            self.unlogged += 1;
+            return None;
+        }
+        self.logged_at = Some(now);
+        Some(std::mem::take(&mut self.unlogged))
+    }
+}
+
+struct Rule {
+    config: AclRuleConfig,
+    schedule: Option<Schedule>,
+    /// the rule's name, or else its index
+    label: String,
+    metrics: RuleMetrics,
+}
+
+/// What the requests one rule decided amounted to.
+#[derive(Debug, Default)]
+pub struct RuleMetrics {
+    pub requests: AtomicU64,
+    pub bytes_to_remote: AtomicU64,
+    pub bytes_to_client: AtomicU64,
+}
+
+struct Schedule {
+    /// every day when empty
+    days: Vec<Weekday>,
+    /// start inclusive, end exclusive. an end before the start wraps past midnight.
+    hours: Option<(Time, Time)>,
+}
+
+impl Acl {
+    pub fn new(config: AclConfig) -> io::Result<Self> {
+        let timezone = match &config.timezone {
+            Some(name) => TimeZone::get(name).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("acl timezone {name:?}: {err}"),
+                )
+            })?,
+            None => TimeZone::system(),
+        };
+        let matcher = Matcher::new(&config.rules)?;
+        let rules = config
+            .rules
+            .into_iter()
+            .enumerate()
+            .map(|(i, config)| {
+                if let Some(dscp) = config.dscp {
+                    check_dscp(dscp, &format!("acl rule {i}"))?;
+                }
+                let schedule = config
+                    .schedule
+                    .as_ref()
+                    .map(Schedule::parse)
+                    .transpose()
+                    .map_err(|err| io::Error::new(err.kind(), format!("acl rule {i}: {err}")))?;
+                let label = config.name.clone().unwrap_or_else(|| i.to_string());
+                Ok(Rule {
+                    config,
+                    schedule,
+                    label,
+                    metrics: RuleMetrics::default(),
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            default: config.default,
+            default_metrics: RuleMetrics::default(),
+            rules,
+            matcher,
+            timezone,
+            dry_run: config.dry_run,
+            dry_run_log: Mutex::default(),
+        })
+    }
+
+    /// Decides the request, counting it for the rule that did. Returns the counters of that
+    /// rule, or of the default action, for the bytes relayed for the request. In dry run,
+    /// denials are only logged.
+    pub fn check(
+        &self,
+        client: SocketAddr,
+        request: &proto::ClientConnectionRequest,
+    ) -> (AclAction, &RuleMetrics) {
+        let (action, metrics, rule) = self.check_at(client, request, Timestamp::now());
+        metrics.requests.fetch_add(1, Ordering::Relaxed);
+        if self.dry_run && action == AclAction::Deny {
+            let admitted = self.dry_run_log.lock().unwrap().admit(Instant::now());
+            if let Some(unlogged) = admitted {
+                eprintln!(
+                    "acl dry run: rule {} would have denied {:?} from {client} to {}:{} \
+                     ({unlogged} more denials unlogged)",
+                    escape_field(rule),
+                    request.cmd,
+                    escape_field(&request.dest_addr.to_string()),
+                    request.dest_port
+                );
+            }
+            return (AclAction::Allow, metrics);
+        }
+        (action, metrics)
+    }
+
+    /// Writes the counters of every rule in the prometheus text format, as series labeled
+    /// with `labels` besides the rule and its action.
+    pub(crate) fn render_metrics(&self, requests: &mut String, bytes: &mut String, labels: &str) {
+        let default = (&self.default_metrics, "default", self.default);
+        let rules = self
+            .rules
+            .iter()
+            .map(|rule| (&rule.metrics, rule.label.as_str(), rule.config.action));
+        for (metrics, rule, action) in rules.chain([default]) {
+            let action = match action {
+                AclAction::Allow => "allow",
+                AclAction::Deny => "deny",
+            };
+            let labels = format!(
+                "{labels}rule=\"{}\",action=\"{action}\"",
+                escape_label(rule)
+            );
+            let _ = writeln!(
+                requests,
+                "socks5_acl_requests_total{{{labels}}} {}",
+                metrics.requests.load(Ordering::Relaxed)
+            );
+            for (direction, value) in [
+                ("to_remote", &metrics.bytes_to_remote),
+                ("to_client", &metrics.bytes_to_client),
+            ] {
+                let _ = writeln!(
+                    bytes,
+                    "socks5_acl_relay_bytes_total{{{labels},direction=\"{direction}\"}} {}",
+                    value.load(Ordering::Relaxed)
+                );
+            }
+        }
+    }
+
+    /// The DSCP codepoint the rule deciding the request marks its connections with, if it
+    /// sets one.
+    pub fn dscp(&self, client: SocketAddr, request: &proto::ClientConnectionRequest) -> Option<u8> {
+        self.deciding_rule(client, request, Timestamp::now())
+            .and_then(|rule| rule.config.dscp)
+    }
+
+    fn check_at(
+        &self,
+        client: SocketAddr,
+        request: &proto::ClientConnectionRequest,
+        now: Timestamp,
+    ) -> (AclAction, &RuleMetrics, &str) {
+        self.deciding_rule(client, request, now)
+            .map_or((self.default, &self.default_metrics, "default"), |rule| {
+                (rule.config.action, &rule.metrics, &rule.label)
+            })
+    }
+
+    fn deciding_rule(
+        &self,
+        client: SocketAddr,
+        request: &proto::ClientConnectionRequest,
+        now: Timestamp,
+    ) -> Option<&Rule> {
+        let now = now.to_zoned(self.timezone.clone());
+        let (weekday, time) = (now.weekday(), now.time());
+        let active = |i: usize| {
+            self.rules[i]
+                .schedule
+                .as_ref()
+                .is_none_or(|s| s.is_active(weekday, time))
+        };
+        let i = self.matcher.first(client.ip(), request, active)?;
+        Some(&self.rules[i])
+    }
+}
+
+/// Destinations written like those of acl rules, that requests are checked against apart
+/// from any acl.
+pub(crate) struct Destinations {
+    /// `None` for an empty list, which allows nothing
+    matcher: Option<Matcher>,
+}
+
+impl Destinations {
+    #[cfg_attr(not(feature = "jwt"), allow(dead_code))]
+    pub(crate) fn new(destinations: Vec<String>) -> io::Result<Self> {
+        if destinations.is_empty() {
+            return Ok(Self { matcher: None });
+        }
+        let rule = AclRuleConfig {
+            name: None,
+            action: AclAction::Allow,
+            clients: Vec::new(),
+            destinations,
+            schedule: None,
+            dscp: None,
+        };
+        Ok(Self {
+            matcher: Some(Matcher::new(&[rule])?),
+        })
+    }
+
+    pub(crate) fn contains(
+        &self,
+        client: SocketAddr,
+        request: &proto::ClientConnectionRequest,
+    ) -> bool {
+        self.matcher
+            .as_ref()
+            .is_some_and(|matcher| matcher.first(client.ip(), request, |_| true).is_some())
+    }
+}
+
+pub struct PortPolicy {
+    config: PortPolicyConfig,
+}
+
+impl PortPolicy {
+    pub fn new(config: PortPolicyConfig) -> Self {
+        Self { config }
+    }
+
+    /// `username` is who the client authenticated as, if anyone.
+    pub fn allows(&self, username: Option<&str>, port: u16) -> bool {
+        let user = username.and_then(|name| self.config.users.get(name));
+        if let Some(user) = user {
+            if user.allowed.contains(&port) {
+                return true;
+            }
+            if user.denied.contains(&port) {
+                return false;
+            }
+        }
+        !self.config.denied.contains(&port)
+    }
+}
+
+impl Schedule {
+    fn parse(config: &ScheduleConfig) -> io::Result<Self> {
+        let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidInput, reason);
+        let days = config
+            .days
+            .iter()
+            .map(|day| parse_weekday(day).ok_or_else(|| invalid(format!("unknown day {day:?}"))))
+            .collect::<io::Result<_>>()?;
+        let hours = config
+            .hours
+            .as_deref()
+            .map(|hours| {
+                let parsed = hours.split_once('-').and_then(|(start, end)| {
+                    Some((start.trim().parse().ok()?, end.trim().parse().ok()?))
+                });
+                parsed.ok_or_else(|| {
+                    invalid(format!("hours {hours:?} should look like \"09:00-17:30\""))
+                })
+            })
+            .transpose()?;
+        Ok(Self { days, hours })
+    }
+
+    /// `weekday` is the day `time` falls on. Hours wrapping past midnight belong to the day
+    /// they start on, so after midnight they are active if the previous day is scheduled.
+    fn is_active(&self, weekday: Weekday, time: Time) -> bool {
+        let scheduled = |day| self.days.is_empty() || self.days.contains(&day);
+        match self.hours {
+            None => scheduled(weekday),
+            Some((start, end)) if start <= end => scheduled(weekday) && start <= time && time < end,
+            Some((start, end)) => {
+                (scheduled(weekday) && time >= start)
+                    || (scheduled(weekday.previous()) && time < end)
+            }
+        }
+    }
+}
+
+/// Escapes a label value of the prometheus text format.
+pub(crate) fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn parse_weekday(day: &str) -> Option<Weekday> {
+    let day = day.to_ascii_lowercase();
+    let weekday = match day.get(..3)? {
+        "mon" => Weekday::Monday,
+        "tue" => Weekday::Tuesday,
+        "wed" => Weekday::Wednesday,
+        "thu" => Weekday::Thursday,
+        "fri" => Weekday::Friday,
+        "sat" => Weekday::Saturday,
+        "sun" => Weekday::Sunday,
+        _ => return None,
+    };
+    // either the abbreviation or the full name
+    let full = format!("{weekday:?}").to_ascii_lowercase();
+    (day.len() == 3 || day == full).then_some(weekday)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scheduled_rules_only_match_during_their_hours() {
+        let config: AclConfig = toml::from_str(
+            r#"
+            default = "deny"
+            timezone = "UTC"
+
+            [[rules]]
+            action = "allow"
+            destinations = ["intranet.example.com"]
+            schedule = { days = ["mon", "tue", "wed", "thu", "friday"], hours = "09:00-17:00" }
+
+            [[rules]]
+            action = "allow"
+            destinations = ["backup.example.com"]
+            schedule = { hours = "22:00-06:00" }
+            "#,
+        )
+        .unwrap();
+        let acl = Acl::new(config).unwrap();
+        let client: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        let request = |host: &str| proto::ClientConnectionRequest {
+            cmd: proto::ClientCommand::EstablishConnection,
+            dest_addr: proto::Address::DomainName(host.to_owned()),
+            dest_port: 443,
+        };
+        let at = |ts: &str| ts.parse::<Timestamp>().unwrap();
+
+        // 2024-01-03 is a wednesday
+        let intranet = request("intranet.example.com");
+        let check = |req, ts| acl.check_at(client, req, at(ts)).0;
+        assert_eq!(check(&intranet, "2024-01-03T10:00:00Z"), AclAction::Allow);
+        assert_eq!(check(&intranet, "2024-01-03T17:00:00Z"), AclAction::Deny);
+        assert_eq!(check(&intranet, "2024-01-06T10:00:00Z"), AclAction::Deny);
+
+        let backup = request("backup.example.com");
+        assert_eq!(check(&backup, "2024-01-03T23:00:00Z"), AclAction::Allow);
+        assert_eq!(check(&backup, "2024-01-04T05:59:00Z"), AclAction::Allow);
+        assert_eq!(check(&backup, "2024-01-04T12:00:00Z"), AclAction::Deny);
+    }
+
+    #[test]
+    fn hours_past_midnight_belong_to_the_day_they_start_on() {
+        let config: AclConfig = toml::from_str(
+            r#"
+            default = "deny"
+            timezone = "UTC"
+
+            [[rules]]
+            action = "allow"
+            schedule = { days = ["fri"], hours = "22:00-02:00" }
+            "#,
+        )
+        .unwrap();
+        let acl = Acl::new(config).unwrap();
+        let client: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        let request = proto::ClientConnectionRequest {
+            cmd: proto::ClientCommand::EstablishConnection,
+            dest_addr: proto::Address::DomainName("example.com".to_owned()),
+            dest_port: 443,
+        };
+        let check = |ts: &str| acl.check_at(client, &request, ts.parse().unwrap()).0;
+
+        // 2024-01-05 is a friday
+        assert_eq!(check("2024-01-05T23:00:00Z"), AclAction::Allow);
+        assert_eq!(check("2024-01-06T01:00:00Z"), AclAction::Allow);
+        assert_eq!(check("2024-01-06T23:00:00Z"), AclAction::Deny);
+        assert_eq!(check("2024-01-05T01:00:00Z"), AclAction::Deny);
+    }
+
+    #[test]
+    fn counts_requests_for_the_rule_deciding_them() {
+        let config: AclConfig = toml::from_str(
+            r#"
+            default = "allow"
+
+            [[rules]]
+            name = "no \"ads\""
+            action = "deny"
+            destinations = ["ads.example.com"]
+
+            [[rules]]
+            action = "allow"
+            destinations = ["intranet.example.com"]
+            "#,
+        )
+        .unwrap();
+        let acl = Acl::new(config).unwrap();
+        let client: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        let request = |host: &str| proto::ClientConnectionRequest {
+            cmd: proto::ClientCommand::EstablishConnection,
+            dest_addr: proto::Address::DomainName(host.to_owned()),
+            dest_port: 443,
+        };
+        for host in ["ads.example.com", "ads.example.com", "www.example.com"] {
+            acl.check(client, &request(host));
+        }
+        acl.check_at(client, &request("intranet.example.com"), Timestamp::now())
+            .1
+            .bytes_to_client
+            .fetch_add(1500, Ordering::Relaxed);
+
+        let (mut requests, mut bytes) = (String::new(), String::new());
+        acl.render_metrics(&mut requests, &mut bytes, "listener=\"lan\",");
+        assert_eq!(
+            requests,
+            "socks5_acl_requests_total{listener=\"lan\",rule=\"no \\\"ads\\\"\",\
+             action=\"deny\"} 2\n\
+             socks5_acl_requests_total{listener=\"lan\",rule=\"1\",action=\"allow\"} 0\n\
+             socks5_acl_requests_total{listener=\"lan\",rule=\"default\",action=\"allow\"} 1\n"
+        );
+        assert!(bytes.contains(
+            "socks5_acl_relay_bytes_total{listener=\"lan\",rule=\"1\",action=\"allow\",\
+             direction=\"to_client\"} 1500\n"
+        ));
+    }
+
+    #[test]
+    fn dry_run_serves_what_it_would_deny() {
+        let config: AclConfig = toml::from_str(
+            r#"
+            default = "deny"
+            dry_run = true
+
+            [[rules]]
+            action = "allow"
+            destinations = ["example.com"]
+            "#,
+        )
+        .unwrap();
+        let acl = Acl::new(config).unwrap();
+        let client: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        let request = proto::ClientConnectionRequest {
+            cmd: proto::ClientCommand::EstablishConnection,
+            dest_addr: proto::Address::DomainName("example.net".to_owned()),
+            dest_port: 443,
+        };
+        assert_eq!(acl.check(client, &request).0, AclAction::Allow);
+        assert_eq!(acl.default_metrics.requests.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn dry_run_logs_at_most_one_denial_an_interval() {
+        let mut log = DryRunLog::default();
+        let start = Instant::now();
+        assert_eq!(log.admit(start), Some(0));
+        assert_eq!(log.admit(start + Duration::from_millis(10)), None);
+        assert_eq!(log.admit(start + Duration::from_millis(999)), None);
+        assert_eq!(log.admit(start + DRY_RUN_LOG_INTERVAL), Some(2));
+        assert_eq!(log.admit(start + DRY_RUN_LOG_INTERVAL * 3), Some(0));
+    }
+
+    #[test]
+    fn port_policy_has_per_user_exceptions() {
+        let policy = PortPolicy::new(
+            toml::from_str(
+                r#"
+                denied = [25, 465]
+                users.mailer = { allowed = [25] }
+                users.intern = { denied = [22] }
+                "#,
+            )
+            .unwrap(),
+        );
+        assert!(!policy.allows(None, 25));
+        assert!(policy.allows(None, 22));
+        assert!(policy.allows(Some("mailer"), 25));
+        assert!(!policy.allows(Some("mailer"), 465));
+        assert!(!policy.allows(Some("intern"), 22));
+        assert!(!policy.allows(Some("intern"), 25));
+    }
+}
@@ -0,0 +1,401 @@
+//! The clients and destinations of the acl rules, compiled when the acl is loaded so that
+//! finding the rules matching a request costs as much as walking the bits of an address or
+//! the labels of a name, however many rules there are.
+//!
+//! Networks go into a binary trie per address family, with the rules of a prefix at the node
+//! it ends at. Names go into a trie of their labels, read from the top-level domain down, with
+//! each node holding the rules of the exact name and of the names under it.
+
+use std::{collections::HashMap, net::IpAddr};
+
+use tokio::io;
+
+use crate::{config::AclRuleConfig, proto};
+
+pub(super) struct Matcher {
+    /// per rule, whether it leaves out clients and so matches any
+    any_client: Vec<bool>,
+    /// per rule, whether it leaves out destinations and so matches any
+    any_destination: Vec<bool>,
+    /// rules with neither, in order
+    match_all: Vec<usize>,
+    clients: PrefixTree<usize>,
+    ips: PrefixTree<(Option<u16>, usize)>,
+    domains: DomainTrie,
+}
+
+impl Matcher {
+    pub(super) fn new(rules: &[AclRuleConfig]) -> io::Result<Self> {
+        let mut matcher = Self {
+            any_client: Vec::with_capacity(rules.len()),
+            any_destination: Vec::with_capacity(rules.len()),
+            match_all: Vec::new(),
+            clients: PrefixTree::default(),
+            ips: PrefixTree::default(),
+            domains: DomainTrie::default(),
+        };
+        for (i, rule) in rules.iter().enumerate() {
+            let in_rule =
+                |err: io::Error| io::Error::new(err.kind(), format!("acl rule {i}: {err}"));
+            for client in &rule.clients {
+                let prefix = Prefix::parse(client).map_err(in_rule)?;
+                matcher.clients.insert(prefix, i);
+            }
+            for destination in &rule.destinations {
+                let (destination, port) = parse_destination(destination).map_err(in_rule)?;
+                match destination {
+                    Destination::Network(prefix) => matcher.ips.insert(prefix, (port, i)),
+                    Destination::Domain { name, subdomains } => {
+                        matcher.domains.insert(&name, subdomains, (port, i));
+                    }
+                }
+            }
+            matcher.any_client.push(rule.clients.is_empty());
+            matcher.any_destination.push(rule.destinations.is_empty());
+            if rule.clients.is_empty() && rule.destinations.is_empty() {
+                matcher.match_all.push(i);
+            }
+        }
+        Ok(matcher)
+    }
+
+    /// The first of the rules matching both `client` and the request's destination that is
+    /// `active`.
+    pub(super) fn first(
+        &self,
+        client: IpAddr,
+        request: &proto::ClientConnectionRequest,
+        active: impl Fn(usize) -> bool,
+    ) -> Option<usize> {
+        let mut clients = Vec::new();
+        self.clients.matches(client, |&i| clients.push(i));
+        clients.sort_unstable();
+        let client_matches = |i: usize| self.any_client[i] || clients.binary_search(&i).is_ok();
+
+        let mut candidates: Vec<usize> = clients
+            .iter()
+            .copied()
+            .filter(|&i| self.any_destination[i])
+            .collect();
+        let add = |&(port, i): &(Option<u16>, usize)| {
+            if port.is_none_or(|port| port == request.dest_port) && client_matches(i) {
+                candidates.push(i);
+            }
+        };
+        match &request.dest_addr {
+            proto::Address::Ipv4(ip) => self.ips.matches((*ip).into(), add),
+            proto::Address::Ipv6(ip) => self.ips.matches((*ip).into(), add),
+            proto::Address::DomainName(name) => match name.parse::<IpAddr>() {
+                Ok(ip) => self.ips.matches(ip, add),
+                Err(_) => self.domains.matches(name, add),
+            },
+        }
+        candidates.sort_unstable();
+
+        let first = |rules: &[usize]| rules.iter().copied().find(|&i| active(i));
+        match (first(&candidates), first(&self.match_all)) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+enum Destination {
+    Network(Prefix),
+    Domain { name: String, subdomains: bool },
+}
+
+/// Parses `host`, `.domain`, `ip` or `ip/len`, each optionally followed by `:port`, ipv6
+/// addresses and networks being bracketed when they are.
+fn parse_destination(destination: &str) -> io::Result<(Destination, Option<u16>)> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid destination {destination:?}"),
+        )
+    };
+    let parse_port = |port: &str| port.parse::<u16>().map_err(|_| invalid());
+
+    if let Some(bracketed) = destination.strip_prefix('[') {
+        let (net, rest) = bracketed.split_once(']').ok_or_else(invalid)?;
+        let port = match rest {
+            "" => None,
+            _ => Some(parse_port(rest.strip_prefix(':').ok_or_else(invalid)?)?),
+        };
+        return Ok((Destination::Network(Prefix::parse(net)?), port));
+    }
+    // an ipv6 address without brackets has no port
+    if let Ok(prefix) = Prefix::parse(destination) {
+        return Ok((Destination::Network(prefix), None));
+    }
+    let (host, port) = match destination.rsplit_once(':') {
+        Some((host, port)) => (host, Some(parse_port(port)?)),
+        None => (destination, None),
+    };
+    if let Ok(prefix) = Prefix::parse(host) {
+        return Ok((Destination::Network(prefix), port));
+    }
+    let (name, subdomains) = match host.strip_prefix('.') {
+        Some(domain) => (domain, true),
+        None => (host, false),
+    };
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.split('.').any(str::is_empty) || name.contains(['/', ':', '[', ']']) {
+        return Err(invalid());
+    }
+    Ok((
+        Destination::Domain {
+            name: name.to_ascii_lowercase(),
+            subdomains,
+        },
+        port,
+    ))
+}
+
+/// An address or network, ipv4 ones held in the last 32 bits.
+#[derive(Clone, Copy)]
+struct Prefix {
+    v6: bool,
+    bits: u128,
+    len: u8,
+}
+
+impl Prefix {
+    /// Parses an address, or a network as `address/len`. Host bits are ignored.
+    fn parse(s: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid address or network {s:?}"),
+            )
+        };
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len.parse::<u8>().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let mut prefix = Self::address(addr);
+        // a prefix of a mapped address is one of the ipv4 address
+        let mapped = addr.to_canonical() != addr;
+        if let Some(len) = len {
+            let len = if mapped {
+                len.checked_sub(96)
+            } else {
+                Some(len)
+            };
+            prefix.len = len.filter(|&len| len <= prefix.len).ok_or_else(invalid)?;
+        }
+        Ok(prefix)
+    }
+
+    fn address(addr: IpAddr) -> Self {
+        match addr.to_canonical() {
+            IpAddr::V4(ip) => Self {
+                v6: false,
+                bits: u32::from(ip).into(),
+                len: 32,
+            },
+            IpAddr::V6(ip) => Self {
+                v6: true,
+                bits: ip.into(),
+                len: 128,
+            },
+        }
+    }
+
+    /// The bits of the prefix, from the most significant down.
+    fn walk(&self) -> impl Iterator<Item = usize> + '_ {
+        let width = if self.v6 { 128 } else { 32 };
+        (0..self.len).map(move |i| ((self.bits >> (width - 1 - i)) & 1) as usize)
+    }
+}
+
+struct PrefixTree<T> {
+    v4: Vec<PrefixNode<T>>,
+    v6: Vec<PrefixNode<T>>,
+}
+
+struct PrefixNode<T> {
+    children: [Option<usize>; 2],
+    values: Vec<T>,
+}
+
+impl<T> Default for PrefixTree<T> {
+    fn default() -> Self {
+        Self {
+            v4: vec![PrefixNode::default()],
+            v6: vec![PrefixNode::default()],
+        }
+    }
+}
+
+impl<T> Default for PrefixNode<T> {
+    fn default() -> Self {
+        Self {
+            children: [None; 2],
+            values: Vec::new(),
+        }
+    }
+}
+
+impl<T> PrefixTree<T> {
+    fn insert(&mut self, prefix: Prefix, value: T) {
+        let nodes = if prefix.v6 {
+            &mut self.v6
+        } else {
+            &mut self.v4
+        };
+        let mut node = 0;
+        for bit in prefix.walk() {
+            node = match nodes[node].children[bit] {
+                Some(child) => child,
+                None => {
+                    nodes.push(PrefixNode::default());
+                    let child = nodes.len() - 1;
+                    nodes[node].children[bit] = Some(child);
+                    child
+                }
+            };
+        }
+        nodes[node].values.push(value);
+    }
+
+    /// Calls `f` with the values of every prefix `ip` is in.
+    fn matches(&self, ip: IpAddr, mut f: impl FnMut(&T)) {
+        let addr = Prefix::address(ip);
+        let nodes = if addr.v6 { &self.v6 } else { &self.v4 };
+        let mut node = Some(0);
+        let mut bits = addr.walk();
+        while let Some(current) = node {
+            nodes[current].values.iter().for_each(&mut f);
+            node = bits.next().and_then(|bit| nodes[current].children[bit]);
+        }
+    }
+}
+
+#[derive(Default)]
+struct DomainTrie {
+    nodes: Vec<DomainNode>,
+}
+
+#[derive(Default)]
+struct DomainNode {
+    children: HashMap<String, usize>,
+    /// rules of exactly this name
+    exact: Vec<(Option<u16>, usize)>,
+    /// rules of this name and every name under it
+    subdomains: Vec<(Option<u16>, usize)>,
+}
+
+impl DomainTrie {
+    fn insert(&mut self, name: &str, subdomains: bool, value: (Option<u16>, usize)) {
+        if self.nodes.is_empty() {
+            self.nodes.push(DomainNode::default());
+        }
+        let mut node = 0;
+        for label in name.rsplit('.') {
+            node = match self.nodes[node].children.get(label) {
+                Some(&child) => child,
+                None => {
+                    self.nodes.push(DomainNode::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children.insert(label.to_owned(), child);
+                    child
+                }
+            };
+        }
+        let node = &mut self.nodes[node];
+        if subdomains {
+            node.subdomains.push(value);
+        } else {
+            node.exact.push(value);
+        }
+    }
+
+    /// Calls `f` with the values of the rules `name` is, or is under.
+    fn matches(&self, name: &str, mut f: impl FnMut(&(Option<u16>, usize))) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let name = name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase();
+        let mut node = 0;
+        for label in name.rsplit('.') {
+            match self.nodes[node].children.get(label) {
+                Some(&child) => node = child,
+                None => return,
+            }
+            self.nodes[node].subdomains.iter().for_each(&mut f);
+        }
+        self.nodes[node].exact.iter().for_each(f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    #[test]
+    fn finds_the_first_rule_by_network_and_domain() {
+        let rule = |clients: &[&str], destinations: &[&str]| -> AclRuleConfig {
+            toml::from_str(&format!(
+                "action = \"allow\"\nclients = {clients:?}\ndestinations = {destinations:?}"
+            ))
+            .unwrap()
+        };
+        let rules = [
+            rule(&["10.0.0.0/8"], &[".internal.example.com"]),
+            rule(&[], &["mail.example.com:25", "[2001:db8::/32]:443"]),
+            rule(&["10.1.0.0/16", "::ffff:192.0.2.1"], &[]),
+            rule(&[], &["192.0.2.0/24", "Example.com"]),
+            rule(&[], &[]),
+        ];
+        let matcher = Matcher::new(&rules).unwrap();
+        let first = |client: &str, dest: &str, port| {
+            let dest_addr = match dest.parse::<IpAddr>() {
+                Ok(ip) => SocketAddr::new(ip, port).into(),
+                Err(_) => proto::Address::DomainName(dest.to_owned()),
+            };
+            let request = proto::ClientConnectionRequest {
+                cmd: proto::ClientCommand::EstablishConnection,
+                dest_addr,
+                dest_port: port,
+            };
+            matcher.first(client.parse().unwrap(), &request, |i| i != 1)
+        };
+
+        assert_eq!(first("10.2.3.4", "db.Internal.example.com.", 5432), Some(0));
+        assert_eq!(first("10.2.3.4", "internal.example.com", 80), Some(0));
+        assert_eq!(first("10.2.3.4", "xinternal.example.com", 80), Some(4));
+        assert_eq!(first("10.1.2.3", "xinternal.example.com", 80), Some(2));
+        assert_eq!(
+            first("::ffff:10.1.2.3", "xinternal.example.com", 80),
+            Some(2)
+        );
+        assert_eq!(first("192.0.2.1", "example.com", 80), Some(2));
+        assert_eq!(first("192.0.2.7", "example.com", 80), Some(3));
+        assert_eq!(first("192.0.2.7", "www.example.com", 80), Some(4));
+        assert_eq!(first("192.0.2.7", "192.0.2.200", 80), Some(3));
+        // the second rule is inactive
+        assert_eq!(first("192.0.2.7", "mail.example.com", 25), Some(4));
+        assert_eq!(first("192.0.2.7", "2001:db8::1", 443), Some(4));
+
+        let inactive = Matcher::new(&rules[..2]).unwrap();
+        let request = proto::ClientConnectionRequest {
+            cmd: proto::ClientCommand::EstablishConnection,
+            dest_addr: "[2001:db8::1]:443".parse::<SocketAddr>().unwrap().into(),
+            dest_port: 443,
+        };
+        let client = "192.0.2.7".parse().unwrap();
+        assert_eq!(inactive.first(client, &request, |_| true), Some(1));
+        assert_eq!(inactive.first(client, &request, |i| i != 1), None);
+
+        for destination in ["", "..example.com", "10.0.0.0/33", "[::1", "host:port"] {
+            assert!(
+                Matcher::new(&[rule(&[], &[destination])]).is_err(),
+                "{destination}"
+            );
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Username/password (RFC 1929) authentication of clients.
+//!
+//! When the server config has an `auth` section, clients must pick the username/password
+//! method and their credentials are checked by an [`Authenticator`]. Without one, only the
+//! no-auth method is offered.
+
+#[cfg(feature = "jwt")]
+mod jwt;
+#[cfg(feature = "ldap")]
+mod ldap;
+#[cfg(all(unix, feature = "pam"))]
+mod pam;
+mod reload;
+mod totp;
+
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use futures::future::BoxFuture;
+use tokio::io;
+
+use crate::{
+    acl::Destinations,
+    config::{AuthBackend, AuthConfig, UserConfig},
+    proto,
+    transport::ServerStream,
+};
+
+#[cfg(feature = "jwt")]
+pub use jwt::JwtAuthenticator;
+#[cfg(feature = "ldap")]
+pub use ldap::LdapAuthenticator;
+#[cfg(all(unix, feature = "pam"))]
+pub use pam::PamAuthenticator;
+pub use reload::ReloadingCredentials;
+
+pub trait Authenticator: Send + Sync {
+    /// Returns whether the credentials are valid. Errors are reserved for failing to check
+    /// them at all.
+    fn authenticate<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, io::Result<bool>>;
+
+    /// Like [`Authenticator::authenticate`], returning what valid credentials entitle the
+    /// client to, which is anything the server's policies allow unless the authenticator
+    /// restricts it further.
+    fn authorize<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, io::Result<Option<Grant>>> {
+        Box::pin(async move {
+            let valid = self.authenticate(username, password).await?;
+            Ok(valid.then(Grant::default))
+        })
+    }
+}
+
+/// What a client's credentials entitle it to.
+#[derive(Default)]
+pub struct Grant {
+    /// the only destinations the client may request, if it is restricted to some
+    destinations: Option<Destinations>,
+}
+
+impl Grant {
+    #[cfg_attr(not(feature = "jwt"), allow(dead_code))]
+    pub(crate) fn restricted_to(destinations: Destinations) -> Self {
+        Self {
+            destinations: Some(destinations),
+        }
+    }
+
+    /// Whether the client may make `request`. The address of a BIND names no destination, so
+    /// clients restricted to some may not bind, and the destinations of UDP ASSOCIATE are
+    /// those of each datagram, which are checked as they are sent.
+    pub(crate) fn allows(
+        &self,
+        client: SocketAddr,
+        request: &proto::ClientConnectionRequest,
+    ) -> bool {
+        self.destinations.as_ref().is_none_or(|destinations| {
+            request.cmd != proto::ClientCommand::EstablishPortBinding
+                && destinations.contains(client, request)
+        })
+    }
+}
+
+/// The sub-negotiation of an auth method from the private range, 0x80 to 0xfe, registered
+/// with [`Server::register_auth_method`](crate::server::Server::register_auth_method).
+pub trait PrivateAuthMethod: Send + Sync {
+    /// Runs the method's exchange with the client once the server has chosen it, returning
+    /// the name the client authenticated as. Implementations should tell the client it was
+    /// rejected before returning an error, which ends up in the server's log and so shouldn't
+    /// quote what the client sent.
+    fn negotiate<'a>(
+        &'a self,
+        stream: &'a mut dyn ServerStream,
+    ) -> BoxFuture<'a, io::Result<String>>;
+}
+
+/// Users configured in the `auth.users` section of the server config.
+pub struct CredentialStore {
+    users: HashMap<String, User>,
+}
+
+struct User {
+    password: String,
+    totp: Option<totp::Totp>,
+}
+
+impl CredentialStore {
+    pub fn new(users: &HashMap<String, UserConfig>) -> io::Result<Self> {
+        let users = users
+            .iter()
+            .map(|(name, user)| {
+                let totp = user
+                    .totp_secret
+                    .as_deref()
+                    .map(totp::Totp::new)
+                    .transpose()
+                    .map_err(|err| io::Error::new(err.kind(), format!("user {name}: {err}")))?;
+                let user = User {
+                    password: user.password.clone(),
+                    totp,
+                };
+                Ok((name.clone(), user))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self { users })
+    }
+
+    /// Carries over totp replay protection from the store this one replaces, for users whose
+    /// secret didn't change.
+    fn inherit_totp_state(&mut self, previous: &CredentialStore) {
+        for (name, user) in &self.users {
+            let previous = previous.users.get(name).and_then(|u| u.totp.as_ref());
+            if let (Some(totp), Some(previous)) = (&user.totp, previous) {
+                totp.inherit(previous);
+            }
+        }
+    }
+
+    fn check(&self, username: &str, password: &str) -> bool {
+        let Some(user) = self.users.get(username) else {
+            return false;
+        };
+        let Some(totp) = &user.totp else {
+            return constant_time_eq(password.as_bytes(), user.password.as_bytes());
+        };
+
+        // users with a totp secret append the current code to their password
+        let Some(split) = password.len().checked_sub(totp::DIGITS) else {
+            return false;
+        };
+        let Some((password, code)) = password
+            .is_char_boundary(split)
+            .then(|| password.split_at(split))
+        else {
+            return false;
+        };
+        // both halves are always checked, so timing doesn't tell which one was wrong
+        let password_ok = constant_time_eq(password.as_bytes(), user.password.as_bytes());
+        let code_ok = totp.verify(code);
+        password_ok && code_ok
+    }
+}
+
+impl Authenticator for CredentialStore {
+    fn authenticate<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, io::Result<bool>> {
+        let ok = self.check(username, password);
+        Box::pin(async move { Ok(ok) })
+    }
+}
+
+pub fn from_config(config: &AuthConfig) -> io::Result<Box<dyn Authenticator>> {
+    match config.backend {
+        AuthBackend::Users => match &config.users_file {
+            Some(path) => Ok(Box::new(ReloadingCredentials::new(
+                path,
+                &config.users,
+                Duration::from_secs(config.reload_interval_secs),
+            )?)),
+            None => Ok(Box::new(CredentialStore::new(&config.users)?)),
+        },
+        #[cfg(feature = "ldap")]
+        AuthBackend::Ldap => {
+            let ldap = config.ldap.clone().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the ldap auth backend requires an [auth.ldap] section",
+                )
+            })?;
+            Ok(Box::new(LdapAuthenticator::new(ldap)?))
+        }
+        #[cfg(not(feature = "ldap"))]
+        AuthBackend::Ldap => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the ldap auth backend requires socks5 to be built with the `ldap` feature",
+        )),
+        #[cfg(all(unix, feature = "pam"))]
+        AuthBackend::Pam => Ok(Box::new(PamAuthenticator::new(
+            &config.pam.clone().unwrap_or_default(),
+        )?)),
+        #[cfg(not(all(unix, feature = "pam")))]
+        AuthBackend::Pam => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the pam auth backend requires a unix build of socks5 with the `pam` feature",
+        )),
+        #[cfg(feature = "jwt")]
+        AuthBackend::Jwt => {
+            let jwt = config.jwt.as_ref().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the jwt auth backend requires an [auth.jwt] section",
+                )
+            })?;
+            Ok(Box::new(JwtAuthenticator::new(jwt)?))
+        }
+        #[cfg(not(feature = "jwt"))]
+        AuthBackend::Jwt => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the jwt auth backend requires socks5 to be built with the `jwt` feature",
+        )),
+    }
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
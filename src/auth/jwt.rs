@@ -0,0 +1,275 @@
+//! Checks json web tokens clients pass as their password, for programmatic access where an
+//! issuer hands out short-lived tokens rather than accounts being set up on the proxy.
+//!
+//! A token is valid when it is signed with one of the configured keys, hasn't expired, names
+//! the client's username in its `sub` claim and matches the configured issuer and audience.
+//! A `destinations` claim restricts the client to the destinations it lists, written like
+//! those of acl rules.
+
+use std::fs;
+
+use futures::future::BoxFuture;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tokio::io;
+
+use super::{Authenticator, Grant};
+use crate::{
+    acl::Destinations,
+    config::{JwtConfig, JwtKeyConfig},
+};
+
+pub struct JwtAuthenticator {
+    keys: Vec<Key>,
+    config: JwtConfig,
+}
+
+struct Key {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    decoding: DecodingKey,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    destinations: Option<Vec<String>>,
+}
+
+impl JwtAuthenticator {
+    pub fn new(config: &JwtConfig) -> io::Result<Self> {
+        if config.keys.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the jwt auth backend requires at least one key",
+            ));
+        }
+        let keys = config
+            .keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                Key::new(key)
+                    .map_err(|err| io::Error::new(err.kind(), format!("jwt key {i}: {err}")))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            keys,
+            config: config.clone(),
+        })
+    }
+
+    /// What the token entitles the client to, if it is valid for `username`.
+    fn check(&self, username: &str, token: &str) -> Option<Grant> {
+        let header = jsonwebtoken::decode_header(token).ok()?;
+        let claims = self
+            .keys
+            .iter()
+            .filter(|key| key.algorithm == header.alg)
+            .filter(|key| header.kid.is_none() || key.kid == header.kid)
+            .find_map(|key| {
+                jsonwebtoken::decode::<Claims>(token, &key.decoding, &self.validation(key)).ok()
+            })?
+            .claims;
+        if claims.sub != username {
+            return None;
+        }
+        match claims.destinations {
+            // a token with destinations that don't parse grants none of them
+            Some(destinations) => Destinations::new(destinations)
+                .ok()
+                .map(Grant::restricted_to),
+            None => Some(Grant::default()),
+        }
+    }
+
+    fn validation(&self, key: &Key) -> Validation {
+        let mut validation = Validation::new(key.algorithm);
+        validation.leeway = self.config.leeway_secs;
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Some(issuer) = &self.config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        validation
+    }
+}
+
+impl Key {
+    fn new(config: &JwtKeyConfig) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let algorithm: Algorithm = config
+            .algorithm
+            .parse()
+            .map_err(|_| invalid(format!("unknown algorithm {:?}", config.algorithm)))?;
+        let decoding = match (algorithm, &config.secret, &config.public_key) {
+            (Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512, Some(secret), None) => {
+                DecodingKey::from_secret(secret.as_bytes())
+            }
+            (Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512, _, _) => {
+                return Err(invalid(format!(
+                    "{algorithm:?} takes a secret, and no public key"
+                )))
+            }
+            (_, None, Some(path)) => {
+                let pem = fs::read(path)?;
+                let decoding = match algorithm {
+                    Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&pem),
+                    Algorithm::EdDSA => DecodingKey::from_ed_pem(&pem),
+                    _ => DecodingKey::from_rsa_pem(&pem),
+                };
+                decoding.map_err(|err| invalid(format!("{}: {err}", path.display())))?
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "{algorithm:?} takes a public key, and no secret"
+                )))
+            }
+        };
+        Ok(Self {
+            kid: config.kid.clone(),
+            algorithm,
+            decoding,
+        })
+    }
+}
+
+impl Authenticator for JwtAuthenticator {
+    fn authenticate<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, io::Result<bool>> {
+        let valid = self.check(username, password).is_some();
+        Box::pin(async move { Ok(valid) })
+    }
+
+    fn authorize<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, io::Result<Option<Grant>>> {
+        let grant = self.check(username, password);
+        Box::pin(async move { Ok(grant) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde::Serialize;
+
+    use super::*;
+    use crate::proto;
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        sub: &'a str,
+        exp: u64,
+        aud: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        destinations: Option<&'a [&'a str]>,
+    }
+
+    fn token(secret: &str, kid: Option<&str>, claims: &TestClaims) -> String {
+        let header = Header {
+            kid: kid.map(str::to_owned),
+            ..Header::default()
+        };
+        let key = EncodingKey::from_secret(secret.as_bytes());
+        jsonwebtoken::encode(&header, claims, &key).unwrap()
+    }
+
+    #[test]
+    fn accepts_signed_unexpired_tokens_of_the_user_restricted_to_their_destinations() {
+        let config: JwtConfig = toml::from_str(
+            r#"
+            audience = "socks5"
+            leeway_secs = 0
+            [[keys]]
+            kid = "old"
+            algorithm = "HS256"
+            secret = "old secret"
+            [[keys]]
+            kid = "new"
+            algorithm = "HS256"
+            secret = "new secret"
+            "#,
+        )
+        .unwrap();
+        let auth = JwtAuthenticator::new(&config).unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let claims = TestClaims {
+            sub: "robot",
+            exp: now + 300,
+            aud: "socks5",
+            destinations: Some(&[".example.com:443"]),
+        };
+        let client: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        let command = |cmd, host: &str| proto::ClientConnectionRequest {
+            cmd,
+            dest_addr: proto::Address::DomainName(host.to_owned()),
+            dest_port: 443,
+        };
+        let request = |host: &str| command(proto::ClientCommand::EstablishConnection, host);
+
+        let grant = auth
+            .check("robot", &token("new secret", Some("new"), &claims))
+            .unwrap();
+        assert!(grant.allows(client, &request("api.example.com")));
+        assert!(!grant.allows(client, &request("example.net")));
+        let datagram = |host| command(proto::ClientCommand::AssociateUdpPort, host);
+        assert!(grant.allows(client, &datagram("api.example.com")));
+        assert!(!grant.allows(client, &datagram("example.net")));
+        let bind = command(
+            proto::ClientCommand::EstablishPortBinding,
+            "api.example.com",
+        );
+        assert!(!grant.allows(client, &bind));
+        let unrestricted = TestClaims {
+            destinations: None,
+            ..claims
+        };
+        let grant = auth
+            .check("robot", &token("old secret", None, &unrestricted))
+            .unwrap();
+        assert!(grant.allows(client, &request("example.net")));
+        assert!(grant.allows(client, &bind));
+
+        assert!(auth
+            .check("human", &token("new secret", None, &claims))
+            .is_none());
+        assert!(auth
+            .check("robot", &token("new secret", Some("old"), &claims))
+            .is_none());
+        assert!(auth
+            .check("robot", &token("other secret", None, &claims))
+            .is_none());
+        let expired = TestClaims {
+            exp: now - 1,
+            ..claims
+        };
+        assert!(auth
+            .check("robot", &token("new secret", None, &expired))
+            .is_none());
+        let elsewhere = TestClaims {
+            aud: "elsewhere",
+            ..claims
+        };
+        assert!(auth
+            .check("robot", &token("new secret", None, &elsewhere))
+            .is_none());
+        assert!(auth.check("robot", "not a token").is_none());
+    }
+}
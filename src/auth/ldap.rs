@@ -0,0 +1,78 @@
+//! Checks credentials by binding to an LDAP or Active Directory server as the client's user.
+
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use ldap3::{dn_escape, LdapConnAsync, LdapConnSettings};
+use tokio::io;
+
+use super::Authenticator;
+use crate::config::LdapConfig;
+
+const RC_SUCCESS: u32 = 0;
+const RC_INVALID_CREDENTIALS: u32 = 49;
+
+pub struct LdapAuthenticator {
+    config: LdapConfig,
+}
+
+impl LdapAuthenticator {
+    pub fn new(config: LdapConfig) -> io::Result<Self> {
+        if !config.bind_dn.contains("{username}") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ldap bind_dn must contain a {username} placeholder",
+            ));
+        }
+        Ok(Self { config })
+    }
+
+    async fn bind(&self, username: &str, password: &str) -> io::Result<bool> {
+        // an empty password makes for an unauthenticated bind, which most servers accept
+        if username.is_empty() || password.is_empty() {
+            return Ok(false);
+        }
+
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(Duration::from_secs(self.config.timeout_secs))
+            .set_starttls(self.config.starttls);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url)
+            .await
+            .map_err(ldap_error)?;
+        ldap3::drive!(conn);
+
+        let dn = self
+            .config
+            .bind_dn
+            .replace("{username}", &dn_escape(username));
+        let res = ldap
+            .with_timeout(Duration::from_secs(self.config.timeout_secs))
+            .simple_bind(&dn, password)
+            .await
+            .map_err(ldap_error)?;
+        let _ = ldap.unbind().await;
+
+        match res.rc {
+            RC_SUCCESS => Ok(true),
+            RC_INVALID_CREDENTIALS => Ok(false),
+            rc => Err(io::Error::other(format!(
+                "ldap bind failed with result code {rc}: {}",
+                res.text
+            ))),
+        }
+    }
+}
+
+impl Authenticator for LdapAuthenticator {
+    fn authenticate<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, io::Result<bool>> {
+        Box::pin(self.bind(username, password))
+    }
+}
+
+fn ldap_error(err: ldap3::LdapError) -> io::Error {
+    io::Error::other(format!("ldap: {err}"))
+}
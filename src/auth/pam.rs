@@ -0,0 +1,214 @@
+//! Checks credentials against the host's system accounts through PAM.
+//!
+//! Each attempt runs `pam_authenticate` and `pam_acct_mgmt` for the configured service on a
+//! blocking thread, answering password prompts with the client's password.
+
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    ptr,
+};
+
+use futures::future::BoxFuture;
+use tokio::io;
+
+use super::Authenticator;
+use crate::config::PamConfig;
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_PERM_DENIED: c_int = 6;
+const PAM_AUTH_ERR: c_int = 7;
+const PAM_CRED_INSUFFICIENT: c_int = 8;
+const PAM_USER_UNKNOWN: c_int = 10;
+const PAM_MAXTRIES: c_int = 11;
+const PAM_NEW_AUTHTOK_REQD: c_int = 12;
+const PAM_ACCT_EXPIRED: c_int = 13;
+const PAM_CONV_ERR: c_int = 19;
+
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_PROMPT_ECHO_ON: c_int = 2;
+const PAM_ERROR_MSG: c_int = 3;
+const PAM_TEXT_INFO: c_int = 4;
+
+#[repr(C)]
+struct PamHandle {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+type ConvFn = extern "C" fn(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int;
+
+#[repr(C)]
+struct PamConv {
+    conv: ConvFn,
+    appdata_ptr: *mut c_void,
+}
+
+#[link(name = "pam")]
+extern "C" {
+    fn pam_start(
+        service_name: *const c_char,
+        user: *const c_char,
+        pam_conversation: *const PamConv,
+        pamh: *mut *mut PamHandle,
+    ) -> c_int;
+    fn pam_end(pamh: *mut PamHandle, pam_status: c_int) -> c_int;
+    fn pam_authenticate(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_acct_mgmt(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_strerror(pamh: *mut PamHandle, errnum: c_int) -> *const c_char;
+}
+
+pub struct PamAuthenticator {
+    service: CString,
+}
+
+impl PamAuthenticator {
+    pub fn new(config: &PamConfig) -> io::Result<Self> {
+        let service = CString::new(config.service.as_str()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pam service name must not contain nul bytes",
+            )
+        })?;
+        Ok(Self { service })
+    }
+}
+
+impl Authenticator for PamAuthenticator {
+    fn authenticate<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, io::Result<bool>> {
+        Box::pin(async move {
+            // credentials with nul bytes cannot be passed to pam, and so cannot be valid
+            let (Ok(username), Ok(password)) = (CString::new(username), CString::new(password))
+            else {
+                return Ok(false);
+            };
+            let service = self.service.clone();
+            // pam modules block, some of them (pam_unix, pam_faildelay) for seconds on failure
+            tokio::task::spawn_blocking(move || pam_check(&service, &username, &password))
+                .await
+                .map_err(io::Error::other)?
+        })
+    }
+}
+
+/// Credentials handed to the conversation function.
+struct Conversation<'a> {
+    username: &'a CStr,
+    password: &'a CStr,
+}
+
+fn pam_check(service: &CStr, username: &CStr, password: &CStr) -> io::Result<bool> {
+    let mut conversation = Conversation { username, password };
+    let conv = PamConv {
+        conv: converse,
+        appdata_ptr: &mut conversation as *mut Conversation as *mut c_void,
+    };
+
+    let mut handle = ptr::null_mut();
+    let ret = unsafe { pam_start(service.as_ptr(), username.as_ptr(), &conv, &mut handle) };
+    if ret != PAM_SUCCESS {
+        return Err(pam_error(handle, ret));
+    }
+
+    let mut ret = unsafe { pam_authenticate(handle, 0) };
+    if ret == PAM_SUCCESS {
+        ret = unsafe { pam_acct_mgmt(handle, 0) };
+    }
+    let res = match ret {
+        PAM_SUCCESS => Ok(true),
+        PAM_AUTH_ERR
+        | PAM_USER_UNKNOWN
+        | PAM_PERM_DENIED
+        | PAM_CRED_INSUFFICIENT
+        | PAM_MAXTRIES
+        | PAM_NEW_AUTHTOK_REQD
+        | PAM_ACCT_EXPIRED => Ok(false),
+        ret => Err(pam_error(handle, ret)),
+    };
+    unsafe { pam_end(handle, ret) };
+    res
+}
+
+extern "C" fn converse(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int {
+    let Ok(num_msg) = usize::try_from(num_msg) else {
+        return PAM_CONV_ERR;
+    };
+    let conversation = unsafe { &*(appdata_ptr as *const Conversation) };
+
+    // pam takes ownership of the responses and frees them, so they must come from malloc
+    let replies =
+        unsafe { libc::calloc(num_msg, std::mem::size_of::<PamResponse>()) } as *mut PamResponse;
+    if replies.is_null() {
+        return PAM_BUF_ERR;
+    }
+
+    for i in 0..num_msg {
+        let style = unsafe { (**msg.add(i)).msg_style };
+        let answer = match style {
+            PAM_PROMPT_ECHO_OFF => Some(conversation.password),
+            PAM_PROMPT_ECHO_ON => Some(conversation.username),
+            PAM_ERROR_MSG | PAM_TEXT_INFO => None,
+            _ => {
+                free_responses(replies, i);
+                return PAM_CONV_ERR;
+            }
+        };
+        if let Some(answer) = answer {
+            let copy = unsafe { libc::strdup(answer.as_ptr()) };
+            if copy.is_null() {
+                free_responses(replies, i);
+                return PAM_BUF_ERR;
+            }
+            unsafe { (*replies.add(i)).resp = copy };
+        }
+    }
+
+    unsafe { *resp = replies };
+    PAM_SUCCESS
+}
+
+fn free_responses(replies: *mut PamResponse, filled: usize) {
+    unsafe {
+        for i in 0..filled {
+            libc::free((*replies.add(i)).resp as *mut c_void);
+        }
+        libc::free(replies as *mut c_void);
+    }
+}
+
+fn pam_error(handle: *mut PamHandle, ret: c_int) -> io::Error {
+    let reason = unsafe { pam_strerror(handle, ret) };
+    let reason = if reason.is_null() {
+        format!("error {ret}")
+    } else {
+        unsafe { CStr::from_ptr(reason) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    io::Error::other(format!("pam: {reason}"))
+}
@@ -0,0 +1,149 @@
+//! Accounts from a `users_file` that is picked up again whenever it changes on disk.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use tokio::io;
+
+use super::{Authenticator, CredentialStore};
+use crate::config::UserConfig;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UsersFile {
+    #[serde(default)]
+    users: HashMap<String, UserConfig>,
+}
+
+pub struct ReloadingCredentials {
+    path: PathBuf,
+    inline: HashMap<String, UserConfig>,
+    interval: Duration,
+    loaded: Mutex<Loaded>,
+}
+
+struct Loaded {
+    store: Arc<CredentialStore>,
+    modified: SystemTime,
+    checked: Instant,
+}
+
+impl ReloadingCredentials {
+    pub fn new(
+        path: impl Into<PathBuf>,
+        inline: &HashMap<String, UserConfig>,
+        interval: Duration,
+    ) -> io::Result<Self> {
+        let path = path.into();
+        let modified = fs::metadata(&path)?.modified()?;
+        let store = load(&path, inline)?;
+        Ok(Self {
+            path,
+            inline: inline.clone(),
+            interval,
+            loaded: Mutex::new(Loaded {
+                store: Arc::new(store),
+                modified,
+                checked: Instant::now(),
+            }),
+        })
+    }
+
+    /// Returns the current accounts, reloading them first if the file changed since it was
+    /// last checked. A file that fails to load is logged and the previous accounts are kept.
+    fn current(&self) -> Arc<CredentialStore> {
+        let mut loaded = self.loaded.lock().unwrap();
+        if loaded.checked.elapsed() < self.interval {
+            return loaded.store.clone();
+        }
+        loaded.checked = Instant::now();
+
+        match fs::metadata(&self.path).and_then(|m| m.modified()) {
+            Ok(modified) if modified != loaded.modified => {
+                loaded.modified = modified;
+                match load(&self.path, &self.inline) {
+                    Ok(mut store) => {
+                        store.inherit_totp_state(&loaded.store);
+                        eprintln!("users file {} reloaded", self.path.display());
+                        loaded.store = Arc::new(store);
+                    }
+                    Err(err) => {
+                        eprintln!("users file reload failed, keeping previous accounts: {err}")
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(err) => eprintln!("users file {}: {err}", self.path.display()),
+        }
+        loaded.store.clone()
+    }
+}
+
+impl Authenticator for ReloadingCredentials {
+    fn authenticate<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, io::Result<bool>> {
+        let ok = self.current().check(username, password);
+        Box::pin(async move { Ok(ok) })
+    }
+}
+
+fn load(path: &Path, inline: &HashMap<String, UserConfig>) -> io::Result<CredentialStore> {
+    let contents = fs::read_to_string(path)?;
+    let file: UsersFile = toml::from_str(&contents).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("users file {}: {err}", path.display()),
+        )
+    })?;
+    let mut users = inline.clone();
+    users.extend(file.users);
+    CredentialStore::new(&users)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn picks_up_added_and_revoked_users() {
+        let path = env::temp_dir().join(format!("socks5-users-{}.toml", process::id()));
+        let write = |contents: &str, age: u64| {
+            fs::write(&path, contents).unwrap();
+            // mtimes can be coarse, make sure every write looks like a change
+            let mtime = SystemTime::now() - Duration::from_secs(age);
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        };
+
+        write("[users.alice]\npassword = \"a\"\n", 20);
+        let creds = ReloadingCredentials::new(&path, &HashMap::new(), Duration::ZERO).unwrap();
+        assert!(creds.current().check("alice", "a"));
+        assert!(!creds.current().check("bob", "b"));
+
+        write("[users.bob]\npassword = \"b\"\n", 10);
+        assert!(!creds.current().check("alice", "a"));
+        assert!(creds.current().check("bob", "b"));
+
+        // a broken file keeps the previous accounts around
+        write("[users.bob\n", 0);
+        assert!(creds.current().check("bob", "b"));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+//! RFC 6238 time-based one-time passwords, as generated by common authenticator apps: HMAC-SHA1,
+//! 30 second steps and 6 digits.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use tokio::io;
+
+pub(super) const DIGITS: usize = 6;
+const STEP_SECS: u64 = 30;
+// codes from the neighbouring steps are accepted too, to allow for clock drift
+const SKEW_STEPS: u64 = 1;
+
+pub(super) struct Totp {
+    secret: Vec<u8>,
+    /// the most recent step a code was accepted for. its code stays valid, since clients
+    /// opening connections in parallel send the same one with each, but codes of earlier
+    /// steps can't be used once a later one was.
+    last_used_step: AtomicU64,
+}
+
+impl Totp {
+    /// `secret` is base32 encoded, as shown to users when enrolling an authenticator app.
+    pub(super) fn new(secret: &str) -> io::Result<Self> {
+        let secret = base32_decode(secret).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "totp_secret is not valid base32",
+            )
+        })?;
+        Ok(Self {
+            secret,
+            last_used_step: AtomicU64::new(0),
+        })
+    }
+
+    /// Takes over the replay state of `previous` if it was built from the same secret.
+    pub(super) fn inherit(&self, previous: &Totp) {
+        if self.secret == previous.secret {
+            let last = previous.last_used_step.load(Ordering::Acquire);
+            self.last_used_step.fetch_max(last, Ordering::AcqRel);
+        }
+    }
+
+    pub(super) fn verify(&self, code: &str) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.verify_at(code, now / STEP_SECS)
+    }
+
+    fn verify_at(&self, code: &str, current_step: u64) -> bool {
+        let Ok(code) = code.parse::<u32>() else {
+            return false;
+        };
+        let matched = (current_step.saturating_sub(SKEW_STEPS)..=current_step + SKEW_STEPS)
+            .find(|&step| self.code_at(step) == code);
+        let Some(step) = matched else {
+            return false;
+        };
+        self.last_used_step
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |last| {
+                (step >= last).then_some(step)
+            })
+            .is_ok()
+    }
+
+    fn code_at(&self, step: u64) -> u32 {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.secret).expect("hmac accepts any key");
+        mac.update(&step.to_be_bytes());
+        let digest = mac.finalize().into_bytes();
+
+        let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+        let truncated = u32::from_be_bytes(digest[offset..offset + 4].try_into().unwrap());
+        (truncated & 0x7fff_ffff) % 10_u32.pow(DIGITS as u32)
+    }
+}
+
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut bits = 0_u32;
+    let mut nbits = 0;
+    for c in s.chars().filter(|c| !matches!(c, ' ' | '-' | '=')) {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return None,
+        };
+        bits = (bits << 5) | value;
+        nbits += 5;
+        if nbits >= 8 {
+            nbits -= 8;
+            out.push((bits >> nbits) as u8);
+            bits &= (1 << nbits) - 1;
+        }
+    }
+    (!out.is_empty()).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_rfc_6238_vectors_and_rejects_codes_older_than_the_last_used() {
+        // the sha1 test secret from RFC 6238, "12345678901234567890"
+        let totp = Totp::new("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap();
+        assert_eq!(totp.code_at(59 / STEP_SECS), 287082);
+        assert_eq!(totp.code_at(1111111109 / STEP_SECS), 81804);
+
+        let step = 1234567890 / STEP_SECS;
+        assert!(totp.verify_at("005924", step));
+        // parallel connections of the same client
+        assert!(totp.verify_at("005924", step));
+        assert!(!totp.verify_at("000000", step + 1));
+        let next = format!("{:06}", totp.code_at(step + 1));
+        assert!(totp.verify_at(&next, step + 1));
+        assert!(
+            !totp.verify_at("005924", step + 1),
+            "code older than the last used was accepted"
+        );
+    }
+}
@@ -0,0 +1,303 @@
+//! Probes a socks5 server and reports which parts of the protocol it supports, and whether
+//! its replies look the way RFC 1928 says they should.
+
+use std::{
+    env,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    process,
+    time::Duration,
+};
+
+use ::socks5::proto::{self, AuthMethod, ClientCommand, ServerStatus};
+use ::socks5::tcp_sock_stream::sync_proto::{Recievable, Sendable};
+
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+struct Checker {
+    server_addr: String,
+    target: String,
+    credentials: Option<(String, String)>,
+}
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    let Some(checker) = parse_args(&args[1..]) else {
+        eprintln!(
+            "usage: {} <proxy_addr> [--target <host:port>] [--user <username> --pass <password>]",
+            args[0]
+        );
+        process::exit(2);
+    };
+
+    println!(
+        "checking {} against target {}",
+        checker.server_addr, checker.target
+    );
+    checker.check_auth_methods();
+    checker.check_commands();
+    checker.check_address_types();
+    checker.check_error_codes();
+}
+
+fn parse_args(args: &[String]) -> Option<Checker> {
+    let (server_addr, mut rest) = args.split_first()?;
+    let mut target = None;
+    let mut user = None;
+    let mut pass = None;
+    while let [flag, value, tail @ ..] = rest {
+        match flag.as_str() {
+            "--target" => target = Some(value.to_owned()),
+            "--user" => user = Some(value.to_owned()),
+            "--pass" => pass = Some(value.to_owned()),
+            _ => return None,
+        }
+        rest = tail;
+    }
+    if !rest.is_empty() || user.is_some() != pass.is_some() {
+        return None;
+    }
+
+    Some(Checker {
+        server_addr: server_addr.to_owned(),
+        // the proxy can always reach itself, which makes for a target that works anywhere
+        target: target.unwrap_or_else(|| server_addr.to_owned()),
+        credentials: user.zip(pass),
+    })
+}
+
+impl Checker {
+    fn dial(&self) -> io::Result<TcpStream> {
+        let conn = TcpStream::connect(&self.server_addr)?;
+        conn.set_read_timeout(Some(IO_TIMEOUT))?;
+        conn.set_write_timeout(Some(IO_TIMEOUT))?;
+        Ok(conn)
+    }
+
+    /// Offers only `method` and returns whether the server accepted it.
+    fn offer(&self, conn: &mut TcpStream, method: AuthMethod) -> io::Result<bool> {
+        proto::ClientGreeting(vec![method]).write_to(conn)?;
+        let mut buf = [0_u8; 2];
+        conn.read_exact(&mut buf)?;
+        if buf[0] != proto::SOCKS_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("method reply has version {}", buf[0]),
+            ));
+        }
+        match buf[1] {
+            b if b == u8::from(method) => Ok(true),
+            NO_ACCEPTABLE_METHODS => Ok(false),
+            b => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("server picked method {b:#04x}, which was not offered"),
+            )),
+        }
+    }
+
+    /// Connects and authenticates with whichever method this checker can complete.
+    fn dial_authenticated(&self) -> io::Result<TcpStream> {
+        let mut conn = self.dial()?;
+        if self.offer(&mut conn, AuthMethod::NoAuth)? {
+            return Ok(conn);
+        }
+        let Some((username, password)) = &self.credentials else {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "server does not accept NoAuth, pass --user and --pass",
+            ));
+        };
+
+        let mut conn = self.dial()?;
+        if !self.offer(&mut conn, AuthMethod::UserPass)? {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "server accepts neither NoAuth nor UserPass",
+            ));
+        }
+        proto::UserPassRequest {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }
+        .write_to(&mut conn)?;
+        match proto::UserPassResponse::read_from(&mut conn)?.status {
+            0 => Ok(conn),
+            status => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("credentials rejected with status {status}"),
+            )),
+        }
+    }
+
+    fn request(
+        &self,
+        cmd: ClientCommand,
+        dest_addr: proto::Address,
+        dest_port: u16,
+    ) -> io::Result<proto::ServerResponse> {
+        let mut conn = self.dial_authenticated()?;
+        proto::ClientConnectionRequest {
+            cmd,
+            dest_addr,
+            dest_port,
+        }
+        .write_to(&mut conn)?;
+        read_reply(&mut conn)
+    }
+
+    /// Sends a request whose raw bytes follow the version, command and reserved bytes.
+    fn raw_request(&self, bytes: &[u8]) -> io::Result<proto::ServerResponse> {
+        let mut conn = self.dial_authenticated()?;
+        conn.write_all(bytes)?;
+        read_reply(&mut conn)
+    }
+
+    fn target_addrs(&self) -> io::Result<(String, u16, Vec<SocketAddr>)> {
+        let (host, port) = proto::split_host_port(&self.target)?;
+        let port = port.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "target must be host:port")
+        })?;
+        let addrs = (host, port).to_socket_addrs()?.collect();
+        Ok((host.to_owned(), port, addrs))
+    }
+
+    fn check_auth_methods(&self) {
+        println!("auth methods:");
+        for method in [AuthMethod::NoAuth, AuthMethod::GssApi, AuthMethod::UserPass] {
+            let res = self
+                .dial()
+                .and_then(|mut conn| self.offer(&mut conn, method));
+            match res {
+                Ok(true) => println!("  {method:?}: accepted"),
+                Ok(false) => println!("  {method:?}: not accepted"),
+                Err(err) => println!("  {method:?}: error: {err}"),
+            }
+        }
+    }
+
+    fn check_commands(&self) {
+        println!("commands:");
+        let (_, port, addrs) = match self.target_addrs() {
+            Ok(target) => target,
+            Err(err) => return println!("  skipped, cannot resolve target: {err}"),
+        };
+        let Some(&addr) = addrs.first() else {
+            return println!("  skipped, target resolved to nothing");
+        };
+
+        for (name, cmd) in [
+            ("CONNECT", ClientCommand::EstablishConnection),
+            ("BIND", ClientCommand::EstablishPortBinding),
+            ("UDP ASSOCIATE", ClientCommand::AssociateUdpPort),
+        ] {
+            match self.request(cmd, addr.into(), port) {
+                Ok(resp) if resp.status == ServerStatus::RequestGranted => {
+                    println!("  {name}: granted, {}", describe_bound(&resp))
+                }
+                Ok(resp) => println!("  {name}: {:?}", resp.status),
+                Err(err) => println!("  {name}: error: {err}"),
+            }
+        }
+    }
+
+    fn check_address_types(&self) {
+        println!("address types:");
+        let (host, port, addrs) = match self.target_addrs() {
+            Ok(target) => target,
+            Err(err) => return println!("  skipped, cannot resolve target: {err}"),
+        };
+
+        let domain = host
+            .parse::<std::net::IpAddr>()
+            .is_err()
+            .then(|| proto::Address::DomainName(host.clone()));
+        let ipv4 = addrs.iter().find(|a| a.is_ipv4()).map(|&a| a.into());
+        let ipv6 = addrs.iter().find(|a| a.is_ipv6()).map(|&a| a.into());
+
+        for (name, addr) in [("ipv4", ipv4), ("domain name", domain), ("ipv6", ipv6)] {
+            let Some(addr) = addr else {
+                println!("  {name}: skipped, target has no such address");
+                continue;
+            };
+            match self.request(ClientCommand::EstablishConnection, addr, port) {
+                Ok(resp) if resp.status == ServerStatus::AddressTypeNotSupported => {
+                    println!("  {name}: not supported")
+                }
+                Ok(resp) => println!("  {name}: supported ({:?})", resp.status),
+                Err(err) => println!("  {name}: error: {err}"),
+            }
+        }
+    }
+
+    fn check_error_codes(&self) {
+        println!("error codes:");
+        let v = proto::SOCKS_VERSION;
+        let r = proto::RESERVED;
+
+        let unknown_cmd = [v, 0x7f, r, 0x01, 127, 0, 0, 1, 0, 80];
+        self.expect_status(
+            "unknown command",
+            self.raw_request(&unknown_cmd),
+            ServerStatus::CommandNotSupported,
+        );
+
+        let unknown_atyp = [v, ClientCommand::EstablishConnection as u8, r, 0x7f, 0, 80];
+        self.expect_status(
+            "unknown address type",
+            self.raw_request(&unknown_atyp),
+            ServerStatus::AddressTypeNotSupported,
+        );
+
+        // port 1 on the loopback interface of the proxy is about as reliably closed as it gets
+        let refused = self.request(
+            ClientCommand::EstablishConnection,
+            proto::Address::Ipv4([127, 0, 0, 1].into()),
+            1,
+        );
+        self.expect_status(
+            "refused connection",
+            refused,
+            ServerStatus::ConnectionRefusedByDestinationHost,
+        );
+    }
+
+    fn expect_status(
+        &self,
+        name: &str,
+        res: io::Result<proto::ServerResponse>,
+        expected: ServerStatus,
+    ) {
+        match res {
+            Ok(resp) if resp.status == expected => println!("  {name}: ok ({expected:?})"),
+            Ok(resp) => println!("  {name}: got {:?}, expected {expected:?}", resp.status),
+            Err(err) => println!("  {name}: no reply ({err}), expected {expected:?}"),
+        }
+    }
+}
+
+fn read_reply(conn: &mut TcpStream) -> io::Result<proto::ServerResponse> {
+    proto::ServerResponse::read_from(conn).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "server closed the connection without replying",
+        ),
+        _ => err,
+    })
+}
+
+fn describe_bound(resp: &proto::ServerResponse) -> String {
+    let populated = match &resp.bound_address {
+        proto::Address::Ipv4(ip) => !ip.is_unspecified(),
+        proto::Address::Ipv6(ip) => !ip.is_unspecified(),
+        proto::Address::DomainName(name) => !name.is_empty(),
+    };
+    if populated && resp.bound_port != 0 {
+        format!("bound {}:{}", resp.bound_address, resp.bound_port)
+    } else {
+        format!(
+            "BND.ADDR not populated ({}:{})",
+            resp.bound_address, resp.bound_port
+        )
+    }
+}
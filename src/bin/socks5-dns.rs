@@ -0,0 +1,234 @@
+//! Forwards DNS queries arriving on a local udp port to a resolver through the proxy, for
+//! applications that can't be told to use socks for their lookups.
+//!
+//! By default queries are relayed over a single UDP association, which lasts for as long as
+//! the tool runs. With `--tcp` each query instead goes over a CONNECT to the resolver, as DNS
+//! over tcp, for proxies that don't support UDP ASSOCIATE. A bounded number of those are in
+//! flight at once, each given up on after a while, so a stalled proxy can't pile them up.
+
+use std::{
+    collections::HashMap,
+    env,
+    net::{IpAddr, SocketAddr},
+    process,
+    sync::Arc,
+    time::Duration,
+};
+
+use ::socks5::{
+    proto,
+    tcp_sock_stream::{self, ConnectRequest},
+};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    sync::Semaphore,
+    task, time,
+};
+
+const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:5353";
+// the largest dns message over udp, with edns
+const MAX_MESSAGE: usize = 65535;
+// queries waiting for an answer over the association, beyond which all of them are forgotten
+const MAX_PENDING: usize = 4096;
+// queries over tcp in flight at once, beyond which new ones wait to be read
+const MAX_TCP_QUERIES: usize = 256;
+// how long a query over tcp may take, connecting through the proxy included
+const TCP_QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Args {
+    proxy: String,
+    resolver: SocketAddr,
+    listen_addr: String,
+    tcp: bool,
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let args: Vec<_> = env::args().collect();
+    let Some(args) = parse_args(&args[1..]) else {
+        eprintln!(
+            "usage: {} <proxy> <resolver ip:port> [--listen <addr>] [--tcp]",
+            args[0]
+        );
+        process::exit(2);
+    };
+
+    let socket = Arc::new(UdpSocket::bind(&args.listen_addr).await?);
+    eprintln!(
+        "forwarding dns queries on {} to {} through {}",
+        socket.local_addr()?,
+        args.resolver,
+        args.proxy
+    );
+    if args.tcp {
+        forward_over_tcp(&args, socket).await
+    } else {
+        forward_over_udp(&args, &socket).await
+    }
+}
+
+fn parse_args(args: &[String]) -> Option<Args> {
+    let [proxy, resolver, flags @ ..] = args else {
+        return None;
+    };
+    let mut parsed = Args {
+        proxy: proxy.clone(),
+        resolver: resolver.parse().ok()?,
+        listen_addr: DEFAULT_LISTEN_ADDR.to_owned(),
+        tcp: false,
+    };
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--listen" => parsed.listen_addr = flags.next()?.clone(),
+            "--tcp" => parsed.tcp = true,
+            _ => return None,
+        }
+    }
+    Some(parsed)
+}
+
+/// The proxy is either a plain host:port or a socks5:// / socks5h:// url.
+fn request(proxy: &str, dest: SocketAddr) -> io::Result<ConnectRequest> {
+    let dest_addr = dest.ip().to_string();
+    if proxy.contains("://") {
+        return ConnectRequest::from_url(proxy, &dest_addr, dest.port());
+    }
+    Ok(ConnectRequest {
+        server_addr: proxy.to_owned(),
+        dest_addr,
+        dest_port: dest.port(),
+        supported_auth_methods: vec![proto::AuthMethod::NoAuth],
+        credentials: None,
+        credential_provider: None,
+        dns: tcp_sock_stream::DnsMode::Remote,
+    })
+}
+
+async fn forward_over_udp(args: &Args, socket: &UdpSocket) -> io::Result<()> {
+    let unspecified = SocketAddr::new(IpAddr::from([0, 0, 0, 0]), 0);
+    let req = request(&args.proxy, unspecified)?;
+    // the handshake is only implemented blocking
+    let (control, negotiated) =
+        task::spawn_blocking(move || tcp_sock_stream::associate(req)).await??;
+    control.set_nonblocking(true)?;
+    let mut control = TcpStream::from_std(control)?;
+    // proxies commonly leave the address unspecified for the one they are reached at
+    let relay_ip = match negotiated.bound_address {
+        proto::Address::Ipv4(ip) if !ip.is_unspecified() => IpAddr::V4(ip),
+        proto::Address::Ipv6(ip) if !ip.is_unspecified() => IpAddr::V6(ip),
+        _ => control.peer_addr()?.ip(),
+    };
+    let relay_addr = SocketAddr::new(relay_ip, negotiated.bound_port);
+    let relay = UdpSocket::bind(SocketAddr::new(control.local_addr()?.ip(), 0)).await?;
+    relay.connect(relay_addr).await?;
+
+    let header = proto::UdpHeader {
+        frag: 0,
+        dest_addr: args.resolver.into(),
+        dest_port: args.resolver.port(),
+    }
+    .as_bytes();
+    // queries go out under ids of their own, so those of different applications can't clash
+    let mut pending: HashMap<u16, (u16, SocketAddr)> = HashMap::new();
+    let mut next_id: u16 = 0;
+    let mut query = vec![0_u8; MAX_MESSAGE];
+    let mut answer = vec![0_u8; MAX_MESSAGE + 262];
+    let mut control_buf = [0_u8; 64];
+    loop {
+        tokio::select! {
+            res = control.read(&mut control_buf) => {
+                if matches!(res, Ok(0) | Err(_)) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "the proxy closed the udp association",
+                    ));
+                }
+            }
+            res = socket.recv_from(&mut query) => {
+                let (len, from) = res?;
+                if len < 2 {
+                    continue;
+                }
+                if pending.len() >= MAX_PENDING {
+                    pending.clear();
+                }
+                let id = u16::from_be_bytes([query[0], query[1]]);
+                next_id = next_id.wrapping_add(1);
+                pending.insert(next_id, (id, from));
+                let mut datagram = header.clone();
+                datagram.extend_from_slice(&next_id.to_be_bytes());
+                datagram.extend_from_slice(&query[2..len]);
+                if let Err(err) = relay.send(&datagram).await {
+                    eprintln!("query from {from}: {err}");
+                }
+            }
+            res = relay.recv(&mut answer) => {
+                let len = res?;
+                let Ok((_, payload)) = proto::UdpHeader::parse(&answer[..len]) else {
+                    continue;
+                };
+                let Some(sent_id) = payload.get(..2) else {
+                    continue;
+                };
+                let sent_id = u16::from_be_bytes([sent_id[0], sent_id[1]]);
+                let Some((id, from)) = pending.remove(&sent_id) else {
+                    continue;
+                };
+                let mut response = payload.to_vec();
+                response[..2].copy_from_slice(&id.to_be_bytes());
+                if let Err(err) = socket.send_to(&response, from).await {
+                    eprintln!("answering {from}: {err}");
+                }
+            }
+        }
+    }
+}
+
+async fn forward_over_tcp(args: &Args, socket: Arc<UdpSocket>) -> io::Result<()> {
+    let in_flight = Arc::new(Semaphore::new(MAX_TCP_QUERIES));
+    let mut buf = vec![0_u8; MAX_MESSAGE];
+    loop {
+        let permit = Arc::clone(&in_flight)
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let query = buf[..len].to_vec();
+        let req = request(&args.proxy, args.resolver)?;
+        let socket = Arc::clone(&socket);
+        tokio::spawn(async move {
+            let _permit = permit;
+            let res = time::timeout(TCP_QUERY_TIMEOUT, query_over_tcp(req, &query))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "no answer in time"))
+                });
+            match res {
+                Ok(response) => {
+                    if let Err(err) = socket.send_to(&response, from).await {
+                        eprintln!("answering {from}: {err}");
+                    }
+                }
+                Err(err) => eprintln!("query from {from}: {err}"),
+            }
+        });
+    }
+}
+
+/// Sends `query` over a connection of its own, each message prefixed by its length. The
+/// handshake runs on the task, so it is given up on along with the rest of the query.
+async fn query_over_tcp(req: ConnectRequest, query: &[u8]) -> io::Result<Vec<u8>> {
+    let len = u16::try_from(query.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "query too large"))?;
+    let mut stream = TcpStream::connect(&req.server_addr).await?;
+    tcp_sock_stream::handshake_on_async(&mut stream, &req).await?;
+    let mut msg = len.to_be_bytes().to_vec();
+    msg.extend_from_slice(query);
+    stream.write_all(&msg).await?;
+    let len = stream.read_u16().await?;
+    let mut response = vec![0_u8; len.into()];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
@@ -1,30 +1,276 @@
-use std::{env, io, thread};
+use std::{env, fs, net, process};
 
 use ::socks5::proto;
-use ::socks5::tcp_sock_stream;
+use ::socks5::tcp_sock_stream::{self, Credentials};
+use tokio::{
+    io::{self, AsyncWriteExt},
+    net::TcpStream,
+    task,
+};
 
-fn main() {
-    let args: Vec<_> = env::args().collect();
-    let expected_num_args = 4;
-    if args.len() != expected_num_args {
-        eprintln!("expected {expected_num_args} got {}", args.len());
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let mut args: Vec<_> = env::args().collect();
+    let credentials = match take_credentials(&mut args) {
+        Ok(credentials) => credentials,
+        Err(err) => {
+            eprintln!("{}: {err}", args[0]);
+            process::exit(2);
+        }
+    };
+    match &args[..] {
+        [_, server_addr, flag, url] if flag == "--http" => {
+            http_get(server_addr, url, credentials).await
+        }
+        [_, server_addr, dest_addr, dest_port] => {
+            let dest_port = dest_port.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid port {dest_port:?}"),
+                )
+            })?;
+            let piped = match connect(server_addr, dest_addr, dest_port, credentials).await {
+                Ok((stream, _)) => pipe_stdio(stream).await,
+                Err(err) => Err(err),
+            };
+            // stdin is read on a blocking thread that can't be interrupted, and the runtime
+            // would wait for it on shutdown, so exit without returning from main either way
+            match piped {
+                Ok(()) => process::exit(0),
+                Err(err) => {
+                    eprintln!("{}: {err}", args[0]);
+                    process::exit(1)
+                }
+            }
+        }
+        _ => {
+            eprintln!(
+                "usage: {0} [auth] <proxy> <dest_addr> <dest_port>\n       \
+                 {0} [auth] <proxy> --http <url>\n\
+                 auth:  -u/--user <username> -P/--pass <password>\n       \
+                 --auth-file <file with a username:password line>",
+                args[0]
+            );
+            process::exit(2);
+        }
     }
+}
+
+/// Takes the auth flags out of `args`, returning the credentials they give. Without any, the
+/// proxy is only offered NoAuth, unless its url has credentials.
+fn take_credentials(args: &mut Vec<String>) -> io::Result<Option<Credentials>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let (mut user, mut pass, mut auth_file) = (None, None, None);
+    let mut i = 1;
+    while i < args.len() {
+        let slot = match args[i].as_str() {
+            "-u" | "--user" => &mut user,
+            "-P" | "--pass" => &mut pass,
+            "--auth-file" => &mut auth_file,
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        let mut taken = args.drain(i..(i + 2).min(args.len()));
+        let flag = taken.next().unwrap_or_default();
+        *slot = Some(
+            taken
+                .next()
+                .ok_or_else(|| invalid(format!("{flag} takes a value")))?,
+        );
+    }
+
+    match (user, pass, auth_file) {
+        (None, None, None) => Ok(None),
+        (Some(username), Some(password), None) => Ok(Some(Credentials { username, password })),
+        (None, None, Some(path)) => {
+            let contents = fs::read_to_string(&path)?;
+            let (username, password) = contents
+                .lines()
+                .next()
+                .and_then(|line| line.split_once(':'))
+                .ok_or_else(|| invalid(format!("{path}: expected a username:password line")))?;
+            Ok(Some(Credentials {
+                username: username.to_owned(),
+                password: password.to_owned(),
+            }))
+        }
+        (_, _, Some(_)) => Err(invalid(
+            "--auth-file can't be combined with --user and --pass".to_owned(),
+        )),
+        _ => Err(invalid("--user and --pass go together".to_owned())),
+    }
+}
 
-    if let [_, server_addr, dest_addr, dest_port] = &args[..] {
-        let mut stream_in = tcp_sock_stream::connect(tcp_sock_stream::ConnectRequest {
+async fn connect(
+    server_addr: &str,
+    dest_addr: &str,
+    dest_port: u16,
+    credentials: Option<Credentials>,
+) -> io::Result<(TcpStream, tcp_sock_stream::Negotiated)> {
+    // the proxy is either a plain host:port or a socks5:// / socks5h:// url
+    let mut req = if server_addr.contains("://") {
+        tcp_sock_stream::ConnectRequest::from_url(server_addr, dest_addr, dest_port)?
+    } else {
+        tcp_sock_stream::ConnectRequest {
             server_addr: server_addr.to_owned(),
             dest_addr: dest_addr.to_owned(),
             supported_auth_methods: vec![proto::AuthMethod::NoAuth],
-            dest_port: dest_port.parse().unwrap(),
-        })
-        .unwrap();
-        let mut stream_out = stream_in.try_clone().unwrap();
-        thread::spawn(move || {
-            let mut stdin = io::stdin().lock();
-            io::copy(&mut stdin, &mut stream_out).unwrap();
-        });
-
-        let mut stdout = io::stdout().lock();
-        io::copy(&mut stream_in, &mut stdout).unwrap();
+            dest_port,
+            credentials: None,
+            credential_provider: None,
+            dns: tcp_sock_stream::DnsMode::Remote,
+        }
+    };
+    // the flags take precedence over any credentials in the url
+    if let Some(credentials) = credentials {
+        req.credentials = Some(credentials);
+        if !req
+            .supported_auth_methods
+            .contains(&proto::AuthMethod::UserPass)
+        {
+            req.supported_auth_methods.push(proto::AuthMethod::UserPass);
+        }
+    }
+    // the handshake is only implemented blocking
+    let (stream, negotiated): (net::TcpStream, _) =
+        task::spawn_blocking(move || tcp_sock_stream::connect_negotiated(req)).await??;
+    stream.set_nonblocking(true)?;
+    Ok((TcpStream::from_std(stream)?, negotiated))
+}
+
+/// Relays between stdio and `stream`. The end of stdin is passed on as a half close, and
+/// the session ends once the destination is done sending, or either direction fails.
+async fn pipe_stdio(stream: TcpStream) -> io::Result<()> {
+    let (mut from_proxy, mut to_proxy) = stream.into_split();
+    let upstream = async {
+        io::copy(&mut io::stdin(), &mut to_proxy).await?;
+        to_proxy.shutdown().await
+    };
+    let downstream = async {
+        let mut stdout = io::stdout();
+        io::copy(&mut from_proxy, &mut stdout).await?;
+        stdout.flush().await
+    };
+    tokio::select! {
+        res = downstream => res,
+        Err(err) = upstream => Err(err),
+    }
+}
+
+/// Fetches `url` through the proxy and prints the raw response, status line and headers
+/// included. Only plain `http://` urls are supported.
+async fn http_get(
+    server_addr: &str,
+    url: &str,
+    credentials: Option<Credentials>,
+) -> io::Result<()> {
+    let invalid = |reason: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid url {url:?}: {reason}"),
+        )
+    };
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| invalid("expected an http:// url"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = proto::split_host_port(authority)?;
+    let port = port.unwrap_or(80);
+
+    let (mut stream, negotiated) = connect(server_addr, host, port, credentials).await?;
+    let timings = negotiated.timings;
+    eprintln!(
+        "auth method: {:?}, bound address: {}:{}",
+        negotiated.auth_method, negotiated.bound_address, negotiated.bound_port
+    );
+    eprintln!(
+        "proxy connect: {:?}, auth: {:?}, destination connect: {:?}",
+        timings.proxy_connect, timings.auth, timings.dest_connect
+    );
+    let request = format!(
+        "GET {path} HTTP/1.1\r\nHost: {authority}\r\nUser-Agent: socks5-netcat\r\n\
+         Accept: */*\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut stdout = io::stdout();
+    io::copy(&mut stream, &mut stdout).await?;
+    stdout.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| (*arg).to_owned()).collect()
+    }
+
+    /// The `username:password` taken from `given`, along with the arguments left.
+    fn taken(given: &[&str]) -> io::Result<(Option<String>, Vec<String>)> {
+        let mut given = args(given);
+        let credentials = take_credentials(&mut given)?;
+        Ok((
+            credentials.map(|c| format!("{}:{}", c.username, c.password)),
+            given,
+        ))
+    }
+
+    #[test]
+    fn takes_the_auth_flags_out_of_the_arguments() {
+        let rest = args(&["nc", "127.0.0.1:1080", "example.com", "80"]);
+        assert_eq!(
+            taken(&["nc", "127.0.0.1:1080", "example.com", "80"]).unwrap(),
+            (None, rest.clone())
+        );
+        assert_eq!(
+            taken(&[
+                "nc",
+                "-u",
+                "alice",
+                "127.0.0.1:1080",
+                "--pass",
+                "s3cret",
+                "example.com",
+                "80"
+            ])
+            .unwrap(),
+            (Some("alice:s3cret".to_owned()), rest.clone())
+        );
+
+        let path = env::temp_dir().join(format!("socks5-netcat-auth-{}", process::id()));
+        fs::write(&path, "bob:pass:word\nignored\n").unwrap();
+        let path_arg = path.to_str().unwrap();
+        assert_eq!(
+            taken(&[
+                "nc",
+                "--auth-file",
+                path_arg,
+                "127.0.0.1:1080",
+                "example.com",
+                "80"
+            ])
+            .unwrap(),
+            (Some("bob:pass:word".to_owned()), rest)
+        );
+
+        for given in [
+            &["nc", "-u", "alice"][..],
+            &["nc", "-u", "alice", "--auth-file", path_arg],
+            &["nc", "-P", "s3cret", "127.0.0.1:1080"],
+        ] {
+            let err = taken(given).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{given:?}");
+        }
+        fs::write(&path, "no separator\n").unwrap();
+        assert!(taken(&["nc", "--auth-file", path_arg]).is_err());
+        fs::remove_file(&path).unwrap();
+
+        let err = taken(&["nc", "127.0.0.1:1080", "--user"]).unwrap_err();
+        assert_eq!(err.to_string(), "--user takes a value");
     }
 }
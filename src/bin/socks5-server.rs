@@ -1,25 +1,275 @@
-use std::{env, io};
+use std::{env, io, os::unix::io::AsRawFd, sync::Arc, time::Duration};
 
-use socks5::tcp_server_stream;
-use tokio::net::TcpListener;
+use socks5::{
+    config::{self, ServerConfig},
+    health, listener,
+    server::Server,
+    stats,
+    upgrade::{self, ListenerKind},
+};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, Signal, SignalKind},
+};
+
+const USAGE: &str = "usage: {bin} <command>
+
+commands:
+    serve [--config <path> | <listen_addr>]
+                        run the proxy, the default when no command is given
+    check <path>        validate the config at <path> and report on every section
+    version             print the version and the optional features built in
+    gen-config          print a commented config with every setting at its default";
 
 #[tokio::main]
 async fn main() -> Result<(), io::Error> {
-    let expected_num_args = 2;
-    if env::args().len() != expected_num_args {
-        eprintln!("expected {expected_num_args} got {}", env::args().len());
+    let args: Vec<_> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("serve") => serve(serve_config(&args[0], &args[2..])?).await,
+        Some("check") => match &args[2..] {
+            [path] => check_config(path),
+            _ => usage(&args[0]),
+        },
+        Some("version") => {
+            version();
+            Ok(())
+        }
+        Some("gen-config") => {
+            print!("{}", config::DEFAULT_CONFIG);
+            Ok(())
+        }
+        Some("help" | "--help" | "-h") => {
+            println!("{}", USAGE.replace("{bin}", &args[0]));
+            Ok(())
+        }
+        // without a command, the arguments are serve's like they were before there were commands
+        _ => serve(serve_config(&args[0], &args[1..])?).await,
     }
+}
 
-    let addr = env::args().nth(1).unwrap_or("127.0.0.1:4242".to_owned());
-    println!("server listening on {addr}");
-    let lis = TcpListener::bind(addr).await?;
+fn serve_config(bin: &str, args: &[String]) -> io::Result<ServerConfig> {
+    match args {
+        [] => ServerConfig::load(None),
+        [flag, path] if flag == "--config" => ServerConfig::load(Some(path.as_ref())),
+        // the address on the command line wins over SOCKS5_CFG__LISTEN_ADDR
+        [addr] if !addr.starts_with('-') => Ok(ServerConfig {
+            listen_addr: addr.to_owned(),
+            ..ServerConfig::load(None)?
+        }),
+        _ => usage(bin),
+    }
+}
 
-    loop {
-        let (stream, _) = lis.accept().await?;
+fn usage(bin: &str) -> ! {
+    eprintln!("{}", USAGE.replace("{bin}", bin));
+    std::process::exit(2);
+}
+
+fn version() {
+    let features: Vec<&str> = [
+        ("scripting", cfg!(feature = "scripting")),
+        ("doh", cfg!(feature = "doh")),
+        ("ldap", cfg!(feature = "ldap")),
+        ("jwt", cfg!(feature = "jwt")),
+        ("pam", cfg!(feature = "pam")),
+        ("gssapi", cfg!(feature = "gssapi")),
+        ("websocket", cfg!(feature = "websocket")),
+        ("quic", cfg!(feature = "quic")),
+        ("redis", cfg!(feature = "redis")),
+        ("tun2socks", cfg!(feature = "tun2socks")),
+        ("test-util", cfg!(feature = "test-util")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect();
+    println!("socks5-server {}", env!("CARGO_PKG_VERSION"));
+    if features.is_empty() {
+        println!("features: none");
+    } else {
+        println!("features: {}", features.join(", "));
+    }
+}
+
+async fn serve(config: ServerConfig) -> io::Result<()> {
+    let server = Arc::new(Server::new(config)?);
+    let mut inherited = upgrade::inherit_listeners()?.unwrap_or_default();
+    let mut handed_off = Vec::new();
+
+    if let Some(health_addr) = &server.config().health_addr {
+        let health_lis = match inherited.health {
+            Some(lis) => lis,
+            None => TcpListener::bind(health_addr).await?,
+        };
+        println!("health checks listening on {}", health_lis.local_addr()?);
+        handed_off.push((ListenerKind::Health, health_lis.as_raw_fd()));
+        tokio::spawn(health::serve(Arc::clone(&server), health_lis));
+    }
+
+    // SIGUSR1 logs the top talkers. without them, it keeps its default of ending the process
+    if server.top_talkers().is_some() {
+        let mut report_signal = signal(SignalKind::user_defined1())?;
+        let server = Arc::clone(&server);
         tokio::spawn(async move {
-            if let Err(err) = tcp_server_stream::handle(stream).await {
-                eprintln!("handle_stream: {err:?}");
+            while report_signal.recv().await.is_some() {
+                if let Some(top_talkers) = server.top_talkers() {
+                    eprint!("{}", top_talkers.report());
+                }
             }
         });
     }
+
+    if let Some(secs) = server.config().stats_interval_secs {
+        let interval = Duration::from_secs(secs);
+        tokio::spawn(stats::log_periodically(Arc::clone(&server), interval));
+    }
+
+    let http_addr = server
+        .config()
+        .http_proxy
+        .as_ref()
+        .and_then(|http| http.listen_addr.as_ref());
+    if let Some(http_addr) = http_addr {
+        let http_lis = match inherited.http_proxy {
+            Some(lis) => lis,
+            None => listener::bind(http_addr, &server.config().listener)?,
+        };
+        println!("http proxy listening on {}", http_lis.local_addr()?);
+        handed_off.push((ListenerKind::HttpProxy, http_lis.as_raw_fd()));
+        tokio::spawn(Arc::clone(&server).serve_http_proxy(http_lis));
+    }
+
+    if let Some(redirect) = &server.config().redirect {
+        let redirect_lis = match inherited.redirect {
+            Some(lis) => lis,
+            None => listener::bind(&redirect.listen_addr, &server.config().listener)?,
+        };
+        println!(
+            "redirected connections accepted on {}",
+            redirect_lis.local_addr()?
+        );
+        handed_off.push((ListenerKind::Redirect, redirect_lis.as_raw_fd()));
+        tokio::spawn(Arc::clone(&server).serve_redirect(redirect_lis));
+    }
+
+    #[cfg(feature = "quic")]
+    if let Some(quic_config) = &server.config().quic {
+        let socket = match inherited.quic {
+            Some(socket) => socket,
+            None => std::net::UdpSocket::bind(&quic_config.listen_addr)?,
+        };
+        handed_off.push((ListenerKind::Quic, socket.as_raw_fd()));
+        let endpoint = socks5::quic::listen(socket, quic_config)?;
+        println!("quic listening on {}", endpoint.local_addr()?);
+        tokio::spawn(Arc::clone(&server).serve_quic(endpoint));
+    }
+
+    for (index, named) in server.config().listeners.iter().enumerate() {
+        // more than fit are refused by the hand off
+        let index = index as u8;
+        let named_lis = match inherited.named.remove(&index) {
+            Some(lis) => lis,
+            None => listener::bind(&named.listen_addr, &server.config().listener)?,
+        };
+        println!(
+            "listener {} listening on {}",
+            named.name,
+            named_lis.local_addr()?
+        );
+        handed_off.push((ListenerKind::Named(index), named_lis.as_raw_fd()));
+        let server = Arc::clone(&server);
+        let name = named.name.clone();
+        tokio::spawn(async move { server.serve_named(named_lis, &name).await });
+    }
+
+    let lis = match inherited.socks {
+        Some(lis) => lis,
+        None => listener::bind(&server.config().listen_addr, &server.config().listener)?,
+    };
+    println!("server listening on {}", lis.local_addr()?);
+    handed_off.push((ListenerKind::Socks, lis.as_raw_fd()));
+
+    // SIGUSR2 hands the listeners to a freshly exec'd copy of this binary, after which this
+    // process stops accepting and exits once its remaining connections are done.
+    let mut upgrade_signal = signal(SignalKind::user_defined2())?;
+    let mut termination = Termination::new()?;
+    let mut serving = tokio::spawn(Arc::clone(&server).serve(lis));
+    loop {
+        tokio::select! {
+            res = &mut serving => return res?,
+            signal = termination.recv() => terminate(&server, signal).await,
+            _ = upgrade_signal.recv() => match upgrade::hand_off(&handed_off).await {
+                Ok(()) => break,
+                Err(err) => eprintln!("upgrade failed, continuing to serve: {err}"),
+            },
+        }
+    }
+
+    server.stop_accepting();
+    serving.await??;
+    println!(
+        "upgrade: draining {} remaining connections",
+        server.active_connections()
+    );
+    tokio::select! {
+        () = server.drain() => Ok(()),
+        signal = termination.recv() => terminate(&server, signal).await,
+    }
+}
+
+/// SIGTERM and SIGINT, which end the server without waiting for its connections.
+struct Termination {
+    term: Signal,
+    int: Signal,
+}
+
+impl Termination {
+    fn new() -> io::Result<Self> {
+        Ok(Self {
+            term: signal(SignalKind::terminate())?,
+            int: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    /// Waits for either signal, returning its name and number.
+    async fn recv(&mut self) -> (&'static str, i32) {
+        tokio::select! {
+            _ = self.term.recv() => ("SIGTERM", libc::SIGTERM),
+            _ = self.int.recv() => ("SIGINT", libc::SIGINT),
+        }
+    }
+}
+
+/// Aborts the remaining connections, flushes the logs and exits with the status of a process
+/// killed by the signal, so that supervisors can tell a termination from a crash.
+async fn terminate(server: &Server, (name, signal): (&str, i32)) -> ! {
+    eprintln!(
+        "{name}: terminating, aborting {} connections",
+        server.active_connections()
+    );
+    server.terminate().await;
+    stats::log_totals(server);
+    std::process::exit(128 + signal);
+}
+
+/// Prints how every section of the config at `path` fared and exits, with status 1 if any
+/// of them is broken.
+fn check_config(path: &str) -> ! {
+    let config = match ServerConfig::load(Some(path.as_ref())) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("error  {path}: {err}");
+            std::process::exit(1);
+        }
+    };
+    let checks = config::check(&config);
+    for check in &checks {
+        println!("{check}");
+    }
+    let failed = checks.iter().filter(|check| check.result.is_err()).count();
+    if failed > 0 {
+        println!("{failed} of {} sections are broken", checks.len());
+        std::process::exit(1);
+    }
+    println!("{path} is valid");
+    std::process::exit(0);
 }
@@ -0,0 +1,453 @@
+//! Soaks an in-process server with long running sessions to an echoing destination, while
+//! randomly resetting, stalling and half closing either leg. Every echoed byte is checked
+//! against what was sent, and once the sessions stop the server has to get back to the
+//! connections, tasks and file descriptors it started with.
+
+use std::{
+    collections::BTreeMap,
+    env, fs, net, process,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ::socks5::{
+    config::{RelayStrategy, ServerConfig},
+    proto,
+    server::Server,
+    tcp_sock_stream,
+};
+use socket2::SockRef;
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::Handle,
+    task::{self, JoinSet},
+    time::{self, Instant},
+};
+
+const USAGE: &str = "usage: {bin} [--sessions <n>] [--duration <secs>] [--seed <n>]
+                [--relay auto|splice|userspace]
+
+runs <n> concurrent sessions (default 32) for <secs> seconds (default 60), injecting faults
+picked by a generator seeded with <n> (default: the current time), through a server
+relaying the way --relay says (default auto)";
+
+const MAX_PAYLOAD: usize = 256 * 1024;
+const MAX_STALL: Duration = Duration::from_secs(2);
+/// how long the server gets to tear down the last sessions before they count as leaked
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Fault {
+    None,
+    ClientReset,
+    ClientStall,
+    ClientHalfClose,
+    RemoteReset,
+    RemoteStall,
+    RemoteHalfClose,
+}
+
+const FAULTS: [Fault; 7] = [
+    Fault::None,
+    Fault::ClientReset,
+    Fault::ClientStall,
+    Fault::ClientHalfClose,
+    Fault::RemoteReset,
+    Fault::RemoteStall,
+    Fault::RemoteHalfClose,
+];
+
+/// What a session sends and where its fault hits. The client sends it ahead of the payload
+/// so the destination knows its part.
+struct Plan {
+    fault: Fault,
+    len: usize,
+    /// offset into the payload at which the fault is injected
+    cut: usize,
+    stall: Duration,
+}
+
+impl Plan {
+    const HEADER_LEN: usize = 13;
+
+    fn random(rng: &mut Rng) -> Self {
+        let len = 1 + rng.below(MAX_PAYLOAD as u64) as usize;
+        Self {
+            fault: FAULTS[rng.below(FAULTS.len() as u64) as usize],
+            len,
+            cut: rng.below(len as u64) as usize,
+            stall: Duration::from_millis(rng.below(MAX_STALL.as_millis() as u64)),
+        }
+    }
+
+    fn header(&self) -> [u8; Self::HEADER_LEN] {
+        let mut header = [0_u8; Self::HEADER_LEN];
+        header[0] = FAULTS.iter().position(|&f| f == self.fault).unwrap() as u8;
+        header[1..5].copy_from_slice(&(self.len as u32).to_be_bytes());
+        header[5..9].copy_from_slice(&(self.cut as u32).to_be_bytes());
+        header[9..].copy_from_slice(&(self.stall.as_millis() as u32).to_be_bytes());
+        header
+    }
+
+    async fn read_header(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Self> {
+        let mut header = [0_u8; Self::HEADER_LEN];
+        stream.read_exact(&mut header).await?;
+        let field = |at: usize| u32::from_be_bytes(header[at..at + 4].try_into().unwrap());
+        let fault = *FAULTS.get(header[0] as usize).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "unknown fault in plan header")
+        })?;
+        Ok(Self {
+            fault,
+            len: field(1) as usize,
+            cut: field(5) as usize,
+            stall: Duration::from_millis(field(9).into()),
+        })
+    }
+}
+
+/// xorshift64*, plenty for picking faults and reproducible from the seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck at zero
+        Self(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n.max(1)
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(len + 8);
+        while bytes.len() < len {
+            bytes.extend_from_slice(&self.next().to_le_bytes());
+        }
+        bytes.truncate(len);
+        bytes
+    }
+}
+
+struct Options {
+    sessions: usize,
+    duration: Duration,
+    seed: u64,
+    relay: RelayStrategy,
+}
+
+#[derive(Default)]
+struct Tally {
+    runs: u64,
+    /// sessions that failed in a way their fault doesn't explain
+    failures: u64,
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let args: Vec<_> = env::args().collect();
+    let Some(options) = parse_args(&args[1..]) else {
+        eprintln!("{}", USAGE.replace("{bin}", &args[0]));
+        process::exit(2);
+    };
+
+    let server = Arc::new(Server::new(ServerConfig {
+        relay: options.relay,
+        ..ServerConfig::default()
+    })?);
+    let proxy = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = proxy.local_addr()?;
+    tokio::spawn(Arc::clone(&server).serve(proxy));
+    let dest = TcpListener::bind("127.0.0.1:0").await?;
+    let dest_addr = dest.local_addr()?;
+    tokio::spawn(serve_echo(dest));
+
+    let tasks_before = Handle::current().metrics().num_alive_tasks();
+    let fds_before = open_fds();
+    println!(
+        "soaking with {} sessions for {:?}, seed {}, {:?} relay",
+        options.sessions, options.duration, options.seed, options.relay
+    );
+
+    let deadline = Instant::now() + options.duration;
+    let mut workers = JoinSet::new();
+    for i in 0..options.sessions {
+        let mut rng = Rng::new(options.seed.wrapping_add(i as u64));
+        workers.spawn(async move {
+            let mut tally = BTreeMap::<Fault, Tally>::new();
+            while Instant::now() < deadline {
+                let plan = Plan::random(&mut rng);
+                let payload = rng.bytes(plan.len);
+                let res = session(proxy_addr, dest_addr, &plan, &payload).await;
+                let entry = tally.entry(plan.fault).or_default();
+                entry.runs += 1;
+                if let Err(err) = judge(plan.fault, res) {
+                    entry.failures += 1;
+                    eprintln!("{:?} session failed: {err}", plan.fault);
+                }
+            }
+            tally
+        });
+    }
+    let mut tally = BTreeMap::<Fault, Tally>::new();
+    while let Some(worker) = workers.join_next().await {
+        for (fault, t) in worker? {
+            let entry = tally.entry(fault).or_default();
+            entry.runs += t.runs;
+            entry.failures += t.failures;
+        }
+    }
+
+    let mut ok = true;
+    for (fault, t) in &tally {
+        println!("{fault:?}: {} sessions, {} failed", t.runs, t.failures);
+        ok &= t.failures == 0;
+    }
+    if time::timeout(DRAIN_TIMEOUT, server.drain()).await.is_err() {
+        println!(
+            "leak: {} connections still active after {DRAIN_TIMEOUT:?}",
+            server.active_connections()
+        );
+        ok = false;
+    }
+    // the destination's handlers end shortly after the proxy closed their connection
+    let tasks_after = settle(
+        || Handle::current().metrics().num_alive_tasks(),
+        tasks_before,
+    )
+    .await;
+    if tasks_after > tasks_before {
+        println!("leak: {tasks_before} tasks before the soak, {tasks_after} after");
+        ok = false;
+    }
+    if let Some(fds_before) = fds_before {
+        // pipes the splice relay pools for later connections aren't leaked
+        let unpooled_fds = || {
+            let pooled = 2 * server.metrics().pooled_splice_pipes();
+            open_fds().unwrap_or(0).saturating_sub(pooled)
+        };
+        let fds_after = settle(unpooled_fds, fds_before).await;
+        if fds_after > fds_before {
+            println!("leak: {fds_before} open fds before the soak, {fds_after} after");
+            ok = false;
+        }
+    }
+
+    if !ok {
+        process::exit(1);
+    }
+    println!("ok");
+    Ok(())
+}
+
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut options = Options {
+        sessions: 32,
+        duration: Duration::from_secs(60),
+        seed: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()?
+            .as_nanos() as u64,
+        relay: RelayStrategy::Auto,
+    };
+    let mut rest = args;
+    while let [flag, value, tail @ ..] = rest {
+        match flag.as_str() {
+            "--sessions" => options.sessions = value.parse().ok()?,
+            "--duration" => options.duration = Duration::from_secs(value.parse().ok()?),
+            "--seed" => options.seed = value.parse().ok()?,
+            "--relay" => {
+                options.relay = match value.as_str() {
+                    "auto" => RelayStrategy::Auto,
+                    "splice" => RelayStrategy::Splice,
+                    "userspace" => RelayStrategy::Userspace,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        }
+        rest = tail;
+    }
+    rest.is_empty().then_some(options)
+}
+
+/// Polls `count` until it gets down to `target` or [`DRAIN_TIMEOUT`] passes, and returns
+/// the last count.
+async fn settle(count: impl Fn() -> usize, target: usize) -> usize {
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    loop {
+        let n = count();
+        if n <= target || Instant::now() >= deadline {
+            return n;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// The file descriptors open in this process, where /proc tells.
+fn open_fds() -> Option<usize> {
+    Some(fs::read_dir("/proc/self/fd").ok()?.count())
+}
+
+/// Runs one session through the proxy, failing with `InvalidData` if the echo ever differs
+/// from what was sent.
+async fn session(
+    proxy: net::SocketAddr,
+    dest: net::SocketAddr,
+    plan: &Plan,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut stream = connect(proxy, dest).await?;
+    stream.write_all(&plan.header()).await?;
+
+    if plan.fault == Fault::ClientReset {
+        stream.write_all(&payload[..plan.cut]).await?;
+        return reset(stream);
+    }
+
+    let (mut from_proxy, mut to_proxy) = stream.split();
+    let upstream = async {
+        if plan.fault == Fault::ClientStall {
+            to_proxy.write_all(&payload[..plan.cut]).await?;
+            time::sleep(plan.stall).await;
+            to_proxy.write_all(&payload[plan.cut..]).await?;
+        } else {
+            to_proxy.write_all(payload).await?;
+        }
+        if plan.fault == Fault::ClientHalfClose {
+            to_proxy.shutdown().await?;
+        }
+        Ok(())
+    };
+    let expected = match plan.fault {
+        Fault::RemoteReset | Fault::RemoteHalfClose => &payload[..plan.cut],
+        _ => payload,
+    };
+    let downstream = read_echo(&mut from_proxy, expected);
+    let (upstream, echoed): (io::Result<()>, _) = tokio::join!(upstream, downstream);
+    let echoed = echoed?;
+    upstream?;
+    if echoed < expected.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("echo ended after {echoed} of {} bytes", expected.len()),
+        ));
+    }
+
+    // the echo is complete, so the proxy has to pass on the end of the stream both ways
+    if plan.fault != Fault::ClientHalfClose {
+        stream.shutdown().await?;
+    }
+    if stream.read(&mut [0_u8; 1]).await? != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "echo went on past the payload",
+        ));
+    }
+    Ok(())
+}
+
+/// Whether a session ended the way its fault allows. Resets may cut the echo short, but
+/// nothing excuses corrupted data.
+fn judge(fault: Fault, res: io::Result<()>) -> io::Result<()> {
+    match res {
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => Err(err),
+        Err(_) if matches!(fault, Fault::ClientReset | Fault::RemoteReset) => Ok(()),
+        res => res,
+    }
+}
+
+async fn connect(proxy: net::SocketAddr, dest: net::SocketAddr) -> io::Result<TcpStream> {
+    let req = tcp_sock_stream::ConnectRequest {
+        server_addr: proxy.to_string(),
+        dest_addr: dest.ip().to_string(),
+        dest_port: dest.port(),
+        supported_auth_methods: vec![proto::AuthMethod::NoAuth],
+        credentials: None,
+        credential_provider: None,
+        dns: tcp_sock_stream::DnsMode::Remote,
+    };
+    // the handshake is only implemented blocking
+    let (stream, _): (net::TcpStream, _) =
+        task::spawn_blocking(move || tcp_sock_stream::connect_negotiated(req)).await??;
+    stream.set_nonblocking(true)?;
+    TcpStream::from_std(stream)
+}
+
+/// Reads until all of `expected` arrived or the stream ends, checking every byte on the
+/// way, and returns how many did.
+async fn read_echo(stream: &mut (impl AsyncRead + Unpin), expected: &[u8]) -> io::Result<usize> {
+    let mut buf = vec![0_u8; 16 * 1024];
+    let mut pos = 0;
+    while pos < expected.len() {
+        let want = buf.len().min(expected.len() - pos);
+        let n = match stream.read(&mut buf[..want]).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) => {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("after {pos} bytes of echo: {err}"),
+                ))
+            }
+        };
+        if buf[..n] != expected[pos..pos + n] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "echo differs from the payload within bytes {pos}..{}",
+                    pos + n
+                ),
+            ));
+        }
+        pos += n;
+    }
+    Ok(pos)
+}
+
+/// Closes `stream` with a RST instead of a FIN.
+fn reset(stream: TcpStream) -> io::Result<()> {
+    SockRef::from(&stream).set_linger(Some(Duration::ZERO))
+}
+
+async fn serve_echo(listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (conn, _) = listener.accept().await?;
+        // the session judges the outcome, the destination's errors are expected fallout
+        tokio::spawn(echo(conn));
+    }
+}
+
+/// Echoes a session's payload, injecting the destination's side of its plan.
+async fn echo(mut conn: TcpStream) -> io::Result<()> {
+    let plan = Plan::read_header(&mut conn).await?;
+    let (mut rd, mut wr) = conn.split();
+    match plan.fault {
+        Fault::RemoteReset => {
+            io::copy(&mut (&mut rd).take(plan.cut as u64), &mut wr).await?;
+            reset(conn)
+        }
+        Fault::RemoteStall => {
+            io::copy(&mut (&mut rd).take(plan.cut as u64), &mut wr).await?;
+            time::sleep(plan.stall).await;
+            io::copy(&mut rd, &mut wr).await?;
+            wr.shutdown().await
+        }
+        Fault::RemoteHalfClose => {
+            io::copy(&mut (&mut rd).take(plan.cut as u64), &mut wr).await?;
+            wr.shutdown().await?;
+            io::copy(&mut rd, &mut io::sink()).await.map(drop)
+        }
+        _ => {
+            io::copy(&mut rd, &mut wr).await?;
+            wr.shutdown().await
+        }
+    }
+}
@@ -0,0 +1,905 @@
+//! Sends the traffic routed into a TUN device through the proxy, for applications that
+//! can't be told to use socks at all.
+//!
+//! TCP connections are terminated here, by a user-space tcp stack, and their payload is
+//! relayed over a CONNECT to where they were headed. UDP datagrams are relayed over a UDP
+//! ASSOCIATE, one for each local address and port sending them, which is closed after a
+//! minute without traffic. Other packets are dropped.
+//!
+//! The device is expected to exist and be up, with routes sending the traffic to proxy into
+//! it, e.g.:
+//!
+//! ```text
+//! ip tuntap add mode tun dev tun0
+//! ip link set tun0 up
+//! ip route add 203.0.113.0/24 dev tun0
+//! ```
+//!
+//! Routing everything into it also takes a route keeping the connections to the proxy out.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::fd::{AsRawFd, OwnedFd},
+    process,
+    sync::Arc,
+    time::Duration,
+};
+
+use ::socks5::{proto, tcp_sock_stream};
+use smoltcp::{
+    iface::{Config, Interface, SocketHandle, SocketSet},
+    phy::{self, ChecksumCapabilities, DeviceCapabilities, Medium},
+    socket::tcp,
+    time::Instant,
+    wire::{
+        HardwareAddress, IpAddress, IpCidr, IpListenEndpoint, IpProtocol, Ipv4Packet, Ipv4Repr,
+        Ipv6Packet, Ipv6Repr, TcpPacket, UdpPacket, UdpRepr, UDP_HEADER_LEN,
+    },
+};
+use tokio::{
+    io::{self, unix::AsyncFd, AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    sync::{mpsc, Notify},
+    task, time,
+};
+
+const DEFAULT_MTU: usize = 1500;
+// the buffers of each tcp connection, which bound its window
+const TCP_BUFFER: usize = 64 * 1024;
+// beyond which new connections are refused
+const MAX_FLOWS: usize = 4096;
+// chunks of payload queued between a connection and its relay, each way
+const FLOW_QUEUE: usize = 16;
+const UDP_IDLE: Duration = Duration::from_secs(60);
+const UDP_QUEUE: usize = 64;
+// how long the tcp stack sleeps at most between polls
+const MAX_POLL_DELAY: Duration = Duration::from_secs(1);
+
+struct Args {
+    proxy: Arc<str>,
+    tun: String,
+    mtu: usize,
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let args: Vec<_> = env::args().collect();
+    let Some(args) = parse_args(&args[1..]) else {
+        eprintln!("usage: {} <proxy> <tun device> [--mtu <bytes>]", args[0]);
+        process::exit(2);
+    };
+
+    let tun = AsyncFd::new(open_tun(&args.tun)?)?;
+    eprintln!("relaying traffic of {} through {}", args.tun, args.proxy);
+    Stack::new(args).run(&tun).await
+}
+
+fn parse_args(args: &[String]) -> Option<Args> {
+    let [proxy, tun, flags @ ..] = args else {
+        return None;
+    };
+    let mut parsed = Args {
+        proxy: proxy.as_str().into(),
+        tun: tun.clone(),
+        mtu: DEFAULT_MTU,
+    };
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--mtu" => parsed.mtu = flags.next()?.parse().ok()?,
+            _ => return None,
+        }
+    }
+    Some(parsed)
+}
+
+/// Attaches to the TUN device `name`, whose packets are read and written without any
+/// header in front.
+#[cfg(target_os = "linux")]
+fn open_tun(name: &str) -> io::Result<OwnedFd> {
+    use std::os::fd::FromRawFd;
+
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("device name {name:?} is too long"),
+        ));
+    }
+    let fd = unsafe {
+        libc::open(
+            c"/dev/net/tun".as_ptr(),
+            libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, &src) in ifr.ifr_name.iter_mut().zip(name.as_bytes()) {
+        *dst = src as libc::c_char;
+    }
+    ifr.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+    if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &mut ifr) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_tun(_name: &str) -> io::Result<OwnedFd> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "tun devices are only supported on linux",
+    ))
+}
+
+/// Packets passed between the TUN device and the tcp stack.
+struct Queues {
+    mtu: usize,
+    rx: VecDeque<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+}
+
+struct RxToken(Vec<u8>);
+
+struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::Device for Queues {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _: Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+        Some((RxToken(packet), TxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _: Instant) -> Option<TxToken<'_>> {
+        Some(TxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+        f(&self.0)
+    }
+}
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+        let mut packet = vec![0_u8; len];
+        let res = f(&mut packet);
+        self.0.push_back(packet);
+        res
+    }
+}
+
+/// A tcp connection terminated by the stack, and the relay carrying its payload.
+struct Flow {
+    handle: SocketHandle,
+    /// until the connection is established and its relay started
+    unstarted: Option<RelayEnds>,
+    /// dropped once the application is done sending
+    to_proxy: Option<mpsc::Sender<Vec<u8>>>,
+    from_proxy: mpsc::Receiver<io::Result<Vec<u8>>>,
+    /// payload from the proxy the socket had no room for yet
+    pending: Vec<u8>,
+    sent: usize,
+}
+
+/// The relay's ends of a flow's queues.
+struct RelayEnds {
+    upstream: mpsc::Receiver<Vec<u8>>,
+    downstream: mpsc::Sender<io::Result<Vec<u8>>>,
+}
+
+/// A datagram from the proxy to write to the device.
+struct Reply {
+    local: SocketAddr,
+    remote: SocketAddr,
+    payload: Vec<u8>,
+}
+
+struct Stack {
+    proxy: Arc<str>,
+    device: Queues,
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    /// connections by their local and remote endpoints
+    flows: HashMap<(SocketAddr, SocketAddr), Flow>,
+    /// udp associations by the local endpoint they relay for
+    associations: HashMap<SocketAddr, mpsc::Sender<(SocketAddr, Vec<u8>)>>,
+    replies: (mpsc::Sender<Reply>, mpsc::Receiver<Reply>),
+    /// notified by the relays whenever a flow has something for the stack
+    wake: Arc<Notify>,
+}
+
+impl Stack {
+    fn new(args: Args) -> Self {
+        let mut device = Queues {
+            mtu: args.mtu,
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+        };
+        let mut iface = Interface::new(Config::new(HardwareAddress::Ip), &mut device, now());
+        // the stack answers for any address routed through one of its own, which the
+        // default routes make all of them
+        let v4 = Ipv4Addr::new(198, 18, 0, 1);
+        let v6 = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+        iface.update_ip_addrs(|addrs| {
+            addrs.push(IpCidr::new(v4.into(), 15)).unwrap();
+            addrs.push(IpCidr::new(v6.into(), 64)).unwrap();
+        });
+        iface.routes_mut().add_default_ipv4_route(v4).unwrap();
+        iface.routes_mut().add_default_ipv6_route(v6).unwrap();
+        iface.set_any_ip(true);
+
+        Self {
+            proxy: args.proxy,
+            device,
+            iface,
+            sockets: SocketSet::new(Vec::new()),
+            flows: HashMap::new(),
+            associations: HashMap::new(),
+            replies: mpsc::channel(UDP_QUEUE),
+            wake: Arc::new(Notify::new()),
+        }
+    }
+
+    async fn run(mut self, tun: &AsyncFd<OwnedFd>) -> io::Result<()> {
+        let mut buf = vec![0_u8; 65535];
+        loop {
+            self.iface.poll(now(), &mut self.device, &mut self.sockets);
+            self.service_flows();
+            // what the flows were serviced with may have the stack send more
+            self.iface.poll(now(), &mut self.device, &mut self.sockets);
+            for packet in self.device.tx.drain(..) {
+                write_packet(tun, &packet)?;
+            }
+
+            let delay = self
+                .iface
+                .poll_delay(now(), &self.sockets)
+                .map_or(MAX_POLL_DELAY, |delay| {
+                    Duration::from_micros(delay.total_micros()).min(MAX_POLL_DELAY)
+                });
+            tokio::select! {
+                guard = tun.readable() => {
+                    let mut guard = guard?;
+                    loop {
+                        match read_packet(tun, &mut buf) {
+                            Ok(len) => self.inbound(&buf[..len]),
+                            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                                guard.clear_ready();
+                                break;
+                            }
+                            Err(err) => return Err(err),
+                        }
+                    }
+                }
+                Some(reply) = self.replies.1.recv() => {
+                    if let Some(packet) = udp_packet(reply.remote, reply.local, &reply.payload) {
+                        write_packet(tun, &packet)?;
+                    }
+                }
+                _ = self.wake.notified() => {}
+                _ = time::sleep(delay) => {}
+            }
+        }
+    }
+
+    /// Takes in a packet read from the device.
+    fn inbound(&mut self, packet: &[u8]) {
+        match classify(packet) {
+            Inbound::TcpSyn { local, remote } => {
+                self.accept(local, remote);
+                self.device.rx.push_back(packet.to_vec());
+            }
+            Inbound::Udp {
+                local,
+                remote,
+                payload,
+            } => self.relay_datagram(local, remote, payload),
+            Inbound::Tcp => self.device.rx.push_back(packet.to_vec()),
+            Inbound::Other => {}
+        }
+    }
+
+    /// Has a socket listen for the connection from `local` to `remote` opened by a syn,
+    /// unless there is one already. Without one, the stack refuses the connection.
+    fn accept(&mut self, local: SocketAddr, remote: SocketAddr) {
+        if self.flows.contains_key(&(local, remote)) || self.flows.len() >= MAX_FLOWS {
+            return;
+        }
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER]),
+        );
+        let endpoint = IpListenEndpoint {
+            addr: Some(IpAddress::from(remote.ip())),
+            port: remote.port(),
+        };
+        if socket.listen(endpoint).is_err() {
+            return;
+        }
+
+        let (to_proxy, upstream) = mpsc::channel(FLOW_QUEUE);
+        let (downstream, from_proxy) = mpsc::channel(FLOW_QUEUE);
+        self.flows.insert(
+            (local, remote),
+            Flow {
+                handle: self.sockets.add(socket),
+                unstarted: Some(RelayEnds {
+                    upstream,
+                    downstream,
+                }),
+                to_proxy: Some(to_proxy),
+                from_proxy,
+                pending: Vec::new(),
+                sent: 0,
+            },
+        );
+    }
+
+    /// Starts the relays of connections that were just established, moves payload between
+    /// the sockets and their relays, and forgets the connections that are over. Syns alone
+    /// don't have the proxy dialed, so spoofed or scanning ones cost no connections.
+    fn service_flows(&mut self) {
+        let sockets = &mut self.sockets;
+        let (proxy, wake) = (&self.proxy, &self.wake);
+        self.flows.retain(|&(_, remote), flow| {
+            let socket = sockets.get_mut::<tcp::Socket>(flow.handle);
+            let established = !matches!(
+                socket.state(),
+                tcp::State::Listen | tcp::State::SynReceived | tcp::State::Closed
+            );
+            if established {
+                if let Some(ends) = flow.unstarted.take() {
+                    tokio::spawn(relay_flow(
+                        Arc::clone(proxy),
+                        remote,
+                        ends.upstream,
+                        ends.downstream,
+                        Arc::clone(wake),
+                    ));
+                }
+            }
+            service(socket, flow);
+            let over = matches!(
+                socket.state(),
+                tcp::State::Closed | tcp::State::TimeWait | tcp::State::Listen
+            );
+            if over {
+                sockets.remove(flow.handle);
+            }
+            !over
+        });
+    }
+
+    fn relay_datagram(&mut self, local: SocketAddr, remote: SocketAddr, payload: &[u8]) {
+        if self
+            .associations
+            .get(&local)
+            .is_none_or(|association| association.is_closed())
+        {
+            let (datagrams, rx) = mpsc::channel(UDP_QUEUE);
+            let proxy = Arc::clone(&self.proxy);
+            let replies = self.replies.0.clone();
+            tokio::spawn(async move {
+                if let Err(err) = relay_datagrams(&proxy, local, rx, replies).await {
+                    eprintln!("udp from {local}: {err}");
+                }
+            });
+            self.associations
+                .retain(|_, association| !association.is_closed());
+            self.associations.insert(local, datagrams);
+        }
+        // like any datagram, one finding the queue full is lost
+        let _ = self.associations[&local].try_send((remote, payload.to_vec()));
+    }
+}
+
+fn service(socket: &mut tcp::Socket, flow: &mut Flow) {
+    if let Some(to_proxy) = &flow.to_proxy {
+        while socket.can_recv() {
+            let Ok(permit) = to_proxy.try_reserve() else {
+                break;
+            };
+            let data = socket.recv(|buf| (buf.len(), buf.to_vec())).unwrap();
+            permit.send(data);
+        }
+        let done = !socket.can_recv()
+            && matches!(
+                socket.state(),
+                tcp::State::CloseWait
+                    | tcp::State::LastAck
+                    | tcp::State::Closing
+                    | tcp::State::TimeWait
+                    | tcp::State::Closed
+            );
+        if done || to_proxy.is_closed() {
+            flow.to_proxy = None;
+        }
+    }
+
+    loop {
+        if flow.sent == flow.pending.len() {
+            match flow.from_proxy.try_recv() {
+                // the proxy is done sending
+                Ok(Ok(data)) if data.is_empty() => socket.close(),
+                Ok(Ok(data)) => {
+                    flow.pending = data;
+                    flow.sent = 0;
+                }
+                Ok(Err(_)) => socket.abort(),
+                Err(mpsc::error::TryRecvError::Empty) => {}
+                Err(mpsc::error::TryRecvError::Disconnected) => socket.close(),
+            }
+            if flow.sent == flow.pending.len() {
+                return;
+            }
+        }
+        if !socket.can_send() {
+            return;
+        }
+        match socket.send_slice(&flow.pending[flow.sent..]) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => flow.sent += n,
+        }
+    }
+}
+
+/// Connects to `remote` through the proxy and relays the payload of a flow over it. An
+/// empty chunk from the proxy is the end of its stream.
+async fn relay_flow(
+    proxy: Arc<str>,
+    remote: SocketAddr,
+    mut upstream: mpsc::Receiver<Vec<u8>>,
+    downstream: mpsc::Sender<io::Result<Vec<u8>>>,
+    wake: Arc<Notify>,
+) {
+    let stream = match connect(&proxy, remote).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            eprintln!("connecting to {remote}: {err}");
+            let _ = downstream.send(Err(err)).await;
+            wake.notify_one();
+            return;
+        }
+    };
+    let (mut reader, mut writer) = stream.into_split();
+    let to_remote = async {
+        while let Some(data) = upstream.recv().await {
+            writer.write_all(&data).await?;
+            // the stack may be waiting on room in the queue
+            wake.notify_one();
+        }
+        writer.shutdown().await
+    };
+    let to_local = async {
+        loop {
+            let mut buf = vec![0_u8; TCP_BUFFER];
+            let len = tokio::select! {
+                res = reader.read(&mut buf) => res?,
+                // the connection is gone
+                _ = downstream.closed() => return Ok(()),
+            };
+            buf.truncate(len);
+            if downstream.send(Ok(buf)).await.is_err() {
+                return Ok(());
+            }
+            wake.notify_one();
+            if len == 0 {
+                return Ok(());
+            }
+        }
+    };
+    if let Err(err) = tokio::try_join!(to_remote, to_local) {
+        let _ = downstream.send(Err(err)).await;
+    }
+    wake.notify_one();
+}
+
+/// The proxy is either a plain host:port or a socks5:// / socks5h:// url.
+fn request(proxy: &str, dest: SocketAddr) -> io::Result<tcp_sock_stream::ConnectRequest> {
+    let dest_addr = dest.ip().to_string();
+    if proxy.contains("://") {
+        return tcp_sock_stream::ConnectRequest::from_url(proxy, &dest_addr, dest.port());
+    }
+    Ok(tcp_sock_stream::ConnectRequest {
+        server_addr: proxy.to_owned(),
+        dest_addr,
+        dest_port: dest.port(),
+        supported_auth_methods: vec![proto::AuthMethod::NoAuth],
+        credentials: None,
+        credential_provider: None,
+        dns: tcp_sock_stream::DnsMode::Remote,
+    })
+}
+
+async fn connect(proxy: &str, dest: SocketAddr) -> io::Result<TcpStream> {
+    let req = request(proxy, dest)?;
+    // the handshake is only implemented blocking
+    let stream: net::TcpStream =
+        task::spawn_blocking(move || tcp_sock_stream::connect(req)).await??;
+    stream.set_nonblocking(true)?;
+    TcpStream::from_std(stream)
+}
+
+/// Relays the datagrams `local` sends over an association of its own, until it has been
+/// idle for a while.
+async fn relay_datagrams(
+    proxy: &str,
+    local: SocketAddr,
+    mut datagrams: mpsc::Receiver<(SocketAddr, Vec<u8>)>,
+    replies: mpsc::Sender<Reply>,
+) -> io::Result<()> {
+    let unspecified = SocketAddr::new(IpAddr::from([0, 0, 0, 0]), 0);
+    let req = request(proxy, unspecified)?;
+    let (control, negotiated) =
+        task::spawn_blocking(move || tcp_sock_stream::associate(req)).await??;
+    control.set_nonblocking(true)?;
+    let mut control = TcpStream::from_std(control)?;
+    // proxies commonly leave the address unspecified for the one they are reached at
+    let relay_ip = match negotiated.bound_address {
+        proto::Address::Ipv4(ip) if !ip.is_unspecified() => IpAddr::V4(ip),
+        proto::Address::Ipv6(ip) if !ip.is_unspecified() => IpAddr::V6(ip),
+        _ => control.peer_addr()?.ip(),
+    };
+    let relay = UdpSocket::bind(SocketAddr::new(control.local_addr()?.ip(), 0)).await?;
+    relay
+        .connect(SocketAddr::new(relay_ip, negotiated.bound_port))
+        .await?;
+
+    let idle = time::sleep(UDP_IDLE);
+    tokio::pin!(idle);
+    let mut buf = vec![0_u8; 65535];
+    let mut control_buf = [0_u8; 64];
+    loop {
+        tokio::select! {
+            res = control.read(&mut control_buf) => {
+                if matches!(res, Ok(0) | Err(_)) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "the proxy closed the udp association",
+                    ));
+                }
+            }
+            datagram = datagrams.recv() => {
+                let Some((remote, payload)) = datagram else {
+                    return Ok(());
+                };
+                let mut datagram = proto::UdpHeader {
+                    frag: 0,
+                    dest_addr: remote.into(),
+                    dest_port: remote.port(),
+                }
+                .as_bytes();
+                datagram.extend_from_slice(&payload);
+                relay.send(&datagram).await?;
+                idle.as_mut().reset(time::Instant::now() + UDP_IDLE);
+            }
+            res = relay.recv(&mut buf) => {
+                let len = res?;
+                let Ok((header, payload)) = proto::UdpHeader::parse(&buf[..len]) else {
+                    continue;
+                };
+                let ip = match header.dest_addr {
+                    proto::Address::Ipv4(ip) => IpAddr::V4(ip),
+                    proto::Address::Ipv6(ip) => IpAddr::V6(ip),
+                    proto::Address::DomainName(_) => continue,
+                };
+                let reply = Reply {
+                    local,
+                    remote: SocketAddr::new(ip, header.dest_port),
+                    payload: payload.to_vec(),
+                };
+                if replies.send(reply).await.is_err() {
+                    return Ok(());
+                }
+                idle.as_mut().reset(time::Instant::now() + UDP_IDLE);
+            }
+            _ = &mut idle => return Ok(()),
+        }
+    }
+}
+
+/// What a packet read from the device is, as far as it matters to where it goes.
+enum Inbound<'a> {
+    /// opens a tcp connection
+    TcpSyn {
+        local: SocketAddr,
+        remote: SocketAddr,
+    },
+    Tcp,
+    Udp {
+        local: SocketAddr,
+        remote: SocketAddr,
+        payload: &'a [u8],
+    },
+    Other,
+}
+
+fn classify(packet: &[u8]) -> Inbound<'_> {
+    let (src, dst, protocol, payload) = match packet.first().map(|b| b >> 4) {
+        Some(4) => {
+            let Ok(ip) = Ipv4Packet::new_checked(packet) else {
+                return Inbound::Other;
+            };
+            // fragments are left to the stack, which reassembles none
+            if ip.more_frags() || ip.frag_offset() != 0 {
+                return Inbound::Other;
+            }
+            (
+                IpAddr::V4(ip.src_addr()),
+                IpAddr::V4(ip.dst_addr()),
+                ip.next_header(),
+                ip.payload(),
+            )
+        }
+        Some(6) => {
+            let Ok(ip) = Ipv6Packet::new_checked(packet) else {
+                return Inbound::Other;
+            };
+            (
+                IpAddr::V6(ip.src_addr()),
+                IpAddr::V6(ip.dst_addr()),
+                ip.next_header(),
+                ip.payload(),
+            )
+        }
+        _ => return Inbound::Other,
+    };
+    match protocol {
+        IpProtocol::Tcp => {
+            let Ok(tcp) = TcpPacket::new_checked(payload) else {
+                return Inbound::Other;
+            };
+            if !tcp.syn() || tcp.ack() {
+                return Inbound::Tcp;
+            }
+            Inbound::TcpSyn {
+                local: SocketAddr::new(src, tcp.src_port()),
+                remote: SocketAddr::new(dst, tcp.dst_port()),
+            }
+        }
+        IpProtocol::Udp => {
+            let Ok(udp) = UdpPacket::new_checked(payload) else {
+                return Inbound::Other;
+            };
+            Inbound::Udp {
+                local: SocketAddr::new(src, udp.src_port()),
+                remote: SocketAddr::new(dst, udp.dst_port()),
+                payload: udp.payload(),
+            }
+        }
+        _ => Inbound::Other,
+    }
+}
+
+/// Builds the ip packet carrying a datagram from `src` to `dst`, unless their families
+/// differ or the payload is too large for one.
+fn udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Option<Vec<u8>> {
+    let udp = UdpRepr {
+        src_port: src.port(),
+        dst_port: dst.port(),
+    };
+    let udp_len = UDP_HEADER_LEN + payload.len();
+    let caps = ChecksumCapabilities::default();
+    let (mut packet, offset) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_addr), IpAddr::V4(dst_addr)) => {
+            let ip = Ipv4Repr {
+                src_addr,
+                dst_addr,
+                next_header: IpProtocol::Udp,
+                payload_len: udp_len,
+                hop_limit: 64,
+            };
+            u16::try_from(ip.buffer_len() + udp_len).ok()?;
+            let mut packet = vec![0_u8; ip.buffer_len() + udp_len];
+            ip.emit(&mut Ipv4Packet::new_unchecked(&mut packet), &caps);
+            (packet, ip.buffer_len())
+        }
+        (IpAddr::V6(src_addr), IpAddr::V6(dst_addr)) => {
+            let ip = Ipv6Repr {
+                src_addr,
+                dst_addr,
+                next_header: IpProtocol::Udp,
+                payload_len: udp_len,
+                hop_limit: 64,
+            };
+            u16::try_from(udp_len).ok()?;
+            let mut packet = vec![0_u8; ip.buffer_len() + udp_len];
+            ip.emit(&mut Ipv6Packet::new_unchecked(&mut packet));
+            (packet, ip.buffer_len())
+        }
+        _ => return None,
+    };
+    udp.emit(
+        &mut UdpPacket::new_unchecked(&mut packet[offset..]),
+        &src.ip().into(),
+        &dst.ip().into(),
+        payload.len(),
+        |buf| buf.copy_from_slice(payload),
+        &caps,
+    );
+    Some(packet)
+}
+
+fn read_packet(tun: &AsyncFd<OwnedFd>, buf: &mut [u8]) -> io::Result<usize> {
+    let len = unsafe { libc::read(tun.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(len as usize)
+}
+
+/// Writes a packet to the device, dropping it if the device can't take it right away.
+fn write_packet(tun: &AsyncFd<OwnedFd>, packet: &[u8]) -> io::Result<()> {
+    let len = unsafe { libc::write(tun.as_raw_fd(), packet.as_ptr().cast(), packet.len()) };
+    if len < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::WouldBlock {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+fn now() -> Instant {
+    Instant::now()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const LOCAL: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 18, 0, 1)), 40000);
+    const REMOTE: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)), 80);
+
+    fn stack(proxy: &str) -> Stack {
+        Stack::new(Args {
+            proxy: proxy.into(),
+            tun: String::new(),
+            mtu: DEFAULT_MTU,
+        })
+    }
+
+    /// Opens a connection from `LOCAL` to `REMOTE` on the stack's own interface, as an
+    /// application behind the device would.
+    fn open(stack: &mut Stack) -> SocketHandle {
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER]),
+        );
+        socket
+            .connect(stack.iface.context(), REMOTE, LOCAL)
+            .unwrap();
+        stack.sockets.add(socket)
+    }
+
+    /// Runs the stack once and routes what it sent back into it, returning those packets.
+    fn pump(stack: &mut Stack) -> Vec<Vec<u8>> {
+        stack
+            .iface
+            .poll(now(), &mut stack.device, &mut stack.sockets);
+        stack.service_flows();
+        stack
+            .iface
+            .poll(now(), &mut stack.device, &mut stack.sockets);
+        let sent: Vec<_> = stack.device.tx.drain(..).collect();
+        for packet in &sent {
+            stack.inbound(packet);
+        }
+        sent
+    }
+
+    fn state(stack: &Stack, handle: SocketHandle) -> tcp::State {
+        stack.sockets.get::<tcp::Socket>(handle).state()
+    }
+
+    #[test]
+    fn classifies_packets_and_builds_datagrams() {
+        let v6: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+        let packet = udp_packet(LOCAL, REMOTE, b"query").unwrap();
+        let Inbound::Udp {
+            local,
+            remote,
+            payload,
+        } = classify(&packet)
+        else {
+            panic!("not classified as udp");
+        };
+        assert_eq!((local, remote, payload), (LOCAL, REMOTE, &b"query"[..]));
+
+        let from: SocketAddr = "[fd00::2]:5353".parse().unwrap();
+        let packet = udp_packet(from, v6, b"").unwrap();
+        assert!(matches!(
+            classify(&packet),
+            Inbound::Udp { local, remote, payload: [] } if local == from && remote == v6
+        ));
+
+        assert!(udp_packet(LOCAL, v6, b"").is_none());
+        assert!(udp_packet(LOCAL, REMOTE, &[0; 65535]).is_none());
+
+        let mut fragment = udp_packet(LOCAL, REMOTE, b"query").unwrap();
+        Ipv4Packet::new_unchecked(&mut fragment).set_more_frags(true);
+        assert!(matches!(classify(&fragment), Inbound::Other));
+        assert!(matches!(classify(&[]), Inbound::Other));
+        assert!(matches!(classify(&[0x45, 0, 0]), Inbound::Other));
+    }
+
+    #[test]
+    fn services_flows_between_the_socket_and_the_relay() {
+        let mut stack = stack("127.0.0.1:1");
+        let client = open(&mut stack);
+        let syn = pump(&mut stack);
+        assert!(matches!(
+            classify(&syn[0]),
+            Inbound::TcpSyn { local, remote } if local == LOCAL && remote == REMOTE
+        ));
+        // stand in for the relay
+        let flow = stack.flows.get_mut(&(LOCAL, REMOTE)).unwrap();
+        let RelayEnds {
+            mut upstream,
+            downstream,
+        } = flow.unstarted.take().unwrap();
+        for _ in 0..4 {
+            pump(&mut stack);
+        }
+        assert_eq!(state(&stack, client), tcp::State::Established);
+
+        let socket = stack.sockets.get_mut::<tcp::Socket>(client);
+        socket.send_slice(b"hello").unwrap();
+        pump(&mut stack);
+        pump(&mut stack);
+        assert_eq!(upstream.try_recv().unwrap(), b"hello");
+
+        downstream.try_send(Ok(b"world".to_vec())).unwrap();
+        downstream.try_send(Ok(Vec::new())).unwrap();
+        pump(&mut stack);
+        pump(&mut stack);
+        let socket = stack.sockets.get_mut::<tcp::Socket>(client);
+        let mut buf = [0_u8; 16];
+        let len = socket.recv_slice(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"world");
+        // the relay's end of the stream closes the connection
+        assert_eq!(state(&stack, client), tcp::State::CloseWait);
+    }
+
+    #[tokio::test]
+    async fn dials_the_proxy_only_once_connections_are_established() {
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut stack = stack(&proxy.local_addr().unwrap().to_string());
+        let client = open(&mut stack);
+        pump(&mut stack);
+        pump(&mut stack);
+        let flow = &stack.flows[&(LOCAL, REMOTE)];
+        assert_eq!(state(&stack, flow.handle), tcp::State::SynReceived);
+        assert!(flow.unstarted.is_some());
+        assert!(time::timeout(Duration::from_millis(100), proxy.accept())
+            .await
+            .is_err());
+
+        for _ in 0..3 {
+            pump(&mut stack);
+        }
+        assert_eq!(state(&stack, client), tcp::State::Established);
+        assert!(stack.flows[&(LOCAL, REMOTE)].unstarted.is_none());
+        time::timeout(Duration::from_secs(5), proxy.accept())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
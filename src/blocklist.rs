@@ -0,0 +1,219 @@
+//! Domains requests may not go to, loaded from the files configured as `blocklist.files`,
+//! which lets the proxy double as a dns-name firewall.
+//!
+//! A listed domain blocks its subdomains too. Each line of a file is one of:
+//!
+//! - a hosts file entry, `0.0.0.0 ads.example.com`, blocking every name after the address.
+//!   Names without a dot, like `localhost`, are skipped.
+//! - an adblock domain rule, `||ads.example.com^`. Rules with options, exceptions and
+//!   url patterns are skipped, as they can't be decided from the name alone.
+//! - a bare domain, `ads.example.com`.
+//!
+//! Lines starting with `#` or `!` are comments. The server checks the files for changes
+//! every `reload_interval_secs`, and reloads them on a blocking thread when one was modified.
+//! Requests are checked against the domains loaded last meanwhile.
+
+use std::{
+    collections::HashSet,
+    fs,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use tokio::io;
+
+use crate::config::BlocklistConfig;
+
+pub struct Blocklist {
+    files: Vec<PathBuf>,
+    interval: Duration,
+    loaded: Mutex<Loaded>,
+}
+
+struct Loaded {
+    domains: Arc<HashSet<String>>,
+    /// modification times of `files`, in order
+    modified: Vec<SystemTime>,
+}
+
+impl Blocklist {
+    pub fn new(config: &BlocklistConfig) -> io::Result<Self> {
+        if config.reload_interval_secs == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "blocklist reload_interval_secs must be at least a second",
+            ));
+        }
+        let modified = modification_times(&config.files)?;
+        let domains = load(&config.files)?;
+        Ok(Self {
+            files: config.files.clone(),
+            interval: Duration::from_secs(config.reload_interval_secs),
+            loaded: Mutex::new(Loaded {
+                domains: Arc::new(domains),
+                modified,
+            }),
+        })
+    }
+
+    /// Whether `host` or a domain it is under is listed.
+    pub fn blocks(&self, host: &str) -> bool {
+        let domains = Arc::clone(&self.loaded.lock().unwrap().domains);
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let mut suffix = host.as_str();
+        loop {
+            if domains.contains(suffix) {
+                return true;
+            }
+            match suffix.split_once('.') {
+                Some((_, parent)) => suffix = parent,
+                None => return false,
+            }
+        }
+    }
+
+    pub(crate) fn reload_interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Reloads the domains if a file changed since they were last loaded, blocking on the
+    /// filesystem. Files that fail to load are logged and the previous domains are kept.
+    /// Loading happens outside the lock, requests arriving meanwhile see the previous list.
+    pub(crate) fn reload_if_changed(&self) {
+        let previous = self.loaded.lock().unwrap().modified.clone();
+        let modified = match modification_times(&self.files) {
+            Ok(modified) if modified != previous => modified,
+            Ok(_) => return,
+            Err(err) => {
+                eprintln!("blocklist: {err}");
+                return;
+            }
+        };
+
+        let reloaded = load(&self.files);
+        let mut loaded = self.loaded.lock().unwrap();
+        loaded.modified = modified;
+        match reloaded {
+            Ok(domains) => {
+                eprintln!("blocklist reloaded, {} domains", domains.len());
+                loaded.domains = Arc::new(domains);
+            }
+            Err(err) => eprintln!("blocklist reload failed, keeping previous domains: {err}"),
+        }
+    }
+}
+
+fn modification_times(files: &[PathBuf]) -> io::Result<Vec<SystemTime>> {
+    files
+        .iter()
+        .map(|path| {
+            fs::metadata(path)
+                .and_then(|m| m.modified())
+                .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))
+        })
+        .collect()
+}
+
+fn load(files: &[PathBuf]) -> io::Result<HashSet<String>> {
+    let mut domains = HashSet::new();
+    for path in files {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+        for line in contents.lines() {
+            parse_line(line, &mut domains);
+        }
+    }
+    Ok(domains)
+}
+
+fn parse_line(line: &str, domains: &mut HashSet<String>) {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+        return;
+    }
+    if let Some(rule) = line.strip_prefix("||") {
+        if let Some(domain) = rule.strip_suffix('^').filter(|d| is_domain(d)) {
+            domains.insert(domain.to_ascii_lowercase());
+        }
+        return;
+    }
+    let mut fields = line.split_whitespace();
+    let first = fields.next().unwrap_or_default();
+    if first.parse::<IpAddr>().is_ok() {
+        domains.extend(
+            fields
+                .filter(|name| name.contains('.') && is_domain(name))
+                .map(|name| name.trim_end_matches('.').to_ascii_lowercase()),
+        );
+    } else if fields.next().is_none() && is_domain(first) {
+        domains.insert(first.trim_end_matches('.').to_ascii_lowercase());
+    }
+}
+
+fn is_domain(name: &str) -> bool {
+    !name.is_empty()
+        && name.parse::<IpAddr>().is_err()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn blocks_listed_domains_and_their_subdomains() {
+        let path = env::temp_dir().join(format!("socks5-blocklist-{}.txt", process::id()));
+        fs::write(
+            &path,
+            "# hosts\n\
+             127.0.0.1 localhost\n\
+             0.0.0.0 ads.example.com tracker.example.net # inline comment\n\
+             ! adblock\n\
+             [Adblock Plus 2.0]\n\
+             ||Metrics.Example.org^\n\
+             ||cdn.example.org^$third-party\n\
+             @@||ok.example.com^\n\
+             ||example.edu/path^\n\
+             malware.example\n",
+        )
+        .unwrap();
+        let blocklist = Blocklist::new(&BlocklistConfig {
+            files: vec![path.clone()],
+            reload_interval_secs: 60,
+        })
+        .unwrap();
+
+        assert!(blocklist.blocks("ads.example.com"));
+        assert!(blocklist.blocks("eu.ADS.example.com."));
+        assert!(blocklist.blocks("tracker.example.net"));
+        assert!(blocklist.blocks("metrics.example.org"));
+        assert!(blocklist.blocks("malware.example"));
+        assert!(!blocklist.blocks("example.com"));
+        assert!(!blocklist.blocks("localhost"));
+        assert!(!blocklist.blocks("cdn.example.org"));
+        assert!(!blocklist.blocks("ok.example.com"));
+        assert!(!blocklist.blocks("example.edu"));
+
+        fs::write(&path, "0.0.0.0 other.example.com\n").unwrap();
+        // mtimes can be coarse, make sure the write looks like a change
+        let mtime = SystemTime::now() + Duration::from_secs(10);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        assert!(blocklist.blocks("ads.example.com"));
+        blocklist.reload_if_changed();
+        assert!(!blocklist.blocks("ads.example.com"));
+        assert!(blocklist.blocks("other.example.com"));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,351 @@
+//! Debug capture of relayed traffic into a pcap file.
+//!
+//! Relayed payloads are wrapped in synthesized IP and TCP headers describing a flow between
+//! the client and the remote peer, so the capture can be opened in wireshark/tcpdump and
+//! followed like a regular TCP stream. Captured connections are relayed through userspace
+//! instead of splice, since the payload has to pass through the proxy to be recorded.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+};
+
+use crate::{
+    config::CaptureConfig,
+    metrics::{ConnectionRelay, RelayCounter},
+    proto,
+};
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+// keeps every synthesized packet, including ipv6 + tcp headers, within SNAPLEN
+const MAX_SEGMENT: usize = 65535 - 60;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_PSH_ACK: u8 = 0x18;
+
+pub struct Capture {
+    config: CaptureConfig,
+    out: Mutex<BufWriter<File>>,
+}
+
+impl Capture {
+    pub fn create(config: CaptureConfig) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(&config.file)?);
+        out.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        out.write_all(&2_u16.to_le_bytes())?; // version major
+        out.write_all(&4_u16.to_le_bytes())?; // version minor
+        out.write_all(&0_i32.to_le_bytes())?; // thiszone
+        out.write_all(&0_u32.to_le_bytes())?; // sigfigs
+        out.write_all(&SNAPLEN.to_le_bytes())?;
+        out.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        out.flush()?;
+        Ok(Self {
+            config,
+            out: Mutex::new(out),
+        })
+    }
+
+    /// Writes out the packets still buffered, which relays otherwise only do when they end.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut out = self.out.lock().unwrap();
+        out.flush()?;
+        out.get_ref().sync_data()
+    }
+
+    /// A connection is captured when it matches any configured client or destination. With
+    /// no filters configured every connection is captured.
+    pub fn matches(&self, client: SocketAddr, request: &proto::ClientConnectionRequest) -> bool {
+        let CaptureConfig {
+            clients,
+            destinations,
+            ..
+        } = &self.config;
+        if clients.is_empty() && destinations.is_empty() {
+            return true;
+        }
+
+        let dest = request.dest_addr.to_string();
+        let dest_with_port = format!("{dest}:{}", request.dest_port);
+        clients.contains(&client.ip())
+            || destinations
+                .iter()
+                .any(|d| *d == dest || *d == dest_with_port)
+    }
+
+    pub(crate) async fn relay(
+        &self,
+        client: TcpStream,
+        remote: TcpStream,
+        relayed: &ConnectionRelay<'_>,
+    ) -> io::Result<()> {
+        let client_addr = client.peer_addr()?;
+        let remote_addr = remote.peer_addr()?;
+        let (client_read, client_write) = client.into_split();
+        let (remote_read, remote_write) = remote.into_split();
+
+        let upstream = Direction::new(client_addr, remote_addr);
+        let downstream = Direction::new(remote_addr, client_addr);
+        let res = tokio::try_join!(
+            self.copy(
+                client_read,
+                remote_write,
+                (&upstream, &downstream),
+                relayed.to_remote()
+            ),
+            self.copy(
+                remote_read,
+                client_write,
+                (&downstream, &upstream),
+                relayed.to_client()
+            ),
+        );
+        self.out.lock().unwrap().flush()?;
+        res.map(|_| ())
+    }
+
+    async fn copy(
+        &self,
+        mut reader: OwnedReadHalf,
+        mut writer: OwnedWriteHalf,
+        (dir, reverse): (&Direction, &Direction),
+        relayed: RelayCounter<'_>,
+    ) -> io::Result<()> {
+        let mut buf = vec![0_u8; MAX_SEGMENT];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                self.record(dir, reverse, TCP_FIN | TCP_PSH_ACK, &[])?;
+                return match writer.shutdown().await {
+                    Err(err) if err.kind() == io::ErrorKind::NotConnected => Ok(()),
+                    res => res,
+                };
+            }
+            writer.write_all(&buf[..n]).await?;
+            relayed.relayed(n);
+            self.record(dir, reverse, TCP_PSH_ACK, &buf[..n])?;
+        }
+    }
+
+    fn record(
+        &self,
+        dir: &Direction,
+        reverse: &Direction,
+        flags: u8,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let seq_len = payload.len() as u32 + (flags & TCP_FIN) as u32;
+        let seq = dir.seq.fetch_add(seq_len, Ordering::Relaxed);
+        let packet = dir.packet(seq, reverse.seq.load(Ordering::Relaxed), flags, payload);
+
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut out = self.out.lock().unwrap();
+        out.write_all(&(ts.as_secs() as u32).to_le_bytes())?;
+        out.write_all(&ts.subsec_micros().to_le_bytes())?;
+        out.write_all(&(packet.len() as u32).to_le_bytes())?; // incl_len
+        out.write_all(&(packet.len() as u32).to_le_bytes())?; // orig_len
+        out.write_all(&packet)
+    }
+}
+
+/// One half of the synthesized tcp flow.
+struct Direction {
+    src: SocketAddr,
+    dst: SocketAddr,
+    /// next sequence number, read by the reverse direction as its ack number
+    seq: AtomicU32,
+}
+
+impl Direction {
+    fn new(src: SocketAddr, dst: SocketAddr) -> Self {
+        Self {
+            src,
+            dst,
+            seq: AtomicU32::new(1),
+        }
+    }
+
+    fn packet(&self, seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut tcp = Vec::with_capacity(20 + payload.len());
+        tcp.extend_from_slice(&self.src.port().to_be_bytes());
+        tcp.extend_from_slice(&self.dst.port().to_be_bytes());
+        tcp.extend_from_slice(&seq.to_be_bytes());
+        tcp.extend_from_slice(&ack.to_be_bytes());
+        tcp.push(5 << 4); // data offset: 5 words, no options
+        tcp.push(flags);
+        tcp.extend_from_slice(&u16::MAX.to_be_bytes()); // window
+        tcp.extend_from_slice(&[0, 0]); // checksum, left for the reader to ignore
+        tcp.extend_from_slice(&[0, 0]); // urgent pointer
+        tcp.extend_from_slice(payload);
+
+        let len = tcp.len();
+        let mut packet = Vec::with_capacity(40 + len);
+        match (self.src.ip().to_canonical(), self.dst.ip().to_canonical()) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                let mut header = [0_u8; 20];
+                header[0] = 0x45; // version 4, 5 word header
+                header[2..4].copy_from_slice(&((20 + len) as u16).to_be_bytes());
+                header[6] = 0x40; // don't fragment
+                header[8] = 64; // ttl
+                header[9] = libc::IPPROTO_TCP as u8;
+                header[12..16].copy_from_slice(&src.octets());
+                header[16..20].copy_from_slice(&dst.octets());
+                let checksum = ipv4_checksum(&header);
+                header[10..12].copy_from_slice(&checksum.to_be_bytes());
+                packet.extend_from_slice(&header);
+            }
+            (src, dst) => {
+                packet.extend_from_slice(&0x6000_0000_u32.to_be_bytes());
+                packet.extend_from_slice(&(len as u16).to_be_bytes());
+                packet.push(libc::IPPROTO_TCP as u8);
+                packet.push(64); // hop limit
+                packet.extend_from_slice(&to_ipv6_octets(src));
+                packet.extend_from_slice(&to_ipv6_octets(dst));
+            }
+        }
+        packet.extend_from_slice(&tcp);
+        packet
+    }
+}
+
+/// Both ends of a synthesized packet need the same ip version, so mixed flows are recorded as
+/// ipv6 with the ipv4 end mapped into the ipv6 address space.
+fn to_ipv6_octets(addr: IpAddr) -> [u8; 16] {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped().octets(),
+        IpAddr::V6(addr) => addr.octets(),
+    }
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let sum = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum::<u32>();
+    let folded = (sum & 0xffff) + (sum >> 16);
+    !((folded & 0xffff) + (folded >> 16)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process, sync::Arc};
+
+    use tokio::{net::TcpListener, sync::watch};
+
+    use super::*;
+    use crate::metrics::RelayMetrics;
+
+    /// Both ends of a loopback tcp connection.
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connecting = TcpStream::connect(listener.local_addr().unwrap());
+        let (connected, accepted) = tokio::join!(connecting, listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    #[test]
+    fn matches_configured_clients_and_destinations() {
+        let file = env::temp_dir().join(format!("socks5-capture-match-{}.pcap", process::id()));
+        let capture = Capture::create(CaptureConfig {
+            file: file.clone(),
+            clients: vec!["192.0.2.1".parse().unwrap()],
+            destinations: vec!["example.com:443".to_owned(), "example.net".to_owned()],
+        })
+        .unwrap();
+        let request = |host: &str, port| proto::ClientConnectionRequest {
+            cmd: proto::ClientCommand::EstablishConnection,
+            dest_addr: proto::Address::DomainName(host.to_owned()),
+            dest_port: port,
+        };
+        let (client, other) = (
+            "192.0.2.1:50000".parse().unwrap(),
+            "192.0.2.2:50000".parse().unwrap(),
+        );
+        assert!(capture.matches(client, &request("example.org", 80)));
+        assert!(capture.matches(other, &request("example.com", 443)));
+        assert!(!capture.matches(other, &request("example.com", 80)));
+        assert!(capture.matches(other, &request("example.net", 22)));
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn records_the_relayed_payloads_as_a_tcp_flow() {
+        let file = env::temp_dir().join(format!("socks5-capture-flow-{}.pcap", process::id()));
+        let capture = Capture::create(CaptureConfig {
+            file: file.clone(),
+            clients: Vec::new(),
+            destinations: Vec::new(),
+        })
+        .unwrap();
+        let (mut client_peer, client) = socket_pair().await;
+        let (remote, mut remote_peer) = socket_pair().await;
+        let (client_addr, remote_addr) = (client.peer_addr().unwrap(), remote.peer_addr().unwrap());
+        let totals = RelayMetrics::default();
+        let relayed = ConnectionRelay::new(&totals, Arc::new(watch::Sender::default()));
+
+        let peers = async {
+            client_peer.write_all(b"ping").await.unwrap();
+            client_peer.shutdown().await.unwrap();
+            let mut request = Vec::new();
+            remote_peer.read_to_end(&mut request).await.unwrap();
+            remote_peer.write_all(b"pong").await.unwrap();
+            remote_peer.shutdown().await.unwrap();
+            let mut response = Vec::new();
+            client_peer.read_to_end(&mut response).await.unwrap();
+            (request, response)
+        };
+        let (res, (request, response)) =
+            tokio::join!(capture.relay(client, remote, &relayed), peers);
+        res.unwrap();
+        assert_eq!((&request[..], &response[..]), (&b"ping"[..], &b"pong"[..]));
+
+        let pcap = fs::read(&file).unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(pcap[..4], PCAP_MAGIC.to_le_bytes());
+        assert_eq!(pcap[20..24], LINKTYPE_RAW.to_le_bytes());
+        let mut packets = Vec::new();
+        let mut rest = &pcap[24..];
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+            packets.push(&rest[16..16 + len]);
+            rest = &rest[16 + len..];
+        }
+        // (source port, flags, payload) of each packet, along with a valid ipv4 header
+        let flow: Vec<(u16, u8, &[u8])> = packets
+            .iter()
+            .map(|packet| {
+                assert_eq!(packet[0], 0x45);
+                assert_eq!(ipv4_checksum(&packet[..20]), 0);
+                let tcp = &packet[20..];
+                (u16::from_be_bytes([tcp[0], tcp[1]]), tcp[13], &tcp[20..])
+            })
+            .collect();
+        let (from_client, from_remote) = (client_addr.port(), remote_addr.port());
+        assert_eq!(
+            flow,
+            [
+                (from_client, TCP_PSH_ACK, &b"ping"[..]),
+                (from_client, TCP_FIN | TCP_PSH_ACK, &[][..]),
+                (from_remote, TCP_PSH_ACK, &b"pong"[..]),
+                (from_remote, TCP_FIN | TCP_PSH_ACK, &[][..]),
+            ]
+        );
+    }
+}
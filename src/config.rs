@@ -0,0 +1,1146 @@
+//! The server's configuration, read from a toml file.
+//!
+//! Any field can also be set through a `SOCKS5_CFG__` environment variable, which takes
+//! precedence over the file: the field's path upper-cased, with `__` between nested keys.
+//! `SOCKS5_CFG__LISTEN_ADDR` sets `listen_addr` and `SOCKS5_CFG__AUTH__BACKEND` sets
+//! `backend` of the `[auth]` table. Other `SOCKS5_` variables are left alone. Values are
+//! read as toml when they parse as such, so numbers, booleans and arrays work as expected,
+//! and anything else is taken as a string. Strings that would parse as something else have
+//! to be quoted.
+
+use std::{
+    collections::HashMap, env, fmt, fs, io, net::IpAddr, num::NonZeroUsize, path::Path,
+    path::PathBuf,
+};
+
+use serde::Deserialize;
+
+use crate::redact::{self, Redacted};
+
+mod check;
+
+pub use check::{check, Check};
+
+const ENV_PREFIX: &str = "SOCKS5_CFG__";
+
+/// A config with every setting at its default and the optional sections commented out,
+/// documenting each of them.
+pub const DEFAULT_CONFIG: &str = include_str!("config/default.toml");
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen_addr: String,
+    pub listener: ListenerConfig,
+    /// more socks listeners, each with policy of its own
+    pub listeners: Vec<NamedListenerConfig>,
+    /// require clients to authenticate with a username and password
+    pub auth: Option<AuthConfig>,
+    /// offer kerberos authentication through gssapi. requires the `gssapi` feature.
+    pub gssapi: Option<GssapiConfig>,
+    pub outbound: OutboundConfig,
+    pub resolver: ResolverConfig,
+    pub relay: RelayStrategy,
+    /// bytes buffered per direction when relaying through userspace. larger buffers move
+    /// more per syscall on fast links, smaller ones keep idle sessions cheap.
+    pub relay_buffer_size: NonZeroUsize,
+    /// send with `MSG_ZEROCOPY` when relaying through userspace, sparing the cpu copying
+    /// into socket buffers. linux only, and only pays off with buffers of 64 KiB and up.
+    pub relay_zerocopy: bool,
+    /// tcp keepalive probing of both legs of relayed connections
+    pub keepalive: Option<KeepaliveConfig>,
+    pub relay_close: RelayCloseConfig,
+    /// upper bound on concurrently handled client connections
+    pub max_connections: Option<usize>,
+    pub handshake_timeouts: HandshakeTimeoutsConfig,
+    pub handshake_limits: HandshakeLimitsConfig,
+    /// address of the http listener serving `/healthz`, `/readyz`, `/metrics` and the admin
+    /// endpoints, see [`crate::health`]
+    pub health_addr: Option<String>,
+    /// bearer token the health listener requires for listing or cancelling connections, or
+    /// cancelling the server. without one, it refuses to.
+    pub admin_token: Option<String>,
+    /// how often a summary of the server's activity is logged, at least a second, see
+    /// [`crate::stats`]
+    pub stats_interval_secs: Option<u64>,
+    pub top_talkers: Option<TopTalkersConfig>,
+    /// rhai script consulted for every client request. requires the `scripting` feature.
+    pub route_script: Option<PathBuf>,
+    pub capture: Option<CaptureConfig>,
+    pub priority: Option<PriorityConfig>,
+    pub acl: Option<AclConfig>,
+    pub port_policy: Option<PortPolicyConfig>,
+    pub blocklist: Option<BlocklistConfig>,
+    pub dnsbl: Option<DnsblConfig>,
+    pub tarpit: Option<TarpitConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    /// file every client request is logged to, see [`crate::access_log`]
+    pub access_log: Option<PathBuf>,
+    pub access_log_rotation: Option<LogRotationConfig>,
+    /// the local syslog daemon client requests and connection errors are also logged to,
+    /// see [`crate::syslog`]. unix only.
+    pub syslog: Option<SyslogConfig>,
+    /// file every denied request is logged to, see [`crate::honeypot`]
+    pub honeypot_log: Option<PathBuf>,
+    pub udp: UdpConfig,
+    /// also serve clients speaking the SOCKS6 draft (draft-olteanu-intarea-socks-6-11) on
+    /// the same listener. experimental: only unauthenticated CONNECTs are supported.
+    pub socks6: bool,
+    pub http_proxy: Option<HttpProxyConfig>,
+    pub redirect: Option<RedirectConfig>,
+    pub websocket: Option<WebSocketConfig>,
+    pub quic: Option<QuicConfig>,
+    /// misbehave on purpose, for testing clients, see [`crate::faults`]
+    pub faults: Option<FaultsConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: "127.0.0.1:4242".to_owned(),
+            listener: ListenerConfig::default(),
+            listeners: Vec::new(),
+            auth: None,
+            gssapi: None,
+            outbound: OutboundConfig::default(),
+            resolver: ResolverConfig::default(),
+            relay: RelayStrategy::default(),
+            relay_buffer_size: NonZeroUsize::new(8 << 10).unwrap(),
+            relay_zerocopy: false,
+            keepalive: None,
+            relay_close: RelayCloseConfig::default(),
+            max_connections: None,
+            handshake_timeouts: HandshakeTimeoutsConfig::default(),
+            handshake_limits: HandshakeLimitsConfig::default(),
+            health_addr: None,
+            admin_token: None,
+            stats_interval_secs: None,
+            top_talkers: None,
+            route_script: None,
+            capture: None,
+            priority: None,
+            acl: None,
+            port_policy: None,
+            blocklist: None,
+            dnsbl: None,
+            tarpit: None,
+            rate_limit: None,
+            access_log: None,
+            access_log_rotation: None,
+            syslog: None,
+            honeypot_log: None,
+            udp: UdpConfig::default(),
+            socks6: false,
+            http_proxy: None,
+            redirect: None,
+            websocket: None,
+            quic: None,
+            faults: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        toml::from_str(&contents).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("failed to parse config {}: {err}", path.display()),
+            )
+        })
+    }
+
+    /// Reads the config file at `path`, if any, with the `SOCKS5_` environment variables
+    /// applied over it.
+    pub fn load(path: Option<&Path>) -> io::Result<Self> {
+        let (mut table, source) = match path {
+            Some(path) => {
+                let contents = fs::read_to_string(path)?;
+                let table = toml::from_str(&contents).map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("failed to parse config {}: {err}", path.display()),
+                    )
+                })?;
+                (table, format!("config {}", path.display()))
+            }
+            None => (toml::Table::new(), "config".to_owned()),
+        };
+        let overridden = apply_env_overrides(&mut table, env::vars())?;
+        toml::Value::Table(table).try_into().map_err(|err| {
+            let with = if overridden {
+                " with environment overrides"
+            } else {
+                ""
+            };
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid {source}{with}: {err}"),
+            )
+        })
+    }
+}
+
+/// Sets the field named by each `SOCKS5_CFG__` variable in `vars`, creating tables along its path
+/// as needed. Returns whether any were applied.
+fn apply_env_overrides(
+    table: &mut toml::Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> io::Result<bool> {
+    let mut overridden = false;
+    for (name, raw) in vars {
+        let Some(path) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let keys: Vec<String> = path.split("__").map(str::to_lowercase).collect();
+        let (field, parents) = keys.split_last().expect("split yields at least one key");
+        let mut target = &mut *table;
+        for key in parents {
+            target = target
+                .entry(key.as_str())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{name}: `{key}` is not a table"),
+                    )
+                })?;
+        }
+        target.insert(field.clone(), env_value(&raw));
+        overridden = true;
+    }
+    Ok(overridden)
+}
+
+fn env_value(raw: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("v = {raw}"))
+        .ok()
+        .and_then(|mut parsed| parsed.remove("v"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_owned()))
+}
+
+/// Socket options for the socks listener. Unset options keep the OS defaults, except for
+/// `reuse_address` which defaults to on.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub backlog: Option<i32>,
+    pub ipv6_only: Option<bool>,
+    pub reuse_address: Option<bool>,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    /// accept multipath tcp connections, falling back to tcp where the kernel lacks support
+    pub mptcp: bool,
+}
+
+/// A socks listener besides the one on `listen_addr`, with auth requirements, access rules
+/// and a connection limit of its own. Whatever it leaves unset is the server's. It shares the
+/// socket options of `[listener]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamedListenerConfig {
+    /// tags the connections of the listener in logs
+    pub name: String,
+    pub listen_addr: String,
+    /// let clients in without authenticating, whatever the server requires elsewhere
+    #[serde(default)]
+    pub no_auth: bool,
+    pub auth: Option<AuthConfig>,
+    pub acl: Option<AclConfig>,
+    pub port_policy: Option<PortPolicyConfig>,
+    /// upper bound on concurrently handled connections from this listener, which count
+    /// towards the server's `max_connections` as well
+    pub max_connections: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub backend: AuthBackend,
+    /// accounts checked by the `users` backend
+    pub users: HashMap<String, UserConfig>,
+    /// toml file with more `[users.<name>]` accounts for the `users` backend, replacing inline
+    /// ones of the same name. it is reloaded when it changes, so accounts can be added and
+    /// revoked without a restart.
+    pub users_file: Option<PathBuf>,
+    /// how often `users_file` is checked for changes, 0 checking on every login
+    pub reload_interval_secs: u64,
+    pub ldap: Option<LdapConfig>,
+    pub pam: Option<PamConfig>,
+    pub jwt: Option<JwtConfig>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            backend: AuthBackend::default(),
+            users: HashMap::new(),
+            users_file: None,
+            reload_interval_secs: 5,
+            ldap: None,
+            pam: None,
+            jwt: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthBackend {
+    /// the accounts listed in `users`
+    #[default]
+    Users,
+    /// bind to the directory in `ldap` as the client's user. requires the `ldap` feature.
+    Ldap,
+    /// the host's system accounts, checked through PAM. requires the `pam` feature.
+    Pam,
+    /// json web tokens passed as the password, signed with one of the `jwt` keys. requires
+    /// the `jwt` feature.
+    Jwt,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    #[serde(default)]
+    pub password: String,
+    /// base32 totp secret. the user then appends the current 6 digit code to their password.
+    pub totp_secret: Option<String>,
+}
+
+impl fmt::Debug for UserConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserConfig")
+            .field("password", &Redacted)
+            .field("totp_secret", &self.totp_secret.as_ref().map(|_| Redacted))
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LdapConfig {
+    /// `ldap://` or `ldaps://` url of the directory server
+    pub url: String,
+    /// dn to bind as, with `{username}` replaced by the escaped client username. for active
+    /// directory this can also be a user principal name like `{username}@corp.example.com`.
+    pub bind_dn: String,
+    /// upgrade `ldap://` connections with StartTLS
+    #[serde(default)]
+    pub starttls: bool,
+    #[serde(default = "LdapConfig::default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl LdapConfig {
+    fn default_timeout_secs() -> u64 {
+        5
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PamConfig {
+    /// name of the policy under /etc/pam.d to authenticate against
+    pub service: String,
+}
+
+impl Default for PamConfig {
+    fn default() -> Self {
+        Self {
+            service: "socks5".to_owned(),
+        }
+    }
+}
+
+/// Validation of the tokens clients pass as their password with the `jwt` backend. Tokens
+/// must carry an `exp` claim and a `sub` claim naming the user they log in as.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtConfig {
+    /// keys tokens may be signed with, several while they are rotated
+    pub keys: Vec<JwtKeyConfig>,
+    /// required `iss` claim
+    pub issuer: Option<String>,
+    /// required among the `aud` claim
+    pub audience: Option<String>,
+    /// seconds of clock skew tolerated checking `exp` and `nbf`
+    #[serde(default = "JwtConfig::default_leeway_secs")]
+    pub leeway_secs: u64,
+}
+
+impl JwtConfig {
+    fn default_leeway_secs() -> u64 {
+        60
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtKeyConfig {
+    /// matched against the `kid` header of tokens naming their key
+    pub kid: Option<String>,
+    /// `HS256`, `RS256`, `ES256`, `EdDSA` and the like
+    pub algorithm: String,
+    /// shared secret of the `HS*` algorithms
+    pub secret: Option<String>,
+    /// PEM file with the public key of the others
+    pub public_key: Option<PathBuf>,
+}
+
+impl fmt::Debug for JwtKeyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtKeyConfig")
+            .field("kid", &self.kid)
+            .field("algorithm", &self.algorithm)
+            .field("secret", &self.secret.as_ref().map(|_| Redacted))
+            .field("public_key", &self.public_key)
+            .finish()
+    }
+}
+
+/// Options for the sockets dialed towards client requested destinations.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundConfig {
+    /// dial destinations over multipath tcp, falling back to tcp where the kernel lacks support
+    pub mptcp: bool,
+    /// local addresses dials are made from, spread out as `source_selection` says. the OS
+    /// picks the source when empty.
+    pub source_addresses: Vec<IpAddr>,
+    pub source_selection: SourceSelection,
+    /// which of the addresses a domain name resolves to is dialed first
+    pub address_selection: AddressSelection,
+    /// for how long a destination whose name doesn't exist or that refused the connection
+    /// fails further requests right away, without dialing it again. lookups that timed out
+    /// or failed otherwise are retried.
+    pub failure_cache_secs: Option<u64>,
+    /// DSCP codepoint (0 to 63) destination-facing sockets mark their packets with, for
+    /// network QoS policies to classify proxied traffic by. acl rules can override it.
+    pub dscp: Option<u8>,
+    /// first and last local port destination-facing sockets, tcp and udp alike, are bound
+    /// to, for egress firewall rules. the OS picks from its ephemeral range when unset.
+    pub local_ports: Option<[u16; 2]>,
+    /// dials to the same destination host that may be in flight at once. requests beyond
+    /// it fail right away, so that a burst of them to a host that doesn't answer can't tie
+    /// up sockets until they all time out.
+    pub max_dials_per_destination: Option<NonZeroUsize>,
+}
+
+/// How dials are spread over [`OutboundConfig::source_addresses`]. Only addresses of the
+/// destination's family are candidates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceSelection {
+    /// each dial uses the next address
+    #[default]
+    RoundRobin,
+    /// the same destination host is always dialed from the same address
+    HashDestination,
+}
+
+/// The order the addresses of a domain name destination are tried in. Whichever comes
+/// first, the rest are still tried in turn when it can't be connected to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AddressSelection {
+    /// the order the resolver returned them in
+    #[default]
+    First,
+    /// starting from a random address
+    Random,
+    /// starting from the address after the one the previous dial to the same host started
+    /// from, spreading load over a destination's replicas
+    RoundRobin,
+}
+
+/// How domain name destinations are resolved.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResolverConfig {
+    pub backend: ResolverBackend,
+    /// `https://host[:port][/path]` endpoint queried by the doh backend
+    pub doh_url: Option<String>,
+    /// hosts(5) format file whose entries take precedence over the backend
+    pub hosts_file: Option<PathBuf>,
+    /// name to addresses overrides, taking precedence over `hosts_file`
+    pub hosts: HashMap<String, Vec<IpAddr>>,
+    /// how long the backend gets to resolve a name before the request fails as if the host
+    /// was unreachable, apart from the time allowed to connect
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolverBackend {
+    /// the operating system's resolver
+    #[default]
+    System,
+    /// DNS-over-HTTPS against `doh_url`. requires the `doh` feature.
+    Doh,
+}
+
+/// How relayed bytes are moved between the client and the destination.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayStrategy {
+    /// splice where the kernel allows it, copy through userspace otherwise
+    #[default]
+    Auto,
+    /// always splice, failing connections where that is not possible. linux only.
+    Splice,
+    /// always copy through userspace buffers
+    Userspace,
+}
+
+/// How long a client may take over the socks handshake, before the connection is dropped.
+/// The per-phase limits cut off clients trickling in a byte at a time well before the
+/// overall one would. Unset limits don't apply.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HandshakeTimeoutsConfig {
+    /// from accepting the connection until its request has been read, covering the listener
+    /// transport and the socks6 and http proxy handshakes too
+    pub total_secs: Option<u64>,
+    /// for the greeting listing the client's auth methods
+    pub greeting_secs: Option<u64>,
+    /// for the subnegotiation of the chosen auth method, including checking credentials
+    pub auth_secs: Option<u64>,
+    /// for the request naming the destination
+    pub request_secs: Option<u64>,
+}
+
+/// How much a client may send during the socks handshake, before the connection is turned
+/// away with the reply for a malformed message. The protocol's length fields allow messages
+/// of a few hundred bytes, which internet-facing listeners may want to cut down. Unset
+/// limits don't apply.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HandshakeLimitsConfig {
+    /// auth methods a greeting may offer
+    pub max_auth_methods: Option<u8>,
+    /// bytes of a message buffered before all of it arrived and it can be parsed. a
+    /// message needing more is rejected as soon as its length fields say so.
+    pub max_pending_bytes: Option<usize>,
+}
+
+/// Keepalive probes tear down relayed connections whose peer vanished without closing them, once
+/// `count` probes sent `interval_secs` apart after `idle_secs` of silence went unanswered.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeepaliveConfig {
+    pub idle_secs: u64,
+    pub interval_secs: u64,
+    pub count: u32,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            idle_secs: 60,
+            interval_secs: 10,
+            count: 6,
+        }
+    }
+}
+
+/// How the legs of relayed connections are closed. By default both are closed as soon as the
+/// relay ends, with a FIN, or with a RST if data the relay didn't read is left.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayCloseConfig {
+    /// reset both legs when they are closed, with SO_LINGER set to 0, instead of sending a
+    /// FIN. the client's leg is left alone when a listener transport wraps it.
+    pub abort_on_close: bool,
+    /// when the relay fails on one side, send a FIN to both and wait this long for the peers
+    /// to close their sides, instead of closing the connections right away
+    pub drain_timeout_ms: Option<u64>,
+}
+
+/// Counts the bytes relayed by client and by destination, to report the top ones, see
+/// [`crate::top_talkers`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TopTalkersConfig {
+    /// how far back the report looks
+    pub window_secs: u64,
+    /// how many clients and destinations the report lists
+    pub top: usize,
+}
+
+impl Default for TopTalkersConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 300,
+            top: 10,
+        }
+    }
+}
+
+/// Records relayed traffic of matching connections into a pcap file. Leaving both `clients`
+/// and `destinations` empty captures every connection.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureConfig {
+    pub file: PathBuf,
+    #[serde(default)]
+    pub clients: Vec<IpAddr>,
+    /// matched against the requested destination, either as `host` or `host:port`
+    #[serde(default)]
+    pub destinations: Vec<String>,
+}
+
+/// Splits `bandwidth` between classes of connections by weight. See [`crate::priority`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PriorityConfig {
+    /// bytes per second shared by all scheduled connections, usually the egress link capacity
+    pub bandwidth: u64,
+    /// a connection belongs to the first class it matches, connections matching none of them
+    /// are relayed without scheduling
+    pub classes: Vec<PriorityClassConfig>,
+}
+
+/// A class of connections matched by client or destination, like [`CaptureConfig`]. A class
+/// with neither matches every connection.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PriorityClassConfig {
+    pub name: String,
+    pub weight: u32,
+    #[serde(default)]
+    pub clients: Vec<IpAddr>,
+    #[serde(default)]
+    pub destinations: Vec<String>,
+}
+
+/// Rules deciding which requests are served. See [`crate::acl`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclConfig {
+    /// what happens to requests matching none of the rules
+    #[serde(default)]
+    pub default: AclAction,
+    /// iana name of the timezone schedules are in, defaulting to the system's
+    pub timezone: Option<String>,
+    /// logs the requests the acl would deny, at most one a second, instead of denying them
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub rules: Vec<AclRuleConfig>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AclAction {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// Matches requests by client and destination, like [`CaptureConfig`], except that both have
+/// to match when both are given.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclRuleConfig {
+    /// labels the rule's series in the acl metrics, instead of its index among the rules
+    pub name: Option<String>,
+    pub action: AclAction,
+    /// addresses or networks such as `10.0.0.0/8`, any client when empty
+    #[serde(default)]
+    pub clients: Vec<String>,
+    /// names, domains along with the names under them such as `.example.com`, or addresses
+    /// or networks, each optionally followed by `:port`. ipv6 ones are bracketed when they
+    /// have a port. any destination when empty.
+    #[serde(default)]
+    pub destinations: Vec<String>,
+    /// restricts the rule to certain times, outside of which it matches nothing
+    pub schedule: Option<ScheduleConfig>,
+    /// DSCP codepoint for the connections of requests the rule allows, instead of
+    /// [`OutboundConfig::dscp`]
+    pub dscp: Option<u8>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// `mon` to `sun` or full day names, every day when empty
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// `HH:MM-HH:MM`, wrapping past midnight into the day after a scheduled one when the end is
+    /// before the start
+    pub hours: Option<String>,
+}
+
+/// Destination ports clients may not connect to, checked before the acl rules.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortPolicyConfig {
+    /// denied for every user, e.g. 25 and 465 to keep the proxy from relaying spam
+    pub denied: Vec<u16>,
+    /// per-user exceptions, by the name the client authenticated as
+    pub users: HashMap<String, UserPortPolicyConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserPortPolicyConfig {
+    /// globally denied ports this user may still connect to
+    pub allowed: Vec<u16>,
+    /// ports denied to this user on top of the global ones
+    pub denied: Vec<u16>,
+}
+
+/// Files listing domains requests may not go to, see [`crate::blocklist`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlocklistConfig {
+    pub files: Vec<PathBuf>,
+    /// how often the files are checked for changes, at least a second
+    pub reload_interval_secs: u64,
+}
+
+impl Default for BlocklistConfig {
+    fn default() -> Self {
+        Self {
+            files: Vec::new(),
+            reload_interval_secs: 60,
+        }
+    }
+}
+
+/// Reputation checks of connecting clients against DNS blocklists, see [`crate::dnsbl`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsblConfig {
+    /// blocklist zones queried for every client address, e.g. `zen.spamhaus.org`
+    pub zones: Vec<String>,
+    pub action: DnsblAction,
+    /// how long listed clients are held up before their handshake goes on, with `throttle`
+    pub throttle_ms: u64,
+    /// how long the verdict on an address is remembered
+    pub cache_secs: u64,
+    /// addresses whose verdict is remembered at most, the least recently seen ones making
+    /// room for others
+    pub cache_entries: usize,
+    /// how long to wait for a zone's answer before taking the client as not listed
+    pub timeout_ms: u64,
+}
+
+impl Default for DnsblConfig {
+    fn default() -> Self {
+        Self {
+            zones: Vec::new(),
+            action: DnsblAction::default(),
+            throttle_ms: 5000,
+            cache_secs: 3600,
+            cache_entries: 65536,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+/// What becomes of clients listed in a dnsbl zone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsblAction {
+    /// close the connection before reading the handshake
+    #[default]
+    Reject,
+    /// serve the client after `throttle_ms`
+    Throttle,
+}
+
+/// Holds up the failure replies to clients that were denied by the port policy, blocklist,
+/// acl or route script, or gave a wrong username or password, slowing down scanners and
+/// password guessing, or failed gssapi or private auth methods. Each reply waits `delay_ms`
+/// plus a random share of `jitter_ms`, so that the delay can't be told apart from a slow
+/// backend. Private methods reply themselves, so their failures hold up closing the
+/// connection instead. Handshake timeouts still apply, cutting the connection instead if
+/// they run out first.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TarpitConfig {
+    pub delay_ms: u64,
+    pub jitter_ms: u64,
+    /// clients held up at once. tarpitted clients keep counting against `max_connections`,
+    /// so beyond this many, connections are closed right away without a reply
+    pub max_clients: usize,
+}
+
+impl Default for TarpitConfig {
+    fn default() -> Self {
+        Self {
+            delay_ms: 3000,
+            jitter_ms: 2000,
+            max_clients: 256,
+        }
+    }
+}
+
+/// How many requests each client address and user may make per window of time, see
+/// [`crate::rate_limit`].
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub limits: Vec<LimitConfig>,
+    /// `redis://host:port/db` of a redis server the counts are kept in, shared by every
+    /// server using it, instead of in the process. requires the `redis` feature.
+    pub redis_url: Option<String>,
+    /// prefix of the redis keys
+    pub key_prefix: String,
+    /// how long to wait for redis before letting the request through uncounted
+    pub timeout_ms: u64,
+}
+
+impl fmt::Debug for RateLimitConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimitConfig")
+            .field("limits", &self.limits)
+            .field("redis_url", &self.redis_url.as_deref().map(redact::url))
+            .field("key_prefix", &self.key_prefix)
+            .field("timeout_ms", &self.timeout_ms)
+            .finish()
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            limits: Vec::new(),
+            redis_url: None,
+            key_prefix: "socks5".to_owned(),
+            timeout_ms: 200,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitConfig {
+    pub per: LimitScope,
+    /// requests allowed in each window, counting from its start
+    pub requests: Option<u64>,
+    /// bytes relayed over tcp connections, both directions together, allowed in each window.
+    /// counted as connections end, so requests are denied once the bytes of those that
+    /// ended reached it.
+    pub bytes: Option<u64>,
+    pub window_secs: u64,
+}
+
+/// Whose requests a limit counts together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitScope {
+    /// those of a client address
+    Ip,
+    /// those of a user, leaving out clients that didn't authenticate
+    User,
+}
+
+/// GSSAPI authentication as described by RFC 1961. Clients offering it are preferred over
+/// username/password ones, and clients offering neither are turned away.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GssapiConfig {
+    /// keytab holding the service keys, instead of the kerberos library's default
+    pub keytab: Option<PathBuf>,
+    /// least protection the client has to agree to for the rest of the connection
+    pub protection: GssapiProtection,
+}
+
+/// RFC 1961 per-message protection levels, weakest first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GssapiProtection {
+    /// messages are signed, so tampering is detected
+    #[default]
+    Integrity,
+    /// messages are encrypted as well as signed
+    Confidentiality,
+}
+
+/// Plain HTTP/1.1 forward proxying, of CONNECT tunnels and of absolute-URI requests to http
+/// origins. Requests pass the same policy checks as socks ones, and are authenticated with
+/// basic `Proxy-Authorization` credentials when the server has an `auth` section. Each client
+/// connection carries a single request, see [`crate::http_proxy`].
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpProxyConfig {
+    /// serve http proxy requests on their own listener. without it, they are accepted on the
+    /// socks listener and told apart from socks handshakes by their first byte.
+    pub listen_addr: Option<String>,
+}
+
+/// Transparent proxying of connections redirected to a listener of their own by an iptables
+/// or nftables `REDIRECT` rule. Each is relayed to the destination it was headed to, as
+/// recovered with `SO_ORIGINAL_DST`, without any handshake: the connections are not
+/// authenticated, and pass the acl and other policy checks like socks CONNECTs without a
+/// username. Linux only.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectConfig {
+    /// address the redirected connections arrive at, the target of the `REDIRECT` rule
+    pub listen_addr: String,
+}
+
+/// Expect clients to tunnel the socks stream through a WebSocket instead of connecting
+/// directly, so it can pass CDNs and http-only middleboxes. Needs the `websocket` feature,
+/// and turns off SOCKS6 and the http proxy on the socks listener.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    /// request path the upgrade is accepted on, others get a 404
+    pub path: String,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            path: "/".to_owned(),
+        }
+    }
+}
+
+/// Also accept socks sessions over QUIC, one per bidirectional stream, see [`crate::quic`].
+/// Needs the `quic` feature.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuicConfig {
+    /// udp address the QUIC endpoint listens on
+    pub listen_addr: String,
+    /// PEM file with the certificate chain presented to clients, leaf first
+    pub cert_chain: PathBuf,
+    /// PEM file with the certificate's private key
+    pub private_key: PathBuf,
+    /// PEM file with the certificates of the authorities client certificates are verified
+    /// against. clients presenting a certificate are authenticated by it instead of by
+    /// password, as the user its identity maps to.
+    pub client_ca: Option<PathBuf>,
+    /// turn away clients without a certificate, rather than authenticating them as usual
+    #[serde(default)]
+    pub require_client_cert: bool,
+    /// users by certificate identity: the first DNS name or email address among the
+    /// certificate's subject alternative names, or else its common name. an identity
+    /// without an entry is the user's name itself.
+    #[serde(default)]
+    pub client_users: HashMap<String, String>,
+}
+
+/// Faults injected into the socks5 handshake of every connection, per phase.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaultsConfig {
+    /// the reply choosing an auth method
+    pub greeting: PhaseFaultsConfig,
+    /// the username/password status reply
+    pub auth: PhaseFaultsConfig,
+    /// the reply to the request
+    pub request: PhaseFaultsConfig,
+    /// `ServerStatus` code every request is answered with, instead of being served
+    pub reply_status: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PhaseFaultsConfig {
+    /// how long the reply is held back
+    pub delay_ms: u64,
+    /// close the connection instead of replying
+    pub close: bool,
+    /// send the reply with every byte inverted
+    pub garble: bool,
+}
+
+/// When to start a new access log file, and how many old ones to keep. Either limit
+/// triggers a rotation.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogRotationConfig {
+    /// rotate before a line would take the file past this size
+    pub max_size_bytes: Option<u64>,
+    /// rotate once the file has been written to for this long
+    pub interval_secs: Option<u64>,
+    /// rotated files to keep around as `<path>.1` (the newest) to `<path>.<keep>`
+    pub keep: usize,
+}
+
+impl Default for LogRotationConfig {
+    fn default() -> Self {
+        Self {
+            max_size_bytes: None,
+            interval_secs: None,
+            keep: 7,
+        }
+    }
+}
+
+/// Logging to the local syslog daemon, in the format of RFC 5424.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyslogConfig {
+    /// datagram socket the daemon listens on
+    pub socket: PathBuf,
+    pub facility: SyslogFacility,
+    /// APP-NAME of the messages, up to 48 printable ascii characters
+    pub app_name: String,
+    /// log client requests like the access log does
+    pub access: bool,
+    /// log connections that ended in an error
+    pub errors: bool,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        Self {
+            socket: PathBuf::from("/dev/log"),
+            facility: SyslogFacility::Daemon,
+            app_name: "socks5".to_owned(),
+            access: true,
+            errors: true,
+        }
+    }
+}
+
+/// The facilities of RFC 5424 a daemon may log as, in the order of their codes from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFacility {
+    User = 1,
+    Mail,
+    Daemon,
+    Auth,
+    Syslog,
+    Lpr,
+    News,
+    Uucp,
+    Cron,
+    Authpriv,
+    Ftp,
+    Local0 = 16,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+/// Limits on UDP ASSOCIATE relays.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UdpConfig {
+    /// serve UDP ASSOCIATE requests. clients denied it are told the command isn't supported.
+    pub enabled: bool,
+    /// associations that relayed nothing for this long are torn down, even while their
+    /// controlling tcp connection is still open
+    pub idle_timeout_secs: u64,
+    /// accept datagrams from any address and answer wherever the client last sent from,
+    /// instead of only the address declared in the ASSOCIATE request. needed for clients
+    /// behind NATs that rebind, at the cost of letting anyone who learns the relay port use it.
+    pub allow_client_rebinding: bool,
+    /// read and write runs of same-sized datagrams with a single syscall through UDP_GRO
+    /// and UDP_SEGMENT, where the kernel supports them. linux only, ignored elsewhere.
+    pub offload: bool,
+    pub dns: Option<DnsRelayConfig>,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            idle_timeout_secs: 120,
+            allow_client_rebinding: false,
+            offload: true,
+            dns: None,
+        }
+    }
+}
+
+/// DNS queries clients send through udp associations, to port 53, answered from a cache
+/// where possible, see [`crate::dns_relay`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsRelayConfig {
+    /// `ip:port` of the resolver queries missing the cache are sent to, instead of the
+    /// destination the client named
+    pub upstream: Option<String>,
+    /// answers kept across all associations. 0 disables caching. without an upstream, each
+    /// association caches up to 256 of the answers to its own queries instead.
+    pub cache_entries: usize,
+    /// answers are cached for the smallest ttl of their records, but no longer than this
+    pub max_ttl_secs: u64,
+}
+
+impl Default for DnsRelayConfig {
+    fn default() -> Self {
+        Self {
+            upstream: None,
+            cache_entries: 10_000,
+            max_ttl_secs: 3600,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_matches_the_defaults() {
+        let config: ServerConfig = toml::from_str(DEFAULT_CONFIG).unwrap();
+        let defaults = ServerConfig::default();
+        assert_eq!(config.listen_addr, defaults.listen_addr);
+        assert_eq!(config.relay, defaults.relay);
+        assert_eq!(config.relay_buffer_size, defaults.relay_buffer_size);
+        assert_eq!(config.relay_zerocopy, defaults.relay_zerocopy);
+        assert_eq!(
+            config.outbound.source_selection,
+            defaults.outbound.source_selection
+        );
+        assert_eq!(config.udp.idle_timeout_secs, defaults.udp.idle_timeout_secs);
+        assert_eq!(config.udp.offload, defaults.udp.offload);
+        assert!(config.auth.is_none() && config.acl.is_none() && config.quic.is_none());
+    }
+
+    #[test]
+    fn environment_overrides_take_precedence_over_the_file() {
+        let mut table: toml::Table = toml::from_str(
+            r#"
+            listen_addr = "127.0.0.1:1080"
+            max_connections = 10
+
+            [udp]
+            idle_timeout_secs = 60
+            "#,
+        )
+        .unwrap();
+        let vars = [
+            ("SOCKS5_CFG__LISTEN_ADDR", "[::]:1080"),
+            ("SOCKS5_CFG__MAX_CONNECTIONS", "500"),
+            ("SOCKS5_CFG__UDP__ALLOW_CLIENT_REBINDING", "true"),
+            ("SOCKS5_CFG__SOCKS6", "true"),
+            ("SOCKS5_UPGRADE_SOCKET", "/tmp/socks5-upgrade.sock"),
+            ("SOCKS5_PROXY", "127.0.0.1:1080"),
+            ("HOME", "/root"),
+        ];
+        let overridden = apply_env_overrides(
+            &mut table,
+            vars.iter()
+                .map(|&(name, value)| (name.to_owned(), value.to_owned())),
+        )
+        .unwrap();
+        assert!(overridden);
+
+        let config: ServerConfig = toml::Value::Table(table).try_into().unwrap();
+        assert_eq!(config.listen_addr, "[::]:1080");
+        assert_eq!(config.max_connections, Some(500));
+        assert_eq!(config.udp.idle_timeout_secs, 60);
+        assert!(config.udp.allow_client_rebinding);
+        assert!(config.socks6);
+    }
+}
@@ -0,0 +1,332 @@
+//! Validation of a whole config without starting a server, for `--check-config`. Every
+//! configured section is loaded the way the server would load it, except that nothing gets
+//! created or bound.
+
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+};
+
+use tokio::io;
+
+use super::{QuicConfig, RelayStrategy, ServerConfig, SyslogConfig};
+use crate::{
+    acl::Acl, auth, blocklist::Blocklist, dial, dns_relay::DnsRelay, dnsbl::Dnsbl,
+    priority::Scheduler, proto, rate_limit::RateLimiter, resolve, stats, top_talkers::TopTalkers,
+};
+
+/// The outcome of checking one section of the config.
+pub struct Check {
+    pub section: &'static str,
+    pub result: io::Result<()>,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.result {
+            Ok(()) => write!(f, "ok     {}", self.section),
+            Err(err) => write!(f, "error  {}: {err}", self.section),
+        }
+    }
+}
+
+/// Checks every section that is set in `config`, rather than stopping at the first error.
+pub fn check(config: &ServerConfig) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let mut add = |section, result| checks.push(Check { section, result });
+
+    add("listen_addr", resolves(&config.listen_addr));
+    if let Some(addr) = &config.health_addr {
+        add("health_addr", resolves(addr));
+    }
+    if config.admin_token.as_deref() == Some("") {
+        add(
+            "admin_token",
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "admin_token is empty",
+            )),
+        );
+    }
+    if let Some(auth) = &config.auth {
+        let users_file = auth.users_file.as_deref().map_or(Ok(()), readable);
+        add(
+            "auth",
+            users_file.and_then(|()| auth::from_config(auth).map(drop)),
+        );
+    }
+    if !config.listeners.is_empty() {
+        add("listeners", listeners(config));
+    }
+    if let Some(gssapi) = &config.gssapi {
+        add(
+            "gssapi",
+            requires_feature(cfg!(all(unix, feature = "gssapi")), "gssapi")
+                .and_then(|()| gssapi.keytab.as_deref().map_or(Ok(()), readable)),
+        );
+    }
+    add("resolver", resolve::from_config(&config.resolver).map(drop));
+    if config.outbound.dscp.is_some() || config.outbound.local_ports.is_some() {
+        add("outbound", outbound(config));
+    }
+    if config.relay == RelayStrategy::Splice {
+        add("relay", requires_linux());
+    }
+    if config.relay_zerocopy {
+        add("relay_zerocopy", requires_linux());
+    }
+    if let Some(status) = config
+        .faults
+        .as_ref()
+        .and_then(|faults| faults.reply_status)
+    {
+        add("faults", proto::ServerStatus::try_from(status).map(drop));
+    }
+    if let Some(path) = &config.route_script {
+        add("route_script", route_script(path));
+    }
+    if let Some(capture) = &config.capture {
+        add("capture", parent_exists(&capture.file));
+    }
+    if let Some(secs) = config.stats_interval_secs {
+        add("stats_interval_secs", stats::check_interval(secs));
+    }
+    if let Some(top_talkers) = &config.top_talkers {
+        add("top_talkers", TopTalkers::new(top_talkers).map(drop));
+    }
+    if let Some(priority) = &config.priority {
+        add("priority", Scheduler::new(priority.clone()).map(drop));
+    }
+    if let Some(acl) = &config.acl {
+        add("acl", Acl::new(acl.clone()).map(drop));
+    }
+    if let Some(blocklist) = &config.blocklist {
+        add("blocklist", Blocklist::new(blocklist).map(drop));
+    }
+    if let Some(dnsbl) = &config.dnsbl {
+        add("dnsbl", Dnsbl::new(dnsbl.clone()).map(drop));
+    }
+    if let Some(rate_limit) = &config.rate_limit {
+        add("rate_limit", RateLimiter::new(rate_limit.clone()).map(drop));
+    }
+    if let Some(dns) = &config.udp.dns {
+        add("udp.dns", DnsRelay::new(dns.clone()).map(drop));
+    }
+    if let Some(path) = &config.access_log {
+        add("access_log", parent_exists(path));
+    }
+    if let Some(path) = &config.honeypot_log {
+        add("honeypot_log", parent_exists(path));
+    }
+    if let Some(syslog) = &config.syslog {
+        add("syslog", self::syslog(syslog));
+    }
+    if let Some(addr) = config
+        .http_proxy
+        .as_ref()
+        .and_then(|http| http.listen_addr.as_ref())
+    {
+        add("http_proxy", resolves(addr));
+    }
+    if let Some(redirect) = &config.redirect {
+        add(
+            "redirect",
+            requires_linux().and_then(|()| resolves(&redirect.listen_addr)),
+        );
+    }
+    if config.websocket.is_some() {
+        add(
+            "websocket",
+            requires_feature(cfg!(feature = "websocket"), "websocket"),
+        );
+    }
+    if let Some(quic) = &config.quic {
+        add(
+            "quic",
+            requires_feature(cfg!(feature = "quic"), "quic").and_then(|()| {
+                resolves(&quic.listen_addr)?;
+                quic_credentials(quic)
+            }),
+        );
+    }
+    checks
+}
+
+fn outbound(config: &ServerConfig) -> io::Result<()> {
+    if let Some(dscp) = config.outbound.dscp {
+        dial::check_dscp(dscp, "outbound")?;
+    }
+    config
+        .outbound
+        .local_ports
+        .map_or(Ok(()), dial::check_local_ports)
+}
+
+/// Checks every `[[listeners]]` entry, stopping at the first broken one.
+fn listeners(config: &ServerConfig) -> io::Result<()> {
+    let mut names = HashSet::new();
+    for listener in &config.listeners {
+        let in_listener =
+            |err: io::Error| io::Error::new(err.kind(), format!("{}: {err}", listener.name));
+        if !names.insert(listener.name.as_str()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("more than one listener is named {}", listener.name),
+            ));
+        }
+        resolves(&listener.listen_addr).map_err(in_listener)?;
+        if let Some(auth) = &listener.auth {
+            if listener.no_auth {
+                return Err(in_listener(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "no_auth and an auth section exclude each other",
+                )));
+            }
+            auth.users_file
+                .as_deref()
+                .map_or(Ok(()), readable)
+                .and_then(|()| auth::from_config(auth).map(drop))
+                .map_err(in_listener)?;
+        }
+        if let Some(acl) = &listener.acl {
+            Acl::new(acl.clone()).map_err(in_listener)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn syslog(config: &SyslogConfig) -> io::Result<()> {
+    crate::syslog::Syslog::new(config).map(drop)
+}
+
+#[cfg(not(unix))]
+fn syslog(_: &SyslogConfig) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "requires a unix system",
+    ))
+}
+
+fn resolves(addr: &str) -> io::Result<()> {
+    addr.to_socket_addrs()
+        .map(drop)
+        .map_err(|err| io::Error::new(err.kind(), format!("{addr}: {err}")))
+}
+
+fn readable(path: &Path) -> io::Result<()> {
+    fs::File::open(path)
+        .map(drop)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))
+}
+
+/// Files the server creates only need their directory to exist.
+fn parent_exists(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    if parent.is_dir() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{}: no such directory", parent.display()),
+        ))
+    }
+}
+
+fn requires_feature(enabled: bool, feature: &str) -> io::Result<()> {
+    if enabled {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("requires socks5 to be built with the `{feature}` feature"),
+        ))
+    }
+}
+
+fn requires_linux() -> io::Result<()> {
+    if cfg!(target_os = "linux") {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "only supported on linux",
+        ))
+    }
+}
+
+#[cfg(feature = "scripting")]
+fn route_script(path: &Path) -> io::Result<()> {
+    crate::script::RouteScript::load(path).map(drop)
+}
+
+#[cfg(not(feature = "scripting"))]
+fn route_script(_path: &Path) -> io::Result<()> {
+    requires_feature(false, "scripting")
+}
+
+#[cfg(feature = "quic")]
+fn quic_credentials(config: &QuicConfig) -> io::Result<()> {
+    crate::quic::server_config(config).map(drop)
+}
+
+#[cfg(not(feature = "quic"))]
+fn quic_credentials(_config: &QuicConfig) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AclConfig, CaptureConfig, FaultsConfig, OutboundConfig};
+
+    #[test]
+    fn reports_every_broken_section() {
+        let acl: AclConfig = toml::from_str(
+            r#"
+            timezone = "Nowhere/Special"
+            "#,
+        )
+        .unwrap();
+        let config = ServerConfig {
+            acl: Some(acl),
+            capture: Some(CaptureConfig {
+                file: "/nonexistent-socks5-dir/out.pcap".into(),
+                clients: Vec::new(),
+                destinations: Vec::new(),
+            }),
+            outbound: OutboundConfig {
+                dscp: Some(64),
+                ..OutboundConfig::default()
+            },
+            faults: Some(FaultsConfig {
+                reply_status: Some(0x42),
+                ..FaultsConfig::default()
+            }),
+            honeypot_log: Some("/nonexistent-socks5-dir/honeypot.log".into()),
+            stats_interval_secs: Some(0),
+            ..ServerConfig::default()
+        };
+        let failed: Vec<_> = check(&config)
+            .into_iter()
+            .filter(|check| check.result.is_err())
+            .map(|check| check.section)
+            .collect();
+        assert_eq!(
+            failed,
+            [
+                "outbound",
+                "faults",
+                "capture",
+                "stats_interval_secs",
+                "acl",
+                "honeypot_log"
+            ]
+        );
+    }
+}
@@ -0,0 +1,787 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use tokio::{
+    io,
+    net::{TcpSocket, TcpStream},
+    time::Instant,
+};
+
+use crate::{
+    config::{AddressSelection, KeepaliveConfig, OutboundConfig, SourceSelection},
+    proto, random,
+    resolve::Resolver,
+};
+
+/// Dials the destinations of client requests, from the configured source addresses.
+pub struct Dialer {
+    config: OutboundConfig,
+    /// round-robin position in the source addresses
+    next_source: AtomicUsize,
+    /// where the search for a free port of `local_ports` starts next, where the kernel can't
+    /// be left to pick from them
+    next_port: AtomicUsize,
+    /// round-robin positions in the addresses of destination hosts
+    next_address: Mutex<HashMap<String, usize>>,
+    /// recent failures by destination, if `failure_cache_secs` is set
+    failures: Mutex<HashMap<(proto::Address, u16), Failure>>,
+    /// dials in flight by destination host, if `max_dials_per_destination` is set
+    in_flight: Mutex<HashMap<proto::Address, usize>>,
+}
+
+/// A dial counted towards the limit of its destination until it is dropped.
+struct InFlight<'a> {
+    in_flight: &'a Mutex<HashMap<proto::Address, usize>>,
+    dest_addr: &'a proto::Address,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(n) = in_flight.get_mut(self.dest_addr) {
+            *n -= 1;
+            if *n == 0 {
+                in_flight.remove(self.dest_addr);
+            }
+        }
+    }
+}
+
+/// A failed dial, replayed to requests for the same destination until `expires`.
+struct Failure {
+    kind: io::ErrorKind,
+    message: String,
+    expires: Instant,
+}
+
+impl Dialer {
+    pub fn new(config: OutboundConfig) -> Self {
+        Self {
+            config,
+            next_source: AtomicUsize::new(0),
+            next_port: AtomicUsize::new(0),
+            next_address: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Connects to the destination of a client request, trying every address it resolves to,
+    /// starting from the one `address_selection` picks, and marks the connection's packets
+    /// with `dscp` if given. Failing to resolve a name that doesn't exist or being refused is
+    /// remembered for `failure_cache_secs`, during which the same error is returned without
+    /// dialing. Other failures, such as timeouts, may well pass and are not remembered.
+    pub async fn connect(
+        &self,
+        resolver: &dyn Resolver,
+        dest_addr: &proto::Address,
+        dest_port: u16,
+        dscp: Option<u8>,
+    ) -> io::Result<TcpStream> {
+        let Some(ttl) = self.config.failure_cache_secs else {
+            return self
+                .dial(resolver, dest_addr, dest_port, dscp)
+                .await
+                .map_err(io::Error::from);
+        };
+        let key = (dest_addr.clone(), dest_port);
+        if let Some(failure) = self.failures.lock().unwrap().get(&key) {
+            if failure.expires > Instant::now() {
+                return Err(io::Error::new(
+                    failure.kind,
+                    format!("{} (cached)", failure.message),
+                ));
+            }
+        }
+
+        let err = match self.dial(resolver, dest_addr, dest_port, dscp).await {
+            // a resolver that timed out or a server that failed may well answer the next time
+            Err(Dialing::Resolve(err)) if err.kind() == io::ErrorKind::NotFound => err,
+            Err(Dialing::Connect(err)) if err.kind() == io::ErrorKind::ConnectionRefused => err,
+            res => return res.map_err(io::Error::from),
+        };
+        let mut failures = self.failures.lock().unwrap();
+        // expired failures are swept periodically, until then new ones wait for room
+        if failures.len() < MAX_CACHED_FAILURES || failures.contains_key(&key) {
+            failures.insert(
+                key,
+                Failure {
+                    kind: err.kind(),
+                    message: err.to_string(),
+                    expires: Instant::now() + Duration::from_secs(ttl),
+                },
+            );
+        }
+        Err(err)
+    }
+
+    /// Forgets failures that expired, see [`crate::server::Server`]'s periodic sweep.
+    pub(crate) fn sweep(&self) {
+        let now = Instant::now();
+        self.failures
+            .lock()
+            .unwrap()
+            .retain(|_, failure| failure.expires > now);
+    }
+
+    async fn dial(
+        &self,
+        resolver: &dyn Resolver,
+        dest_addr: &proto::Address,
+        dest_port: u16,
+        dscp: Option<u8>,
+    ) -> Result<TcpStream, Dialing> {
+        let _in_flight = self.start_dial(dest_addr).map_err(Dialing::Connect)?;
+        let addrs = match dest_addr {
+            proto::Address::Ipv4(ip) => vec![SocketAddr::new((*ip).into(), dest_port)],
+            proto::Address::Ipv6(ip) => vec![SocketAddr::new((*ip).into(), dest_port)],
+            proto::Address::DomainName(host) => {
+                let mut addrs = resolver
+                    .resolve(host, dest_port)
+                    .await
+                    .map_err(Dialing::Resolve)?;
+                if addrs.len() > 1 {
+                    let first = self.first_address(host, addrs.len());
+                    addrs.rotate_left(first);
+                }
+                addrs
+            }
+        };
+
+        let mut last_err = None;
+        for addr in addrs {
+            match self.connect_addr(dest_addr, addr, dscp).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(Dialing::Connect(err)),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            Dialing::Resolve(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{dest_addr}:{dest_port} did not resolve to any address"),
+            ))
+        }))
+    }
+
+    async fn connect_addr(
+        &self,
+        dest_addr: &proto::Address,
+        addr: SocketAddr,
+        dscp: Option<u8>,
+    ) -> io::Result<TcpStream> {
+        let socket = tcp_socket(Domain::for_address(addr), self.config.mptcp)?;
+        let source = self.source_for(dest_addr, addr)?;
+        match self.config.local_ports {
+            Some(ports) => self.bind_local_port(&socket, source, addr, ports)?,
+            None => {
+                if let Some(source) = source {
+                    socket.bind(&SocketAddr::new(source, 0).into())?;
+                }
+            }
+        }
+        if let Some(dscp) = dscp {
+            set_dscp(SockRef::from(&socket), addr.is_ipv6(), dscp)?;
+        }
+        socket.set_nonblocking(true)?;
+        TcpSocket::from_std_stream(socket.into())
+            .connect(addr)
+            .await
+    }
+
+    /// Counts a dial to `dest_addr` until the returned guard is dropped, failing if as many
+    /// as `max_dials_per_destination` are already in flight.
+    fn start_dial<'a>(&'a self, dest_addr: &'a proto::Address) -> io::Result<Option<InFlight<'a>>> {
+        let Some(max) = self.config.max_dials_per_destination else {
+            return Ok(None);
+        };
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let n = in_flight.entry(dest_addr.clone()).or_default();
+        if *n >= max.get() {
+            return Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                format!("{n} dials to {dest_addr} are in flight already"),
+            ));
+        }
+        *n += 1;
+        Ok(Some(InFlight {
+            in_flight: &self.in_flight,
+            dest_addr,
+        }))
+    }
+
+    /// Binds the socket for dialing `addr` to a port from `first` to `last`, and to `source`
+    /// if given. Where the kernel supports a per-socket port range, it picks the port when
+    /// connecting, and ports are shared by connections to different destinations. Otherwise
+    /// the search for a port no other socket is bound to goes round the range.
+    fn bind_local_port(
+        &self,
+        socket: &Socket,
+        source: Option<IpAddr>,
+        addr: SocketAddr,
+        [first, last]: [u16; 2],
+    ) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if set_local_port_range(socket, first, last).is_ok() {
+            return match source {
+                Some(source) => socket.bind(&SocketAddr::new(source, 0).into()),
+                None => Ok(()),
+            };
+        }
+
+        let ip = source.unwrap_or(match addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        });
+        self.bind_free_port(socket, ip, first, last)
+    }
+
+    /// Binds a socket sending datagrams to destinations to `ip` and a port from `local_ports`,
+    /// or to one the OS picks if unset. As the kernel can't pick from the range when nothing
+    /// connects the socket, each one takes a port of its own.
+    pub(crate) fn bind_datagram(&self, socket: &Socket, ip: IpAddr) -> io::Result<()> {
+        match self.config.local_ports {
+            Some([first, last]) => self.bind_free_port(socket, ip, first, last),
+            None => socket.bind(&SocketAddr::new(ip, 0).into()),
+        }
+    }
+
+    /// Binds the socket to `ip` and the next port from `first` to `last` that no other socket
+    /// is bound to.
+    fn bind_free_port(&self, socket: &Socket, ip: IpAddr, first: u16, last: u16) -> io::Result<()> {
+        let len = usize::from(last - first) + 1;
+        let start = self.next_port.fetch_add(1, Ordering::Relaxed);
+        for i in 0..len {
+            let port = first + ((start + i) % len) as u16;
+            match socket.bind(&SocketAddr::new(ip, port).into()) {
+                Ok(()) => {
+                    self.next_port.store(start + i + 1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                Err(err) if err.kind() == io::ErrorKind::AddrInUse => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("every local port from {first} to {last} is in use"),
+        ))
+    }
+
+    /// Picks which of the `len` addresses `host` resolved to is dialed first.
+    fn first_address(&self, host: &str, len: usize) -> usize {
+        match self.config.address_selection {
+            AddressSelection::First => 0,
+            AddressSelection::Random => random::below(len as u64) as usize,
+            AddressSelection::RoundRobin => {
+                let host = host.to_ascii_lowercase();
+                let mut next = self.next_address.lock().unwrap();
+                // forgetting every position now and then keeps clients requesting ever new
+                // hosts from growing the map without bound
+                if next.len() >= MAX_ROUND_ROBIN_HOSTS && !next.contains_key(&host) {
+                    next.clear();
+                }
+                let n = next.entry(host).or_default();
+                let first = *n % len;
+                *n = n.wrapping_add(1);
+                first
+            }
+        }
+    }
+
+    /// Picks the source address to dial `addr` from, if any are configured. Destinations of
+    /// a family no source address has are not dialed, rather than dialed from the address
+    /// the OS picks.
+    fn source_for(
+        &self,
+        dest_addr: &proto::Address,
+        addr: SocketAddr,
+    ) -> io::Result<Option<IpAddr>> {
+        let sources = &self.config.source_addresses;
+        if sources.is_empty() {
+            return Ok(None);
+        }
+        let candidates: Vec<IpAddr> = sources
+            .iter()
+            .copied()
+            .filter(|source| source.is_ipv4() == addr.is_ipv4())
+            .collect();
+        if candidates.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no source address of the same family as {addr}"),
+            ));
+        }
+        let n = match self.config.source_selection {
+            SourceSelection::RoundRobin => self.next_source.fetch_add(1, Ordering::Relaxed),
+            SourceSelection::HashDestination => {
+                // the requested host rather than the address it resolved to, so that all
+                // of a host's addresses see the same source
+                let mut hasher = DefaultHasher::new();
+                dest_addr.hash(&mut hasher);
+                hasher.finish() as usize
+            }
+        };
+        Ok(Some(candidates[n % candidates.len()]))
+    }
+}
+
+/// Destination hosts whose round-robin position is remembered at most.
+const MAX_ROUND_ROBIN_HOSTS: usize = 4096;
+
+/// Destinations whose failure is remembered at most.
+const MAX_CACHED_FAILURES: usize = 4096;
+
+/// Which step of dialing failed.
+enum Dialing {
+    Resolve(io::Error),
+    Connect(io::Error),
+}
+
+impl From<Dialing> for io::Error {
+    fn from(err: Dialing) -> Self {
+        match err {
+            Dialing::Resolve(err) | Dialing::Connect(err) => err,
+        }
+    }
+}
+
+/// Enables keepalive probes on the stream, so that the relay fails once the peer stops
+/// answering them.
+pub(crate) fn set_keepalive(stream: &TcpStream, config: &KeepaliveConfig) -> io::Result<()> {
+    let keepalive = TcpKeepalive::new()
+        .with_time(Duration::from_secs(config.idle_secs))
+        .with_interval(Duration::from_secs(config.interval_secs));
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd"
+    ))]
+    let keepalive = keepalive.with_retries(config.count);
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+/// Makes closing the stream reset the connection, see
+/// [`crate::config::RelayCloseConfig::abort_on_close`].
+pub(crate) fn reset_on_close(stream: &TcpStream) -> io::Result<()> {
+    SockRef::from(stream).set_linger(Some(Duration::ZERO))
+}
+
+/// Rejects local port ranges that are empty or include port 0, which binding takes for any
+/// port.
+pub(crate) fn check_local_ports([first, last]: [u16; 2]) -> io::Result<()> {
+    if first == 0 || first > last {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("outbound: local_ports {first} to {last} is not a range of ports from 1"),
+        ));
+    }
+    Ok(())
+}
+
+// from linux/in.h, which the libc crate lacks
+#[cfg(target_os = "linux")]
+const IP_LOCAL_PORT_RANGE: libc::c_int = 51;
+
+/// Has the kernel pick the ports of the socket from `first` to `last`, which fails on
+/// kernels before 6.3.
+#[cfg(target_os = "linux")]
+fn set_local_port_range(socket: &Socket, first: u16, last: u16) -> io::Result<()> {
+    use std::{mem, os::unix::io::AsRawFd, ptr};
+
+    let range = u32::from(first) | (u32::from(last) << 16);
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            IP_LOCAL_PORT_RANGE,
+            ptr::from_ref(&range).cast(),
+            mem::size_of_val(&range) as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Rejects values that don't fit the 6 bits of a DSCP codepoint.
+pub(crate) fn check_dscp(dscp: u8, setting: &str) -> io::Result<()> {
+    if dscp > 63 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{setting}: dscp {dscp} is not a codepoint from 0 to 63"),
+        ));
+    }
+    Ok(())
+}
+
+/// Marks the packets the socket sends with the DSCP codepoint, in the upper 6 bits of the
+/// ipv4 TOS byte or the ipv6 traffic class, leaving the ECN bits to the kernel.
+pub(crate) fn set_dscp(socket: SockRef<'_>, ipv6: bool, dscp: u8) -> io::Result<()> {
+    let tos = u32::from(dscp) << 2;
+    if !ipv6 {
+        return socket.set_tos_v4(tos);
+    }
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    socket.set_tclass_v6(tos)?;
+    // a dual stack socket sends to ipv4-mapped addresses with the TOS byte instead. ipv6-only
+    // sockets may refuse it, which is of no consequence to them
+    let _ = socket.set_tos_v4(tos);
+    Ok(())
+}
+
+/// Creates a tcp socket, using multipath tcp if requested and the kernel supports it.
+pub(crate) fn tcp_socket(domain: Domain, mptcp: bool) -> io::Result<Socket> {
+    #[cfg(target_os = "linux")]
+    if mptcp {
+        match Socket::new(domain, Type::STREAM, Some(socket2::Protocol::MPTCP)) {
+            Ok(socket) => return Ok(socket),
+            Err(err) if is_mptcp_unsupported(&err) => {
+                eprintln!("multipath tcp is not available, falling back to tcp: {err}");
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    if mptcp {
+        eprintln!("multipath tcp is only supported on linux, falling back to tcp");
+    }
+
+    Socket::new(domain, Type::STREAM, None)
+}
+
+#[cfg(target_os = "linux")]
+fn is_mptcp_unsupported(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EPROTONOSUPPORT | libc::ENOPROTOOPT | libc::EINVAL)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr},
+        num::NonZeroUsize,
+    };
+
+    use futures::future::{self, BoxFuture};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{config::ResolverConfig, resolve};
+
+    /// A range of `len` local ports that no socket is bound to right now.
+    fn free_port_range(len: u16) -> [u16; 2] {
+        let free = |port| {
+            std::net::TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).is_ok()
+                && std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).is_ok()
+        };
+        loop {
+            let first = std::net::TcpListener::bind("0.0.0.0:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            match first.checked_add(len - 1) {
+                Some(last) if (first..=last).all(free) => return [first, last],
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn spreads_dials_over_the_source_addresses() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let resolver = resolve::from_config(&ResolverConfig::default()).unwrap();
+        let sources: Vec<IpAddr> = vec![[127, 0, 0, 2].into(), [127, 0, 0, 3].into()];
+        let dest = proto::Address::Ipv4(Ipv4Addr::LOCALHOST);
+
+        let dialed_from = |dialer: Dialer| {
+            let (resolver, dest, listener) = (&resolver, &dest, &listener);
+            async move {
+                let mut seen = Vec::new();
+                for _ in 0..3 {
+                    let _conn = dialer
+                        .connect(resolver.as_ref(), dest, port, None)
+                        .await
+                        .unwrap();
+                    seen.push(listener.accept().await.unwrap().1.ip());
+                }
+                seen
+            }
+        };
+        let round_robin = dialed_from(Dialer::new(OutboundConfig {
+            source_addresses: sources.clone(),
+            ..OutboundConfig::default()
+        }))
+        .await;
+        assert_eq!(round_robin, [sources[0], sources[1], sources[0]]);
+
+        let dialer = Dialer::new(OutboundConfig {
+            source_addresses: sources.clone(),
+            source_selection: SourceSelection::HashDestination,
+            ..OutboundConfig::default()
+        });
+        let ipv6 = proto::Address::Ipv6(Ipv6Addr::LOCALHOST);
+        let err = dialer.connect(resolver.as_ref(), &ipv6, port, None).await;
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::AddrNotAvailable);
+        let hashed = dialed_from(dialer).await;
+        assert!(hashed
+            .iter()
+            .all(|ip| *ip == hashed[0] && sources.contains(ip)));
+    }
+
+    #[tokio::test]
+    async fn picks_the_address_to_dial_first() {
+        let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let replicas: Vec<IpAddr> = vec![[127, 0, 0, 2].into(), [127, 0, 0, 3].into()];
+        let resolver = resolve::from_config(&ResolverConfig {
+            hosts: HashMap::from([("replicas.test".to_owned(), replicas.clone())]),
+            ..ResolverConfig::default()
+        })
+        .unwrap();
+        let dest = proto::Address::DomainName("replicas.test".to_owned());
+
+        let dialed = |selection| {
+            let dialer = Dialer::new(OutboundConfig {
+                address_selection: selection,
+                ..OutboundConfig::default()
+            });
+            let (resolver, dest) = (&resolver, &dest);
+            async move {
+                let mut seen = Vec::new();
+                for _ in 0..3 {
+                    let conn = dialer
+                        .connect(resolver.as_ref(), dest, port, None)
+                        .await
+                        .unwrap();
+                    seen.push(conn.peer_addr().unwrap().ip());
+                }
+                seen
+            }
+        };
+        assert_eq!(dialed(AddressSelection::First).await, [replicas[0]; 3]);
+        assert_eq!(
+            dialed(AddressSelection::RoundRobin).await,
+            [replicas[0], replicas[1], replicas[0]]
+        );
+        let random = dialed(AddressSelection::Random).await;
+        assert!(random.iter().all(|ip| replicas.contains(ip)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_recently_refused_destinations_without_dialing() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let resolver = resolve::from_config(&ResolverConfig::default()).unwrap();
+        let dialer = Dialer::new(OutboundConfig {
+            failure_cache_secs: Some(10),
+            ..OutboundConfig::default()
+        });
+        let dest = proto::Address::Ipv4(Ipv4Addr::LOCALHOST);
+        let dial = || dialer.connect(resolver.as_ref(), &dest, addr.port(), None);
+
+        let refused = dial().await.unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::ConnectionRefused);
+
+        let _listener = TcpListener::bind(addr).await.unwrap();
+        let cached = dial().await.unwrap_err();
+        assert_eq!(cached.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(cached.to_string().ends_with("(cached)"));
+
+        tokio::time::advance(Duration::from_secs(11)).await;
+        dialer.sweep();
+        assert!(dialer.failures.lock().unwrap().is_empty());
+        dial().await.unwrap();
+    }
+
+    /// Fails every lookup with an error of `kind`, counting them.
+    struct Failing {
+        kind: io::ErrorKind,
+        lookups: AtomicUsize,
+    }
+
+    impl Resolver for Failing {
+        fn resolve<'a>(
+            &'a self,
+            host: &'a str,
+            _port: u16,
+        ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            let err = io::Error::new(self.kind, format!("resolving {host} failed"));
+            Box::pin(future::ready(Err(err)))
+        }
+    }
+
+    #[tokio::test]
+    async fn caches_only_names_that_do_not_exist() {
+        let dialer = Dialer::new(OutboundConfig {
+            failure_cache_secs: Some(10),
+            ..OutboundConfig::default()
+        });
+        let dest = proto::Address::DomainName("example.com".to_owned());
+        for (kind, lookups) in [
+            (io::ErrorKind::NotFound, 1),
+            // what a timed out lookup fails with
+            (io::ErrorKind::HostUnreachable, 2),
+            (io::ErrorKind::InvalidData, 2),
+        ] {
+            let resolver = Failing {
+                kind,
+                lookups: AtomicUsize::new(0),
+            };
+            for _ in 0..2 {
+                let err = dialer
+                    .connect(&resolver, &dest, 80, None)
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.kind(), kind);
+            }
+            assert_eq!(resolver.lookups.into_inner(), lookups, "{kind:?}");
+            dialer.failures.lock().unwrap().clear();
+        }
+    }
+
+    #[tokio::test]
+    async fn marks_dialed_connections_with_the_dscp() {
+        let resolver = resolve::from_config(&ResolverConfig::default()).unwrap();
+        let dialer = Dialer::new(OutboundConfig::default());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let dest = proto::Address::Ipv4(Ipv4Addr::LOCALHOST);
+        let conn = dialer
+            .connect(resolver.as_ref(), &dest, port, Some(46))
+            .await
+            .unwrap();
+        assert_eq!(SockRef::from(&conn).tos_v4().unwrap(), 46 << 2);
+        let unmarked = dialer
+            .connect(resolver.as_ref(), &dest, port, None)
+            .await
+            .unwrap();
+        assert_eq!(SockRef::from(&unmarked).tos_v4().unwrap(), 0);
+
+        let Ok(listener) = TcpListener::bind("[::1]:0").await else {
+            return;
+        };
+        let port = listener.local_addr().unwrap().port();
+        let dest = proto::Address::Ipv6(Ipv6Addr::LOCALHOST);
+        let conn = dialer
+            .connect(resolver.as_ref(), &dest, port, Some(10))
+            .await
+            .unwrap();
+        assert_eq!(SockRef::from(&conn).tclass_v6().unwrap(), 10 << 2);
+    }
+
+    /// Resolves every name to `127.0.0.1` once a permit is added.
+    struct Stalled(tokio::sync::Semaphore);
+
+    impl Resolver for Stalled {
+        fn resolve<'a>(
+            &'a self,
+            _host: &'a str,
+            port: u16,
+        ) -> futures::future::BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+            Box::pin(async move {
+                self.0.acquire().await.unwrap().forget();
+                Ok(vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)])
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_dials_beyond_the_limit_of_the_destination() {
+        let resolver = Stalled(tokio::sync::Semaphore::new(0));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let dialer = Dialer::new(OutboundConfig {
+            max_dials_per_destination: NonZeroUsize::new(2),
+            ..OutboundConfig::default()
+        });
+        let stalled = proto::Address::DomainName("stalled.test".to_owned());
+        let dial = |dest| dialer.connect(&resolver, dest, port, None);
+
+        let (first, second) = (dial(&stalled), dial(&stalled));
+        tokio::pin!(first, second);
+        assert!(futures::poll!(&mut first).is_pending());
+        assert!(futures::poll!(&mut second).is_pending());
+        let err = dial(&stalled).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        dial(&proto::Address::Ipv4(Ipv4Addr::LOCALHOST))
+            .await
+            .unwrap();
+
+        resolver.0.add_permits(3);
+        first.await.unwrap();
+        second.await.unwrap();
+        dial(&stalled).await.unwrap();
+        assert!(dialer.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dials_from_the_local_port_range() {
+        let resolver = resolve::from_config(&ResolverConfig::default()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let dest = proto::Address::Ipv4(Ipv4Addr::LOCALHOST);
+        let [first, last] = free_port_range(4);
+        let ports = first..=last;
+        let dialer = Dialer::new(OutboundConfig {
+            local_ports: Some([first, last]),
+            ..OutboundConfig::default()
+        });
+        let conn = dialer
+            .connect(resolver.as_ref(), &dest, port, None)
+            .await
+            .unwrap();
+        assert!(ports.contains(&conn.local_addr().unwrap().port()));
+
+        // without the kernel picking, every socket takes a port of its own
+        let ip = Ipv4Addr::LOCALHOST.into();
+        let [first, last] = free_port_range(2);
+        let bound: Vec<Socket> = (0..2)
+            .map(|_| {
+                let socket = tcp_socket(Domain::IPV4, false).unwrap();
+                dialer.bind_free_port(&socket, ip, first, last).unwrap();
+                socket
+            })
+            .collect();
+        let local_port = |socket: &Socket| socket.local_addr().unwrap().as_socket().unwrap().port();
+        assert_ne!(local_port(&bound[0]), local_port(&bound[1]));
+        let socket = tcp_socket(Domain::IPV4, false).unwrap();
+        let err = dialer.bind_free_port(&socket, ip, first, last).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        // as do the sockets of udp associations
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        dialer.bind_datagram(&socket, ip).unwrap();
+        assert!(ports.contains(&local_port(&socket)));
+
+        assert!(check_local_ports([1024, 1024]).is_ok());
+        assert!(check_local_ports([0, 1024]).is_err());
+        assert!(check_local_ports([2000, 1999]).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+//! DNS queries relayed through udp associations, configured by the `udp.dns` section of the
+//! server config.
+//!
+//! Datagrams a client sends to port 53 are taken for DNS queries. Those the cache has an
+//! answer for are answered right away, without leaving the server. The rest go to the
+//! configured upstream resolver, or where the client sent them if there is none, and the
+//! answers coming back are cached for their ttl.
+//!
+//! Answers of the upstream are cached for all associations. Without one, clients pick their
+//! resolvers, which may be any server answering whatever it likes, so each association gets
+//! a smaller cache of its own instead.
+//!
+//! Only answers to standard queries with a single question are cached: successes and
+//! nxdomains, as long as they are not truncated and answer the question that was asked.
+//! Answers are served with their ttls counted down by the time they spent in the cache, and
+//! with the id and question of the query they answer, so clients randomizing the case of
+//! names find theirs.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex, time::Duration};
+
+use tokio::{io, time::Instant};
+
+use crate::config::DnsRelayConfig;
+
+pub(crate) const DNS_PORT: u16 = 53;
+
+const HEADER_LEN: usize = 12;
+const TYPE_OPT: u16 = 41;
+const RCODE_NXDOMAIN: u8 = 3;
+// the most answers an association caches for itself, when there is no upstream
+const ASSOCIATION_CACHE_ENTRIES: usize = 256;
+
+pub struct DnsRelay {
+    config: DnsRelayConfig,
+    upstream: Option<SocketAddr>,
+    /// the answers of the upstream, if there is one
+    shared: Option<AnswerCache>,
+}
+
+/// Answers to dns queries, by the question they answer with its name lowercased.
+pub struct AnswerCache {
+    entries: Mutex<HashMap<Vec<u8>, Cached>>,
+    capacity: usize,
+    max_ttl_secs: u64,
+}
+
+struct Cached {
+    response: Vec<u8>,
+    /// where in `response` the ttls of its records are
+    ttl_offsets: Vec<usize>,
+    stored: Instant,
+    expires: Instant,
+}
+
+impl DnsRelay {
+    pub fn new(config: DnsRelayConfig) -> io::Result<Self> {
+        let upstream = config
+            .upstream
+            .as_deref()
+            .map(|upstream| {
+                upstream.parse().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("dns upstream {upstream:?} is not an ip:port"),
+                    )
+                })
+            })
+            .transpose()?;
+        let shared = upstream.map(|_| AnswerCache::new(config.cache_entries, config.max_ttl_secs));
+        Ok(Self {
+            config,
+            upstream,
+            shared,
+        })
+    }
+
+    /// The resolver queries go to instead of where clients sent them, if one is configured.
+    pub fn upstream(&self) -> Option<SocketAddr> {
+        self.upstream
+    }
+
+    /// The cache all associations share, which only exists along with an upstream.
+    pub fn cache(&self) -> Option<&AnswerCache> {
+        self.shared.as_ref()
+    }
+
+    /// A cache for a single association, which needs one when there is no upstream.
+    pub fn association_cache(&self) -> Option<AnswerCache> {
+        self.upstream.is_none().then(|| {
+            AnswerCache::new(
+                self.config.cache_entries.min(ASSOCIATION_CACHE_ENTRIES),
+                self.config.max_ttl_secs,
+            )
+        })
+    }
+}
+
+impl AnswerCache {
+    fn new(capacity: usize, max_ttl_secs: u64) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            capacity,
+            max_ttl_secs,
+        }
+    }
+
+    /// Returns the cached answer to `query`, if there is one.
+    pub fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let (key, question_end) = question(query, false)?;
+        let now = Instant::now();
+        let mut cache = self.entries.lock().unwrap();
+        let cached = cache.get(&key)?;
+        if cached.expires <= now {
+            cache.remove(&key);
+            return None;
+        }
+
+        let mut response = cached.response.clone();
+        response[..2].copy_from_slice(&query[..2]);
+        response[HEADER_LEN..question_end].copy_from_slice(&query[HEADER_LEN..question_end]);
+        let aged = (now - cached.stored).as_secs() as u32;
+        for &offset in &cached.ttl_offsets {
+            let ttl = &mut response[offset..offset + 4];
+            let left = u32::from_be_bytes(ttl.try_into().unwrap()).saturating_sub(aged);
+            ttl.copy_from_slice(&left.to_be_bytes());
+        }
+        Some(response)
+    }
+
+    /// Caches `response` if it is an answer worth keeping to the question `asked`, as
+    /// returned by [`question_key`] for the query it answers.
+    pub fn store(&self, response: &[u8], asked: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let Some((key, question_end)) = question(response, true) else {
+            return;
+        };
+        if key != asked {
+            return;
+        }
+        let truncated = response[2] & 0x02 != 0;
+        let rcode = response[3] & 0x0f;
+        if truncated || !(rcode == 0 || rcode == RCODE_NXDOMAIN) {
+            return;
+        }
+        let Some((ttl_offsets, min_ttl)) = records(response, question_end) else {
+            return;
+        };
+        let ttl = min_ttl.min(self.max_ttl_secs);
+        if ttl == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut cache = self.entries.lock().unwrap();
+        if cache.len() >= self.capacity {
+            cache.retain(|_, cached| cached.expires > now);
+            if cache.len() >= self.capacity {
+                return;
+            }
+        }
+        cache.insert(
+            key,
+            Cached {
+                response: response.to_vec(),
+                ttl_offsets,
+                stored: now,
+                expires: now + Duration::from_secs(ttl),
+            },
+        );
+    }
+}
+
+/// The id a query or response is matched by.
+pub(crate) fn message_id(msg: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes(msg.get(..2)?.try_into().unwrap()))
+}
+
+/// The question of `query` as the cache keys it, if it is a standard query with a single one.
+pub(crate) fn question_key(query: &[u8]) -> Option<Vec<u8>> {
+    question(query, false).map(|(key, _)| key)
+}
+
+/// Returns the single question of a standard query, or of a response to one if `response`,
+/// as a cache key, along with where it ends.
+fn question(msg: &[u8], response: bool) -> Option<(Vec<u8>, usize)> {
+    let header = msg.get(..HEADER_LEN)?;
+    let is_response = header[2] & 0x80 != 0;
+    let opcode = (header[2] >> 3) & 0x0f;
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    if is_response != response || opcode != 0 || questions != 1 {
+        return None;
+    }
+
+    let mut key = Vec::new();
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = usize::from(*msg.get(pos)?);
+        // names in questions come first in a message, so they have nothing to point back to
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        let label = msg.get(pos..pos + 1 + len)?;
+        key.extend(label.iter().map(u8::to_ascii_lowercase));
+        pos += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+    // the type and class
+    key.extend_from_slice(msg.get(pos..pos + 4)?);
+    Some((key, pos + 4))
+}
+
+/// Walks the records following the question, returning where their ttls are and the
+/// smallest of them, or `None` if the message is malformed or has no records with a ttl.
+fn records(msg: &[u8], mut pos: usize) -> Option<(Vec<usize>, u64)> {
+    let count = |i: usize| usize::from(u16::from_be_bytes([msg[i], msg[i + 1]]));
+    let answers = count(6);
+    let records = answers + count(8) + count(10);
+    let mut ttl_offsets = Vec::with_capacity(records);
+    let mut min_ttl = None;
+    for i in 0..records {
+        pos = skip_name(msg, pos)?;
+        let fixed = msg.get(pos..pos + 10)?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+        let rdata_len = usize::from(u16::from_be_bytes([fixed[8], fixed[9]]));
+        // the ttl field of an edns record holds flags
+        if rtype != TYPE_OPT {
+            ttl_offsets.push(pos + 4);
+            // without answers, the soa in the authority section says how long the name
+            // or type is known not to exist
+            if i < answers || answers == 0 {
+                min_ttl = Some(min_ttl.unwrap_or(u32::MAX).min(ttl));
+            }
+        }
+        pos += 10 + rdata_len;
+        if pos > msg.len() {
+            return None;
+        }
+    }
+    Some((ttl_offsets, min_ttl?.into()))
+}
+
+/// Returns where the name at `pos` ends, compressed or not.
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = usize::from(*msg.get(pos)?);
+        match len & 0xc0 {
+            0x00 if len == 0 => return Some(pos + 1),
+            0x00 => pos += 1 + len,
+            // a pointer ends the name
+            0xc0 => return msg.get(pos + 1).map(|_| pos + 2),
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> AnswerCache {
+        let config = DnsRelayConfig::default();
+        AnswerCache::new(config.cache_entries, config.max_ttl_secs)
+    }
+
+    /// A query for the A records of `<name>.com`, `name` being seven letters long.
+    fn query(id: u16, name: &[u8]) -> Vec<u8> {
+        let mut msg = id.to_be_bytes().to_vec();
+        msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        msg.push(7);
+        msg.extend_from_slice(name);
+        msg.extend_from_slice(b"\x03com\x00\x00\x01\x00\x01");
+        msg
+    }
+
+    /// The answer to `query`, with an address record of the given ttl.
+    fn response(query: &[u8], ttl: u32) -> Vec<u8> {
+        let mut msg = query.to_vec();
+        msg[2] = 0x81;
+        msg[3] = 0x80;
+        msg[7] = 1;
+        msg.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        msg.extend_from_slice(&ttl.to_be_bytes());
+        msg.extend_from_slice(&[0, 4, 192, 0, 2, 1]);
+        msg
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn answers_repeated_queries_until_the_ttl_runs_out() {
+        let cache = cache();
+        let first = query(1, b"example");
+        assert_eq!(cache.answer(&first), None);
+        cache.store(&response(&first, 300), &question_key(&first).unwrap());
+
+        tokio::time::advance(Duration::from_secs(100)).await;
+        let again = query(2, b"ExAmPlE");
+        let answer = cache.answer(&again).unwrap();
+        assert_eq!(answer, response(&again, 200));
+
+        tokio::time::advance(Duration::from_secs(200)).await;
+        assert_eq!(cache.answer(&again), None);
+    }
+
+    #[test]
+    fn caches_only_complete_answers() {
+        let cache = cache();
+        let query = query(1, b"example");
+        let asked = question_key(&query).unwrap();
+        let mut truncated = response(&query, 300);
+        truncated[2] |= 0x02;
+        cache.store(&truncated, &asked);
+        let mut servfail = response(&query, 300);
+        servfail[3] = 0x82;
+        cache.store(&servfail, &asked);
+        cache.store(&response(&query, 0), &asked);
+        // a query is no answer
+        cache.store(&query, &asked);
+        assert_eq!(cache.answer(&query), None);
+
+        let mut cut_short = response(&query, 300);
+        cut_short.pop();
+        cache.store(&cut_short, &asked);
+        assert_eq!(cache.answer(&query), None);
+
+        // an answer to another question than the one asked
+        let mut other = query.clone();
+        other[HEADER_LEN + 1] = b'x';
+        cache.store(&response(&other, 300), &asked);
+        assert_eq!(cache.answer(&other), None);
+        assert_eq!(cache.answer(&query), None);
+    }
+
+    #[test]
+    fn shares_a_cache_only_for_the_answers_of_the_upstream() {
+        let relay = DnsRelay::new(DnsRelayConfig::default()).unwrap();
+        assert!(relay.cache().is_none());
+        let (one, other) = (
+            relay.association_cache().unwrap(),
+            relay.association_cache().unwrap(),
+        );
+        let query = query(1, b"example");
+        one.store(&response(&query, 300), &question_key(&query).unwrap());
+        assert!(one.answer(&query).is_some());
+        assert_eq!(other.answer(&query), None);
+
+        let relay = DnsRelay::new(DnsRelayConfig {
+            upstream: Some("192.0.2.53:53".to_owned()),
+            ..DnsRelayConfig::default()
+        })
+        .unwrap();
+        assert!(relay.cache().is_some());
+        assert!(relay.association_cache().is_none());
+    }
+}
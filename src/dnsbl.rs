@@ -0,0 +1,300 @@
+//! Reputation checks of client addresses against DNS blocklists, configured by the `dnsbl`
+//! section of the server config.
+//!
+//! A client is listed in a zone if the zone has an address record for its reversed ip: for
+//! 192.0.2.1 and `zen.spamhaus.org`, `1.2.0.192.zen.spamhaus.org`. ipv6 addresses are
+//! reversed a nibble at a time. Zones are queried concurrently through the server's
+//! resolver, and the verdict is cached per address, for at most `cache_entries` of the most
+//! recently seen addresses. Lookups that fail or time out count as not listed, so an
+//! unreachable zone doesn't lock clients out. Loopback, private and link-local addresses are
+//! never looked up.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::Duration,
+};
+
+use futures::future::join_all;
+use tokio::{io, time, time::Instant};
+
+use crate::{config::DnsblConfig, resolve::Resolver};
+
+pub struct Dnsbl {
+    config: DnsblConfig,
+    cache: Mutex<Cache>,
+}
+
+/// Recent verdicts by client address, least recently used ones evicted first.
+#[derive(Default)]
+struct Cache {
+    verdicts: HashMap<IpAddr, Verdict>,
+    /// addresses by the use of their verdict, counting up
+    by_use: BTreeMap<u64, IpAddr>,
+    uses: u64,
+}
+
+struct Verdict {
+    /// the zone listing the address, if any
+    listed_in: Option<String>,
+    expires: Instant,
+    used: u64,
+}
+
+impl Cache {
+    /// The zone listing `ip` if the verdict on it is still fresh, and whether it is.
+    fn get(&mut self, ip: IpAddr, now: Instant) -> Option<Option<String>> {
+        let verdict = self.verdicts.get_mut(&ip)?;
+        if verdict.expires <= now {
+            return None;
+        }
+        self.by_use.remove(&verdict.used);
+        self.uses += 1;
+        verdict.used = self.uses;
+        self.by_use.insert(verdict.used, ip);
+        Some(verdict.listed_in.clone())
+    }
+
+    fn insert(&mut self, ip: IpAddr, listed_in: Option<String>, expires: Instant, max: usize) {
+        if max == 0 {
+            return;
+        }
+        self.uses += 1;
+        let verdict = Verdict {
+            listed_in,
+            expires,
+            used: self.uses,
+        };
+        match self.verdicts.insert(ip, verdict) {
+            Some(replaced) => {
+                self.by_use.remove(&replaced.used);
+            }
+            None if self.verdicts.len() > max => {
+                if let Some((_, lru)) = self.by_use.pop_first() {
+                    self.verdicts.remove(&lru);
+                }
+            }
+            None => {}
+        }
+        self.by_use.insert(self.uses, ip);
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        let by_use = &mut self.by_use;
+        self.verdicts.retain(|_, verdict| {
+            let fresh = verdict.expires > now;
+            if !fresh {
+                by_use.remove(&verdict.used);
+            }
+            fresh
+        });
+    }
+}
+
+impl Dnsbl {
+    pub fn new(config: DnsblConfig) -> io::Result<Self> {
+        if config.zones.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "dnsbl needs at least one zone",
+            ));
+        }
+        Ok(Self {
+            config,
+            cache: Mutex::new(Cache::default()),
+        })
+    }
+
+    pub fn config(&self) -> &DnsblConfig {
+        &self.config
+    }
+
+    /// Returns the first configured zone that lists `ip`, if any does.
+    pub async fn listing(&self, resolver: &dyn Resolver, ip: IpAddr) -> Option<String> {
+        if !is_public(ip) {
+            return None;
+        }
+        if let Some(listed_in) = self.cache.lock().unwrap().get(ip, Instant::now()) {
+            return listed_in;
+        }
+
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        let reversed = reverse(ip);
+        let lookups = self.config.zones.iter().map(|zone| {
+            let name = format!("{reversed}.{zone}");
+            async move {
+                let addrs = time::timeout(timeout, resolver.resolve(&name, 0)).await;
+                matches!(addrs, Ok(Ok(addrs)) if addrs.iter().any(is_listing))
+            }
+        });
+        let listed = join_all(lookups).await;
+        let listed_in = self
+            .config
+            .zones
+            .iter()
+            .zip(listed)
+            .find_map(|(zone, listed)| listed.then(|| zone.clone()));
+
+        let expires = Instant::now() + Duration::from_secs(self.config.cache_secs);
+        self.cache.lock().unwrap().insert(
+            ip,
+            listed_in.clone(),
+            expires,
+            self.config.cache_entries,
+        );
+        listed_in
+    }
+
+    /// Forgets verdicts that expired, see [`crate::server::Server`]'s periodic sweep.
+    pub(crate) fn sweep(&self) {
+        self.cache.lock().unwrap().sweep(Instant::now());
+    }
+}
+
+/// Zones answer listed addresses with a record in 127.0.0.0/8, whose last octet tells the
+/// reason. Anything else is an error page of a resolver that hijacks nxdomain answers.
+fn is_listing(addr: &SocketAddr) -> bool {
+    match addr.ip() {
+        IpAddr::V4(ip) => ip.octets()[0] == 127,
+        IpAddr::V6(_) => false,
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified())
+        }
+        IpAddr::V6(ip) => {
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local())
+        }
+    }
+}
+
+/// The labels an address is looked up under, least significant first.
+fn reverse(ip: IpAddr) -> String {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{d}.{c}.{b}.{a}")
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::with_capacity(63);
+            for byte in ip.octets().iter().rev() {
+                let _ = write!(name, "{:x}.{:x}.", byte & 0xf, byte >> 4);
+            }
+            name.pop();
+            name
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use futures::future::BoxFuture;
+
+    use super::*;
+
+    /// Lists 192.0.2.1 in `bad.example`, and nothing in `good.example`.
+    struct Zones {
+        queries: AtomicUsize,
+    }
+
+    impl Resolver for Zones {
+        fn resolve<'a>(
+            &'a self,
+            host: &'a str,
+            port: u16,
+        ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+            self.queries.fetch_add(1, Ordering::Relaxed);
+            let records = HashMap::from([("1.2.0.192.bad.example", [127, 0, 0, 2])]);
+            let res = match records.get(host) {
+                Some(&ip) => Ok(vec![SocketAddr::from((ip, port))]),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "nxdomain")),
+            };
+            Box::pin(async move { res })
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_listed_clients_and_caches_the_verdict() {
+        let dnsbl = Dnsbl::new(DnsblConfig {
+            zones: vec!["good.example".to_owned(), "bad.example".to_owned()],
+            ..DnsblConfig::default()
+        })
+        .unwrap();
+        let zones = Zones {
+            queries: AtomicUsize::new(0),
+        };
+
+        let listed: IpAddr = [192, 0, 2, 1].into();
+        assert_eq!(
+            dnsbl.listing(&zones, listed).await.as_deref(),
+            Some("bad.example")
+        );
+        assert_eq!(
+            dnsbl.listing(&zones, listed).await.as_deref(),
+            Some("bad.example")
+        );
+        assert_eq!(zones.queries.load(Ordering::Relaxed), 2);
+
+        assert_eq!(dnsbl.listing(&zones, [192, 0, 2, 2].into()).await, None);
+        assert_eq!(dnsbl.listing(&zones, [10, 0, 0, 1].into()).await, None);
+        assert_eq!(zones.queries.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_the_least_recently_used_verdicts() {
+        let dnsbl = Dnsbl::new(DnsblConfig {
+            zones: vec!["bad.example".to_owned()],
+            cache_secs: 60,
+            cache_entries: 2,
+            ..DnsblConfig::default()
+        })
+        .unwrap();
+        let zones = Zones {
+            queries: AtomicUsize::new(0),
+        };
+        let [a, b, c]: [IpAddr; 3] = [
+            [192, 0, 2, 1].into(),
+            [192, 0, 2, 2].into(),
+            [192, 0, 2, 3].into(),
+        ];
+        let queries = || zones.queries.load(Ordering::Relaxed);
+
+        dnsbl.listing(&zones, a).await;
+        dnsbl.listing(&zones, b).await;
+        dnsbl.listing(&zones, a).await;
+        assert_eq!(queries(), 2);
+        // b was used least recently, so it makes room for c
+        dnsbl.listing(&zones, c).await;
+        dnsbl.listing(&zones, a).await;
+        assert_eq!(queries(), 3);
+        dnsbl.listing(&zones, b).await;
+        assert_eq!(queries(), 4);
+
+        time::advance(Duration::from_secs(61)).await;
+        dnsbl.sweep();
+        let cache = dnsbl.cache.lock().unwrap();
+        assert!(cache.verdicts.is_empty() && cache.by_use.is_empty());
+    }
+
+    #[test]
+    fn reverses_addresses_for_lookups() {
+        assert_eq!(reverse([192, 0, 2, 1].into()), "1.2.0.192");
+        assert_eq!(
+            reverse("2001:db8::1".parse().unwrap()),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+//! Deliberate misbehavior for testing socks clients against, configured by the `faults`
+//! section of the server config. Each phase of the handshake can have its reply delayed,
+//! garbled or withheld by closing the connection, and requests can be answered with a fixed
+//! status instead of being served. Not meant for servers with real clients.
+
+use std::{borrow::Cow, fmt, time::Duration};
+
+use tokio::{io, time};
+
+use crate::config::{FaultsConfig, PhaseFaultsConfig};
+
+/// The server replies the faults can hit, one per handshake phase.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Phase {
+    /// choosing an auth method from the client's greeting
+    Greeting,
+    /// the username/password status
+    Auth,
+    /// the reply to the request
+    Request,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Greeting => "greeting",
+            Self::Auth => "auth",
+            Self::Request => "request",
+        })
+    }
+}
+
+/// Applies the faults configured for `phase` to `reply` before it is sent: waits out the
+/// delay, then returns the bytes to send instead, or fails if the connection is to be
+/// closed without a reply.
+pub(crate) async fn inject<'a>(
+    config: Option<&FaultsConfig>,
+    phase: Phase,
+    reply: &'a [u8],
+) -> io::Result<Cow<'a, [u8]>> {
+    let Some(config) = config else {
+        return Ok(Cow::Borrowed(reply));
+    };
+    let faults: &PhaseFaultsConfig = match phase {
+        Phase::Greeting => &config.greeting,
+        Phase::Auth => &config.auth,
+        Phase::Request => &config.request,
+    };
+    if faults.delay_ms > 0 {
+        time::sleep(Duration::from_millis(faults.delay_ms)).await;
+    }
+    if faults.close {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            format!("fault injection: closing instead of the {phase} reply"),
+        ));
+    }
+    if faults.garble {
+        // inverting every byte turns the version byte leading each reply into nonsense
+        return Ok(Cow::Owned(reply.iter().map(|b| !b).collect()));
+    }
+    Ok(Cow::Borrowed(reply))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn applies_the_faults_of_the_phase() {
+        let config = FaultsConfig {
+            greeting: PhaseFaultsConfig {
+                delay_ms: 2000,
+                ..PhaseFaultsConfig::default()
+            },
+            auth: PhaseFaultsConfig {
+                garble: true,
+                ..PhaseFaultsConfig::default()
+            },
+            request: PhaseFaultsConfig {
+                close: true,
+                ..PhaseFaultsConfig::default()
+            },
+            reply_status: None,
+        };
+
+        let started = time::Instant::now();
+        let reply = inject(Some(&config), Phase::Greeting, &[5, 0])
+            .await
+            .unwrap();
+        assert_eq!(*reply, [5, 0]);
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+
+        let reply = inject(Some(&config), Phase::Auth, &[1, 0]).await.unwrap();
+        assert_eq!(*reply, [0xfe, 0xff]);
+
+        let err = inject(Some(&config), Phase::Request, &[5, 0]).await;
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+
+        let reply = inject(None, Phase::Request, &[5, 0]).await.unwrap();
+        assert_eq!(*reply, [5, 0]);
+    }
+}
@@ -0,0 +1,311 @@
+//! Acceptor side GSSAPI security contexts, through the system's MIT kerberos library.
+//!
+//! Only what RFC 1961 needs is wrapped: accepting a context from the client's tokens, and
+//! wrapping/unwrapping messages once it is established.
+
+use std::{
+    ffi::{c_int, c_void, CString},
+    path::Path,
+    ptr,
+    sync::Mutex,
+};
+
+use tokio::io;
+
+type OmUint32 = u32;
+type GssCtxId = *mut c_void;
+type GssName = *mut c_void;
+
+const GSS_S_COMPLETE: OmUint32 = 0;
+const GSS_S_CONTINUE_NEEDED: OmUint32 = 1;
+const GSS_C_GSS_CODE: c_int = 1;
+const GSS_C_MECH_CODE: c_int = 2;
+
+#[repr(C)]
+struct GssBuffer {
+    length: usize,
+    value: *mut c_void,
+}
+
+impl GssBuffer {
+    fn empty() -> Self {
+        Self {
+            length: 0,
+            value: ptr::null_mut(),
+        }
+    }
+
+    /// Borrows `data` for the duration of a call that only reads the buffer.
+    fn borrowed(data: &[u8]) -> Self {
+        Self {
+            length: data.len(),
+            value: data.as_ptr() as *mut c_void,
+        }
+    }
+
+    /// Copies out a buffer allocated by the library and releases it.
+    fn take(mut self) -> Vec<u8> {
+        let data = if self.value.is_null() {
+            Vec::new()
+        } else {
+            unsafe { std::slice::from_raw_parts(self.value as *const u8, self.length) }.to_vec()
+        };
+        let mut minor = 0;
+        unsafe { gss_release_buffer(&mut minor, &mut self) };
+        data
+    }
+}
+
+#[link(name = "gssapi_krb5")]
+extern "C" {
+    fn gss_accept_sec_context(
+        minor_status: *mut OmUint32,
+        context_handle: *mut GssCtxId,
+        acceptor_cred_handle: *mut c_void,
+        input_token_buffer: *mut GssBuffer,
+        input_chan_bindings: *mut c_void,
+        src_name: *mut GssName,
+        mech_type: *mut *mut c_void,
+        output_token: *mut GssBuffer,
+        ret_flags: *mut OmUint32,
+        time_rec: *mut OmUint32,
+        delegated_cred_handle: *mut *mut c_void,
+    ) -> OmUint32;
+    fn gss_wrap(
+        minor_status: *mut OmUint32,
+        context_handle: GssCtxId,
+        conf_req_flag: c_int,
+        qop_req: OmUint32,
+        input_message_buffer: *mut GssBuffer,
+        conf_state: *mut c_int,
+        output_message_buffer: *mut GssBuffer,
+    ) -> OmUint32;
+    fn gss_unwrap(
+        minor_status: *mut OmUint32,
+        context_handle: GssCtxId,
+        input_message_buffer: *mut GssBuffer,
+        output_message_buffer: *mut GssBuffer,
+        conf_state: *mut c_int,
+        qop_state: *mut OmUint32,
+    ) -> OmUint32;
+    fn gss_display_name(
+        minor_status: *mut OmUint32,
+        input_name: GssName,
+        output_name_buffer: *mut GssBuffer,
+        output_name_type: *mut *mut c_void,
+    ) -> OmUint32;
+    fn gss_display_status(
+        minor_status: *mut OmUint32,
+        status_value: OmUint32,
+        status_type: c_int,
+        mech_type: *mut c_void,
+        message_context: *mut OmUint32,
+        status_string: *mut GssBuffer,
+    ) -> OmUint32;
+    fn gss_release_name(minor_status: *mut OmUint32, name: *mut GssName) -> OmUint32;
+    fn gss_release_buffer(minor_status: *mut OmUint32, buffer: *mut GssBuffer) -> OmUint32;
+    fn gss_delete_sec_context(
+        minor_status: *mut OmUint32,
+        context_handle: *mut GssCtxId,
+        output_token: *mut GssBuffer,
+    ) -> OmUint32;
+    fn krb5_gss_register_acceptor_identity(keytab: *const std::ffi::c_char) -> OmUint32;
+}
+
+/// Makes contexts accepted from now on use the service keys in `keytab` instead of the
+/// default keytab.
+pub fn use_keytab(keytab: &Path) -> io::Result<()> {
+    let keytab = CString::new(keytab.as_os_str().as_encoded_bytes()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "keytab path must not contain nul bytes",
+        )
+    })?;
+    match unsafe { krb5_gss_register_acceptor_identity(keytab.as_ptr()) } {
+        GSS_S_COMPLETE => Ok(()),
+        major => Err(gss_error("registering keytab", major, 0)),
+    }
+}
+
+/// What [`ServerContext::accept`] made of a token from the client.
+pub enum Step {
+    /// send the token to the client and wait for its next one
+    Continue(Vec<u8>),
+    /// the context is established, with a final token for the client that may be empty
+    Complete(Vec<u8>),
+}
+
+pub struct ServerContext {
+    // gss calls on a single context must not run concurrently
+    handle: Mutex<GssCtxId>,
+    client_name: Option<String>,
+}
+
+// the context handle is only ever used behind the mutex
+unsafe impl Send for ServerContext {}
+unsafe impl Sync for ServerContext {}
+
+impl ServerContext {
+    pub fn new() -> Self {
+        Self {
+            handle: Mutex::new(ptr::null_mut()),
+            client_name: None,
+        }
+    }
+
+    pub fn accept(&mut self, token: &[u8]) -> io::Result<Step> {
+        let handle = self.handle.get_mut().unwrap();
+        let mut minor = 0;
+        let mut input = GssBuffer::borrowed(token);
+        let mut output = GssBuffer::empty();
+        let mut src_name: GssName = ptr::null_mut();
+        let major = unsafe {
+            gss_accept_sec_context(
+                &mut minor,
+                handle,
+                ptr::null_mut(),
+                &mut input,
+                ptr::null_mut(),
+                &mut src_name,
+                ptr::null_mut(),
+                &mut output,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        let output = output.take();
+        match major {
+            GSS_S_COMPLETE => {
+                self.client_name = display_name(src_name);
+                unsafe { gss_release_name(&mut minor, &mut src_name) };
+                Ok(Step::Complete(output))
+            }
+            GSS_S_CONTINUE_NEEDED => Ok(Step::Continue(output)),
+            major => Err(gss_error("accepting security context", major, minor)),
+        }
+    }
+
+    /// The client's principal, once the context is established.
+    pub fn client_name(&self) -> Option<&str> {
+        self.client_name.as_deref()
+    }
+
+    /// Protects `data` for integrity, and for confidentiality too if `encrypt` is set.
+    pub fn wrap(&self, encrypt: bool, data: &[u8]) -> io::Result<Vec<u8>> {
+        let handle = self.handle.lock().unwrap();
+        let mut minor = 0;
+        let mut input = GssBuffer::borrowed(data);
+        let mut output = GssBuffer::empty();
+        let major = unsafe {
+            gss_wrap(
+                &mut minor,
+                *handle,
+                encrypt as c_int,
+                0,
+                &mut input,
+                ptr::null_mut(),
+                &mut output,
+            )
+        };
+        let output = output.take();
+        match major {
+            GSS_S_COMPLETE => Ok(output),
+            major => Err(gss_error("wrapping message", major, minor)),
+        }
+    }
+
+    /// Checks and unprotects a token, also returning whether it was encrypted.
+    pub fn unwrap(&self, token: &[u8]) -> io::Result<(Vec<u8>, bool)> {
+        let handle = self.handle.lock().unwrap();
+        let mut minor = 0;
+        let mut input = GssBuffer::borrowed(token);
+        let mut output = GssBuffer::empty();
+        let mut conf_state: c_int = 0;
+        let major = unsafe {
+            gss_unwrap(
+                &mut minor,
+                *handle,
+                &mut input,
+                &mut output,
+                &mut conf_state,
+                ptr::null_mut(),
+            )
+        };
+        let output = output.take();
+        match major {
+            GSS_S_COMPLETE => Ok((output, conf_state != 0)),
+            major => Err(gss_error("unwrapping message", major, minor)),
+        }
+    }
+}
+
+impl Default for ServerContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ServerContext {
+    fn drop(&mut self) {
+        let handle = self.handle.get_mut().unwrap();
+        if !handle.is_null() {
+            let mut minor = 0;
+            unsafe { gss_delete_sec_context(&mut minor, handle, ptr::null_mut()) };
+        }
+    }
+}
+
+fn display_name(name: GssName) -> Option<String> {
+    if name.is_null() {
+        return None;
+    }
+    let mut minor = 0;
+    let mut output = GssBuffer::empty();
+    let major = unsafe { gss_display_name(&mut minor, name, &mut output, ptr::null_mut()) };
+    let output = output.take();
+    (major == GSS_S_COMPLETE).then(|| String::from_utf8_lossy(&output).into_owned())
+}
+
+fn gss_error(action: &str, major: OmUint32, minor: OmUint32) -> io::Error {
+    let mut reason = status_messages(major, GSS_C_GSS_CODE);
+    if minor != 0 {
+        reason = format!("{reason} ({})", status_messages(minor, GSS_C_MECH_CODE));
+    }
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("gssapi: {action}: {reason}"),
+    )
+}
+
+fn status_messages(status: OmUint32, status_type: c_int) -> String {
+    let mut messages = Vec::new();
+    let mut message_context = 0;
+    loop {
+        let mut minor = 0;
+        let mut output = GssBuffer::empty();
+        let major = unsafe {
+            gss_display_status(
+                &mut minor,
+                status,
+                status_type,
+                ptr::null_mut(),
+                &mut message_context,
+                &mut output,
+            )
+        };
+        let output = output.take();
+        if major != GSS_S_COMPLETE {
+            break;
+        }
+        messages.push(String::from_utf8_lossy(&output).into_owned());
+        if message_context == 0 {
+            break;
+        }
+    }
+    if messages.is_empty() {
+        format!("status {status:#x}")
+    } else {
+        messages.join(", ")
+    }
+}
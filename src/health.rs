@@ -0,0 +1,287 @@
+//! Minimal http listener for liveness and readiness probes.
+//!
+//! `/healthz` answers 200 as long as the process is able to serve requests at all.
+//! `/readyz` answers 200 only while the socks listener is accepting connections and the
+//! server has capacity for more of them, and 503 otherwise.
+//! `/metrics` serves the server's [`crate::metrics`], and `/top` its
+//! [`crate::top_talkers`] if they are kept.
+//!
+//! It doubles as an admin interface: `/connections` lists the connections being handled
+//! with the bytes they relayed so far and their recent throughput,
+//! `POST /connections/<id>/cancel` cancels one of them, and `POST /cancel` cancels the whole
+//! server, see [`Server::cancel`]. As the list tells who connects where, all three need
+//! `Authorization: Bearer <admin_token>`, and are refused while no `admin_token` is
+//! configured.
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+use crate::{
+    auth::constant_time_eq,
+    server::{ConnectionId, Server},
+    stats::Throughput,
+};
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// the most of a request that is read, up to the end of its headers
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+pub async fn serve(server: Arc<Server>, listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            if let Err(err) = respond(&server, stream).await {
+                eprintln!("health: {err:?}");
+            }
+        });
+    }
+}
+
+async fn respond(server: &Server, mut stream: TcpStream) -> io::Result<()> {
+    let request = timeout(READ_TIMEOUT, read_head(&mut stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out reading request"))??;
+    let request = &request[..];
+    let request_line = request.split(|&b| b == b'\n').next().unwrap_or_default();
+    let admin = authorized(request, server.config().admin_token.as_deref());
+
+    let (status, body) = match request_line
+        .split(|&b| b == b' ')
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [b"GET", b"/healthz", ..] => ("200 OK", "ok\n".to_owned()),
+        [b"GET", b"/readyz", ..] => readiness(server),
+        [b"GET", b"/metrics", ..] => ("200 OK", server.metrics().render(server)),
+        [b"GET", b"/connections", ..] | [b"POST", ..] if !admin => {
+            ("403 Forbidden", "admin_token required\n".to_owned())
+        }
+        [b"GET", b"/connections", ..] => ("200 OK", connections(server)),
+        [b"GET", b"/top", ..] => match server.top_talkers() {
+            Some(top_talkers) => ("200 OK", top_talkers.report()),
+            None => ("404 Not Found", "top talkers are not kept\n".to_owned()),
+        },
+        [b"POST", b"/cancel", ..] => {
+            server.cancel();
+            ("200 OK", "cancelled\n".to_owned())
+        }
+        [b"POST", path, ..] => match cancel_path(path) {
+            Some(id) if server.cancel_connection(id) => ("200 OK", format!("cancelled {id}\n")),
+            Some(id) => ("404 Not Found", format!("no connection {id}\n")),
+            None => ("404 Not Found", "not found\n".to_owned()),
+        },
+        _ => ("404 Not Found", "not found\n".to_owned()),
+    };
+
+    let resp = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(resp.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Reads the request up to the end of its headers, which may take several segments. The body
+/// of a request, if any, is of no interest.
+async fn read_head(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0_u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("request headers exceed {MAX_REQUEST_SIZE} bytes"),
+            ));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before the end of the request headers",
+            ));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(buf)
+}
+
+fn connections(server: &Server) -> String {
+    server
+        .connections()
+        .iter()
+        .map(|info| {
+            let progress = server
+                .connection_progress(info.id)
+                .map(|progress| *progress.borrow())
+                .unwrap_or_default();
+            format!(
+                "{} {} {:.1}s {} bytes to remote {} bytes to client {} to remote {} to client\n",
+                info,
+                info.peer_addr,
+                info.accepted_at.elapsed().as_secs_f64(),
+                progress.to_remote,
+                progress.to_client,
+                Throughput(progress.rate_to_remote.bytes_per_sec()),
+                Throughput(progress.rate_to_client.bytes_per_sec()),
+            )
+        })
+        .collect()
+}
+
+/// Whether the headers of `request` carry `token` as a bearer token. Nothing is authorized
+/// without a token.
+fn authorized(request: &[u8], token: Option<&str>) -> bool {
+    let Some(token) = token.filter(|token| !token.is_empty()) else {
+        return false;
+    };
+    request
+        .split(|&b| b == b'\n')
+        .skip(1)
+        .filter_map(|line| std::str::from_utf8(line).ok()?.split_once(':'))
+        .any(|(name, value)| {
+            name.eq_ignore_ascii_case("authorization")
+                && value
+                    .trim()
+                    .strip_prefix("Bearer ")
+                    .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+        })
+}
+
+/// The connection a `/connections/<id>/cancel` path is about.
+fn cancel_path(path: &[u8]) -> Option<ConnectionId> {
+    let id = std::str::from_utf8(path)
+        .ok()?
+        .strip_prefix("/connections/")?
+        .strip_suffix("/cancel")?;
+    id.parse().ok()
+}
+
+fn readiness(server: &Server) -> (&'static str, String) {
+    let active = server.active_connections();
+    let capacity = match server.config().max_connections {
+        Some(max) => max.to_string(),
+        None => "unlimited".to_owned(),
+    };
+    let details = format!(
+        "listening: {}\nconnections: {active}/{capacity}\n",
+        server.is_listening()
+    );
+
+    if server.is_listening() && !server.at_capacity() {
+        ("200 OK", format!("ready\n{details}"))
+    } else {
+        ("503 Service Unavailable", format!("not ready\n{details}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+
+    /// Sends `request_line` to the health listener at `addr`, returning the status line and
+    /// the body of the response.
+    async fn get(addr: std::net::SocketAddr, request_line: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("{request_line}\r\nHost: localhost\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        let (head, body) = resp.split_once("\r\n\r\n").unwrap();
+        let status = head.lines().next().unwrap().to_owned();
+        assert!(
+            head.contains(&format!("Content-Length: {}", body.len())),
+            "{head}"
+        );
+        (status, body.to_owned())
+    }
+
+    #[tokio::test]
+    async fn answers_probes_as_the_server_is_ready() {
+        let config = ServerConfig {
+            max_connections: Some(10),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(config).unwrap());
+        let health = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = health.local_addr().unwrap();
+        tokio::spawn(serve(Arc::clone(&server), health));
+
+        let (status, body) = get(addr, "GET /healthz HTTP/1.1").await;
+        assert_eq!(
+            (status.as_str(), body.as_str()),
+            ("HTTP/1.1 200 OK", "ok\n")
+        );
+        let (status, body) = get(addr, "GET /readyz HTTP/1.1").await;
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+        assert_eq!(body, "not ready\nlistening: false\nconnections: 0/10\n");
+
+        let socks = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        tokio::spawn(Arc::clone(&server).serve(socks));
+        while !server.is_listening() {
+            tokio::task::yield_now().await;
+        }
+        let (status, body) = get(addr, "GET /readyz HTTP/1.1").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, "ready\nlistening: true\nconnections: 0/10\n");
+
+        let (status, _) = get(addr, "GET /nowhere HTTP/1.1").await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn lists_connections_to_admins_whose_token_arrives_late() {
+        let config = ServerConfig {
+            admin_token: Some("s3cret".to_owned()),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(config).unwrap());
+        let health = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = health.local_addr().unwrap();
+        tokio::spawn(serve(server, health));
+
+        let (status, _) = get(addr, "GET /connections HTTP/1.1").await;
+        assert_eq!(status, "HTTP/1.1 403 Forbidden");
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /connections HTTP/1.1\r\nHost: localhost\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stream
+            .write_all(b"Authorization: Bearer s3cret\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{resp}");
+    }
+
+    #[test]
+    fn authorizes_only_the_configured_bearer_token() {
+        let request = b"POST /cancel HTTP/1.1\r\nHost: x\r\nauthorization: Bearer s3cret\r\n\r\n";
+        assert!(authorized(request, Some("s3cret")));
+        assert!(!authorized(request, Some("other")));
+        assert!(!authorized(request, None));
+        assert!(!authorized(
+            b"POST /cancel HTTP/1.1\r\n\r\n",
+            Some("s3cret")
+        ));
+        assert!(!authorized(
+            b"POST /cancel HTTP/1.1\r\nAuthorization: Bearer \r\n\r\n",
+            Some("")
+        ));
+        assert_eq!(
+            cancel_path(b"/connections/conn-7/cancel"),
+            "conn-7".parse().ok()
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! One line per request the server turned away, appended to the file configured as
+//! `honeypot_log`, for feeding threat intelligence. Denied requests are never dialed, so the
+//! log only tells what clients were after.
+//!
+//! Lines are space separated: time, connection id, client address, listener, protocol, the
+//! auth methods the client offered in the order it offered them, username, command,
+//! requested destination, the time from accepting the connection to the denial, and the
+//! reason, which runs to the end of the line. Fields without a value are written as `-`, and
+//! usernames and destinations are escaped like those of the access log.
+//! The offered methods and the timing fingerprint the client software, scanners sending
+//! canned greetings much faster than any person or browser would.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{LineWriter, Write},
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+use jiff::Timestamp;
+use tokio::io;
+
+use crate::{access_log::escape_field, proto, server::ConnectionInfo};
+
+pub struct HoneypotLog {
+    out: Mutex<LineWriter<File>>,
+}
+
+/// A request the server turned away, as far as the client got before that.
+pub struct DeniedRecord<'a> {
+    pub info: ConnectionInfo,
+    /// `socks5`, `socks6` or `http`, `-` for clients turned away before they sent anything
+    pub protocol: &'static str,
+    /// the methods of the client's socks5 greeting
+    pub auth_methods: &'a [proto::AuthMethod],
+    /// who the client authenticated or tried to authenticate as
+    pub username: Option<&'a str>,
+    /// the request, unless the client was turned away before sending one
+    pub request: Option<&'a proto::ClientConnectionRequest>,
+    pub reason: &'a str,
+}
+
+impl HoneypotLog {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            out: Mutex::new(LineWriter::new(file)),
+        })
+    }
+
+    pub fn record(&self, record: &DeniedRecord) {
+        let line = format_record(Timestamp::now(), record.info.accepted_at.elapsed(), record);
+        if let Err(err) = self.out.lock().unwrap().write_all(line.as_bytes()) {
+            eprintln!("honeypot log: {err}");
+        }
+    }
+
+    /// Makes sure every line recorded so far reached the disk.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut out = self.out.lock().unwrap();
+        out.flush()?;
+        out.get_ref().sync_data()
+    }
+}
+
+fn format_record(now: Timestamp, handshake: Duration, record: &DeniedRecord) -> String {
+    let methods = if record.auth_methods.is_empty() {
+        "-".to_owned()
+    } else {
+        record
+            .auth_methods
+            .iter()
+            .map(|&method| format!("{:02x}", u8::from(method)))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let (cmd, dest) = match record.request {
+        Some(request) => {
+            let cmd = match request.cmd {
+                proto::ClientCommand::EstablishConnection => "CONNECT",
+                proto::ClientCommand::EstablishPortBinding => "BIND",
+                proto::ClientCommand::AssociateUdpPort => "UDP_ASSOCIATE",
+            };
+            let dest = format!("{}:{}", request.dest_addr, request.dest_port);
+            (cmd, escape_field(&dest))
+        }
+        None => ("-", "-".to_owned()),
+    };
+    format!(
+        "{now} {} {} {} {} {methods} {} {cmd} {dest} {}ms {}\n",
+        record.info.id,
+        record.info.peer_addr,
+        record.info.listener.unwrap_or("-"),
+        record.protocol,
+        record.username.map_or_else(|| "-".to_owned(), escape_field),
+        handshake.as_millis(),
+        record.reason,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ConnectionId;
+
+    #[test]
+    fn records_what_the_client_offered_and_asked_for() {
+        let info = ConnectionInfo {
+            id: ConnectionId(7),
+            peer_addr: "192.0.2.1:50000".parse().unwrap(),
+            accepted_at: std::time::Instant::now(),
+            listener: Some("wan"),
+        };
+        let request = proto::ClientConnectionRequest {
+            cmd: proto::ClientCommand::EstablishConnection,
+            dest_addr: proto::Address::DomainName("intranet.example.com".to_owned()),
+            dest_port: 22,
+        };
+        let record = DeniedRecord {
+            info,
+            protocol: "socks5",
+            auth_methods: &[proto::AuthMethod::UserPass, proto::AuthMethod::NoAuth],
+            username: Some("ad min"),
+            request: Some(&request),
+            reason: "destination port policy",
+        };
+        let now = "2024-01-03T10:00:00Z".parse().unwrap();
+        assert_eq!(
+            format_record(now, Duration::from_millis(42), &record),
+            "2024-01-03T10:00:00Z conn-7 192.0.2.1:50000 wan socks5 02,00 ad\\x20min \
+             CONNECT intranet.example.com:22 42ms destination port policy\n"
+        );
+
+        let request = proto::ClientConnectionRequest {
+            dest_addr: proto::Address::DomainName("a.com:1 1ms x\nforged".to_owned()),
+            ..request
+        };
+        let forging = DeniedRecord {
+            request: Some(&request),
+            ..record
+        };
+        assert!(format_record(now, Duration::from_millis(42), &forging)
+            .contains(" CONNECT a.com:1\\x201ms\\x20x\\nforged:22 42ms "));
+
+        let record = DeniedRecord {
+            protocol: "http",
+            auth_methods: &[],
+            username: None,
+            request: None,
+            reason: "authentication failed",
+            ..record
+        };
+        assert_eq!(
+            format_record(now, Duration::from_millis(3), &record),
+            "2024-01-03T10:00:00Z conn-7 192.0.2.1:50000 wan http - - - - 3ms \
+             authentication failed\n"
+        );
+    }
+}
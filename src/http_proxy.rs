@@ -0,0 +1,631 @@
+//! HTTP/1.1 forward proxying, configured by the `http_proxy` section of the server config.
+//!
+//! `CONNECT` requests are tunneled like socks CONNECTs. Requests with an absolute `http://`
+//! target are forwarded to the origin with an origin-form target and `Connection: close`,
+//! and the origin's response is passed back as is. Every client connection carries a single
+//! request: whatever the client sends past the end of the first one's body is read and
+//! dropped rather than forwarded, since later requests would reach the origin without their
+//! destination having been checked. For the same reason, request bodies have to come with a
+//! `Content-Length`, chunked ones are refused. The `Host` header is rewritten to the authority
+//! of the target, which is what the server's policies were checked against, so that origins
+//! serving several hosts can't be asked for another one than the client was allowed.
+
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, Take};
+
+use crate::{
+    auth::Grant,
+    honeypot::DeniedRecord,
+    proto,
+    server::{ConnectionInfo, Server},
+    tcp_server_stream::{
+        check_policies, outbound_dscp, record_access, record_denied, relay_plain, within,
+        within_handshake, ClientConn, Requested,
+    },
+};
+
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Request headers that only concern the hop between the client and the proxy.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+];
+
+struct Request<'a> {
+    method: &'a str,
+    target: &'a str,
+    version: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+}
+
+pub(crate) async fn handle(
+    server: &Server,
+    mut stream: ClientConn,
+    info: ConnectionInfo,
+) -> io::Result<()> {
+    let timeouts = &server.config().handshake_timeouts;
+    let (head, early_data) = within_handshake(
+        server,
+        info,
+        within(timeouts.request_secs, "request", read_head(&mut stream)),
+    )
+    .await?;
+    let request = match std::str::from_utf8(&head)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        .and_then(Request::parse)
+    {
+        Ok(request) => request,
+        Err(err) => {
+            respond(&mut stream, "400 Bad Request", "").await?;
+            return Err(err);
+        }
+    };
+
+    let authenticated = within_handshake(
+        server,
+        info,
+        within(
+            timeouts.auth_secs,
+            "auth",
+            authenticate(server, &request, info),
+        ),
+    )
+    .await;
+    let (username, grant) = match authenticated {
+        Ok(authenticated) => authenticated,
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            let challenge = "Proxy-Authenticate: Basic realm=\"socks5\"\r\n";
+            respond(&mut stream, "407 Proxy Authentication Required", challenge).await?;
+            return Err(err);
+        }
+        Err(err) => {
+            respond(&mut stream, "503 Service Unavailable", "").await?;
+            return Err(err);
+        }
+    };
+
+    let destination = request.destination().and_then(|(host, port, path)| {
+        let body_len = match path {
+            Some(_) => request.body_len()?,
+            None => 0,
+        };
+        Ok((host.parse()?, host, port, path, body_len))
+    });
+    let (dest_addr, host, port, path, body_len) = match destination {
+        Ok(destination) => destination,
+        Err(err) if err.kind() == io::ErrorKind::Unsupported => {
+            respond(&mut stream, "411 Length Required", "").await?;
+            return Err(err);
+        }
+        Err(err) => {
+            respond(&mut stream, "400 Bad Request", "").await?;
+            return Err(err);
+        }
+    };
+    let socks_request = proto::ClientConnectionRequest {
+        cmd: proto::ClientCommand::EstablishConnection,
+        dest_addr,
+        dest_port: port,
+    };
+
+    let client = info.peer_addr;
+    let checked = check_policies(
+        server,
+        info,
+        username.as_deref(),
+        Some(&grant),
+        &socks_request,
+    )
+    .await;
+    let acl_rule = match checked {
+        Ok(acl_rule) => acl_rule,
+        Err(rule) => {
+            record_denied(
+                server,
+                &DeniedRecord {
+                    info,
+                    protocol: "http",
+                    auth_methods: &[],
+                    username: username.as_deref(),
+                    request: Some(&socks_request),
+                    reason: rule,
+                },
+            );
+            server.tarpit().await?;
+            respond(&mut stream, "403 Forbidden", "").await?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "{rule} denied http {} from {client} to {host}:{port}",
+                    request.method
+                ),
+            ));
+        }
+    };
+
+    let dialed = server
+        .dialer()
+        .connect(
+            server.resolver(),
+            &socks_request.dest_addr,
+            port,
+            outbound_dscp(server, info, &socks_request),
+        )
+        .await;
+    record_access(server, info, username.as_deref(), &socks_request, &dialed);
+    let mut remote = match dialed {
+        Ok(remote) => remote,
+        Err(err) => {
+            let status = match err.kind() {
+                io::ErrorKind::TimedOut => "504 Gateway Timeout",
+                _ => "502 Bad Gateway",
+            };
+            respond(&mut stream, status, "").await?;
+            return Err(err);
+        }
+    };
+
+    let stream = match path {
+        None => {
+            stream
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await?;
+            remote.write_all(&early_data).await?;
+            stream
+        }
+        Some(path) => {
+            let head = request.forwarded_head(path, host, port);
+            remote.write_all(head.as_bytes()).await?;
+            let sent = early_data
+                .len()
+                .min(usize::try_from(body_len).unwrap_or(usize::MAX));
+            remote.write_all(&early_data[..sent]).await?;
+            // the client is wrapped, so these are relayed through userspace
+            ClientConn::Wrapped {
+                local_addr: stream.local_addr()?,
+                stream: Box::new(OneRequest(stream.take(body_len - sent as u64))),
+            }
+        }
+    };
+
+    let requested = Requested {
+        request: &socks_request,
+        username: username.as_deref(),
+        acl_rule,
+    };
+    relay_plain(server, stream, remote, requested, info).await
+}
+
+/// Reads up to the end of the request head, returning it along with whatever the client sent
+/// after it.
+async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0_u8; 4096];
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(end + 4);
+            return Ok((buf, rest));
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("http request head exceeds {MAX_HEAD_SIZE} bytes"),
+            ));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before the end of the http request head",
+            ));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+impl<'a> Request<'a> {
+    fn parse(head: &'a str) -> io::Result<Self> {
+        let mut lines = head.split("\r\n").filter(|line| !line.is_empty());
+        let request_line = lines.next().unwrap_or_default();
+        let [method, target, version] = request_line
+            .split(' ')
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| invalid(format!("malformed request line: {request_line:?}")))?;
+        let headers = lines
+            .map(|line| {
+                line.split_once(':')
+                    .map(|(name, value)| (name, value.trim()))
+                    .ok_or_else(|| invalid(format!("malformed header: {line:?}")))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            method,
+            target,
+            version,
+            headers,
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|&(_, value)| value)
+    }
+
+    /// The decoded `username:password` of basic proxy credentials, if the client sent any.
+    fn basic_credentials(&self) -> Option<String> {
+        let (scheme, encoded) = self.header("proxy-authorization")?.split_once(' ')?;
+        // auth schemes are case-insensitive
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        String::from_utf8(decode_base64(encoded.trim())?).ok()
+    }
+
+    /// The length of the request body, refusing bodies of unknown length as
+    /// [`io::ErrorKind::Unsupported`].
+    fn body_len(&self) -> io::Result<u64> {
+        if self.header("transfer-encoding").is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "http request body without a content-length",
+            ));
+        }
+        let Some(len) = self.header("content-length") else {
+            return Ok(0);
+        };
+        len.parse()
+            .map_err(|_| invalid(format!("malformed content-length: {len:?}")))
+    }
+
+    /// The host and port to connect to, and the origin-form target to forward unless the
+    /// request is a CONNECT.
+    fn destination(&self) -> io::Result<(&'a str, u16, Option<&'a str>)> {
+        if self.method == "CONNECT" {
+            let (host, port) = parse_authority(self.target, None)?;
+            return Ok((host, port, None));
+        }
+        let rest = self
+            .target
+            .get(..7)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))
+            .map(|_| &self.target[7..])
+            .ok_or_else(|| invalid(format!("expected an absolute http uri: {}", self.target)))?;
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let authority = authority
+            .rsplit_once('@')
+            .map_or(authority, |(_, host)| host);
+        let (host, port) = parse_authority(authority, Some(80))?;
+        Ok((host, port, Some(path)))
+    }
+
+    fn forwarded_head(&self, path: &str, host: &str, port: u16) -> String {
+        // the client can name further hop-by-hop headers in its `Connection` header
+        let named: Vec<_> = self
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("connection"))
+            .flat_map(|(_, value)| value.split(','))
+            .map(str::trim)
+            .collect();
+        let mut head = format!("{} {path} {}\r\n", self.method, self.version);
+        for (name, value) in &self.headers {
+            let dropped = name.eq_ignore_ascii_case("host")
+                || HOP_BY_HOP_HEADERS
+                    .iter()
+                    .chain(&named)
+                    .any(|hop| name.eq_ignore_ascii_case(hop));
+            if !dropped {
+                head.push_str(&format!("{name}: {value}\r\n"));
+            }
+        }
+        let host = if host.contains(':') {
+            format!("[{host}]")
+        } else {
+            host.to_owned()
+        };
+        match port {
+            80 => head.push_str(&format!("Host: {host}\r\n")),
+            _ => head.push_str(&format!("Host: {host}:{port}\r\n")),
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        head
+    }
+}
+
+/// The client's side of a forwarded request, which reads no further than the end of the
+/// request body. Anything after it is read and dropped until the client closes its side.
+struct OneRequest(Take<ClientConn>);
+
+impl AsyncRead for OneRequest {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let body = &mut self.get_mut().0;
+        if body.limit() > 0 {
+            return Pin::new(body).poll_read(cx, buf);
+        }
+        let mut dropped = [0_u8; 4096];
+        loop {
+            let mut dropped = ReadBuf::new(&mut dropped);
+            ready!(Pin::new(body.get_mut()).poll_read(cx, &mut dropped))?;
+            if dropped.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl AsyncWrite for OneRequest {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(self.get_mut().0.get_mut()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(self.get_mut().0.get_mut()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(self.get_mut().0.get_mut()).poll_shutdown(cx)
+    }
+}
+
+/// Splits `host:port`, where the port is optional if there is a default.
+fn parse_authority(authority: &str, default_port: Option<u16>) -> io::Result<(&str, u16)> {
+    let (host, port) = proto::split_host_port(authority)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    match port.or(default_port) {
+        Some(port) => Ok((host, port)),
+        None => Err(invalid(format!("missing port in {authority}"))),
+    }
+}
+
+/// Returns the user the client authenticated as, if the server requires authentication, and
+/// what their credentials entitle them to.
+async fn authenticate(
+    server: &Server,
+    request: &Request<'_>,
+    info: ConnectionInfo,
+) -> io::Result<(Option<String>, Grant)> {
+    let Some(authenticator) = server.authenticator(&info) else {
+        let other_methods =
+            server.config().gssapi.is_some() || !server.private_auth_methods().is_empty();
+        if other_methods && !server.skips_auth(&info) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "http clients can't use any of the server's authentication methods",
+            ));
+        }
+        return Ok((None, Grant::default()));
+    };
+
+    let credentials = request.basic_credentials();
+    let Some((username, password)) = credentials.as_deref().and_then(|c| c.split_once(':')) else {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "http request without basic proxy credentials",
+        ));
+    };
+    match authenticator.authorize(username, password).await {
+        Ok(Some(grant)) => Ok((Some(username.to_owned()), grant)),
+        Ok(None) => {
+            record_denied(
+                server,
+                &DeniedRecord {
+                    info,
+                    protocol: "http",
+                    auth_methods: &[],
+                    username: Some(username),
+                    request: None,
+                    reason: "authentication failed",
+                },
+            );
+            server.tarpit().await?;
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "authentication failed",
+            ))
+        }
+        Err(err) => Err(io::Error::new(
+            err.kind(),
+            format!("checking credentials: {err}"),
+        )),
+    }
+}
+
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut acc = 0_u32;
+    let mut bits = 0;
+    for c in encoded.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+async fn respond(stream: &mut ClientConn, status: &str, headers: &str) -> io::Result<()> {
+    let resp =
+        format!("HTTP/1.1 {status}\r\n{headers}Content-Length: 0\r\nConnection: close\r\n\r\n");
+    stream.write_all(resp.as_bytes()).await
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::config::{HttpProxyConfig, ServerConfig};
+
+    #[tokio::test]
+    async fn forwards_absolute_uri_requests_to_the_origin() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            http_proxy: Some(HttpProxyConfig::default()),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(config).unwrap());
+        tokio::spawn(Arc::clone(&server).serve(listener));
+
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut conn, _) = origin.accept().await.unwrap();
+            let (head, _) = read_head(&mut conn).await.unwrap();
+            let head = String::from_utf8(head).unwrap();
+            assert!(head.starts_with("GET /hello?x=1 HTTP/1.1\r\n"), "{head}");
+            assert!(!head.contains("Proxy-Connection"), "{head}");
+            assert!(
+                head.contains(&format!("\r\nHost: {origin_addr}\r\n")),
+                "{head}"
+            );
+            assert!(!head.contains("intranet"), "{head}");
+            assert!(head.ends_with("Connection: close\r\n\r\n"), "{head}");
+            conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi")
+                .await
+                .unwrap();
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let request = format!(
+            "GET http://{origin_addr}/hello?x=1 HTTP/1.1\r\nHost: intranet.example\r\n\
+             Proxy-Connection: keep-alive\r\n\r\n"
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert_eq!(resp, "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi");
+    }
+
+    #[tokio::test]
+    async fn forwards_a_single_request_per_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            http_proxy: Some(HttpProxyConfig::default()),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(config).unwrap());
+        tokio::spawn(Arc::clone(&server).serve(listener));
+
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let forwarded = tokio::spawn(async move {
+            let (mut conn, _) = origin.accept().await.unwrap();
+            let (head, body) = read_head(&mut conn).await.unwrap();
+            conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            conn.shutdown().await.unwrap();
+            let mut rest = body;
+            conn.read_to_end(&mut rest).await.unwrap();
+            (String::from_utf8(head).unwrap(), rest)
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let request = format!(
+            "POST http://{origin_addr}/ HTTP/1.1\r\nConnection: keep-alive, X-Secret\r\n\
+             X-Secret: hunter2\r\nContent-Length: 5\r\n\r\nhello\
+             GET http://intranet.example/ HTTP/1.1\r\nHost: intranet.example\r\n\r\n"
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert_eq!(resp, "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        drop(client);
+
+        let (head, body) = forwarded.await.unwrap();
+        assert!(!head.contains("X-Secret"), "{head}");
+        assert!(head.ends_with("Connection: close\r\n\r\n"), "{head}");
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn refuses_bodies_of_unknown_length() {
+        let request =
+            Request::parse("POST http://a/ HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n")
+                .unwrap();
+        let err = request.body_len().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        let request =
+            Request::parse("POST http://a/ HTTP/1.1\r\nContent-Length: 12\r\n\r\n").unwrap();
+        assert_eq!(request.body_len().unwrap(), 12);
+    }
+
+    #[test]
+    fn names_the_checked_authority_as_host() {
+        let request =
+            Request::parse("GET http://[2001:db8::1]/ HTTP/1.1\r\nhost: other.example\r\n\r\n")
+                .unwrap();
+        let (host, port, path) = request.destination().unwrap();
+        assert_eq!(
+            request.forwarded_head(path.unwrap(), host, port),
+            "GET / HTTP/1.1\r\nHost: [2001:db8::1]\r\nConnection: close\r\n\r\n"
+        );
+        let request = Request::parse("GET http://example.com:8080/ HTTP/1.1\r\n\r\n").unwrap();
+        let (host, port, path) = request.destination().unwrap();
+        assert_eq!(
+            request.forwarded_head(path.unwrap(), host, port),
+            "GET / HTTP/1.1\r\nHost: example.com:8080\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn decodes_basic_credentials() {
+        let decoded = decode_base64("dXNlcjpwYXNzd29yZA==").unwrap();
+        assert_eq!(decoded, b"user:password");
+        for scheme in ["Basic", "basic", "BASIC"] {
+            let head = format!(
+                "GET http://a/ HTTP/1.1\r\n\
+                 Proxy-Authorization: {scheme} dXNlcjpwYXNzd29yZA==\r\n\r\n"
+            );
+            let request = Request::parse(&head).unwrap();
+            assert_eq!(
+                request.basic_credentials().as_deref(),
+                Some("user:password")
+            );
+        }
+        let request =
+            Request::parse("GET http://a/ HTTP/1.1\r\nProxy-Authorization: Bearer abc\r\n\r\n")
+                .unwrap();
+        assert_eq!(request.basic_credentials(), None);
+    }
+}
@@ -1,7 +1,44 @@
-#![feature(iterator_try_collect)]
+pub mod access_log;
+pub mod acl;
+pub mod auth;
+pub mod blocklist;
+pub mod capture;
+pub mod config;
+pub mod dial;
+pub mod dns_relay;
+pub mod dnsbl;
+pub mod faults;
+#[cfg(all(unix, feature = "gssapi"))]
+pub mod gssapi;
+pub mod health;
+pub mod honeypot;
+pub mod http_proxy;
+pub mod listener;
+pub mod metrics;
+#[cfg(feature = "test-util")]
+pub mod mock;
+pub mod priority;
 pub mod proto;
+#[cfg(feature = "quic")]
+pub mod quic;
+mod random;
+pub mod rate_limit;
+pub mod redact;
+pub mod redirect;
+pub mod relay;
+pub mod resolve;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod server;
+pub mod stats;
+#[cfg(unix)]
+pub mod syslog;
 pub mod tcp_server_stream;
 pub mod tcp_sock_stream;
+pub mod top_talkers;
+pub mod transport;
+#[cfg(unix)]
+pub mod upgrade;
 
 pub fn add(left: usize, right: usize) -> usize {
     left + right
@@ -16,4 +53,19 @@ mod tests {
         let result = add(2, 2);
         assert_eq!(result, 4);
     }
+
+    /// Builds the crate with the toolchain of its `rust-version`, which rustup has to have
+    /// installed. Run with `cargo test -- --ignored minimum_supported`.
+    #[test]
+    #[ignore]
+    fn builds_on_the_minimum_supported_rust_version() {
+        let msrv = env!("CARGO_PKG_RUST_VERSION");
+        let status = std::process::Command::new("cargo")
+            .arg(format!("+{msrv}"))
+            .args(["check", "--all-targets", "--target-dir", "target/msrv"])
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .status()
+            .unwrap();
+        assert!(status.success(), "cargo +{msrv} check failed");
+    }
 }
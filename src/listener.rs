@@ -0,0 +1,41 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use socket2::Domain;
+use tokio::{io, net::TcpListener};
+
+use crate::{config::ListenerConfig, dial};
+
+const DEFAULT_BACKLOG: i32 = 1024;
+
+/// Binds the socks listener, applying the socket options from the config.
+pub fn bind(addr: &str, config: &ListenerConfig) -> io::Result<TcpListener> {
+    let addr = resolve(addr)?;
+    let socket = dial::tcp_socket(Domain::for_address(addr), config.mptcp)?;
+
+    socket.set_reuse_address(config.reuse_address.unwrap_or(true))?;
+    if addr.is_ipv6() {
+        if let Some(only_v6) = config.ipv6_only {
+            socket.set_only_v6(only_v6)?;
+        }
+    }
+    if let Some(size) = config.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = config.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+
+    socket.bind(&addr.into())?;
+    socket.listen(config.backlog.unwrap_or(DEFAULT_BACKLOG))?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("listen address {addr} did not resolve to anything"),
+        )
+    })
+}
@@ -0,0 +1,587 @@
+//! Counters describing what the server has been doing, served in the prometheus text format
+//! at `/metrics` on the health listener.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::watch,
+    time::Instant,
+};
+
+use crate::{
+    acl::{escape_label, RuleMetrics},
+    server::Server,
+    top_talkers::TopTalkers,
+};
+
+#[derive(Default)]
+pub struct Metrics {
+    /// connections accepted on any listener, including those turned away at the limit
+    pub connections_accepted: AtomicU64,
+    /// connections whose handler panicked, which also count as errors
+    pub connection_panics: AtomicU64,
+    pub relay: RelayMetrics,
+    pub udp: UdpMetrics,
+    /// connections that ended in an error, by kind of error
+    connection_errors: Mutex<HashMap<io::ErrorKind, u64>>,
+}
+
+/// Payload bytes of relayed tcp sessions, counted as they are written out.
+#[derive(Default)]
+pub struct RelayMetrics {
+    pub bytes_to_remote: AtomicU64,
+    pub bytes_to_client: AtomicU64,
+}
+
+/// Payload bytes a single tcp session has relayed so far, published while it relays at most
+/// ten times a second, and in full once it ends, see [`Server::connection_progress`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Progress {
+    pub to_remote: u64,
+    pub to_client: u64,
+    pub rate_to_remote: Ewma,
+    pub rate_to_client: Ewma,
+}
+
+/// How far back an [`Ewma`] mostly looks: bytes relayed this many seconds ago weigh about a
+/// third of those relayed just now.
+const EWMA_SECS: f64 = 10.0;
+
+/// The recent throughput of one direction of a session, as an exponentially weighted moving
+/// average. It decays while nothing is relayed, so the rate of a stalled session drops
+/// towards zero while it is still open.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Ewma {
+    /// bytes per second as of `at`
+    rate: f64,
+    /// when bytes were last relayed
+    at: Option<Instant>,
+}
+
+impl Ewma {
+    fn record(&mut self, len: u64, now: Instant) {
+        self.rate = self.rate_at(now) + len as f64 / EWMA_SECS;
+        self.at = Some(now);
+    }
+
+    fn rate_at(&self, now: Instant) -> f64 {
+        let Some(at) = self.at else {
+            return 0.0;
+        };
+        let elapsed = now.saturating_duration_since(at).as_secs_f64();
+        self.rate * (-elapsed / EWMA_SECS).exp()
+    }
+
+    /// Bytes per second, as of now.
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.rate_at(Instant::now())
+    }
+}
+
+/// How long the bytes a connection relays are gathered before they are published in its
+/// [`Progress`], sparing its followers a wakeup on each chunk.
+pub(crate) const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where the relay of one connection counts what it moves: into the server's totals, the
+/// connection's own [`Progress`] and the top talkers, if they are kept. Whatever the progress
+/// and the top talkers don't count yet, they do once it is dropped.
+pub(crate) struct ConnectionRelay<'a> {
+    totals: &'a RelayMetrics,
+    progress: Arc<watch::Sender<Progress>>,
+    /// bytes relayed to the remote and to the client that aren't in the progress yet
+    pending: [Mutex<Batch>; 2],
+    talker: Option<Talker<'a>>,
+    acl_rule: Option<&'a RuleMetrics>,
+}
+
+/// How long the bytes a connection relays are gathered before they count in the top talkers,
+/// sparing the lock every connection shares on each chunk.
+const TALKER_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whom the bytes of a connection count for in the top talkers.
+#[derive(Debug)]
+struct Talker<'a> {
+    top_talkers: &'a TopTalkers,
+    client: IpAddr,
+    /// `host:port` as requested
+    destination: String,
+    /// bytes relayed in both directions that don't count yet
+    pending: Mutex<Batch>,
+}
+
+impl Talker<'_> {
+    fn relayed(&self, len: u64) {
+        let now = Instant::now();
+        let due = self
+            .pending
+            .lock()
+            .unwrap()
+            .add(len, now, TALKER_FLUSH_INTERVAL);
+        if let Some(bytes) = due {
+            self.top_talkers
+                .record(self.client, &self.destination, bytes);
+        }
+    }
+}
+
+impl Drop for Talker<'_> {
+    fn drop(&mut self) {
+        let bytes = self.pending.get_mut().unwrap().take();
+        if bytes > 0 {
+            self.top_talkers
+                .record(self.client, &self.destination, bytes);
+        }
+    }
+}
+
+/// Bytes gathered before they are published somewhere shared, at most once an interval.
+#[derive(Debug, Default)]
+struct Batch {
+    bytes: u64,
+    /// when the bytes were last published, if they were
+    flushed_at: Option<Instant>,
+}
+
+impl Batch {
+    /// Adds `len` bytes, returning all those gathered if they are due to be published at
+    /// `now`, `interval` after they last were.
+    fn add(&mut self, len: u64, now: Instant, interval: Duration) -> Option<u64> {
+        self.bytes += len;
+        if self
+            .flushed_at
+            .is_some_and(|at| now.saturating_duration_since(at) < interval)
+        {
+            return None;
+        }
+        self.flushed_at = Some(now);
+        Some(self.take())
+    }
+
+    /// The bytes gathered so far, which are gone from the batch.
+    fn take(&mut self) -> u64 {
+        std::mem::take(&mut self.bytes)
+    }
+}
+
+impl<'a> ConnectionRelay<'a> {
+    pub(crate) fn new(totals: &'a RelayMetrics, progress: Arc<watch::Sender<Progress>>) -> Self {
+        Self {
+            totals,
+            progress,
+            pending: Default::default(),
+            talker: None,
+            acl_rule: None,
+        }
+    }
+
+    /// Also counts the relayed bytes for the acl rule that allowed the connection.
+    pub(crate) fn with_acl_rule(mut self, acl_rule: &'a RuleMetrics) -> Self {
+        self.acl_rule = Some(acl_rule);
+        self
+    }
+
+    /// Also counts the relayed bytes for `client` and `destination` in `top_talkers`, about
+    /// once a second and when the relay is dropped.
+    pub(crate) fn with_top_talkers(
+        mut self,
+        top_talkers: &'a TopTalkers,
+        client: IpAddr,
+        destination: String,
+    ) -> Self {
+        self.talker = Some(Talker {
+            top_talkers,
+            client,
+            destination,
+            pending: Mutex::default(),
+        });
+        self
+    }
+
+    pub(crate) fn to_remote(&self) -> RelayCounter<'_> {
+        RelayCounter {
+            total: &self.totals.bytes_to_remote,
+            progress: &self.progress,
+            pending: &self.pending[0],
+            talker: self.talker.as_ref(),
+            acl_rule: self.acl_rule.map(|rule| &rule.bytes_to_remote),
+            to_remote: true,
+        }
+    }
+
+    pub(crate) fn to_client(&self) -> RelayCounter<'_> {
+        RelayCounter {
+            total: &self.totals.bytes_to_client,
+            progress: &self.progress,
+            pending: &self.pending[1],
+            talker: self.talker.as_ref(),
+            acl_rule: self.acl_rule.map(|rule| &rule.bytes_to_client),
+            to_remote: false,
+        }
+    }
+}
+
+impl Drop for ConnectionRelay<'_> {
+    fn drop(&mut self) {
+        let [to_remote, to_client] = self
+            .pending
+            .each_mut()
+            .map(|batch| batch.get_mut().unwrap().take());
+        if to_remote + to_client > 0 {
+            let now = Instant::now();
+            self.progress.send_modify(|progress| {
+                progress.to_remote += to_remote;
+                progress.rate_to_remote.record(to_remote, now);
+                progress.to_client += to_client;
+                progress.rate_to_client.record(to_client, now);
+            });
+        }
+    }
+}
+
+/// Counts one direction of a connection's relay.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RelayCounter<'a> {
+    total: &'a AtomicU64,
+    progress: &'a watch::Sender<Progress>,
+    /// bytes relayed in this direction that aren't in the progress yet
+    pending: &'a Mutex<Batch>,
+    talker: Option<&'a Talker<'a>>,
+    acl_rule: Option<&'a AtomicU64>,
+    to_remote: bool,
+}
+
+impl RelayCounter<'_> {
+    pub(crate) fn relayed(&self, len: usize) {
+        if len == 0 {
+            return;
+        }
+        self.total.fetch_add(len as u64, Ordering::Relaxed);
+        if let Some(acl_rule) = self.acl_rule {
+            acl_rule.fetch_add(len as u64, Ordering::Relaxed);
+        }
+        if let Some(talker) = self.talker {
+            talker.relayed(len as u64);
+        }
+        let now = Instant::now();
+        let due = self
+            .pending
+            .lock()
+            .unwrap()
+            .add(len as u64, now, PROGRESS_INTERVAL);
+        let Some(len) = due else {
+            return;
+        };
+        self.progress.send_modify(|progress| {
+            let (bytes, rate) = if self.to_remote {
+                (&mut progress.to_remote, &mut progress.rate_to_remote)
+            } else {
+                (&mut progress.to_client, &mut progress.rate_to_client)
+            };
+            *bytes += len;
+            rate.record(len, now);
+        });
+    }
+}
+
+/// Datagrams and bytes count payloads only, without the socks header.
+#[derive(Default)]
+pub struct UdpMetrics {
+    pub associations_active: AtomicU64,
+    pub datagrams_to_remote: AtomicU64,
+    pub bytes_to_remote: AtomicU64,
+    pub datagrams_to_client: AtomicU64,
+    pub bytes_to_client: AtomicU64,
+    /// too large to forward once the header is added
+    pub dropped_oversize: AtomicU64,
+    /// malformed, fragmented or naming an unusable destination
+    pub dropped_bad_header: AtomicU64,
+    /// sent by someone other than the association's client
+    pub dropped_unauthorized: AtomicU64,
+    /// to a destination the server's policies deny
+    pub dropped_denied: AtomicU64,
+    /// failed to resolve or send
+    pub dropped_failed: AtomicU64,
+}
+
+impl UdpMetrics {
+    pub(crate) fn relayed(datagrams: &AtomicU64, bytes: &AtomicU64, len: usize) {
+        datagrams.fetch_add(1, Ordering::Relaxed);
+        bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub(crate) fn connection_failed(&self, err: &io::Error) {
+        *self
+            .connection_errors
+            .lock()
+            .unwrap()
+            .entry(err.kind())
+            .or_default() += 1;
+    }
+
+    /// Kernel pipes the splice relay keeps open to reuse for upcoming connections, two file
+    /// descriptors each.
+    pub fn pooled_splice_pipes(&self) -> usize {
+        crate::tcp_server_stream::pooled_splice_pipes()
+    }
+
+    /// How many connections ended in each kind of error so far.
+    pub fn connection_errors(&self) -> HashMap<io::ErrorKind, u64> {
+        self.connection_errors.lock().unwrap().clone()
+    }
+
+    pub fn render(&self, server: &Server) -> String {
+        let mut out = String::new();
+        gauge(
+            &mut out,
+            "socks5_connections_active",
+            "client connections being handled",
+            server.active_connections() as u64,
+        );
+        counter(
+            &mut out,
+            "socks5_connections_accepted_total",
+            "client connections accepted",
+            &[("", &self.connections_accepted)],
+        );
+        counter(
+            &mut out,
+            "socks5_connection_panics_total",
+            "client connections whose handler panicked",
+            &[("", &self.connection_panics)],
+        );
+        let name = "socks5_connection_errors_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} client connections that ended in an error\n# TYPE {name} counter"
+        );
+        for (kind, n) in self.connection_errors() {
+            let _ = writeln!(out, "{name}{{kind=\"{kind:?}\"}} {n}");
+        }
+        counter(
+            &mut out,
+            "socks5_relay_bytes_total",
+            "payload bytes relayed over tcp",
+            &[
+                ("direction=\"to_remote\"", &self.relay.bytes_to_remote),
+                ("direction=\"to_client\"", &self.relay.bytes_to_client),
+            ],
+        );
+
+        gauge(
+            &mut out,
+            "socks5_splice_pipes_pooled",
+            "kernel pipes kept open for reuse by the splice relay",
+            self.pooled_splice_pipes() as u64,
+        );
+
+        let udp = &self.udp;
+        gauge(
+            &mut out,
+            "socks5_udp_associations_active",
+            "open udp associations",
+            udp.associations_active.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "socks5_udp_datagrams_total",
+            "datagrams relayed",
+            &[
+                ("direction=\"to_remote\"", &udp.datagrams_to_remote),
+                ("direction=\"to_client\"", &udp.datagrams_to_client),
+            ],
+        );
+        counter(
+            &mut out,
+            "socks5_udp_bytes_total",
+            "payload bytes relayed",
+            &[
+                ("direction=\"to_remote\"", &udp.bytes_to_remote),
+                ("direction=\"to_client\"", &udp.bytes_to_client),
+            ],
+        );
+        counter(
+            &mut out,
+            "socks5_udp_dropped_total",
+            "datagrams dropped instead of relayed",
+            &[
+                ("reason=\"oversize\"", &udp.dropped_oversize),
+                ("reason=\"bad_header\"", &udp.dropped_bad_header),
+                ("reason=\"unauthorized\"", &udp.dropped_unauthorized),
+                ("reason=\"denied\"", &udp.dropped_denied),
+                ("reason=\"failed\"", &udp.dropped_failed),
+            ],
+        );
+
+        let mut acl_requests = String::new();
+        let mut acl_bytes = String::new();
+        for (listener, acl) in server.acls() {
+            // the server's own acl goes without the label, as if it was empty
+            let labels = listener.map_or_else(String::new, |name| {
+                format!("listener=\"{}\",", escape_label(name))
+            });
+            acl.render_metrics(&mut acl_requests, &mut acl_bytes, &labels);
+        }
+        if !acl_requests.is_empty() {
+            let name = "socks5_acl_requests_total";
+            let _ = writeln!(
+                out,
+                "# HELP {name} requests decided by each acl rule\n\
+                 # TYPE {name} counter\n{acl_requests}"
+            );
+            let name = "socks5_acl_relay_bytes_total";
+            let _ = write!(
+                out,
+                "# HELP {name} payload bytes relayed for requests each acl rule allowed\n\
+                 # TYPE {name} counter\n{acl_bytes}"
+            );
+        }
+        out
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(
+        out,
+        "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+    );
+}
+
+fn counter(out: &mut String, name: &str, help: &str, series: &[(&str, &AtomicU64)]) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+    for (labels, value) in series {
+        let value = value.load(Ordering::Relaxed);
+        let _ = if labels.is_empty() {
+            writeln!(out, "{name} {value}")
+        } else {
+            writeln!(out, "{name}{{{labels}}} {value}")
+        };
+    }
+}
+
+/// Counts what is read from and written to the client's side of a relay that is copied
+/// by someone else, like [`tokio::io::copy_bidirectional`].
+pub(crate) struct Counted<'a, S> {
+    pub(crate) inner: S,
+    pub(crate) relay: &'a ConnectionRelay<'a>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.relay.to_remote().relayed(buf.filled().len() - before);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.relay.to_client().relayed(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn averages_throughput_and_decays_while_stalled() {
+        let start = Instant::now();
+        let mut ewma = Ewma::default();
+        for sec in 1..=60 {
+            ewma.record(1000, start + Duration::from_secs(sec));
+        }
+        let steady = ewma.rate_at(start + Duration::from_secs(60));
+        assert!((steady - 1000.0).abs() < 60.0, "{steady}");
+
+        let stalled = ewma.rate_at(start + Duration::from_secs(90));
+        assert!(stalled < steady / 10.0, "{stalled}");
+        assert_eq!(Ewma::default().rate_at(start), 0.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn publishes_once_an_interval_and_when_dropped() {
+        let top_talkers = TopTalkers::new(&crate::config::TopTalkersConfig {
+            window_secs: 60,
+            top: 1,
+        })
+        .unwrap();
+        let totals = RelayMetrics::default();
+        let client = [192, 0, 2, 1].into();
+        let destination = "example.com:443".to_owned();
+        let progress = Arc::new(watch::Sender::default());
+        let relayed = ConnectionRelay::new(&totals, Arc::clone(&progress)).with_top_talkers(
+            &top_talkers,
+            client,
+            destination,
+        );
+        let published = || {
+            let progress = *progress.borrow();
+            (progress.to_remote, progress.to_client)
+        };
+        let reported = |bytes: u64| {
+            format!(
+                "top clients, last 60s:\n192.0.2.1 {bytes} bytes\n\
+                 top destinations, last 60s:\nexample.com:443 {bytes} bytes\n"
+            )
+        };
+
+        // the first bytes are published right away, those after them once an interval passed
+        relayed.to_remote().relayed(100);
+        relayed.to_remote().relayed(150);
+        relayed.to_client().relayed(200);
+        assert_eq!(published(), (100, 200));
+        assert_eq!(top_talkers.report(), reported(100));
+        tokio::time::advance(PROGRESS_INTERVAL).await;
+        relayed.to_remote().relayed(300);
+        assert_eq!(published(), (550, 200));
+        assert_eq!(top_talkers.report(), reported(100));
+        tokio::time::advance(TALKER_FLUSH_INTERVAL).await;
+        relayed.to_client().relayed(400);
+        assert_eq!(top_talkers.report(), reported(1150));
+
+        relayed.to_remote().relayed(50);
+        relayed.to_client().relayed(60);
+        drop(relayed);
+        assert_eq!(published(), (600, 660));
+        assert_eq!(top_talkers.report(), reported(1260));
+        assert_eq!(totals.bytes_to_remote.load(Ordering::Relaxed), 600);
+    }
+}
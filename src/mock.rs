@@ -0,0 +1,251 @@
+//! A scriptable socks5 server for testing client integrations against, behind the
+//! `test-util` feature. It serves one connection at a time the way a [`MockScript`] says,
+//! and hands back what the client sent for the test to assert on.
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::proto::{
+    self, AuthMethod, ClientConnectionRequest, ClientGreeting, ServerResponse, ServerStatus,
+    UserPassRequest,
+};
+
+/// How the mock answers each step of the handshake. The default accepts `NoAuth` and grants
+/// the request without sending anything.
+#[derive(Debug, Clone)]
+pub struct MockScript {
+    /// the method answered to the client's greeting, whether or not it was offered. `None`
+    /// answers that none of the offered methods is acceptable, ending the session.
+    pub auth_method: Option<AuthMethod>,
+    /// whether credentials are accepted when `auth_method` is `UserPass`
+    pub accept_credentials: bool,
+    pub reply: ServerStatus,
+    pub bound_address: proto::Address,
+    pub bound_port: u16,
+    /// sent after a granted reply, as if by the destination, before the mock stops sending
+    pub payload: Vec<u8>,
+}
+
+impl Default for MockScript {
+    fn default() -> Self {
+        Self {
+            auth_method: Some(AuthMethod::NoAuth),
+            accept_credentials: true,
+            reply: ServerStatus::RequestGranted,
+            bound_address: proto::Address::Ipv4(Ipv4Addr::UNSPECIFIED),
+            bound_port: 0,
+            payload: Vec::new(),
+        }
+    }
+}
+
+/// What the client sent over one connection, as far as the session got.
+#[derive(Debug, Default)]
+pub struct MockSession {
+    pub offered_methods: Vec<AuthMethod>,
+    pub credentials: Option<UserPassRequest>,
+    pub request: Option<ClientConnectionRequest>,
+    /// everything the client sent after a granted reply, until it closed its side
+    pub received: Vec<u8>,
+}
+
+pub struct MockServer {
+    listener: TcpListener,
+}
+
+impl MockServer {
+    /// Listens on an ephemeral port on localhost.
+    pub async fn bind() -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts the next connection and plays `script` on it. Ends once the client closed
+    /// the connection or the script ended the session.
+    pub async fn serve_one(&self, script: &MockScript) -> io::Result<MockSession> {
+        let (mut stream, _) = self.listener.accept().await?;
+        let mut session = MockSession {
+            offered_methods: ClientGreeting::read_from_stream(&mut stream).await?.0,
+            ..MockSession::default()
+        };
+
+        let Some(method) = script.auth_method else {
+            stream.write_all(&[proto::SOCKS_VERSION, 0xff]).await?;
+            return Ok(session);
+        };
+        stream
+            .write_all(&[proto::SOCKS_VERSION, method.into()])
+            .await?;
+        if method == AuthMethod::UserPass {
+            let credentials = UserPassRequest::read_from_stream(&mut stream).await?;
+            session.credentials = Some(credentials);
+            let status = if script.accept_credentials { 0 } else { 1 };
+            stream
+                .write_all(&[proto::USER_PASS_VERSION, status])
+                .await?;
+            if !script.accept_credentials {
+                return Ok(session);
+            }
+        }
+
+        session.request = Some(ClientConnectionRequest::read_from_stream(&mut stream).await?);
+        let reply = ServerResponse {
+            status: script.reply,
+            bound_address: script.bound_address.clone(),
+            bound_port: script.bound_port,
+        };
+        stream.write_all(&reply.as_bytes()).await?;
+        if script.reply != ServerStatus::RequestGranted {
+            return Ok(session);
+        }
+
+        stream.write_all(&script.payload).await?;
+        stream.shutdown().await?;
+        stream.read_to_end(&mut session.received).await?;
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use super::*;
+    use crate::tcp_sock_stream::{
+        self, ConnectError, ConnectRequest, CredentialProvider, Credentials, DnsMode,
+    };
+
+    fn request(proxy_addr: SocketAddr, credentials: Option<Credentials>) -> ConnectRequest {
+        ConnectRequest {
+            server_addr: proxy_addr.to_string(),
+            dest_addr: "example.com".to_owned(),
+            dest_port: 443,
+            supported_auth_methods: vec![AuthMethod::NoAuth, AuthMethod::UserPass],
+            credentials,
+            credential_provider: None,
+            dns: DnsMode::Remote,
+        }
+    }
+
+    #[tokio::test]
+    async fn plays_the_script_and_records_the_session() {
+        let mock = MockServer::bind().await.unwrap();
+        let proxy_addr = mock.local_addr().unwrap();
+        let script = MockScript {
+            auth_method: Some(AuthMethod::UserPass),
+            payload: b"hello".to_vec(),
+            ..MockScript::default()
+        };
+        let serving = tokio::spawn(async move { mock.serve_one(&script).await });
+
+        let greeting = tokio::task::spawn_blocking(move || {
+            let credentials = Credentials {
+                username: "alice".to_owned(),
+                password: "secret".to_owned(),
+            };
+            let mut conn =
+                tcp_sock_stream::connect(request(proxy_addr, Some(credentials))).unwrap();
+            conn.write_all(b"ping").unwrap();
+            conn.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut greeting = String::new();
+            conn.read_to_string(&mut greeting).unwrap();
+            greeting
+        })
+        .await
+        .unwrap();
+        assert_eq!(greeting, "hello");
+
+        let session = serving.await.unwrap().unwrap();
+        assert_eq!(
+            session.offered_methods,
+            [AuthMethod::NoAuth, AuthMethod::UserPass]
+        );
+        assert_eq!(session.credentials.unwrap().username, "alice");
+        let request = session.request.unwrap();
+        assert_eq!(
+            request.dest_addr,
+            proto::Address::DomainName("example.com".to_owned())
+        );
+        assert_eq!(request.dest_port, 443);
+        assert_eq!(session.received, b"ping");
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_with_the_scripted_reply() {
+        let mock = MockServer::bind().await.unwrap();
+        let proxy_addr = mock.local_addr().unwrap();
+        let script = MockScript {
+            reply: ServerStatus::ConnectionNotAllowedByRuleset,
+            ..MockScript::default()
+        };
+        let serving = tokio::spawn(async move { mock.serve_one(&script).await });
+
+        let res = tokio::task::spawn_blocking(move || {
+            tcp_sock_stream::connect(request(proxy_addr, None)).map(drop)
+        })
+        .await
+        .unwrap();
+        assert!(matches!(
+            res,
+            Err(ConnectError::Destination(
+                ServerStatus::ConnectionNotAllowedByRuleset
+            ))
+        ));
+        assert!(serving.await.unwrap().unwrap().received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn asks_the_credential_provider_only_when_needed() {
+        let asked = Arc::new(AtomicUsize::new(0));
+        let provider = {
+            let asked = Arc::clone(&asked);
+            move |proxy: &str| {
+                asked.fetch_add(1, Ordering::Relaxed);
+                Ok(Credentials {
+                    username: format!("user-for-{proxy}"),
+                    password: "secret".to_owned(),
+                })
+            }
+        };
+        let provided = Some(Arc::new(provider) as Arc<dyn CredentialProvider>);
+
+        for auth_method in [AuthMethod::NoAuth, AuthMethod::UserPass] {
+            let mock = MockServer::bind().await.unwrap();
+            let proxy_addr = mock.local_addr().unwrap();
+            let script = MockScript {
+                auth_method: Some(auth_method),
+                ..MockScript::default()
+            };
+            let serving = tokio::spawn(async move { mock.serve_one(&script).await });
+            let req = ConnectRequest {
+                credential_provider: provided.clone(),
+                ..request(proxy_addr, None)
+            };
+            tokio::task::spawn_blocking(move || tcp_sock_stream::connect(req).map(drop))
+                .await
+                .unwrap()
+                .unwrap();
+            let session = serving.await.unwrap().unwrap();
+            if auth_method == AuthMethod::UserPass {
+                let username = session.credentials.unwrap().username;
+                assert_eq!(username, format!("user-for-{proxy_addr}"));
+            }
+        }
+        assert_eq!(asked.load(Ordering::Relaxed), 1);
+    }
+}
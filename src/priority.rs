@@ -0,0 +1,255 @@
+//! Weighted bandwidth scheduling between classes of relayed connections.
+//!
+//! Every class is guaranteed a share of the configured bandwidth proportional to its weight.
+//! Bandwidth a class leaves unused spills into a spare pool that any busy class may draw
+//! from, so a lone bulk transfer still gets the whole link while interactive sessions in a
+//! higher weighted class are never starved by it. Scheduled connections are relayed through
+//! userspace, since every chunk has to be admitted before it is written.
+
+use std::{
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    time::sleep,
+};
+
+use crate::{
+    config::{PriorityClassConfig, PriorityConfig},
+    metrics::{ConnectionRelay, RelayCounter},
+    proto,
+};
+
+// small enough that a bulk transfer can't hold the link for long with a single write
+const CHUNK: usize = 16 << 10;
+// keeps contended classes from trickling out tiny writes as single tokens become available
+const MIN_GRANT: f64 = 1500.0;
+
+pub struct Scheduler {
+    classes: Vec<PriorityClassConfig>,
+    /// bytes per second guaranteed to each class
+    rates: Vec<f64>,
+    bandwidth: f64,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    refilled: Instant,
+    tokens: Vec<f64>,
+    spare: f64,
+}
+
+/// A connection's membership in one of the scheduler's classes.
+#[derive(Clone, Copy)]
+pub struct Class<'a> {
+    scheduler: &'a Scheduler,
+    index: usize,
+}
+
+impl Scheduler {
+    pub fn new(config: PriorityConfig) -> io::Result<Self> {
+        if let Some(class) = config.classes.iter().find(|c| c.weight == 0) {
+            // a class without a share would wait forever for its first byte
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("priority class {:?} needs a non-zero weight", class.name),
+            ));
+        }
+        let total_weight: u64 = config.classes.iter().map(|c| c.weight as u64).sum();
+        if config.bandwidth == 0 || total_weight == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "priority scheduling needs a non-zero bandwidth and at least one weighted class",
+            ));
+        }
+
+        let bandwidth = config.bandwidth as f64;
+        let rates: Vec<f64> = config
+            .classes
+            .iter()
+            .map(|c| bandwidth * c.weight as f64 / total_weight as f64)
+            .collect();
+        let buckets = Buckets {
+            refilled: Instant::now(),
+            tokens: rates.clone(),
+            spare: 0.0,
+        };
+        Ok(Self {
+            classes: config.classes,
+            rates,
+            bandwidth,
+            buckets: Mutex::new(buckets),
+        })
+    }
+
+    /// Returns the first class matching the connection, if any. Connections matching no class
+    /// are not scheduled.
+    pub fn classify(
+        &self,
+        client: SocketAddr,
+        request: &proto::ClientConnectionRequest,
+    ) -> Option<Class<'_>> {
+        let dest = request.dest_addr.to_string();
+        let dest_with_port = format!("{dest}:{}", request.dest_port);
+        self.classes
+            .iter()
+            .position(|class| {
+                let any = class.clients.is_empty() && class.destinations.is_empty();
+                any || class.clients.contains(&client.ip())
+                    || class
+                        .destinations
+                        .iter()
+                        .any(|d| *d == dest || *d == dest_with_port)
+            })
+            .map(|index| Class {
+                scheduler: self,
+                index,
+            })
+    }
+
+    /// Admits up to `want` bytes for the class, or returns how long to wait before asking
+    /// again.
+    fn take(&self, class: usize, want: usize) -> Result<usize, Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        self.refill(&mut buckets);
+
+        let available = buckets.tokens[class] + buckets.spare;
+        let needed = (want as f64).min(MIN_GRANT);
+        if available < needed {
+            let wait = (needed - available) / self.rates[class];
+            return Err(Duration::from_secs_f64(wait));
+        }
+
+        let granted = (want as f64).min(available.floor());
+        let from_own = granted.min(buckets.tokens[class]);
+        buckets.tokens[class] -= from_own;
+        buckets.spare -= granted - from_own;
+        Ok(granted as usize)
+    }
+
+    fn refill(&self, buckets: &mut Buckets) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(buckets.refilled).as_secs_f64();
+        buckets.refilled = now;
+
+        // each class holds at most a second worth of its share, the rest goes to the spare pool
+        let mut overflow = 0.0;
+        for (tokens, &rate) in buckets.tokens.iter_mut().zip(&self.rates) {
+            *tokens += rate * elapsed;
+            if *tokens > rate {
+                overflow += *tokens - rate;
+                *tokens = rate;
+            }
+        }
+        buckets.spare = (buckets.spare + overflow).min(self.bandwidth);
+    }
+}
+
+impl Class<'_> {
+    pub fn name(&self) -> &str {
+        &self.scheduler.classes[self.index].name
+    }
+
+    async fn admit(&self, want: usize) -> usize {
+        loop {
+            match self.scheduler.take(self.index, want) {
+                Ok(granted) => return granted,
+                Err(wait) => sleep(wait).await,
+            }
+        }
+    }
+
+    pub(crate) async fn relay(
+        &self,
+        client: TcpStream,
+        remote: TcpStream,
+        relayed: &ConnectionRelay<'_>,
+    ) -> io::Result<()> {
+        let (client_read, client_write) = client.into_split();
+        let (remote_read, remote_write) = remote.into_split();
+        tokio::try_join!(
+            self.copy(client_read, remote_write, relayed.to_remote()),
+            self.copy(remote_read, client_write, relayed.to_client()),
+        )
+        .map(|_| ())
+    }
+
+    async fn copy(
+        &self,
+        mut reader: OwnedReadHalf,
+        mut writer: OwnedWriteHalf,
+        relayed: RelayCounter<'_>,
+    ) -> io::Result<()> {
+        let mut buf = vec![0_u8; CHUNK];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return match writer.shutdown().await {
+                    Err(err) if err.kind() == io::ErrorKind::NotConnected => Ok(()),
+                    res => res,
+                };
+            }
+            let mut written = 0;
+            while written < n {
+                let granted = self.admit(n - written).await;
+                writer.write_all(&buf[written..written + granted]).await?;
+                relayed.relayed(granted);
+                written += granted;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(weights: &[u32]) -> PriorityConfig {
+        PriorityConfig {
+            bandwidth: 10_000,
+            classes: weights
+                .iter()
+                .enumerate()
+                .map(|(i, &weight)| PriorityClassConfig {
+                    name: format!("class-{i}"),
+                    weight,
+                    clients: Vec::new(),
+                    destinations: Vec::new(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn rejects_zero_weights() {
+        let err = Scheduler::new(config(&[3, 0])).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(Scheduler::new(config(&[])).is_err());
+        assert!(Scheduler::new(PriorityConfig {
+            bandwidth: 0,
+            ..config(&[1])
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn splits_bandwidth_by_weight() {
+        let scheduler = Scheduler::new(config(&[3, 1])).unwrap();
+        assert_eq!(scheduler.rates, [7500.0, 2500.0]);
+
+        // each class starts out with a second worth of its share
+        assert_eq!(scheduler.take(0, 8000), Ok(7500));
+        let wait = scheduler.take(0, 2000).unwrap_err();
+        assert!(wait > Duration::from_millis(150) && wait <= Duration::from_millis(200));
+        // which the other class's exhaustion leaves untouched
+        assert_eq!(scheduler.take(1, 2500), Ok(2500));
+        assert!(scheduler.take(1, 100).is_err());
+    }
+}
@@ -1,18 +1,39 @@
 use std::{
-    io,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     str::FromStr,
 };
 
+mod partial;
+mod wire;
+
+pub(crate) use partial::{missing, Framed};
+
+use crate::redact::Redacted;
+
 pub const SOCKS_VERSION: u8 = 0x05;
 pub const RESERVED: u8 = 0x00;
+pub const USER_PASS_VERSION: u8 = 0x01;
 pub const EMPTY_ADDRESS: Address = Address::Ipv4(Ipv4Addr::new(0, 0, 0, 0));
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum AuthMethod {
-    NoAuth = 0x00,
-    GssApi = 0x01,
-    UserPass = 0x02,
+    NoAuth,
+    GssApi,
+    UserPass,
+    /// a method in the range reserved for private use, 0x80 to 0xfe
+    Private(u8),
+}
+
+impl From<AuthMethod> for u8 {
+    fn from(method: AuthMethod) -> Self {
+        match method {
+            AuthMethod::NoAuth => 0x00,
+            AuthMethod::GssApi => 0x01,
+            AuthMethod::UserPass => 0x02,
+            AuthMethod::Private(method) => method,
+        }
+    }
 }
 
 impl TryFrom<u8> for AuthMethod {
@@ -23,6 +44,7 @@ impl TryFrom<u8> for AuthMethod {
             0x00 => Ok(Self::NoAuth),
             0x01 => Ok(Self::GssApi),
             0x02 => Ok(Self::UserPass),
+            0x80..=0xfe => Ok(Self::Private(value)),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 format!("unxpected AuthMethod: {value}"),
@@ -37,7 +59,28 @@ pub struct ClientGreeting(pub Vec<AuthMethod>);
 #[derive(Debug)]
 pub struct ServerAuthChoice(pub AuthMethod);
 
+/// RFC 1929 username/password sub-negotiation request.
+pub struct UserPassRequest {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for UserPassRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserPassRequest")
+            .field("username", &Redacted)
+            .field("password", &Redacted)
+            .finish()
+    }
+}
+
+/// RFC 1929 sub-negotiation reply. A `status` of 0 means success, anything else failure.
 #[derive(Debug)]
+pub struct UserPassResponse {
+    pub status: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Address {
     Ipv4(Ipv4Addr),
     DomainName(String),
@@ -47,35 +90,88 @@ pub enum Address {
 impl Address {
     pub fn as_bytes(&self) -> Vec<u8> {
         let mut buf = Vec::new();
-        match self {
-            Address::Ipv4(addr) => {
-                buf.push(0x01);
-                buf.extend_from_slice(&addr.octets());
-            }
-            Address::DomainName(dn) => {
-                buf.push(0x03);
-                buf.push(dn.len() as u8);
-                buf.extend_from_slice(dn.as_bytes());
-            }
-            Address::Ipv6(addr) => {
-                buf.push(0x04);
-                buf.extend_from_slice(&addr.octets());
-            }
-        }
+        self.encode(&mut buf);
         buf
     }
 }
+/// Parses an ip literal, with IPv6 ones optionally in brackets as in urls, and takes
+/// anything else for a domain name. Internationalized domain names are converted to their
+/// punycode form, which is what proxies and resolvers expect.
 impl FromStr for Address {
     type Err = io::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.parse::<std::net::SocketAddr>() {
-            Ok(std::net::SocketAddr::V4(v4)) => Ok(Address::Ipv4(*v4.ip())),
-            Ok(std::net::SocketAddr::V6(v6)) => Ok(Address::Ipv6(*v6.ip())),
+        let unbracketed = s.strip_prefix('[').and_then(|rest| rest.strip_suffix(']'));
+        if let Some(ip) = unbracketed {
+            return ip.parse().map(Address::Ipv6).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{s:?} is not an IPv6 address"),
+                )
+            });
+        }
+        match s.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => Ok(Address::Ipv4(ip)),
+            Ok(IpAddr::V6(ip)) => Ok(Address::Ipv6(ip)),
             // must be a domain name
-            Err(_) => Ok(Address::DomainName(s.to_owned())),
+            Err(_) if s.is_ascii() => Ok(Address::DomainName(s.to_owned())),
+            Err(_) => idna::domain_to_ascii(s)
+                .map(Address::DomainName)
+                .map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{s:?} is not a valid domain name: {err}"),
+                    )
+                }),
+        }
+    }
+}
+
+/// Splits `host[:port]` into the host and the port, if there is one. IPv6 hosts are
+/// unbracketed, and taken to have no port when not in brackets.
+pub fn split_host_port(s: &str) -> io::Result<(&str, Option<u16>)> {
+    let invalid =
+        |reason: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{reason} in {s:?}"));
+    let (host, port) = match s.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| invalid("unterminated IPv6 address"))?;
+            match rest {
+                "" => (host, None),
+                _ => (
+                    host,
+                    Some(
+                        rest.strip_prefix(':')
+                            .ok_or_else(|| invalid("junk after host"))?,
+                    ),
+                ),
+            }
         }
+        None => match s.split_once(':') {
+            Some((host, port)) if !port.contains(':') => (host, Some(port)),
+            _ => (s, None),
+        },
+    };
+    if host.is_empty() {
+        return Err(invalid("missing host"));
     }
+    let port = port
+        .map(|port| port.parse().map_err(|_| invalid("invalid port")))
+        .transpose()?;
+    Ok((host, port))
+}
+
+/// Parses `host:port` into a destination, as given on command lines and in configs.
+pub fn parse_host_port(s: &str) -> io::Result<(Address, u16)> {
+    let (host, port) = split_host_port(s)?;
+    let port = port.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("missing port in {s:?}"),
+        )
+    })?;
+    Ok((host.parse()?, port))
 }
 
 impl From<SocketAddr> for Address {
@@ -87,12 +183,12 @@ impl From<SocketAddr> for Address {
     }
 }
 
-impl Address {
-    pub fn to_string(&self) -> String {
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Address::Ipv4(addr) => addr.to_string(),
-            Address::DomainName(dn) => dn.to_owned(),
-            Address::Ipv6(addr) => addr.to_string(),
+            Address::Ipv4(addr) => addr.fmt(f),
+            Address::DomainName(dn) => dn.fmt(f),
+            Address::Ipv6(addr) => addr.fmt(f),
         }
     }
 }
@@ -114,18 +210,55 @@ impl TryFrom<u8> for ClientCommand {
             0x03 => Ok(Self::AssociateUdpPort),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                format!(
-                    "error parsing client command: got: {}, expected one of: {}, {}, {}",
-                    value,
-                    Self::EstablishConnection as u8,
-                    Self::EstablishPortBinding as u8,
-                    Self::AssociateUdpPort as u8,
-                ),
+                Undefined::Command(value),
             )),
         }
     }
 }
 
+/// A request field holding a value the protocol doesn't define, which the server answers with
+/// a reply status of its own rather than a general failure.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Undefined {
+    Command(u8),
+    AddressType(u8),
+}
+
+impl Undefined {
+    /// The field `err` failed on, if it is one of them.
+    pub fn of(err: &io::Error) -> Option<Self> {
+        err.get_ref()?.downcast_ref().copied()
+    }
+
+    /// The status replying to a request holding the value.
+    pub fn status(self) -> ServerStatus {
+        match self {
+            Undefined::Command(_) => ServerStatus::CommandNotSupported,
+            Undefined::AddressType(_) => ServerStatus::AddressTypeNotSupported,
+        }
+    }
+}
+
+impl fmt::Display for Undefined {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Undefined::Command(value) => write!(
+                f,
+                "error parsing client command: got: {value}, expected one of: {}, {}, {}",
+                ClientCommand::EstablishConnection as u8,
+                ClientCommand::EstablishPortBinding as u8,
+                ClientCommand::AssociateUdpPort as u8,
+            ),
+            Undefined::AddressType(atyp) => write!(
+                f,
+                "proto: failed to parse address. expected 0x01, 0x03, 0x04: got: {atyp}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Undefined {}
+
 #[derive(Debug)]
 pub struct ClientConnectionRequest {
     pub cmd: ClientCommand,
@@ -133,7 +266,7 @@ pub struct ClientConnectionRequest {
     pub dest_port: u16,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ServerStatus {
     RequestGranted = 0x00,
     GeneralFailure = 0x01,
@@ -178,12 +311,85 @@ pub struct ServerResponse {
 impl ServerResponse {
     pub fn as_bytes(&self) -> Vec<u8> {
         let mut buf = Vec::new();
-        buf.push(SOCKS_VERSION);
-        buf.push(self.status as u8);
-        buf.push(RESERVED);
-        buf.extend_from_slice(&self.bound_address.as_bytes());
-        buf.extend_from_slice(&self.bound_port.to_be_bytes());
+        self.encode(&mut buf);
+        buf
+    }
+}
 
+/// The RFC 1928 header in front of every datagram relayed through a UDP association.
+#[derive(Debug)]
+pub struct UdpHeader {
+    /// fragment number, 0 for standalone datagrams
+    pub frag: u8,
+    pub dest_addr: Address,
+    pub dest_port: u16,
+}
+
+impl UdpHeader {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
         buf
     }
+
+    /// Splits a datagram into its header and payload.
+    pub fn parse(datagram: &[u8]) -> io::Result<(Self, &[u8])> {
+        let mut payload = datagram;
+        let header = Self::decode(&mut payload)?;
+        Ok((header, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ip_literals_and_domain_names() {
+        let parse = |s: &str| s.parse::<Address>().ok();
+        assert_eq!(
+            parse("192.0.2.1"),
+            Some(Address::Ipv4([192, 0, 2, 1].into()))
+        );
+        assert_eq!(parse("::1"), Some(Address::Ipv6(Ipv6Addr::LOCALHOST)));
+        assert_eq!(parse("[::1]"), Some(Address::Ipv6(Ipv6Addr::LOCALHOST)));
+        assert_eq!(parse("[192.0.2.1]"), None);
+        assert_eq!(
+            parse("example.com"),
+            Some(Address::DomainName("example.com".to_owned()))
+        );
+        assert_eq!(
+            parse("bücher.example"),
+            Some(Address::DomainName("xn--bcher-kva.example".to_owned()))
+        );
+    }
+
+    #[test]
+    fn parses_host_port_pairs() {
+        let parse = |s: &str| parse_host_port(s).ok();
+        assert_eq!(
+            parse("192.0.2.1:1080"),
+            Some((Address::Ipv4([192, 0, 2, 1].into()), 1080))
+        );
+        assert_eq!(
+            parse("[::1]:1080"),
+            Some((Address::Ipv6(Ipv6Addr::LOCALHOST), 1080))
+        );
+        assert_eq!(
+            parse("example.com:443"),
+            Some((Address::DomainName("example.com".to_owned()), 443))
+        );
+        for bad in [
+            "::1",
+            "[::1]",
+            "example.com",
+            ":80",
+            "[::1:80",
+            "[::1]80",
+            "a:b",
+        ] {
+            assert_eq!(parse(bad), None, "{bad}");
+        }
+        assert_eq!(split_host_port("::1").unwrap(), ("::1", None));
+    }
 }
@@ -0,0 +1,282 @@
+//! Decoding messages from buffers that may hold only their start, as they arrive from a
+//! socket read of whatever size. The length fields of a message are walked as far as the
+//! buffer goes, which tells whether it is complete, and if not, how many bytes it needs at
+//! least. Malformed messages fail as soon as the offending byte is in the buffer.
+
+use tokio::io;
+
+use super::{
+    wire::{expect_version, unknown_address_type},
+    *,
+};
+
+/// A message of the handshake, which can be told apart from what follows it by its length
+/// fields alone.
+pub(crate) trait Framed: Sized {
+    /// The most bytes the message can take.
+    const MAX_LEN: usize;
+
+    /// Steps over the message in `scan`.
+    fn scan(scan: &mut Scan) -> Result<(), Scanned>;
+
+    /// Decodes the message from a buffer holding all of it.
+    fn decode_complete(src: &mut &[u8]) -> io::Result<Self>;
+}
+
+/// How far walking a message's length fields got.
+pub(crate) enum Scanned {
+    /// the buffer ends this many bytes short of the next field, at least
+    Missing(usize),
+    Invalid(io::Error),
+}
+
+impl From<io::Error> for Scanned {
+    fn from(err: io::Error) -> Self {
+        Self::Invalid(err)
+    }
+}
+
+pub(crate) struct Scan<'a> {
+    buf: &'a [u8],
+    /// how many bytes of `buf` were stepped over
+    len: usize,
+}
+
+impl Scan<'_> {
+    fn byte(&mut self) -> Result<u8, Scanned> {
+        let byte = *self.buf.get(self.len).ok_or(Scanned::Missing(1))?;
+        self.len += 1;
+        Ok(byte)
+    }
+
+    fn skip(&mut self, n: usize) -> Result<(), Scanned> {
+        let available = self.buf.len() - self.len;
+        if available < n {
+            return Err(Scanned::Missing(n - available));
+        }
+        self.len += n;
+        Ok(())
+    }
+
+    fn address(&mut self) -> Result<(), Scanned> {
+        match self.byte()? {
+            0x01 => self.skip(4),
+            0x03 => {
+                let len = self.byte()?;
+                self.skip(len.into())
+            }
+            0x04 => self.skip(16),
+            other => Err(unknown_address_type(other).into()),
+        }
+    }
+}
+
+/// How many more bytes the message at the start of `buf` needs at least, or `None` if it is
+/// complete.
+pub(crate) fn missing<M: Framed>(buf: &[u8]) -> io::Result<Option<usize>> {
+    match M::scan(&mut Scan { buf, len: 0 }) {
+        Ok(()) => Ok(None),
+        Err(Scanned::Missing(n)) => Ok(Some(n)),
+        Err(Scanned::Invalid(err)) => Err(err),
+    }
+}
+
+fn decode_partial<M: Framed>(buf: &[u8]) -> io::Result<Option<(M, usize)>> {
+    let mut scan = Scan { buf, len: 0 };
+    match M::scan(&mut scan) {
+        Ok(()) => M::decode_complete(&mut &buf[..scan.len]).map(|msg| Some((msg, scan.len))),
+        Err(Scanned::Missing(_)) => Ok(None),
+        Err(Scanned::Invalid(err)) => Err(err),
+    }
+}
+
+impl Framed for ClientGreeting {
+    const MAX_LEN: usize = 2 + 255;
+
+    fn scan(scan: &mut Scan) -> Result<(), Scanned> {
+        expect_version(SOCKS_VERSION, scan.byte()?)?;
+        let n = scan.byte()?;
+        scan.skip(n.into())
+    }
+
+    fn decode_complete(src: &mut &[u8]) -> io::Result<Self> {
+        Self::decode(src)
+    }
+}
+
+impl Framed for ServerAuthChoice {
+    const MAX_LEN: usize = 2;
+
+    fn scan(scan: &mut Scan) -> Result<(), Scanned> {
+        expect_version(SOCKS_VERSION, scan.byte()?)?;
+        scan.skip(1)
+    }
+
+    fn decode_complete(src: &mut &[u8]) -> io::Result<Self> {
+        Self::decode(src)
+    }
+}
+
+impl Framed for UserPassRequest {
+    const MAX_LEN: usize = 3 + 2 * 255;
+
+    fn scan(scan: &mut Scan) -> Result<(), Scanned> {
+        expect_version(USER_PASS_VERSION, scan.byte()?)?;
+        let username_len = scan.byte()?;
+        scan.skip(username_len.into())?;
+        let password_len = scan.byte()?;
+        scan.skip(password_len.into())
+    }
+
+    fn decode_complete(src: &mut &[u8]) -> io::Result<Self> {
+        Self::decode(src)
+    }
+}
+
+impl Framed for UserPassResponse {
+    const MAX_LEN: usize = 2;
+
+    fn scan(scan: &mut Scan) -> Result<(), Scanned> {
+        expect_version(USER_PASS_VERSION, scan.byte()?)?;
+        scan.skip(1)
+    }
+
+    fn decode_complete(src: &mut &[u8]) -> io::Result<Self> {
+        Self::decode(src)
+    }
+}
+
+impl Framed for Address {
+    const MAX_LEN: usize = 2 + 255;
+
+    fn scan(scan: &mut Scan) -> Result<(), Scanned> {
+        scan.address()
+    }
+
+    fn decode_complete(src: &mut &[u8]) -> io::Result<Self> {
+        Self::decode(src)
+    }
+}
+
+impl Framed for ClientConnectionRequest {
+    const MAX_LEN: usize = 3 + Address::MAX_LEN + 2;
+
+    fn scan(scan: &mut Scan) -> Result<(), Scanned> {
+        expect_version(SOCKS_VERSION, scan.byte()?)?;
+        scan.skip(2)?;
+        scan.address()?;
+        scan.skip(2)
+    }
+
+    fn decode_complete(src: &mut &[u8]) -> io::Result<Self> {
+        Self::decode(src)
+    }
+}
+
+impl Framed for ServerResponse {
+    const MAX_LEN: usize = 3 + Address::MAX_LEN + 2;
+
+    fn scan(scan: &mut Scan) -> Result<(), Scanned> {
+        expect_version(SOCKS_VERSION, scan.byte()?)?;
+        scan.skip(2)?;
+        scan.address()?;
+        scan.skip(2)
+    }
+
+    fn decode_complete(src: &mut &[u8]) -> io::Result<Self> {
+        Self::decode(src)
+    }
+}
+
+impl ClientGreeting {
+    /// Decodes the greeting at the start of `buf`, returning it with the number of bytes it
+    /// took up, or `None` if `buf` doesn't hold all of it yet.
+    pub fn decode_partial(buf: &[u8]) -> io::Result<Option<(Self, usize)>> {
+        decode_partial(buf)
+    }
+}
+
+impl ServerAuthChoice {
+    /// See [`ClientGreeting::decode_partial`].
+    pub fn decode_partial(buf: &[u8]) -> io::Result<Option<(Self, usize)>> {
+        decode_partial(buf)
+    }
+}
+
+impl UserPassRequest {
+    /// See [`ClientGreeting::decode_partial`].
+    pub fn decode_partial(buf: &[u8]) -> io::Result<Option<(Self, usize)>> {
+        decode_partial(buf)
+    }
+}
+
+impl UserPassResponse {
+    /// See [`ClientGreeting::decode_partial`].
+    pub fn decode_partial(buf: &[u8]) -> io::Result<Option<(Self, usize)>> {
+        decode_partial(buf)
+    }
+}
+
+impl Address {
+    /// See [`ClientGreeting::decode_partial`].
+    pub fn decode_partial(buf: &[u8]) -> io::Result<Option<(Self, usize)>> {
+        decode_partial(buf)
+    }
+}
+
+impl ClientConnectionRequest {
+    /// See [`ClientGreeting::decode_partial`].
+    pub fn decode_partial(buf: &[u8]) -> io::Result<Option<(Self, usize)>> {
+        decode_partial(buf)
+    }
+}
+
+impl ServerResponse {
+    /// See [`ClientGreeting::decode_partial`].
+    pub fn decode_partial(buf: &[u8]) -> io::Result<Option<(Self, usize)>> {
+        decode_partial(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_the_whole_message() {
+        let request = b"\x05\x01\x00\x03\x0bexample.com\x01\xbb";
+        for len in 0..request.len() {
+            let partial = ClientConnectionRequest::decode_partial(&request[..len]).unwrap();
+            assert!(partial.is_none(), "decoded from {len} bytes");
+        }
+        assert_eq!(
+            missing::<ClientConnectionRequest>(&request[..4]).unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            missing::<ClientConnectionRequest>(&request[..5]).unwrap(),
+            Some(11)
+        );
+
+        // what follows the message is left alone
+        let mut pipelined = request.to_vec();
+        pipelined.extend_from_slice(b"GET / HTTP/1.1");
+        let (decoded, consumed) = ClientConnectionRequest::decode_partial(&pipelined)
+            .unwrap()
+            .unwrap();
+        assert_eq!(consumed, request.len());
+        assert_eq!(
+            decoded.dest_addr,
+            Address::DomainName("example.com".to_owned())
+        );
+        assert_eq!(decoded.dest_port, 443);
+    }
+
+    #[test]
+    fn fails_as_soon_as_the_message_is_malformed() {
+        let err = ClientGreeting::decode_partial(b"G").unwrap_err();
+        assert_eq!(err.to_string(), "expected socks version: 5, got: 71");
+        let err = ClientConnectionRequest::decode_partial(b"\x05\x01\x00\x07").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,311 @@
+//! The messages in their wire format, encoded into and decoded from in-memory buffers. The
+//! decoders take complete messages: a buffer ending early fails as truncated. Decoding from
+//! a [`bytes::Bytes`] leaves what follows the message in it without copying, which is how
+//! the payload of a udp datagram is split from its header.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use bytes::{Buf, BufMut};
+use tokio::io;
+
+use super::*;
+
+impl ClientGreeting {
+    pub fn encode(&self, dst: &mut impl BufMut) {
+        dst.put_u8(SOCKS_VERSION);
+        dst.put_u8(self.0.len() as u8);
+        for &method in &self.0 {
+            dst.put_u8(method.into());
+        }
+    }
+
+    pub fn decode(src: &mut impl Buf) -> io::Result<Self> {
+        expect_version(SOCKS_VERSION, get_u8(src, "greeting")?)?;
+        let n = get_u8(src, "greeting")?;
+        ensure(src, n.into(), "greeting")?;
+        (0..n)
+            .map(|_| AuthMethod::try_from(src.get_u8()))
+            .collect::<io::Result<_>>()
+            .map(Self)
+    }
+}
+
+impl ServerAuthChoice {
+    pub fn encode(&self, dst: &mut impl BufMut) {
+        dst.put_u8(SOCKS_VERSION);
+        dst.put_u8(self.0.into());
+    }
+
+    pub fn decode(src: &mut impl Buf) -> io::Result<Self> {
+        expect_version(SOCKS_VERSION, get_u8(src, "auth choice")?)?;
+        AuthMethod::try_from(get_u8(src, "auth choice")?).map(Self)
+    }
+}
+
+impl UserPassRequest {
+    pub fn encode(&self, dst: &mut impl BufMut) -> io::Result<()> {
+        let field_len = |field: &str, name: &str| {
+            u8::try_from(field.len()).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{name} is longer than 255 bytes"),
+                )
+            })
+        };
+        let username_len = field_len(&self.username, "username")?;
+        let password_len = field_len(&self.password, "password")?;
+        dst.put_u8(USER_PASS_VERSION);
+        dst.put_u8(username_len);
+        dst.put_slice(self.username.as_bytes());
+        dst.put_u8(password_len);
+        dst.put_slice(self.password.as_bytes());
+        Ok(())
+    }
+
+    pub fn decode(src: &mut impl Buf) -> io::Result<Self> {
+        expect_version(USER_PASS_VERSION, get_u8(src, "username/password request")?)?;
+        let username = get_string(src, "username")?;
+        let password = get_string(src, "password")?;
+        Ok(Self { username, password })
+    }
+}
+
+impl UserPassResponse {
+    pub fn encode(&self, dst: &mut impl BufMut) {
+        dst.put_u8(USER_PASS_VERSION);
+        dst.put_u8(self.status);
+    }
+
+    pub fn decode(src: &mut impl Buf) -> io::Result<Self> {
+        let what = "username/password response";
+        expect_version(USER_PASS_VERSION, get_u8(src, what)?)?;
+        Ok(Self {
+            status: get_u8(src, what)?,
+        })
+    }
+}
+
+impl Address {
+    pub fn encode(&self, dst: &mut impl BufMut) {
+        match self {
+            Address::Ipv4(addr) => {
+                dst.put_u8(0x01);
+                dst.put_slice(&addr.octets());
+            }
+            Address::DomainName(dn) => {
+                dst.put_u8(0x03);
+                dst.put_u8(dn.len() as u8);
+                dst.put_slice(dn.as_bytes());
+            }
+            Address::Ipv6(addr) => {
+                dst.put_u8(0x04);
+                dst.put_slice(&addr.octets());
+            }
+        }
+    }
+
+    pub fn decode(src: &mut impl Buf) -> io::Result<Self> {
+        match get_u8(src, "address")? {
+            0x01 => {
+                ensure(src, 4, "address")?;
+                Ok(Self::Ipv4(Ipv4Addr::from(src.get_u32())))
+            }
+            0x03 => get_string(src, "domain name").map(Self::DomainName),
+            0x04 => {
+                ensure(src, 16, "address")?;
+                Ok(Self::Ipv6(Ipv6Addr::from(src.get_u128())))
+            }
+            other => Err(unknown_address_type(other)),
+        }
+    }
+}
+
+impl ClientConnectionRequest {
+    pub fn encode(&self, dst: &mut impl BufMut) {
+        dst.put_u8(SOCKS_VERSION);
+        dst.put_u8(self.cmd as u8);
+        dst.put_u8(RESERVED);
+        self.dest_addr.encode(dst);
+        dst.put_u16(self.dest_port);
+    }
+
+    pub fn decode(src: &mut impl Buf) -> io::Result<Self> {
+        let what = "request";
+        ensure(src, 3, what)?;
+        expect_version(SOCKS_VERSION, src.get_u8())?;
+        let cmd = src.get_u8();
+        let reserved = src.get_u8();
+        if reserved != RESERVED {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected reserved byte to be: {RESERVED}, got: {reserved}"),
+            ));
+        }
+        let cmd = ClientCommand::try_from(cmd)?;
+        let dest_addr = Address::decode(src)?;
+        let dest_port = get_u16(src, what)?;
+        Ok(Self {
+            cmd,
+            dest_addr,
+            dest_port,
+        })
+    }
+}
+
+impl ServerResponse {
+    pub fn encode(&self, dst: &mut impl BufMut) {
+        dst.put_u8(SOCKS_VERSION);
+        dst.put_u8(self.status as u8);
+        dst.put_u8(RESERVED);
+        self.bound_address.encode(dst);
+        dst.put_u16(self.bound_port);
+    }
+
+    pub fn decode(src: &mut impl Buf) -> io::Result<Self> {
+        let what = "reply";
+        ensure(src, 3, what)?;
+        expect_version(SOCKS_VERSION, src.get_u8())?;
+        let status = ServerStatus::try_from(src.get_u8())?;
+        let reserved = src.get_u8();
+        if reserved != RESERVED {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected RSV byte to be zero, got: {reserved}"),
+            ));
+        }
+        let bound_address = Address::decode(src)?;
+        let bound_port = get_u16(src, what)?;
+        Ok(Self {
+            status,
+            bound_address,
+            bound_port,
+        })
+    }
+}
+
+impl UdpHeader {
+    pub fn encode(&self, dst: &mut impl BufMut) {
+        dst.put_u8(RESERVED);
+        dst.put_u8(RESERVED);
+        dst.put_u8(self.frag);
+        self.dest_addr.encode(dst);
+        dst.put_u16(self.dest_port);
+    }
+
+    /// Decodes the header in front of a datagram, leaving the payload in `src`.
+    pub fn decode(src: &mut impl Buf) -> io::Result<Self> {
+        let what = "udp header";
+        ensure(src, 3, what)?;
+        src.advance(2);
+        let frag = src.get_u8();
+        let dest_addr = Address::decode(src)?;
+        let dest_port = get_u16(src, what)?;
+        Ok(Self {
+            frag,
+            dest_addr,
+            dest_port,
+        })
+    }
+}
+
+/// Fails unless a message of `version` was received.
+pub(crate) fn expect_version(version: u8, got: u8) -> io::Result<()> {
+    if got == version {
+        return Ok(());
+    }
+    let name = if version == USER_PASS_VERSION {
+        "username/password"
+    } else {
+        "socks"
+    };
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("expected {name} version: {version}, got: {got}"),
+    ))
+}
+
+pub(crate) fn unknown_address_type(atyp: u8) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, Undefined::AddressType(atyp))
+}
+
+fn ensure(src: &impl Buf, len: usize, what: &str) -> io::Result<()> {
+    if src.remaining() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("truncated {what}"),
+        ));
+    }
+    Ok(())
+}
+
+fn get_u8(src: &mut impl Buf, what: &str) -> io::Result<u8> {
+    ensure(src, 1, what)?;
+    Ok(src.get_u8())
+}
+
+fn get_u16(src: &mut impl Buf, what: &str) -> io::Result<u16> {
+    ensure(src, 2, what)?;
+    Ok(src.get_u16())
+}
+
+/// A string preceded by its length in a byte, as names and credentials are sent.
+fn get_string(src: &mut impl Buf, what: &str) -> io::Result<String> {
+    let len = get_u8(src, what)?.into();
+    ensure(src, len, what)?;
+    let bytes = src.copy_to_bytes(len);
+    std::str::from_utf8(&bytes)
+        .map(str::to_owned)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+
+    use super::*;
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let mut buf = BytesMut::new();
+        ClientConnectionRequest {
+            cmd: ClientCommand::EstablishConnection,
+            dest_addr: Address::DomainName("example.com".to_owned()),
+            dest_port: 443,
+        }
+        .encode(&mut buf);
+        ServerResponse {
+            status: ServerStatus::HostUnreachable,
+            bound_address: Address::Ipv6(Ipv6Addr::LOCALHOST),
+            bound_port: 1080,
+        }
+        .encode(&mut buf);
+
+        let mut src = buf.freeze();
+        let request = ClientConnectionRequest::decode(&mut src).unwrap();
+        assert_eq!(request.cmd, ClientCommand::EstablishConnection);
+        assert_eq!(
+            request.dest_addr,
+            Address::DomainName("example.com".to_owned())
+        );
+        assert_eq!(request.dest_port, 443);
+        let response = ServerResponse::decode(&mut src).unwrap();
+        assert_eq!(response.status, ServerStatus::HostUnreachable);
+        assert_eq!(response.bound_address, Address::Ipv6(Ipv6Addr::LOCALHOST));
+        assert_eq!(response.bound_port, 1080);
+        assert!(src.is_empty());
+
+        let truncated = ClientGreeting::decode(&mut &[5, 2, 0][..]).unwrap_err();
+        assert_eq!(truncated.to_string(), "truncated greeting");
+    }
+
+    #[test]
+    fn leaves_the_payload_of_a_datagram_in_place() {
+        let datagram = Bytes::from_static(b"\0\0\0\x01\xc0\0\x02\x01\0\x35payload");
+        let mut src = datagram.clone();
+        let header = UdpHeader::decode(&mut src).unwrap();
+        assert_eq!(header.dest_addr, Address::Ipv4([192, 0, 2, 1].into()));
+        assert_eq!(header.dest_port, 53);
+        assert_eq!(src, &b"payload"[..]);
+        assert_eq!(src.as_ptr(), datagram[10..].as_ptr());
+    }
+}
@@ -0,0 +1,535 @@
+//! Socks sessions over QUIC instead of tcp. The client keeps one QUIC connection to the
+//! proxy and opens a bidirectional stream per session, so all its sessions share a single
+//! UDP flow and survive the client moving to another address.
+//!
+//! The server side is [`listen`] plus [`Server::serve_quic`](crate::server::Server::serve_quic),
+//! which runs each stream through the same pipeline as a tcp connection. The client side is
+//! [`QuicClient`] together with [`connect_quic`](crate::tcp_sock_stream::connect_quic).
+//!
+//! With a `client_ca` configured, clients can authenticate with a certificate during the TLS
+//! handshake. Every session of such a client is served as that of the user the certificate
+//! identifies, without a socks auth method of its own.
+
+use std::{
+    fmt, fs,
+    io::{Read, Write},
+    net::{self, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    rustls::{
+        self,
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore,
+    },
+    ClientConfig, Connection, ConnectionError, Endpoint, EndpointConfig, Incoming, RecvStream,
+    SendStream, ServerConfig, TokioRuntime,
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    runtime::{self, Runtime},
+};
+use x509_parser::extensions::GeneralName;
+
+use crate::{
+    config::QuicConfig,
+    server::Server,
+    tcp_server_stream::{self, ClientConn},
+};
+
+/// Reads every certificate in a PEM file.
+pub fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let pem = fs::read(path)?;
+    let certs = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| pem_error(path, err))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: no certificates found", path.display()),
+        ));
+    }
+    Ok(certs)
+}
+
+fn pem_error(path: &Path, err: quinn::rustls::pki_types::pem::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {err}", path.display()),
+    )
+}
+
+/// Starts a server endpoint on `socket` with the certificate, key and client verification
+/// of `config`. The socket is passed in rather than bound from `config.listen_addr` so it can
+/// be inherited across upgrades.
+pub fn listen(socket: net::UdpSocket, config: &QuicConfig) -> io::Result<Endpoint> {
+    server_endpoint(socket, server_config(config)?)
+}
+
+/// Loads what `config` names into the TLS setup of an endpoint.
+pub(crate) fn server_config(config: &QuicConfig) -> io::Result<ServerConfig> {
+    let certs = load_certs(&config.cert_chain)?;
+    let key = load_key(&config.private_key)?;
+    let client_roots = config.client_ca.as_deref().map(load_certs).transpose()?;
+    if config.require_client_cert && client_roots.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "quic: require_client_cert needs a client_ca to verify certificates against",
+        ));
+    }
+    server_crypto(certs, key, client_roots, config.require_client_cert)
+}
+
+/// Reads the first private key in a PEM file.
+pub(crate) fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_slice(&fs::read(path)?).map_err(|err| pem_error(path, err))
+}
+
+/// Clients are asked for a certificate chaining up to one of `client_roots`, if there are
+/// any, and turned away without one if `require_client_cert`.
+fn server_crypto(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    client_roots: Option<Vec<CertificateDer<'static>>>,
+    require_client_cert: bool,
+) -> io::Result<ServerConfig> {
+    let Some(client_roots) = client_roots else {
+        return ServerConfig::with_single_cert(certs, key).map_err(invalid_input);
+    };
+    let provider = Arc::new(ring::default_provider());
+    let roots = Arc::new(root_store(client_roots)?);
+    let verifier = WebPkiClientVerifier::builder_with_provider(roots, Arc::clone(&provider));
+    let verifier = if require_client_cert {
+        verifier
+    } else {
+        verifier.allow_unauthenticated()
+    };
+    let tls = rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(invalid_input)?
+        .with_client_cert_verifier(verifier.build().map_err(invalid_input)?)
+        .with_single_cert(certs, key)
+        .map_err(invalid_input)?;
+    let crypto = QuicServerConfig::try_from(tls).map_err(invalid_input)?;
+    Ok(ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+fn root_store(roots: Vec<CertificateDer<'static>>) -> io::Result<RootCertStore> {
+    let mut root_store = RootCertStore::empty();
+    for root in roots {
+        root_store.add(root).map_err(invalid_input)?;
+    }
+    Ok(root_store)
+}
+
+fn invalid_input(err: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("quic: {err}"))
+}
+
+fn server_endpoint(socket: net::UdpSocket, server_config: ServerConfig) -> io::Result<Endpoint> {
+    Endpoint::new(
+        EndpointConfig::default(),
+        Some(server_config),
+        socket,
+        Arc::new(TokioRuntime),
+    )
+}
+
+/// Accepts a client's connection and serves each stream it opens as a socks session.
+pub(crate) async fn serve_connection(
+    server: &Arc<Server>,
+    incoming: Incoming,
+    local_addr: SocketAddr,
+) -> io::Result<()> {
+    let conn = incoming.await.map_err(connection_error)?;
+    let certified = client_identity(&conn).map(|identity| {
+        server
+            .config()
+            .quic
+            .as_ref()
+            .and_then(|quic| quic.client_users.get(&identity))
+            .cloned()
+            .unwrap_or(identity)
+    });
+    loop {
+        // unaccepted streams count against the client's stream limit, which makes it wait
+        // too
+        server.wait_for_capacity(None).await;
+        let (send, recv) = match conn.accept_bi().await {
+            Ok(stream) => stream,
+            Err(ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed) => {
+                return Ok(())
+            }
+            Err(err) => return Err(connection_error(err)),
+        };
+        // the client's address as of this stream, which may have migrated since the handshake
+        let peer_addr = conn.remote_address();
+        let stream = ClientConn::Wrapped {
+            stream: Box::new(QuicStream { send, recv }),
+            local_addr,
+        };
+        let certified = certified.clone();
+        server.spawn_connection(peer_addr, None, move |server, info| async move {
+            tcp_server_stream::handle_conn(&server, stream, info, certified).await
+        });
+    }
+}
+
+/// The identity of the certificate the client authenticated with, if it did: the first DNS
+/// name or email address among its subject alternative names, or else its common name.
+fn client_identity(conn: &Connection) -> Option<String> {
+    let chain = conn
+        .peer_identity()?
+        .downcast::<Vec<CertificateDer<'static>>>()
+        .ok()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(chain.first()?).ok()?;
+    let alt_name = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .and_then(|ext| {
+            ext.value.general_names.iter().find_map(|name| match name {
+                GeneralName::DNSName(name) | GeneralName::RFC822Name(name) => {
+                    Some(name.to_string())
+                }
+                _ => None,
+            })
+        });
+    alt_name.or_else(|| {
+        let common_name = cert.subject().iter_common_name().next()?;
+        common_name.as_str().ok().map(str::to_owned)
+    })
+}
+
+fn connection_error(err: ConnectionError) -> io::Error {
+    let kind = match err {
+        ConnectionError::TimedOut => io::ErrorKind::TimedOut,
+        ConnectionError::Reset => io::ErrorKind::ConnectionReset,
+        _ => io::ErrorKind::ConnectionAborted,
+    };
+    io::Error::new(kind, format!("quic: {err}"))
+}
+
+/// One bidirectional stream, as seen by the server.
+struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.get_mut().send), cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.get_mut().send), cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_shutdown(Pin::new(&mut self.get_mut().send), cx)
+    }
+}
+
+/// A QUIC connection to the proxy that sessions are opened over. It drives the connection
+/// on a runtime of its own, so it is used from blocking code like the rest of the client,
+/// and must not be used from within an async context.
+pub struct QuicClient {
+    runtime: Arc<Runtime>,
+    endpoint: Endpoint,
+    connection: Connection,
+}
+
+impl QuicClient {
+    /// Connects to the proxy at `server_addr`, which has to present a certificate for
+    /// `server_name` that chains up to one of `roots`.
+    pub fn connect(
+        server_addr: SocketAddr,
+        server_name: &str,
+        roots: Vec<CertificateDer<'static>>,
+    ) -> io::Result<Self> {
+        let client_config = ClientConfig::with_root_certificates(Arc::new(root_store(roots)?))
+            .map_err(invalid_input)?;
+        Self::connect_with(server_addr, server_name, client_config)
+    }
+
+    /// Like [`QuicClient::connect`], authenticating with the certificate `cert_chain`, leaf
+    /// first, whose private key is `key`.
+    pub fn connect_with_client_cert(
+        server_addr: SocketAddr,
+        server_name: &str,
+        roots: Vec<CertificateDer<'static>>,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> io::Result<Self> {
+        let tls = rustls::ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(invalid_input)?
+            .with_root_certificates(root_store(roots)?)
+            .with_client_auth_cert(cert_chain, key)
+            .map_err(invalid_input)?;
+        let crypto = QuicClientConfig::try_from(tls).map_err(invalid_input)?;
+        Self::connect_with(
+            server_addr,
+            server_name,
+            ClientConfig::new(Arc::new(crypto)),
+        )
+    }
+
+    fn connect_with(
+        server_addr: SocketAddr,
+        server_name: &str,
+        client_config: ClientConfig,
+    ) -> io::Result<Self> {
+        // one worker keeps the connection's timers and acknowledgements going between reads
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        let mut endpoint = {
+            let _guard = runtime.enter();
+            Endpoint::client(unspecified_addr(server_addr))?
+        };
+        endpoint.set_default_client_config(client_config);
+        let connecting = endpoint
+            .connect(server_addr, server_name)
+            .map_err(invalid_input)?;
+        let connection = runtime.block_on(connecting).map_err(connection_error)?;
+        Ok(Self {
+            runtime: Arc::new(runtime),
+            endpoint,
+            connection,
+        })
+    }
+
+    /// Opens a stream for a new session.
+    pub fn open(&self) -> io::Result<SyncQuicStream> {
+        let (send, recv) = self
+            .runtime
+            .block_on(self.connection.open_bi())
+            .map_err(connection_error)?;
+        Ok(SyncQuicStream {
+            runtime: Arc::clone(&self.runtime),
+            send,
+            recv,
+        })
+    }
+
+    /// Moves the connection over to a fresh local socket, as after a network change.
+    /// Sessions carry on over the new path.
+    pub fn rebind(&self) -> io::Result<()> {
+        let local_addr = self.endpoint.local_addr()?;
+        let socket = net::UdpSocket::bind(unspecified_addr(local_addr))?;
+        let _guard = self.runtime.enter();
+        self.endpoint.rebind(socket)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+}
+
+fn unspecified_addr(like: SocketAddr) -> SocketAddr {
+    match like {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+/// One session's stream, as seen by the client.
+pub struct SyncQuicStream {
+    runtime: Arc<Runtime>,
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl Read for SyncQuicStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.runtime.block_on(self.recv.read(buf))?;
+        Ok(n.unwrap_or(0))
+    }
+}
+
+impl Write for SyncQuicStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(self.runtime.block_on(self.send.write(buf))?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{
+        config::ServerConfig as SocksServerConfig,
+        proto::AuthMethod,
+        tcp_sock_stream::{connect_quic, ConnectRequest, DnsMode},
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn runs_sessions_over_streams_of_one_connection_across_a_rebind() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert_der = CertificateDer::from(cert.cert);
+        let key = PrivateKeyDer::try_from(cert.key_pair.serialize_der()).unwrap();
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let crypto = server_crypto(vec![cert_der.clone()], key, None, false).unwrap();
+        let endpoint = server_endpoint(socket, crypto).unwrap();
+        let proxy_addr = endpoint.local_addr().unwrap();
+        let server = Arc::new(Server::new(SocksServerConfig::default()).unwrap());
+        tokio::spawn(server.serve_quic(endpoint));
+
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = echo.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0_u8; 4];
+                    conn.read_exact(&mut buf).await.unwrap();
+                    conn.write_all(&buf).await.unwrap();
+                });
+            }
+        });
+
+        tokio::task::spawn_blocking(move || {
+            let client = QuicClient::connect(proxy_addr, "localhost", vec![cert_der]).unwrap();
+            let echo_through = |client: &QuicClient, msg: &[u8; 4]| {
+                let req = ConnectRequest {
+                    server_addr: proxy_addr.to_string(),
+                    dest_addr: "127.0.0.1".to_owned(),
+                    dest_port: echo_port,
+                    supported_auth_methods: vec![AuthMethod::NoAuth],
+                    credentials: None,
+                    credential_provider: None,
+                    dns: DnsMode::Remote,
+                };
+                let (mut conn, _) = connect_quic(req, client).unwrap();
+                conn.write_all(msg).unwrap();
+                let mut buf = [0_u8; 4];
+                conn.read_exact(&mut buf).unwrap();
+                buf
+            };
+            assert_eq!(&echo_through(&client, b"ping"), b"ping");
+
+            let before = client.local_addr().unwrap();
+            client.rebind().unwrap();
+            assert_ne!(client.local_addr().unwrap(), before);
+            assert_eq!(&echo_through(&client, b"pong"), b"pong");
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn authenticates_clients_as_the_user_their_certificate_maps_to() {
+        let server_cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let server_cert_der = CertificateDer::from(server_cert.cert);
+        let server_key = PrivateKeyDer::try_from(server_cert.key_pair.serialize_der()).unwrap();
+        let mut ca_params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let client_key = rcgen::KeyPair::generate().unwrap();
+        let client_cert = rcgen::CertificateParams::new(vec!["laptop.example.com".to_owned()])
+            .unwrap()
+            .signed_by(&client_key, &ca, &ca_key)
+            .unwrap();
+
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let crypto = server_crypto(
+            vec![server_cert_der.clone()],
+            server_key,
+            Some(vec![ca.der().clone()]),
+            true,
+        )
+        .unwrap();
+        let endpoint = server_endpoint(socket, crypto).unwrap();
+        let proxy_addr = endpoint.local_addr().unwrap();
+
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut conn, _) = echo.accept().await.unwrap();
+            let mut buf = [0_u8; 4];
+            conn.read_exact(&mut buf).await.unwrap();
+            conn.write_all(&buf).await.unwrap();
+        });
+        // only alice may connect to the echo server, and nobody has a password
+        let config: SocksServerConfig = toml::from_str(&format!(
+            r#"
+            [auth]
+            [port_policy]
+            denied = [{echo_port}]
+            [port_policy.users.alice]
+            allowed = [{echo_port}]
+            [quic]
+            listen_addr = "{proxy_addr}"
+            cert_chain = "unused.pem"
+            private_key = "unused.pem"
+            client_users = {{ "laptop.example.com" = "alice" }}
+            "#
+        ))
+        .unwrap();
+        let server = Arc::new(Server::new(config).unwrap());
+        tokio::spawn(server.serve_quic(endpoint));
+
+        tokio::task::spawn_blocking(move || {
+            let client = QuicClient::connect_with_client_cert(
+                proxy_addr,
+                "localhost",
+                vec![server_cert_der.clone()],
+                vec![client_cert.der().clone()],
+                PrivateKeyDer::try_from(client_key.serialize_der()).unwrap(),
+            )
+            .unwrap();
+            let req = ConnectRequest {
+                server_addr: proxy_addr.to_string(),
+                dest_addr: "127.0.0.1".to_owned(),
+                dest_port: echo_port,
+                supported_auth_methods: vec![AuthMethod::NoAuth],
+                credentials: None,
+                credential_provider: None,
+                dns: DnsMode::Remote,
+            };
+            let (mut conn, _) = connect_quic(req, &client).unwrap();
+            conn.write_all(b"ping").unwrap();
+            let mut buf = [0_u8; 4];
+            conn.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"ping");
+
+            let anonymous = QuicClient::connect(proxy_addr, "localhost", vec![server_cert_der])
+                .and_then(|client| client.open()?.read(&mut buf));
+            assert!(anonymous.is_err());
+        })
+        .await
+        .unwrap();
+    }
+}
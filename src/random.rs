@@ -0,0 +1,45 @@
+//! Cheap randomness for spreading load and jittering delays, where nothing depends on it being
+//! unpredictable. Numbers come from a splitmix64 sequence the whole process shares, seeded
+//! from the keys std draws from the OS for its hash maps.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// A number from 0 up to, but excluding, `bound`, which must not be 0.
+pub(crate) fn below(bound: u64) -> u64 {
+    next() % bound
+}
+
+fn next() -> u64 {
+    static STATE: OnceLock<AtomicU64> = OnceLock::new();
+    let state = STATE.get_or_init(|| AtomicU64::new(RandomState::new().hash_one(0_u8)));
+    let mut z = state
+        .fetch_add(GAMMA, Ordering::Relaxed)
+        .wrapping_add(GAMMA);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spreads_numbers_below_the_bound() {
+        let mut seen = [0_u32; 4];
+        for _ in 0..4000 {
+            seen[below(4) as usize] += 1;
+        }
+        assert!(seen.iter().all(|&n| n > 800), "{seen:?}");
+        assert_eq!(below(1), 0);
+    }
+}
@@ -0,0 +1,327 @@
+//! Limits on how many requests each client address and each user may make per window of
+//! time, and quotas on the bytes they may relay, configured by the `rate_limit` section of
+//! the server config.
+//!
+//! Requests are counted in fixed windows aligned to the unix epoch, so that servers counting
+//! the same key agree on where windows start. A request going over any of the limits is
+//! denied, and counts all the same, so clients retrying in a loop stay locked out until the
+//! window ends. The bytes of a tcp connection are counted once it ends, in the window it
+//! ends in, and requests are denied once they reached the quota.
+//!
+//! The counts are kept in the process, or in redis when a `redis_url` is configured, which
+//! lets the servers of a fleet behind a load balancer share them. Requests redis fails to
+//! count in time are let through rather than denied, so that an outage of redis doesn't
+//! lock every client out. Counts kept in the process are bounded, and keys beyond the bound
+//! go uncounted until the periodic sweep made room.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::io;
+
+use crate::config::{LimitConfig, LimitScope, RateLimitConfig};
+
+/// Local counts kept at most.
+const MAX_LOCAL_COUNTS: usize = 100_000;
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    store: Store,
+}
+
+enum Store {
+    /// counts by key, with the unix time their window ends at
+    Local(Mutex<HashMap<String, (u64, u64)>>),
+    #[cfg(feature = "redis")]
+    Redis(Box<redis_store::RedisStore>),
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> io::Result<Self> {
+        if config.limits.iter().any(|limit| limit.window_secs == 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "rate limit windows must last at least a second",
+            ));
+        }
+        if config
+            .limits
+            .iter()
+            .any(|limit| limit.requests.is_none() && limit.bytes.is_none())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "rate limits need a number of requests, bytes or both",
+            ));
+        }
+        let store =
+            match &config.redis_url {
+                None => Store::Local(Mutex::new(HashMap::new())),
+                #[cfg(feature = "redis")]
+                Some(url) => Store::Redis(Box::new(redis_store::RedisStore::new(
+                    url,
+                    config.timeout_ms,
+                )?)),
+                #[cfg(not(feature = "redis"))]
+                Some(_) => return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "rate limits kept in redis require socks5 to be built with the `redis` feature",
+                )),
+            };
+        Ok(Self { config, store })
+    }
+
+    /// Counts a request of `client`, authenticated as `username` if at all, returning
+    /// whether it is within every limit.
+    pub async fn admit(&self, client: IpAddr, username: Option<&str>) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.admit_at(client, username, now).await
+    }
+
+    async fn admit_at(&self, client: IpAddr, username: Option<&str>, now: u64) -> bool {
+        for limit in &self.config.limits {
+            let Some((key, ends)) = self.key(limit, client, username, now) else {
+                continue;
+            };
+            if let Some(requests) = limit.requests {
+                match self.count(&key, 1, ends).await {
+                    Ok(count) if count > requests => return false,
+                    Ok(_) => {}
+                    Err(err) => eprintln!("rate limit: counting {key}: {err}"),
+                }
+            }
+            if let Some(bytes) = limit.bytes {
+                let key = format!("{key}:bytes");
+                match self.count(&key, 0, ends).await {
+                    Ok(count) if count >= bytes => return false,
+                    Ok(_) => {}
+                    Err(err) => eprintln!("rate limit: counting {key}: {err}"),
+                }
+            }
+        }
+        true
+    }
+
+    /// Counts `bytes` a connection of `client` relayed towards the quotas, as it ends.
+    pub async fn add_bytes(&self, client: IpAddr, username: Option<&str>, bytes: u64) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.add_bytes_at(client, username, bytes, now).await;
+    }
+
+    async fn add_bytes_at(&self, client: IpAddr, username: Option<&str>, bytes: u64, now: u64) {
+        for limit in self
+            .config
+            .limits
+            .iter()
+            .filter(|limit| limit.bytes.is_some())
+        {
+            let Some((key, ends)) = self.key(limit, client, username, now) else {
+                continue;
+            };
+            let key = format!("{key}:bytes");
+            if let Err(err) = self.count(&key, bytes, ends).await {
+                eprintln!("rate limit: counting {key}: {err}");
+            }
+        }
+    }
+
+    /// The key `limit` counts the requests of `client` under at `now`, and the unix time
+    /// its window ends at. Byte counts add a suffix. `None` if the limit doesn't apply.
+    fn key(
+        &self,
+        limit: &LimitConfig,
+        client: IpAddr,
+        username: Option<&str>,
+        now: u64,
+    ) -> Option<(String, u64)> {
+        let (scope, id) = match (limit.per, username) {
+            (LimitScope::Ip, _) => ("ip", client.to_canonical().to_string()),
+            (LimitScope::User, Some(username)) => ("user", username.to_owned()),
+            (LimitScope::User, None) => return None,
+        };
+        let window = now / limit.window_secs;
+        let key = format!(
+            "{}:{scope}:{}:{window}:{id}",
+            self.config.key_prefix, limit.window_secs
+        );
+        Some((key, (window + 1) * limit.window_secs))
+    }
+
+    /// Adds `by` to the count of `key`, whose window ends at `ends`, returning the new
+    /// count.
+    async fn count(&self, key: &str, by: u64, ends: u64) -> io::Result<u64> {
+        match &self.store {
+            Store::Local(counts) => {
+                let mut counts = counts.lock().unwrap();
+                if let Some((count, _)) = counts.get_mut(key) {
+                    *count += by;
+                    return Ok(*count);
+                }
+                if by > 0 && counts.len() < MAX_LOCAL_COUNTS {
+                    counts.insert(key.to_owned(), (by, ends));
+                }
+                Ok(by)
+            }
+            #[cfg(feature = "redis")]
+            Store::Redis(redis) => redis.count(key, by, ends).await,
+        }
+    }
+
+    /// Forgets the local counts of windows that ended, see [`crate::server::Server`]'s
+    /// periodic sweep. Redis expires them by itself.
+    pub(crate) fn sweep(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.sweep_at(now);
+    }
+
+    fn sweep_at(&self, now: u64) {
+        match &self.store {
+            Store::Local(counts) => counts
+                .lock()
+                .unwrap()
+                .retain(|_, &mut (_, ends)| ends > now),
+            #[cfg(feature = "redis")]
+            Store::Redis(_) => {}
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+mod redis_store {
+    use std::time::Duration;
+
+    use redis::{aio::ConnectionManager, Client};
+    use tokio::{io, sync::OnceCell, time};
+
+    use crate::redact;
+
+    pub(super) struct RedisStore {
+        client: Client,
+        /// connected on first use, and reconnecting by itself from then on
+        conn: OnceCell<ConnectionManager>,
+        timeout: Duration,
+    }
+
+    impl RedisStore {
+        pub(super) fn new(url: &str, timeout_ms: u64) -> io::Result<Self> {
+            let client = Client::open(url).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("rate limit redis_url {:?}: {err}", redact::url(url)),
+                )
+            })?;
+            Ok(Self {
+                client,
+                conn: OnceCell::new(),
+                timeout: Duration::from_millis(timeout_ms),
+            })
+        }
+
+        pub(super) async fn count(&self, key: &str, by: u64, ends: u64) -> io::Result<u64> {
+            let count = async {
+                let mut conn = self
+                    .conn
+                    .get_or_try_init(|| self.client.get_connection_manager())
+                    .await?
+                    .clone();
+                let (count,): (u64,) = redis::pipe()
+                    .atomic()
+                    .incr(key, by)
+                    .expire_at(key, ends as i64)
+                    .ignore()
+                    .query_async(&mut conn)
+                    .await?;
+                Ok::<_, redis::RedisError>(count)
+            };
+            time::timeout(self.timeout, count)
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "redis timed out"))?
+                .map_err(io::Error::other)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn counts_requests_per_address_and_user_in_windows() {
+        let config: RateLimitConfig = toml::from_str(
+            r#"
+            [[limits]]
+            per = "ip"
+            requests = 2
+            window_secs = 60
+
+            [[limits]]
+            per = "user"
+            requests = 3
+            window_secs = 3600
+            "#,
+        )
+        .unwrap();
+        let limiter = RateLimiter::new(config).unwrap();
+        let alice: IpAddr = "192.0.2.1".parse().unwrap();
+        let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        let bob: IpAddr = "192.0.2.2".parse().unwrap();
+        let admit = |client, username, now| limiter.admit_at(client, username, now);
+
+        assert!(admit(alice, Some("alice"), 0).await);
+        assert!(admit(mapped, Some("alice"), 59).await);
+        assert!(!admit(alice, None, 59).await);
+        // a new window for the address, but not for the user
+        assert!(admit(alice, Some("alice"), 60).await);
+        assert!(!admit(bob, Some("alice"), 61).await);
+        assert!(admit(bob, Some("bob"), 61).await);
+        assert!(!admit(bob, None, 62).await);
+        assert!(admit(alice, Some("alice"), 3600).await);
+    }
+
+    #[tokio::test]
+    async fn denies_requests_once_a_byte_quota_is_used_up() {
+        let config: RateLimitConfig = toml::from_str(
+            r#"
+            [[limits]]
+            per = "user"
+            bytes = 1000
+            window_secs = 60
+            "#,
+        )
+        .unwrap();
+        let limiter = RateLimiter::new(config).unwrap();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert!(limiter.admit_at(client, Some("alice"), 0).await);
+        limiter.add_bytes_at(client, Some("alice"), 600, 10).await;
+        assert!(limiter.admit_at(client, Some("alice"), 11).await);
+        limiter.add_bytes_at(client, Some("alice"), 600, 20).await;
+        assert!(!limiter.admit_at(client, Some("alice"), 21).await);
+        assert!(limiter.admit_at(client, Some("bob"), 21).await);
+        assert!(limiter.admit_at(client, Some("alice"), 60).await);
+
+        limiter.sweep_at(60);
+        match &limiter.store {
+            Store::Local(counts) => assert!(counts.lock().unwrap().is_empty()),
+            #[cfg(feature = "redis")]
+            Store::Redis(_) => unreachable!(),
+        }
+        assert!(RateLimiter::new(
+            toml::from_str("[[limits]]\nper = \"ip\"\nwindow_secs = 1").unwrap()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,151 @@
+//! Transparent proxying of connections redirected by iptables or nftables, configured by the
+//! `redirect` section of the server config.
+//!
+//! A `REDIRECT` rule rewrites the destination of a connection to the redirect listener, and
+//! the kernel keeps the one it replaced for `SO_ORIGINAL_DST` to return. The connection is
+//! relayed there as if a socks client had asked for it with a CONNECT: nothing is read from
+//! it before the relay starts.
+
+use std::net::SocketAddr;
+
+use tokio::{io, net::TcpStream};
+
+use crate::{
+    honeypot::DeniedRecord,
+    proto,
+    server::{ConnectionInfo, Server},
+    tcp_server_stream::{
+        check_policies, outbound_dscp, record_access, record_denied, relay_plain, ClientConn,
+        Requested,
+    },
+};
+
+pub(crate) async fn handle(
+    server: &Server,
+    stream: TcpStream,
+    info: ConnectionInfo,
+) -> io::Result<()> {
+    let local_addr = stream.local_addr()?;
+    let dest = match original_dst(&stream, local_addr) {
+        // a connection made to the listener itself would be relayed back to it, and so on
+        Ok(dest)
+            if dest.ip().to_canonical() != local_addr.ip().to_canonical()
+                || dest.port() != local_addr.port() =>
+        {
+            dest
+        }
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        // not found is what conntrack says when it has nothing on the connection
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("connection from {} was not redirected", info.peer_addr),
+            ))
+        }
+    };
+    let request = proto::ClientConnectionRequest {
+        cmd: proto::ClientCommand::EstablishConnection,
+        dest_addr: dest.into(),
+        dest_port: dest.port(),
+    };
+
+    let acl_rule = match check_policies(server, info, None, None, &request).await {
+        Ok(acl_rule) => acl_rule,
+        Err(rule) => {
+            record_denied(
+                server,
+                &DeniedRecord {
+                    info,
+                    protocol: "redirect",
+                    auth_methods: &[],
+                    username: None,
+                    request: Some(&request),
+                    reason: rule,
+                },
+            );
+            server.tarpit().await?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "{rule} denied redirected connection from {} to {dest}",
+                    info.peer_addr
+                ),
+            ));
+        }
+    };
+
+    let dialed = server
+        .dialer()
+        .connect(
+            server.resolver(),
+            &request.dest_addr,
+            request.dest_port,
+            outbound_dscp(server, info, &request),
+        )
+        .await;
+    record_access(server, info, None, &request, &dialed);
+    let requested = Requested {
+        request: &request,
+        username: None,
+        acl_rule,
+    };
+    relay_plain(server, ClientConn::Tcp(stream), dialed?, requested, info).await
+}
+
+/// The destination `stream`, accepted at `local_addr`, had before it was redirected.
+#[cfg(target_os = "linux")]
+fn original_dst(stream: &TcpStream, local_addr: SocketAddr) -> io::Result<SocketAddr> {
+    let socket = socket2::SockRef::from(stream);
+    // ipv4 connections accepted by a dual-stack listener were redirected by iptables
+    let addr = if local_addr.ip().to_canonical().is_ipv6() {
+        socket.original_dst_v6()?
+    } else {
+        socket.original_dst_v4()?
+    };
+    addr.as_socket().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "original destination is not an ip address",
+        )
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn original_dst(_stream: &TcpStream, _local_addr: SocketAddr) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "redirected connections are only supported on linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::config::{RedirectConfig, ServerConfig};
+
+    #[tokio::test]
+    async fn closes_connections_that_were_not_redirected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            redirect: Some(RedirectConfig {
+                listen_addr: listen_addr.to_string(),
+            }),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(config).unwrap());
+        tokio::spawn(Arc::clone(&server).serve_redirect(listener));
+
+        let mut client = TcpStream::connect(listen_addr).await.unwrap();
+        let mut buf = [0_u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        server.drain().await;
+    }
+}
@@ -0,0 +1,220 @@
+//! Relaying bytes both ways between two streams until both are done.
+//!
+//! [`relay`] takes any pair of async streams, and picks the fastest path their types allow:
+//! when both are plain [`TcpStream`]s it splices between them on linux, or copies with
+//! zerocopy sends if configured, and otherwise copies through userspace buffers. The server
+//! relays every connection through it, whichever listener transport the client came over.
+
+use std::any::Any;
+
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::watch,
+};
+
+#[cfg(target_os = "linux")]
+use crate::tcp_server_stream::{copy, zerocopy};
+use crate::{
+    config::{RelayStrategy, ServerConfig},
+    metrics::{ConnectionRelay, Counted, Progress, RelayMetrics},
+};
+
+/// How [`relay`] moves bytes, see the `relay` settings of the server config.
+#[derive(Debug, Clone, Copy)]
+pub struct RelayOptions {
+    pub strategy: RelayStrategy,
+    /// size of each direction's buffer when copying through userspace
+    pub buffer_size: usize,
+    /// copy with zerocopy sends when both streams are tcp and splicing isn't an option
+    pub zerocopy: bool,
+}
+
+impl RelayOptions {
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self {
+            strategy: config.relay,
+            buffer_size: config.relay_buffer_size.get(),
+            zerocopy: config.relay_zerocopy,
+        }
+    }
+}
+
+impl Default for RelayOptions {
+    fn default() -> Self {
+        Self::from_config(&ServerConfig::default())
+    }
+}
+
+/// Relays between `a` and `b` until both directions are done, passing on the end of either
+/// as a half close.
+pub async fn relay<A, B>(a: A, b: B, opts: &RelayOptions) -> io::Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin + 'static,
+    B: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    let totals = RelayMetrics::default();
+    let relayed = ConnectionRelay::new(&totals, watch::Sender::new(Progress::default()).into());
+    relay_counted(a, b, opts, &relayed, None).await
+}
+
+/// Like [`relay`], counting bytes read from `client` as relayed to the remote side and
+/// those written to it as relayed to the client. The path taken is logged for `peers`, if
+/// given.
+pub(crate) async fn relay_counted<A, B>(
+    client: A,
+    remote: B,
+    opts: &RelayOptions,
+    relayed: &ConnectionRelay<'_>,
+    peers: Option<&str>,
+) -> io::Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin + 'static,
+    B: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    // both sides have to be taken apart as tcp streams, or neither
+    let (mut client, mut remote) = (Some(client), Some(remote));
+    if let (Some(client), Some(remote)) = (
+        (&mut client as &mut dyn Any).downcast_mut::<Option<TcpStream>>(),
+        (&mut remote as &mut dyn Any).downcast_mut::<Option<TcpStream>>(),
+    ) {
+        let (client, remote) = client.take().zip(remote.take()).expect("taken once");
+        return relay_tcp(client, remote, opts, relayed, peers).await;
+    }
+    log(peers, "userspace, as not both sides are tcp");
+    copy_userspace(
+        client.expect("not taken"),
+        remote.expect("not taken"),
+        opts,
+        relayed,
+    )
+    .await
+}
+
+#[cfg(target_os = "linux")]
+async fn relay_tcp(
+    client: TcpStream,
+    remote: TcpStream,
+    opts: &RelayOptions,
+    relayed: &ConnectionRelay<'_>,
+    peers: Option<&str>,
+) -> io::Result<()> {
+    if let Some(pipes) = splice_pipes(opts.strategy)? {
+        log(peers, "splice");
+        return copy::splice_bidirectional(client, remote, pipes, relayed).await;
+    }
+    if opts.zerocopy {
+        match zerocopy::enable(&client).and_then(|()| zerocopy::enable(&remote)) {
+            Ok(()) => {
+                log(peers, "userspace with zerocopy sends");
+                return zerocopy::relay(client, remote, opts.buffer_size, relayed).await;
+            }
+            Err(err) => log(peers, &format!("enabling zerocopy sends failed: {err}")),
+        }
+    }
+    log(peers, "userspace");
+    copy_userspace(client, remote, opts, relayed).await
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn relay_tcp(
+    client: TcpStream,
+    remote: TcpStream,
+    opts: &RelayOptions,
+    relayed: &ConnectionRelay<'_>,
+    peers: Option<&str>,
+) -> io::Result<()> {
+    log(peers, "userspace");
+    copy_userspace(client, remote, opts, relayed).await
+}
+
+async fn copy_userspace(
+    client: impl AsyncRead + AsyncWrite + Unpin,
+    mut remote: impl AsyncRead + AsyncWrite + Unpin,
+    opts: &RelayOptions,
+    relayed: &ConnectionRelay<'_>,
+) -> io::Result<()> {
+    let mut client = Counted {
+        inner: client,
+        relay: relayed,
+    };
+    io::copy_bidirectional_with_sizes(&mut client, &mut remote, opts.buffer_size, opts.buffer_size)
+        .await
+        .map(|_| ())
+}
+
+/// Returns the pipes to splice through, or `None` if the connection should be copied through
+/// userspace instead.
+#[cfg(target_os = "linux")]
+fn splice_pipes(strategy: RelayStrategy) -> io::Result<Option<copy::SplicePipes>> {
+    match strategy {
+        RelayStrategy::Userspace => Ok(None),
+        RelayStrategy::Splice => copy::SplicePipes::new().map(Some),
+        RelayStrategy::Auto if !copy::splice_supported() => Ok(None),
+        RelayStrategy::Auto => match copy::SplicePipes::new() {
+            Ok(pipes) => Ok(Some(pipes)),
+            Err(err) => {
+                eprintln!("relay: creating splice pipes failed, falling back to userspace: {err}");
+                Ok(None)
+            }
+        },
+    }
+}
+
+fn log(peers: Option<&str>, path: &str) {
+    if let Some(peers) = peers {
+        eprintln!("relay {peers}: {path}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connecting = TcpStream::connect(listener.local_addr().unwrap());
+        let (connected, accepted) = tokio::join!(connecting, listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn relays_between_tcp_streams_and_any_other_streams() {
+        for strategy in [RelayStrategy::Auto, RelayStrategy::Userspace] {
+            let opts = RelayOptions {
+                strategy,
+                ..RelayOptions::default()
+            };
+            let (mut client, a) = tcp_pair().await;
+            let (b, mut remote) = tcp_pair().await;
+            let relaying = tokio::spawn(async move { relay(a, b, &opts).await });
+            client.write_all(b"ping").await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut buf = Vec::new();
+            remote.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"ping");
+            remote.write_all(b"pong").await.unwrap();
+            drop(remote);
+            buf.clear();
+            client.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"pong");
+            relaying.await.unwrap().unwrap();
+        }
+
+        let (mut client, a) = io::duplex(64);
+        let (b, mut remote) = tcp_pair().await;
+        let relaying = tokio::spawn(async move { relay(a, b, &RelayOptions::default()).await });
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut buf = Vec::new();
+        remote.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"ping");
+        drop(remote);
+        relaying.await.unwrap().unwrap();
+    }
+}
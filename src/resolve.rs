@@ -0,0 +1,165 @@
+//! Name resolution for client requested destinations.
+//!
+//! Destinations given as ip literals are never resolved. Domain names go through the
+//! [`Resolver`] picked by the `resolver` section of the server config, after checking the
+//! configured hosts overrides. Lookups the backend takes longer than `timeout_ms` for fail
+//! as if the host was unreachable.
+
+#[cfg(feature = "doh")]
+mod doh;
+mod hosts;
+
+use std::{net::SocketAddr, time::Duration};
+
+use futures::future::BoxFuture;
+use tokio::{io, net::lookup_host, time};
+
+use crate::config::{ResolverBackend, ResolverConfig};
+
+#[cfg(feature = "doh")]
+pub use doh::DohResolver;
+pub use hosts::HostsResolver;
+
+pub trait Resolver: Send + Sync {
+    /// Returns every address `host` resolves to, with `port` filled in.
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>>;
+}
+
+/// Resolves through the operating system, i.e. `getaddrinfo`. Names that don't exist fail
+/// with [`io::ErrorKind::NotFound`], unlike lookups that could succeed when retried.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        Box::pin(async move {
+            match lookup_host((host, port)).await {
+                Ok(addrs) => Ok(addrs.collect()),
+                Err(err) if is_nonexistent_name(&err) => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{host} does not exist: {err}"),
+                )),
+                Err(err) => Err(err),
+            }
+        })
+    }
+}
+
+/// Whether `getaddrinfo` failed with `EAI_NONAME`, which std reports like any other of its
+/// errors, only with the description of the code in the message.
+#[cfg(unix)]
+fn is_nonexistent_name(err: &io::Error) -> bool {
+    // SAFETY: gai_strerror returns a pointer to a static, nul terminated string
+    let noname = unsafe { std::ffi::CStr::from_ptr(libc::gai_strerror(libc::EAI_NONAME)) };
+    noname
+        .to_str()
+        .is_ok_and(|noname| err.to_string().ends_with(noname))
+}
+
+#[cfg(not(unix))]
+fn is_nonexistent_name(_err: &io::Error) -> bool {
+    false
+}
+
+/// Fails lookups of `inner` that take longer than `timeout`.
+pub struct TimeoutResolver {
+    timeout: Duration,
+    inner: Box<dyn Resolver>,
+}
+
+impl TimeoutResolver {
+    pub fn new(timeout: Duration, inner: Box<dyn Resolver>) -> Self {
+        Self { timeout, inner }
+    }
+}
+
+impl Resolver for TimeoutResolver {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        Box::pin(async move {
+            time::timeout(self.timeout, self.inner.resolve(host, port))
+                .await
+                .unwrap_or_else(|_| {
+                    // answered like a name that doesn't resolve, rather than like a
+                    // destination that doesn't answer
+                    Err(io::Error::new(
+                        io::ErrorKind::HostUnreachable,
+                        format!("resolving {host} took longer than {:?}", self.timeout),
+                    ))
+                })
+        })
+    }
+}
+
+pub fn from_config(config: &ResolverConfig) -> io::Result<Box<dyn Resolver>> {
+    let mut backend = backend_from_config(config)?;
+    // overrides are answered right away, so only the backend is timed
+    if let Some(ms) = config.timeout_ms {
+        backend = Box::new(TimeoutResolver::new(Duration::from_millis(ms), backend));
+    }
+    if config.hosts_file.is_none() && config.hosts.is_empty() {
+        return Ok(backend);
+    }
+    Ok(Box::new(HostsResolver::new(
+        config.hosts_file.as_deref(),
+        &config.hosts,
+        backend,
+    )?))
+}
+
+fn backend_from_config(config: &ResolverConfig) -> io::Result<Box<dyn Resolver>> {
+    match config.backend {
+        ResolverBackend::System => Ok(Box::new(SystemResolver)),
+        #[cfg(feature = "doh")]
+        ResolverBackend::Doh => {
+            let url = config.doh_url.as_deref().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the doh resolver backend requires doh_url",
+                )
+            })?;
+            Ok(Box::new(DohResolver::new(url)?))
+        }
+        #[cfg(not(feature = "doh"))]
+        ResolverBackend::Doh => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the doh resolver requires socks5 to be built with the `doh` feature",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future;
+
+    use super::*;
+
+    struct Wedged;
+
+    impl Resolver for Wedged {
+        fn resolve<'a>(
+            &'a self,
+            _host: &'a str,
+            _port: u16,
+        ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+            Box::pin(future::pending())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_lookups_taking_too_long_as_unreachable() {
+        let resolver = TimeoutResolver::new(Duration::from_secs(2), Box::new(Wedged));
+        let err = resolver.resolve("example.com", 443).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::HostUnreachable);
+    }
+}
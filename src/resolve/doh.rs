@@ -0,0 +1,363 @@
+//! DNS-over-HTTPS (RFC 8484) resolver.
+//!
+//! Every lookup sends an `A` and an `AAAA` query as wire format POST requests over their own
+//! https connection, so destination names never reach the local network's resolver. Only the
+//! name of the DoH endpoint itself is looked up through the system, which can be avoided by
+//! configuring it as an ip literal.
+//!
+//! Each query, connecting included, gives up after five seconds, so that an endpoint that
+//! stops answering fails lookups even without the resolver's `timeout_ms`.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+use tokio_rustls::{
+    rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+use super::Resolver;
+use crate::{proto, redact};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+
+const DEFAULT_PATH: &str = "/dns-query";
+const MAX_RESPONSE: usize = 64 << 10;
+/// how long a query may take, from connecting to the endpoint to the end of its response
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct DohResolver {
+    host: String,
+    port: u16,
+    path: String,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+}
+
+impl DohResolver {
+    /// `url` is the `https://host[:port][/path]` endpoint of the DoH server, the path
+    /// defaulting to `/dns-query`.
+    pub fn new(url: &str) -> io::Result<Self> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid doh url {:?}: {reason}", redact::url(url)),
+            )
+        };
+
+        let rest = url
+            .strip_prefix("https://")
+            .ok_or_else(|| invalid("expected an https:// url"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].to_owned()),
+            None => (rest, DEFAULT_PATH.to_owned()),
+        };
+        let (host, port) = split_authority(authority).ok_or_else(|| invalid("bad host or port"))?;
+        let server_name =
+            ServerName::try_from(host.clone()).map_err(|_| invalid("bad server name"))?;
+
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(Self {
+            host,
+            port,
+            path,
+            server_name,
+            connector: TlsConnector::from(Arc::new(config)),
+        })
+    }
+
+    async fn query(&self, name: &str, qtype: u16) -> io::Result<Vec<IpAddr>> {
+        time::timeout(QUERY_TIMEOUT, self.exchange(name, qtype))
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("doh: no response within {QUERY_TIMEOUT:?}"),
+                )
+            })?
+    }
+
+    async fn exchange(&self, name: &str, qtype: u16) -> io::Result<Vec<IpAddr>> {
+        let query = encode_query(name, qtype)?;
+        let tcp = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let mut tls = self
+            .connector
+            .connect(self.server_name.clone(), tcp)
+            .await?;
+
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/dns-message\r\n\
+             Accept: application/dns-message\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            self.path,
+            self.host,
+            query.len()
+        );
+        tls.write_all(head.as_bytes()).await?;
+        tls.write_all(&query).await?;
+        tls.flush().await?;
+
+        let mut resp = Vec::new();
+        let mut buf = [0_u8; 4096];
+        let body = loop {
+            if let Some(body) = parse_http_response(&resp)? {
+                break body;
+            }
+            if resp.len() > MAX_RESPONSE {
+                return Err(doh_error("response too large"));
+            }
+            let n = tls.read(&mut buf).await?;
+            if n == 0 {
+                return Err(doh_error(
+                    "connection closed before the response was complete",
+                ));
+            }
+            resp.extend_from_slice(&buf[..n]);
+        };
+        decode_answers(&body, qtype)
+    }
+}
+
+impl Resolver for DohResolver {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        Box::pin(async move {
+            let (v4, v6) = tokio::join!(self.query(host, TYPE_A), self.query(host, TYPE_AAAA));
+            let addrs = match (v4, v6) {
+                (Err(err), Err(_)) => return Err(err),
+                (v4, v6) => v4
+                    .unwrap_or_default()
+                    .into_iter()
+                    .chain(v6.unwrap_or_default()),
+            };
+            Ok(addrs.map(|ip| SocketAddr::new(ip, port)).collect())
+        })
+    }
+}
+
+fn split_authority(authority: &str) -> Option<(String, u16)> {
+    let (host, port) = proto::split_host_port(authority).ok()?;
+    Some((host.to_owned(), port.unwrap_or(443)))
+}
+
+fn encode_query(name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    // id 0 as recommended for DoH, recursion desired, a single question
+    let mut msg = vec![0, 0, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot query invalid domain name {name:?}"),
+            ));
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&qtype.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(msg)
+}
+
+fn decode_answers(msg: &[u8], qtype: u16) -> io::Result<Vec<IpAddr>> {
+    let u16_at = |pos: usize| -> io::Result<u16> {
+        msg.get(pos..pos + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| doh_error("truncated dns message"))
+    };
+
+    let rcode = *msg
+        .get(3)
+        .ok_or_else(|| doh_error("truncated dns message"))?
+        & 0x0f;
+    if rcode == RCODE_NXDOMAIN {
+        return Ok(Vec::new());
+    }
+    if rcode != 0 {
+        return Err(doh_error(&format!(
+            "dns server answered with rcode {rcode}"
+        )));
+    }
+
+    let qdcount = u16_at(4)?;
+    let ancount = u16_at(6)?;
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = skip_name(msg, pos)? + 4;
+    }
+
+    let mut addrs = Vec::new();
+    for _ in 0..ancount {
+        pos = skip_name(msg, pos)?;
+        let rtype = u16_at(pos)?;
+        let rdlen = u16_at(pos + 8)? as usize;
+        let rdata = msg
+            .get(pos + 10..pos + 10 + rdlen)
+            .ok_or_else(|| doh_error("truncated dns message"))?;
+        pos += 10 + rdlen;
+
+        // cnames are followed by the server, only the final records matter
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) if qtype == TYPE_A => {
+                addrs.push(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]).into())
+            }
+            (TYPE_AAAA, 16) if qtype == TYPE_AAAA => {
+                let octets: [u8; 16] = rdata.try_into().unwrap();
+                addrs.push(Ipv6Addr::from(octets).into())
+            }
+            _ => {}
+        }
+    }
+    Ok(addrs)
+}
+
+/// Returns the position right after the (possibly compressed) name starting at `pos`.
+fn skip_name(msg: &[u8], mut pos: usize) -> io::Result<usize> {
+    loop {
+        let len = *msg
+            .get(pos)
+            .ok_or_else(|| doh_error("truncated dns message"))?;
+        match len {
+            0 => return Ok(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+/// Returns the body once `resp` holds a complete http response, or `None` if more of it has
+/// to be read first.
+fn parse_http_response(resp: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let Some(header_end) = resp.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Ok(None);
+    };
+    let head = String::from_utf8_lossy(&resp[..header_end]);
+    let body = &resp[header_end + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap_or_default();
+    if status.split(' ').nth(1) != Some("200") {
+        return Err(doh_error(&format!("server answered {status:?}")));
+    }
+
+    let mut content_length = None;
+    let mut chunked = false;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse::<usize>().ok();
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        }
+    }
+
+    if chunked {
+        return decode_chunked(body);
+    }
+    match content_length {
+        Some(len) if body.len() >= len => Ok(Some(body[..len].to_vec())),
+        Some(_) => Ok(None),
+        None => Err(doh_error(
+            "response has neither content-length nor chunked body",
+        )),
+    }
+}
+
+fn decode_chunked(mut body: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let mut decoded = Vec::new();
+    loop {
+        let Some(line_end) = body.windows(2).position(|w| w == b"\r\n") else {
+            return Ok(None);
+        };
+        let size_line = String::from_utf8_lossy(&body[..line_end]);
+        let size_hex = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_hex, 16)
+            .map_err(|_| doh_error("invalid chunk size in response"))?;
+        let chunk_start = line_end + 2;
+        if size == 0 {
+            return Ok(Some(decoded));
+        }
+        if body.len() < chunk_start + size + 2 {
+            return Ok(None);
+        }
+        decoded.extend_from_slice(&body[chunk_start..chunk_start + size]);
+        body = &body[chunk_start + size + 2..];
+    }
+}
+
+fn doh_error(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("doh: {reason}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_endpoints_that_dont_answer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("https://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (_conn, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await
+        });
+        let resolver = DohResolver::new(&url).unwrap();
+        let err = resolver.resolve("example.com", 443).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn decodes_compressed_answers() {
+        let mut msg = encode_query("example.com", TYPE_A).unwrap();
+        msg[2] |= 0x80; // response
+        msg[7] = 2; // two answers
+                    // a cname pointing back at the question name, followed by the a record
+        msg.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+        msg.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+
+        let addrs = decode_answers(&msg, TYPE_A).unwrap();
+        assert_eq!(addrs, vec![IpAddr::from([93, 184, 216, 34])]);
+    }
+
+    #[test]
+    fn parses_chunked_and_sized_responses() {
+        let sized = b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabc";
+        assert_eq!(parse_http_response(sized).unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(
+            parse_http_response(&sized[..sized.len() - 1]).unwrap(),
+            None
+        );
+
+        let chunked =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n1\r\nc\r\n0\r\n\r\n";
+        assert_eq!(parse_http_response(chunked).unwrap(), Some(b"abc".to_vec()));
+
+        let failed = b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n";
+        assert!(parse_http_response(failed).is_err());
+    }
+}
@@ -0,0 +1,77 @@
+//! Static name overrides consulted before the resolver backend, from a hosts(5) format file
+//! and from the config itself.
+
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+use futures::future::BoxFuture;
+use tokio::io;
+
+use super::Resolver;
+
+pub struct HostsResolver {
+    overrides: HashMap<String, Vec<IpAddr>>,
+    inner: Box<dyn Resolver>,
+}
+
+impl HostsResolver {
+    /// Overrides from `inline` replace those for the same name from `file`. Names without an
+    /// override are resolved by `inner`.
+    pub fn new(
+        file: Option<&Path>,
+        inline: &HashMap<String, Vec<IpAddr>>,
+        inner: Box<dyn Resolver>,
+    ) -> io::Result<Self> {
+        let mut overrides = match file {
+            Some(path) => parse_hosts(&fs::read_to_string(path)?),
+            None => HashMap::new(),
+        };
+        for (name, addrs) in inline {
+            overrides.insert(normalize(name), addrs.clone());
+        }
+        Ok(Self { overrides, inner })
+    }
+}
+
+impl Resolver for HostsResolver {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        match self.overrides.get(&normalize(host)) {
+            Some(addrs) => {
+                let addrs = addrs.iter().map(|&ip| SocketAddr::new(ip, port)).collect();
+                Box::pin(async move { Ok(addrs) })
+            }
+            None => self.inner.resolve(host, port),
+        }
+    }
+}
+
+fn parse_hosts(contents: &str) -> HashMap<String, Vec<IpAddr>> {
+    let mut overrides: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(ip) = fields.next() else {
+            continue;
+        };
+        let Ok(ip) = ip.parse::<IpAddr>() else {
+            eprintln!("hosts: skipping line with invalid address: {line:?}");
+            continue;
+        };
+        for name in fields {
+            overrides.entry(normalize(name)).or_default().push(ip);
+        }
+    }
+    overrides
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
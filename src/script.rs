@@ -0,0 +1,259 @@
+//! Per-request routing and policy hook backed by a rhai script.
+//!
+//! The script must define a `route(req)` function. `req` is a map with the keys `cmd`,
+//! `dest_addr` and `dest_port`. The return value decides what happens to the request:
+//!
+//! - `true` or `()`: serve the request as is
+//! - `false`: reject it with `ConnectionNotAllowedByRuleset`
+//! - a `"host:port"` string: serve the request against that destination instead
+//!
+//! The script runs before the server's other policies, which check the destination it
+//! settled on, so a redirect can't take the client past the acl, the blocklist or the
+//! destinations it was granted.
+//!
+//! The server checks the script file for modifications every [`RELOAD_INTERVAL`], and
+//! recompiles it on a blocking thread when it changed, so policies can be updated without
+//! restarting the server. Requests are routed by the version compiled last meanwhile.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+
+use crate::proto;
+
+const ROUTE_FN: &str = "route";
+
+/// How often the server checks the script file for modifications.
+pub(crate) const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum Route {
+    Allow,
+    Deny,
+    Redirect(proto::Address, u16),
+}
+
+pub struct RouteScript {
+    path: PathBuf,
+    engine: Engine,
+    loaded: Mutex<Loaded>,
+}
+
+struct Loaded {
+    ast: Arc<AST>,
+    modified: SystemTime,
+}
+
+impl RouteScript {
+    pub fn load(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let mut engine = Engine::new();
+        // a runaway script must not be able to wedge the handshake
+        engine.set_max_operations(100_000);
+
+        let loaded = compile(&engine, &path)?;
+        Ok(Self {
+            path,
+            engine,
+            loaded: Mutex::new(loaded),
+        })
+    }
+
+    pub fn route(&self, request: &proto::ClientConnectionRequest) -> io::Result<Route> {
+        let ast = Arc::clone(&self.loaded.lock().unwrap().ast);
+
+        let mut req = Map::new();
+        req.insert("cmd".into(), command_name(request.cmd).into());
+        req.insert("dest_addr".into(), request.dest_addr.to_string().into());
+        req.insert("dest_port".into(), (request.dest_port as rhai::INT).into());
+
+        let ret: Dynamic = self
+            .engine
+            .call_fn(&mut Scope::new(), &ast, ROUTE_FN, (req,))
+            .map_err(|err| script_error(&self.path, err))?;
+        decision_from(ret)
+    }
+
+    /// Recompiles the script if the file changed since it was last compiled, blocking on the
+    /// filesystem. A script that fails to compile is logged and the previous version is kept.
+    /// Compiling happens outside the lock, requests arriving meanwhile see the previous
+    /// version.
+    pub(crate) fn reload_if_changed(&self) {
+        let previous = self.loaded.lock().unwrap().modified;
+        let modified = match fs::metadata(&self.path).and_then(|m| m.modified()) {
+            Ok(modified) if modified != previous => modified,
+            Ok(_) => return,
+            Err(err) => {
+                eprintln!("route script {}: {err}", self.path.display());
+                return;
+            }
+        };
+
+        let reloaded = compile(&self.engine, &self.path);
+        let mut loaded = self.loaded.lock().unwrap();
+        match reloaded {
+            Ok(reloaded) => {
+                eprintln!("route script {} reloaded", self.path.display());
+                *loaded = reloaded;
+            }
+            Err(err) => {
+                eprintln!("route script reload failed, keeping previous version: {err}");
+                loaded.modified = modified;
+            }
+        }
+    }
+}
+
+fn compile(engine: &Engine, path: &Path) -> io::Result<Loaded> {
+    let modified = fs::metadata(path)?.modified()?;
+    let source = fs::read_to_string(path)?;
+    let ast = engine
+        .compile(source)
+        .map_err(|err| script_error(path, err))?;
+    if !ast.iter_functions().any(|f| f.name == ROUTE_FN) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "route script {} does not define {ROUTE_FN}(req)",
+                path.display()
+            ),
+        ));
+    }
+    Ok(Loaded {
+        ast: Arc::new(ast),
+        modified,
+    })
+}
+
+fn decision_from(ret: Dynamic) -> io::Result<Route> {
+    if ret.is_unit() {
+        return Ok(Route::Allow);
+    }
+    if let Ok(allow) = ret.as_bool() {
+        return Ok(if allow { Route::Allow } else { Route::Deny });
+    }
+    if let Ok(dest) = ret.clone().into_string() {
+        return parse_redirect(&dest);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "route script returned {}, expected bool or \"host:port\"",
+            ret.type_name()
+        ),
+    ))
+}
+
+fn parse_redirect(dest: &str) -> io::Result<Route> {
+    let (addr, port) = proto::parse_host_port(dest).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("route script returned invalid redirect destination: {err}"),
+        )
+    })?;
+    Ok(Route::Redirect(addr, port))
+}
+
+fn command_name(cmd: proto::ClientCommand) -> &'static str {
+    match cmd {
+        proto::ClientCommand::EstablishConnection => "connect",
+        proto::ClientCommand::EstablishPortBinding => "bind",
+        proto::ClientCommand::AssociateUdpPort => "udp_associate",
+    }
+}
+
+fn script_error(path: &Path, err: impl std::fmt::Display) -> io::Error {
+    io::Error::other(format!("route script {}: {err}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process, time::Duration};
+
+    use super::*;
+
+    fn request(host: &str, port: u16) -> proto::ClientConnectionRequest {
+        proto::ClientConnectionRequest {
+            cmd: proto::ClientCommand::EstablishConnection,
+            dest_addr: proto::Address::DomainName(host.to_owned()),
+            dest_port: port,
+        }
+    }
+
+    /// Moves the modification time of `path` ahead, as coarse timestamps may not tell a
+    /// rewrite from the write before it.
+    fn touch_later(path: &Path) {
+        let later = SystemTime::now() + Duration::from_secs(10);
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(later).unwrap();
+    }
+
+    #[test]
+    fn routes_requests_as_the_script_decides() {
+        let path = env::temp_dir().join(format!("socks5-route-{}.rhai", process::id()));
+        fs::write(
+            &path,
+            r#"
+            fn route(req) {
+                if req.dest_addr == "blocked.example.com" { return false; }
+                if req.dest_port == 8080 { return "127.0.0.1:80"; }
+                if req.cmd == "connect" { return true; }
+            }
+            "#,
+        )
+        .unwrap();
+        let script = RouteScript::load(&path).unwrap();
+
+        assert!(matches!(
+            script.route(&request("example.com", 443)),
+            Ok(Route::Allow)
+        ));
+        assert!(matches!(
+            script.route(&request("blocked.example.com", 443)),
+            Ok(Route::Deny)
+        ));
+        match script.route(&request("example.com", 8080)).unwrap() {
+            Route::Redirect(addr, port) => {
+                assert_eq!((addr.to_string(), port), ("127.0.0.1".to_owned(), 80));
+            }
+            route => panic!("expected a redirect, got {route:?}"),
+        }
+        let bind = proto::ClientConnectionRequest {
+            cmd: proto::ClientCommand::EstablishPortBinding,
+            ..request("example.com", 443)
+        };
+        // falling off the end of the function returns (), which allows the request
+        assert!(matches!(script.route(&bind), Ok(Route::Allow)));
+
+        fs::write(&path, "fn route(req) { 42 }").unwrap();
+        touch_later(&path);
+        script.reload_if_changed();
+        let err = script.route(&request("example.com", 443)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_previous_version_of_scripts_that_fail_to_compile() {
+        let path = env::temp_dir().join(format!("socks5-route-reload-{}.rhai", process::id()));
+        fs::write(&path, "fn route(req) { false }").unwrap();
+        let script = RouteScript::load(&path).unwrap();
+
+        fs::write(&path, "fn route(req) {").unwrap();
+        touch_later(&path);
+        script.reload_if_changed();
+        assert!(matches!(
+            script.route(&request("example.com", 443)),
+            Ok(Route::Deny)
+        ));
+
+        fs::write(&path, "fn allow(req) { true }").unwrap();
+        assert!(RouteScript::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1232 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    panic::AssertUnwindSafe,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+use tokio::{
+    io,
+    net::TcpListener,
+    sync::{watch, Notify},
+    time::{self, MissedTickBehavior},
+};
+use tokio_util::sync::CancellationToken;
+
+#[cfg(not(target_os = "linux"))]
+use crate::config::RelayStrategy;
+use crate::{
+    access_log::AccessLog,
+    acl::{Acl, PortPolicy},
+    auth::{self, Authenticator, PrivateAuthMethod},
+    blocklist::Blocklist,
+    capture::Capture,
+    config::{NamedListenerConfig, ServerConfig},
+    dial::Dialer,
+    dns_relay::DnsRelay,
+    dnsbl::Dnsbl,
+    honeypot::HoneypotLog,
+    http_proxy,
+    metrics::{Metrics, Progress},
+    priority::Scheduler,
+    random,
+    rate_limit::RateLimiter,
+    redirect,
+    resolve::{self, Resolver},
+    tcp_server_stream::{self, ClientConn},
+    top_talkers::TopTalkers,
+    transport::ListenerTransport,
+};
+
+#[cfg(feature = "scripting")]
+use crate::script::RouteScript;
+#[cfg(unix)]
+use crate::syslog::Syslog;
+
+pub struct Server {
+    config: ServerConfig,
+    #[cfg(feature = "scripting")]
+    route_script: Option<RouteScript>,
+    authenticator: Option<Box<dyn Authenticator>>,
+    private_auth_methods: BTreeMap<u8, Box<dyn PrivateAuthMethod>>,
+    listener_transport: Option<Box<dyn ListenerTransport>>,
+    resolver: Box<dyn Resolver>,
+    dialer: Dialer,
+    capture: Option<Capture>,
+    priority: Option<Scheduler>,
+    acl: Option<Acl>,
+    port_policy: Option<PortPolicy>,
+    blocklist: Option<Blocklist>,
+    dnsbl: Option<Dnsbl>,
+    rate_limiter: Option<RateLimiter>,
+    dns_relay: Option<DnsRelay>,
+    top_talkers: Option<TopTalkers>,
+    listeners: Vec<NamedListener>,
+    access_log: Option<AccessLog>,
+    #[cfg(unix)]
+    syslog: Option<Syslog>,
+    honeypot_log: Option<HoneypotLog>,
+    listening: AtomicBool,
+    /// whether the tasks sweeping the server's caches and reloading its blocklist and route
+    /// script were started
+    maintaining: AtomicBool,
+    stop_accepting: watch::Sender<bool>,
+    active_connections: AtomicUsize,
+    /// connections waiting out the tarpit delay
+    tarpitted: AtomicUsize,
+    /// parent of every connection's token, cancelled by [`Server::cancel`]
+    cancel: CancellationToken,
+    connections: Mutex<HashMap<ConnectionId, Tracked>>,
+    connection_closed: Notify,
+    next_connection_id: AtomicU64,
+    metrics: Metrics,
+}
+
+/// One of the `[[listeners]]` of the config, with the policy built from it.
+struct NamedListener {
+    /// leaked once per server, so that connection infos can carry it and stay `Copy`
+    name: &'static str,
+    no_auth: bool,
+    authenticator: Option<Box<dyn Authenticator>>,
+    acl: Option<Acl>,
+    port_policy: Option<PortPolicy>,
+    max_connections: Option<usize>,
+    active_connections: AtomicUsize,
+}
+
+impl NamedListener {
+    fn new(config: &NamedListenerConfig) -> io::Result<Self> {
+        if config.no_auth && config.auth.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "listener {}: no_auth and an auth section exclude each other",
+                    config.name
+                ),
+            ));
+        }
+        Ok(Self {
+            name: Box::leak(config.name.clone().into_boxed_str()),
+            no_auth: config.no_auth,
+            authenticator: config.auth.as_ref().map(auth::from_config).transpose()?,
+            acl: config.acl.clone().map(Acl::new).transpose()?,
+            port_policy: config.port_policy.clone().map(PortPolicy::new),
+            max_connections: config.max_connections,
+            active_connections: AtomicUsize::new(0),
+        })
+    }
+
+    fn at_capacity(&self) -> bool {
+        self.max_connections
+            .is_some_and(|max| self.active_connections.load(Ordering::Relaxed) >= max)
+    }
+}
+
+/// How often expired entries are pruned from the server's caches.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What clients of a listener speak.
+#[derive(Clone, Copy)]
+enum Protocol {
+    Socks,
+    Http,
+    Redirect,
+}
+
+/// Identifies an accepted connection in logs and errors, unique for the life of the process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(pub(crate) u64);
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "conn-{}", self.0)
+    }
+}
+
+/// Parses ids the way they are displayed, `conn-12`, or just the number.
+impl FromStr for ConnectionId {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        s.strip_prefix("conn-")
+            .unwrap_or(s)
+            .parse()
+            .map(Self)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid connection id {s:?}"),
+                )
+            })
+    }
+}
+
+/// What the server knows about a connection from the moment it was accepted, handed to
+/// everything that handles it. For QUIC, every stream counts as a connection of its own.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub peer_addr: SocketAddr,
+    pub accepted_at: Instant,
+    /// the name of the `[[listeners]]` entry that accepted the connection, if another
+    /// listener didn't
+    pub listener: Option<&'static str>,
+}
+
+/// A connection being handled, along with the token that cancels it and where its relay
+/// publishes its progress.
+struct Tracked {
+    info: ConnectionInfo,
+    cancel: CancellationToken,
+    progress: Arc<watch::Sender<Progress>>,
+}
+
+/// Shows the connection id and the named listener it came in on, which is what log lines
+/// are prefixed with.
+impl fmt::Display for ConnectionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.listener {
+            Some(listener) => write!(f, "{} on {listener}", self.id),
+            None => self.id.fmt(f),
+        }
+    }
+}
+
+impl Server {
+    pub fn new(config: ServerConfig) -> io::Result<Self> {
+        #[cfg(feature = "scripting")]
+        let route_script = config
+            .route_script
+            .as_ref()
+            .map(RouteScript::load)
+            .transpose()?;
+
+        #[cfg(not(feature = "scripting"))]
+        if config.route_script.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "route_script requires socks5 to be built with the `scripting` feature",
+            ));
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        if config.relay == RelayStrategy::Splice {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the splice relay strategy is only available on linux",
+            ));
+        }
+        #[cfg(not(target_os = "linux"))]
+        if config.relay_zerocopy {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "zerocopy relaying is only available on linux",
+            ));
+        }
+
+        #[cfg(all(unix, feature = "gssapi"))]
+        if let Some(keytab) = config.gssapi.as_ref().and_then(|gss| gss.keytab.as_deref()) {
+            crate::gssapi::use_keytab(keytab)?;
+        }
+
+        #[cfg(not(all(unix, feature = "gssapi")))]
+        if config.gssapi.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "gssapi requires a unix build of socks5 with the `gssapi` feature",
+            ));
+        }
+
+        #[cfg(feature = "websocket")]
+        let listener_transport = config.websocket.as_ref().map(|ws| {
+            Box::new(crate::transport::WebSocketTransport::new(&ws.path))
+                as Box<dyn ListenerTransport>
+        });
+
+        #[cfg(not(feature = "quic"))]
+        if config.quic.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "quic requires socks5 to be built with the `quic` feature",
+            ));
+        }
+
+        #[cfg(not(feature = "websocket"))]
+        let listener_transport = match config.websocket {
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "websocket requires socks5 to be built with the `websocket` feature",
+                ))
+            }
+            None => None,
+        };
+
+        let authenticator = config.auth.as_ref().map(auth::from_config).transpose()?;
+        let resolver = resolve::from_config(&config.resolver)?;
+        if let Some(status) = config.faults.as_ref().and_then(|f| f.reply_status) {
+            crate::proto::ServerStatus::try_from(status)?;
+        }
+        if let Some(dscp) = config.outbound.dscp {
+            crate::dial::check_dscp(dscp, "outbound")?;
+        }
+        if let Some(ports) = config.outbound.local_ports {
+            crate::dial::check_local_ports(ports)?;
+        }
+        if let Some(secs) = config.stats_interval_secs {
+            crate::stats::check_interval(secs)?;
+        }
+        let dialer = Dialer::new(config.outbound.clone());
+        let capture = config.capture.clone().map(Capture::create).transpose()?;
+        let priority = config.priority.clone().map(Scheduler::new).transpose()?;
+        let acl = config.acl.clone().map(Acl::new).transpose()?;
+        let port_policy = config.port_policy.clone().map(PortPolicy::new);
+        let blocklist = config.blocklist.as_ref().map(Blocklist::new).transpose()?;
+        let dnsbl = config.dnsbl.clone().map(Dnsbl::new).transpose()?;
+        let rate_limiter = config
+            .rate_limit
+            .clone()
+            .map(RateLimiter::new)
+            .transpose()?;
+        let dns_relay = config.udp.dns.clone().map(DnsRelay::new).transpose()?;
+        let top_talkers = config
+            .top_talkers
+            .as_ref()
+            .map(TopTalkers::new)
+            .transpose()?;
+        let mut listeners: Vec<NamedListener> = Vec::new();
+        for listener in &config.listeners {
+            if listeners.iter().any(|named| named.name == listener.name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("more than one listener is named {}", listener.name),
+                ));
+            }
+            listeners.push(NamedListener::new(listener)?);
+        }
+        let access_log = config
+            .access_log
+            .as_deref()
+            .map(|path| AccessLog::open(path, config.access_log_rotation.as_ref()))
+            .transpose()?;
+        #[cfg(unix)]
+        let syslog = config.syslog.as_ref().map(Syslog::new).transpose()?;
+        #[cfg(not(unix))]
+        if config.syslog.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "syslog is only supported on unix",
+            ));
+        }
+        let honeypot_log = config
+            .honeypot_log
+            .as_deref()
+            .map(HoneypotLog::open)
+            .transpose()?;
+
+        Ok(Self {
+            config,
+            #[cfg(feature = "scripting")]
+            route_script,
+            authenticator,
+            private_auth_methods: BTreeMap::new(),
+            listener_transport,
+            resolver,
+            dialer,
+            capture,
+            priority,
+            acl,
+            port_policy,
+            blocklist,
+            dnsbl,
+            rate_limiter,
+            dns_relay,
+            top_talkers,
+            listeners,
+            access_log,
+            #[cfg(unix)]
+            syslog,
+            honeypot_log,
+            listening: AtomicBool::new(false),
+            maintaining: AtomicBool::new(false),
+            stop_accepting: watch::Sender::new(false),
+            active_connections: AtomicUsize::new(0),
+            tarpitted: AtomicUsize::new(0),
+            cancel: CancellationToken::new(),
+            connections: Mutex::new(HashMap::new()),
+            connection_closed: Notify::new(),
+            next_connection_id: AtomicU64::new(1),
+            metrics: Metrics::default(),
+        })
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    #[cfg(feature = "scripting")]
+    pub(crate) fn route_script(&self) -> Option<&RouteScript> {
+        self.route_script.as_ref()
+    }
+
+    fn listener_named(&self, name: &str) -> Option<&NamedListener> {
+        self.listeners.iter().find(|listener| listener.name == name)
+    }
+
+    fn named_listener(&self, info: &ConnectionInfo) -> Option<&NamedListener> {
+        self.listener_named(info.listener?)
+    }
+
+    /// Whether the listener of the connection lets clients in without authenticating,
+    /// whatever the server requires elsewhere.
+    pub(crate) fn skips_auth(&self, info: &ConnectionInfo) -> bool {
+        self.named_listener(info)
+            .is_some_and(|listener| listener.no_auth)
+    }
+
+    /// The authenticator for username/password logins over the connection, its listener's
+    /// if it has one.
+    pub(crate) fn authenticator(&self, info: &ConnectionInfo) -> Option<&dyn Authenticator> {
+        match self.named_listener(info) {
+            Some(listener) if listener.no_auth => None,
+            Some(NamedListener {
+                authenticator: Some(authenticator),
+                ..
+            }) => Some(authenticator.as_ref()),
+            _ => self.authenticator.as_deref(),
+        }
+    }
+
+    /// Offers `method`, which has to be in the private range of 0x80 to 0xfe, to clients and
+    /// hands the connections of those that choose it to `handler`. Registered methods are
+    /// preferred over username/password authentication, and stop the server from accepting
+    /// unauthenticated clients.
+    pub fn register_auth_method(
+        &mut self,
+        method: u8,
+        handler: Box<dyn PrivateAuthMethod>,
+    ) -> io::Result<()> {
+        if !(0x80..=0xfe).contains(&method) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("auth method {method:#04x} is not in the private range 0x80 to 0xfe"),
+            ));
+        }
+        if self.private_auth_methods.contains_key(&method) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("auth method {method:#04x} is already registered"),
+            ));
+        }
+        self.private_auth_methods.insert(method, handler);
+        Ok(())
+    }
+
+    /// Runs every connection accepted by [`Server::serve`] through `transport` before the
+    /// socks handshake. Clients then have to connect through a matching transport, and can't
+    /// use SOCKS6 or the http proxy on that listener.
+    pub fn set_listener_transport(&mut self, transport: Box<dyn ListenerTransport>) {
+        self.listener_transport = Some(transport);
+    }
+
+    pub(crate) fn listener_transport(&self) -> Option<&dyn ListenerTransport> {
+        self.listener_transport.as_deref()
+    }
+
+    pub(crate) fn private_auth_methods(&self) -> &BTreeMap<u8, Box<dyn PrivateAuthMethod>> {
+        &self.private_auth_methods
+    }
+
+    pub(crate) fn resolver(&self) -> &dyn Resolver {
+        self.resolver.as_ref()
+    }
+
+    pub(crate) fn dialer(&self) -> &Dialer {
+        &self.dialer
+    }
+
+    pub(crate) fn capture(&self) -> Option<&Capture> {
+        self.capture.as_ref()
+    }
+
+    pub(crate) fn priority(&self) -> Option<&Scheduler> {
+        self.priority.as_ref()
+    }
+
+    /// The acl requests over the connection are checked against, its listener's if it has
+    /// one.
+    pub(crate) fn acl(&self, info: &ConnectionInfo) -> Option<&Acl> {
+        self.named_listener(info)
+            .and_then(|listener| listener.acl.as_ref())
+            .or(self.acl.as_ref())
+    }
+
+    /// Every acl of the server, along with the name of the listener it belongs to if it is
+    /// not the server's own.
+    pub(crate) fn acls(&self) -> impl Iterator<Item = (Option<&str>, &Acl)> {
+        let listeners = self
+            .listeners
+            .iter()
+            .filter_map(|listener| Some((Some(listener.name), listener.acl.as_ref()?)));
+        self.acl.iter().map(|acl| (None, acl)).chain(listeners)
+    }
+
+    /// The destination port policy for the connection, its listener's if it has one.
+    pub(crate) fn port_policy(&self, info: &ConnectionInfo) -> Option<&PortPolicy> {
+        self.named_listener(info)
+            .and_then(|listener| listener.port_policy.as_ref())
+            .or(self.port_policy.as_ref())
+    }
+
+    pub(crate) fn blocklist(&self) -> Option<&Blocklist> {
+        self.blocklist.as_ref()
+    }
+
+    pub(crate) fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_ref()
+    }
+
+    pub(crate) fn dnsbl(&self) -> Option<&Dnsbl> {
+        self.dnsbl.as_ref()
+    }
+
+    pub(crate) fn dns_relay(&self) -> Option<&DnsRelay> {
+        self.dns_relay.as_ref()
+    }
+
+    /// Waits out the tarpit delay, if one is configured, before a client is told that it
+    /// was denied or failed to authenticate. Fails instead when `max_clients` are waiting
+    /// already, so that the connection is closed without a reply rather than holding up one
+    /// more of the server's connection slots.
+    pub(crate) async fn tarpit(&self) -> io::Result<()> {
+        let Some(config) = &self.config.tarpit else {
+            return Ok(());
+        };
+        if self.tarpitted.fetch_add(1, Ordering::Relaxed) >= config.max_clients {
+            self.tarpitted.fetch_sub(1, Ordering::Relaxed);
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "tarpit full, closing instead of replying",
+            ));
+        }
+        struct Leave<'a>(&'a AtomicUsize);
+        impl Drop for Leave<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        let _leave = Leave(&self.tarpitted);
+        let jitter = random::below(config.jitter_ms + 1);
+        tokio::time::sleep(Duration::from_millis(config.delay_ms + jitter)).await;
+        Ok(())
+    }
+
+    pub(crate) fn access_log(&self) -> Option<&AccessLog> {
+        self.access_log.as_ref()
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn syslog(&self) -> Option<&Syslog> {
+        self.syslog.as_ref()
+    }
+
+    pub(crate) fn honeypot_log(&self) -> Option<&HoneypotLog> {
+        self.honeypot_log.as_ref()
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn top_talkers(&self) -> Option<&TopTalkers> {
+        self.top_talkers.as_ref()
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::Relaxed)
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn at_capacity(&self) -> bool {
+        self.config
+            .max_connections
+            .is_some_and(|max| self.active_connections() >= max)
+    }
+
+    /// Makes [`Server::serve`] return without accepting any more connections. Connections
+    /// that are already being handled are left running, see [`Server::drain`].
+    pub fn stop_accepting(&self) {
+        self.stop_accepting.send_replace(true);
+    }
+
+    /// The connections being handled right now, oldest first.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut infos: Vec<_> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|tracked| tracked.info)
+            .collect();
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+
+    /// The token that cancels the connection `id`, for as long as it is being handled.
+    /// Cancelling it drops the connection wherever it is, from the handshake to the relay.
+    pub fn connection_token(&self, id: ConnectionId) -> Option<CancellationToken> {
+        let connections = self.connections.lock().unwrap();
+        connections.get(&id).map(|tracked| tracked.cancel.clone())
+    }
+
+    /// Follows the bytes the connection `id` relays, for as long as it is being handled.
+    /// The value is updated as the relay moves data, not only when the connection closes.
+    pub fn connection_progress(&self, id: ConnectionId) -> Option<watch::Receiver<Progress>> {
+        let connections = self.connections.lock().unwrap();
+        connections
+            .get(&id)
+            .map(|tracked| tracked.progress.subscribe())
+    }
+
+    /// Where the relay of connection `id` publishes its progress. Connections handled
+    /// without being spawned by the server get one nobody follows.
+    pub(crate) fn progress_sender(&self, id: ConnectionId) -> Arc<watch::Sender<Progress>> {
+        let connections = self.connections.lock().unwrap();
+        connections.get(&id).map_or_else(
+            || Arc::new(watch::Sender::new(Progress::default())),
+            |tracked| Arc::clone(&tracked.progress),
+        )
+    }
+
+    /// Cancels the connection `id`, returning whether it was still being handled.
+    pub fn cancel_connection(&self, id: ConnectionId) -> bool {
+        self.connection_token(id)
+            .map(|cancel| cancel.cancel())
+            .is_some()
+    }
+
+    /// Stops accepting and cancels every connection, including any that race the listeners
+    /// shutting down. The server can't be used to serve again afterwards.
+    pub fn cancel(&self) {
+        self.stop_accepting();
+        self.cancel.cancel();
+    }
+
+    /// Shuts the server down for good, as on a termination signal: cancels every connection
+    /// like [`Server::cancel`], records each one it aborts in the log and the access log,
+    /// and once they are gone, flushes what the access log, honeypot log and capture have
+    /// buffered. Returns how many connections were aborted.
+    pub async fn terminate(&self) -> usize {
+        let aborted: Vec<(ConnectionInfo, Progress)> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|tracked| (tracked.info, *tracked.progress.borrow()))
+            .collect();
+        self.cancel();
+        for (info, progress) in &aborted {
+            eprintln!(
+                "{info}: terminated {} after {:?}, relayed {} bytes to remote, {} to client",
+                info.peer_addr,
+                info.accepted_at.elapsed(),
+                progress.to_remote,
+                progress.to_client,
+            );
+            if let Some(access_log) = &self.access_log {
+                access_log.record_terminated(info.id, info.peer_addr);
+            }
+            #[cfg(unix)]
+            if let Some(syslog) = &self.syslog {
+                syslog.record_terminated(info.id, info.peer_addr);
+            }
+        }
+
+        // cancelled connections end as soon as their tasks are polled again, this only
+        // guards against a runtime too busy to get to them
+        if tokio::time::timeout(Duration::from_secs(5), self.drain())
+            .await
+            .is_err()
+        {
+            eprintln!(
+                "terminate: {} connections still running",
+                self.active_connections()
+            );
+        }
+        let flushed = [
+            ("access log", self.access_log.as_ref().map(AccessLog::flush)),
+            (
+                "honeypot log",
+                self.honeypot_log.as_ref().map(HoneypotLog::flush),
+            ),
+            ("capture", self.capture.as_ref().map(Capture::flush)),
+        ];
+        for (name, res) in flushed {
+            if let Some(Err(err)) = res {
+                eprintln!("terminate: flushing the {name}: {err}");
+            }
+        }
+        aborted.len()
+    }
+
+    /// Waits until the server is below its connection limit, and the named listener called
+    /// `listener` below its own.
+    pub(crate) async fn wait_for_capacity(&self, listener: Option<&'static str>) {
+        let listener = listener.and_then(|name| self.listener_named(name));
+        loop {
+            let closed = self.connection_closed.notified();
+            if !self.at_capacity() && !listener.is_some_and(NamedListener::at_capacity) {
+                return;
+            }
+            closed.await;
+        }
+    }
+
+    /// Waits until every connection handled by this server has finished.
+    pub async fn drain(&self) {
+        loop {
+            let closed = self.connection_closed.notified();
+            if self.active_connections() == 0 {
+                return;
+            }
+            closed.await;
+        }
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        self.listening.store(true, Ordering::Relaxed);
+        let res = self.accept_loop(listener, Protocol::Socks, None).await;
+        self.listening.store(false, Ordering::Relaxed);
+        res
+    }
+
+    /// Serves the `[[listeners]]` entry called `name`, whose policy applies to the
+    /// connections it accepts.
+    pub async fn serve_named(self: Arc<Self>, listener: TcpListener, name: &str) -> io::Result<()> {
+        let Some(named) = self.listener_named(name) else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no listener named {name} is configured"),
+            ));
+        };
+        self.accept_loop(listener, Protocol::Socks, Some(named.name))
+            .await
+    }
+
+    /// Serves the dedicated listener of the `http_proxy` config section.
+    pub async fn serve_http_proxy(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        self.accept_loop(listener, Protocol::Http, None).await
+    }
+
+    /// Serves the listener of the `redirect` config section, see [`crate::redirect`].
+    pub async fn serve_redirect(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        self.accept_loop(listener, Protocol::Redirect, None).await
+    }
+
+    /// Serves socks sessions arriving as streams of QUIC connections, see [`crate::quic`].
+    /// Unlike a tcp listener, the endpoint is closed once the server stops accepting, which
+    /// also ends the sessions still running over it.
+    #[cfg(feature = "quic")]
+    pub async fn serve_quic(self: Arc<Self>, endpoint: quinn::Endpoint) -> io::Result<()> {
+        self.start_maintaining();
+        let local_addr = endpoint.local_addr()?;
+        let mut stop_accepting = self.stop_accepting.subscribe();
+        loop {
+            let incoming = tokio::select! {
+                incoming = endpoint.accept() => match incoming {
+                    Some(incoming) => incoming,
+                    None => return Ok(()),
+                },
+                _ = stop_accepting.wait_for(|&stop| stop) => {
+                    endpoint.close(0_u32.into(), b"shutting down");
+                    return Ok(());
+                }
+            };
+            let server = Arc::clone(&self);
+            tokio::spawn(async move {
+                let peer_addr = incoming.remote_address();
+                if let Err(err) = crate::quic::serve_connection(&server, incoming, local_addr).await
+                {
+                    eprintln!("quic connection from {peer_addr}: {err}");
+                }
+            });
+        }
+    }
+
+    async fn accept_loop(
+        self: &Arc<Self>,
+        listener: TcpListener,
+        protocol: Protocol,
+        name: Option<&'static str>,
+    ) -> io::Result<()> {
+        self.start_maintaining();
+        let named = name.and_then(|name| self.listener_named(name));
+        let mut stop_accepting = self.stop_accepting.subscribe();
+        loop {
+            if self.at_capacity() {
+                eprintln!("connection limit reached, leaving new connections in the backlog");
+            } else if named.is_some_and(NamedListener::at_capacity) {
+                eprintln!(
+                    "connection limit of listener {} reached, leaving new connections in the \
+                     backlog",
+                    name.unwrap_or_default()
+                );
+            }
+            // connections beyond the limit wait in the kernel's backlog rather than being
+            // accepted only to be closed again
+            let accept = async {
+                self.wait_for_capacity(name).await;
+                listener.accept().await
+            };
+            let (stream, peer_addr) = tokio::select! {
+                res = accept => res?,
+                _ = stop_accepting.wait_for(|&stop| stop) => return Ok(()),
+            };
+            self.spawn_connection(peer_addr, name, move |server, info| async move {
+                match protocol {
+                    Protocol::Socks => tcp_server_stream::handle(&server, stream, info).await,
+                    Protocol::Http => {
+                        http_proxy::handle(&server, ClientConn::Tcp(stream), info).await
+                    }
+                    Protocol::Redirect => redirect::handle(&server, stream, info).await,
+                }
+            });
+        }
+    }
+
+    /// Starts pruning the expired entries of the server's caches every [`SWEEP_INTERVAL`],
+    /// unless that is happening already or no caches are kept, for as long as the server is
+    /// around. Entries are otherwise only replaced, never removed, so destinations and
+    /// clients seen once would stay.
+    ///
+    /// Also starts checking the blocklist files for changes every `reload_interval_secs`, and
+    /// the route script every [`RELOAD_INTERVAL`](crate::script::RELOAD_INTERVAL), reloading
+    /// them on a blocking thread, as reading them could hold up connections.
+    fn start_maintaining(self: &Arc<Self>) {
+        if self.maintaining.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let caching = self.config.outbound.failure_cache_secs.is_some()
+            || self.dnsbl.is_some()
+            || self.rate_limiter.is_some();
+        if caching {
+            self.every(SWEEP_INTERVAL, |server| async move {
+                server.dialer.sweep();
+                if let Some(dnsbl) = &server.dnsbl {
+                    dnsbl.sweep();
+                }
+                if let Some(rate_limiter) = &server.rate_limiter {
+                    rate_limiter.sweep();
+                }
+            });
+        }
+        if let Some(blocklist) = &self.blocklist {
+            self.every(blocklist.reload_interval(), |server| async move {
+                let reloading = tokio::task::spawn_blocking(move || {
+                    if let Some(blocklist) = &server.blocklist {
+                        blocklist.reload_if_changed();
+                    }
+                });
+                let _ = reloading.await;
+            });
+        }
+        #[cfg(feature = "scripting")]
+        if self.route_script.is_some() {
+            self.every(crate::script::RELOAD_INTERVAL, |server| async move {
+                let reloading = tokio::task::spawn_blocking(move || {
+                    if let Some(script) = &server.route_script {
+                        script.reload_if_changed();
+                    }
+                });
+                let _ = reloading.await;
+            });
+        }
+    }
+
+    /// Runs `task` every `period`, starting after the first, until the server is dropped.
+    fn every<F, Fut>(self: &Arc<Self>, period: Duration, task: F)
+    where
+        F: Fn(Arc<Self>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let server = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = time::interval(period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let Some(server) = server.upgrade() else {
+                    return;
+                };
+                task(server).await;
+            }
+        });
+    }
+
+    /// Assigns the next connection id and runs `handle` for it in its own task, unless the
+    /// server is at capacity, which it can be despite waiting for capacity before accepting
+    /// when several listeners share the limit. Called right after accepting, which
+    /// `accepted_at` records, by the listener called `listener` if it is a named one.
+    ///
+    /// A panic in `handle` ends the connection like an error would, closing the client's
+    /// socket as the handler is dropped, rather than taking the task down before the
+    /// connection is untracked.
+    pub(crate) fn spawn_connection<F>(
+        self: &Arc<Self>,
+        peer_addr: SocketAddr,
+        listener: Option<&'static str>,
+        handle: impl FnOnce(Arc<Self>, ConnectionInfo) -> F,
+    ) where
+        F: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        self.metrics
+            .connections_accepted
+            .fetch_add(1, Ordering::Relaxed);
+        let info = ConnectionInfo {
+            id: ConnectionId(self.next_connection_id.fetch_add(1, Ordering::Relaxed)),
+            peer_addr,
+            accepted_at: Instant::now(),
+            listener,
+        };
+        if self.at_capacity() {
+            eprintln!("{info}: rejecting {peer_addr}: connection limit reached");
+            return;
+        }
+
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        if let Some(named) = self.named_listener(&info) {
+            named.active_connections.fetch_add(1, Ordering::Relaxed);
+        }
+        let cancel = self.cancel.child_token();
+        self.connections.lock().unwrap().insert(
+            info.id,
+            Tracked {
+                info,
+                cancel: cancel.clone(),
+                progress: Arc::new(watch::Sender::new(Progress::default())),
+            },
+        );
+        let handling = handle(Arc::clone(self), info);
+        let server = Arc::clone(self);
+        tokio::spawn(async move {
+            let res = AssertUnwindSafe(cancel.run_until_cancelled(handling))
+                .catch_unwind()
+                .await
+                .unwrap_or_else(|panic| {
+                    server
+                        .metrics
+                        .connection_panics
+                        .fetch_add(1, Ordering::Relaxed);
+                    let msg = panic
+                        .downcast_ref::<&str>()
+                        .copied()
+                        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                        .unwrap_or("non-string payload");
+                    Some(Err(io::Error::other(format!("handler panicked: {msg}"))))
+                })
+                .unwrap_or_else(|| {
+                    Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "connection cancelled",
+                    ))
+                });
+            server.connections.lock().unwrap().remove(&info.id);
+            if let Err(err) = res {
+                server.metrics.connection_failed(&err);
+                let msg = format!(
+                    "{info}: handle_stream from {peer_addr} after {:?}: {err:?}",
+                    info.accepted_at.elapsed()
+                );
+                eprintln!("{msg}");
+                #[cfg(unix)]
+                if let Some(syslog) = &server.syslog {
+                    syslog.error(&msg);
+                }
+            }
+            if let Some(named) = server.named_listener(&info) {
+                named.active_connections.fetch_sub(1, Ordering::Relaxed);
+            }
+            server.active_connections.fetch_sub(1, Ordering::Relaxed);
+            server.connection_closed.notify_waiters();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time::timeout,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn leaves_connections_beyond_the_limit_in_the_backlog() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let server = Arc::new(
+            Server::new(ServerConfig {
+                max_connections: Some(1),
+                ..ServerConfig::default()
+            })
+            .unwrap(),
+        );
+        tokio::spawn(Arc::clone(&server).serve(listener));
+
+        let mut first = TcpStream::connect(proxy_addr).await.unwrap();
+        first.write_all(&[5, 1, 0]).await.unwrap();
+        let mut choice = [0_u8; 2];
+        first.read_exact(&mut choice).await.unwrap();
+
+        let mut second = TcpStream::connect(proxy_addr).await.unwrap();
+        second.write_all(&[5, 1, 0]).await.unwrap();
+        let waiting = timeout(Duration::from_millis(200), second.read_exact(&mut choice)).await;
+        assert!(waiting.is_err(), "second connection was served or closed");
+
+        drop(first);
+        second.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [5, 0]);
+    }
+
+    /// Starts a destination echoing whatever it receives and returns its port.
+    async fn spawn_echo() -> u16 {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = echo.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut rd, mut wr) = conn.split();
+                    let _ = io::copy(&mut rd, &mut wr).await;
+                });
+            }
+        });
+        echo_port
+    }
+
+    /// Connects through the proxy to the echo destination on `echo_port`.
+    async fn connect_through(proxy_addr: SocketAddr, echo_port: u16) -> TcpStream {
+        let mut conn = TcpStream::connect(proxy_addr).await.unwrap();
+        conn.write_all(&[5, 1, 0]).await.unwrap();
+        conn.write_all(&[5, 1, 0, 1, 127, 0, 0, 1]).await.unwrap();
+        conn.write_all(&echo_port.to_be_bytes()).await.unwrap();
+        let mut replies = [0_u8; 12];
+        conn.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[3], 0);
+        conn
+    }
+
+    #[tokio::test]
+    async fn closes_and_untracks_connections_whose_handler_panics() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(Server::new(ServerConfig::default()).unwrap());
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (stream, peer_addr) = listener.accept().await.unwrap();
+
+        server.spawn_connection(peer_addr, None, move |_, _| async move {
+            let _stream = stream;
+            panic!("bug");
+        });
+        let mut buf = [0_u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        timeout(Duration::from_secs(1), server.drain())
+            .await
+            .unwrap();
+        assert!(server.connections().is_empty());
+        let metrics = server.metrics();
+        assert_eq!(metrics.connection_panics.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.connection_errors()[&io::ErrorKind::Other], 1);
+    }
+
+    #[tokio::test]
+    async fn cancels_connections_mid_relay_and_all_at_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let server = Arc::new(Server::new(ServerConfig::default()).unwrap());
+        tokio::spawn(Arc::clone(&server).serve(listener));
+        let echo_port = spawn_echo().await;
+
+        let mut relayed = connect_through(proxy_addr, echo_port).await;
+        relayed.write_all(b"ping").await.unwrap();
+        let mut buf = [0_u8; 4];
+        relayed.read_exact(&mut buf).await.unwrap();
+
+        let connections = server.connections();
+        assert_eq!(connections.len(), 1);
+        assert!(server.cancel_connection(connections[0].id));
+        assert_eq!(relayed.read(&mut buf).await.unwrap(), 0);
+        timeout(Duration::from_secs(5), server.drain())
+            .await
+            .unwrap();
+        assert!(!server.cancel_connection(connections[0].id));
+
+        let mut greeting = TcpStream::connect(proxy_addr).await.unwrap();
+        greeting.write_all(&[5, 1, 0]).await.unwrap();
+        greeting.read_exact(&mut buf[..2]).await.unwrap();
+        server.cancel();
+        assert_eq!(greeting.read(&mut buf).await.unwrap(), 0);
+        timeout(Duration::from_secs(5), server.drain())
+            .await
+            .unwrap();
+        assert!(server.connections().is_empty());
+    }
+
+    #[tokio::test]
+    async fn records_connections_aborted_by_terminating() {
+        let log_path =
+            std::env::temp_dir().join(format!("socks5-terminate-{}.log", std::process::id()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let server = Arc::new(
+            Server::new(ServerConfig {
+                access_log: Some(log_path.clone()),
+                ..ServerConfig::default()
+            })
+            .unwrap(),
+        );
+        tokio::spawn(Arc::clone(&server).serve(listener));
+        let echo_port = spawn_echo().await;
+
+        let mut relayed = connect_through(proxy_addr, echo_port).await;
+        let id = server.connections()[0].id;
+        assert_eq!(server.terminate().await, 1);
+        let mut buf = [0_u8; 4];
+        assert_eq!(relayed.read(&mut buf).await.unwrap(), 0);
+        assert!(server.connections().is_empty());
+
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(&format!(" {id} ")) && lines[0].ends_with(" ok"));
+        assert!(lines[1].contains(&format!(" {id} ")) && lines[1].ends_with(" - - - - terminated"));
+        std::fs::remove_file(&log_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn publishes_progress_while_relaying() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let server = Arc::new(Server::new(ServerConfig::default()).unwrap());
+        tokio::spawn(Arc::clone(&server).serve(listener));
+        let echo_port = spawn_echo().await;
+
+        let mut relayed = connect_through(proxy_addr, echo_port).await;
+        let id = server.connections()[0].id;
+        let mut progress = server.connection_progress(id).unwrap();
+        let mut buf = [0_u8; 4];
+        for round in 1..=3 {
+            // progress is published at most once an interval
+            tokio::time::sleep(crate::metrics::PROGRESS_INTERVAL).await;
+            relayed.write_all(b"ping").await.unwrap();
+            relayed.read_exact(&mut buf).await.unwrap();
+            let expected = (4 * round, 4 * round);
+            timeout(
+                Duration::from_secs(5),
+                progress.wait_for(|progress| (progress.to_remote, progress.to_client) == expected),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_the_policy_of_the_listener_a_connection_came_in_on() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            [auth.users.alice]
+            password = "secret"
+
+            [[listeners]]
+            name = "lan"
+            listen_addr = "127.0.0.1:0"
+            no_auth = true
+            max_connections = 1
+
+            [[listeners]]
+            name = "wan"
+            listen_addr = "127.0.0.1:0"
+            no_auth = true
+            [listeners.acl]
+            default = "deny"
+            "#,
+        )
+        .unwrap();
+        let server = Arc::new(Server::new(config).unwrap());
+        let main = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let lan = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let wan = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (main_addr, lan_addr, wan_addr) = (
+            main.local_addr().unwrap(),
+            lan.local_addr().unwrap(),
+            wan.local_addr().unwrap(),
+        );
+        tokio::spawn(Arc::clone(&server).serve(main));
+        tokio::spawn(Arc::clone(&server).serve_named(lan, "lan"));
+        tokio::spawn(Arc::clone(&server).serve_named(wan, "wan"));
+        let echo_port = spawn_echo().await;
+        let unknown = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let err = Arc::clone(&server)
+            .serve_named(unknown, "dmz")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        // the server requires a login, which lan waives
+        let mut choice = [0_u8; 2];
+        let mut unauthenticated = TcpStream::connect(main_addr).await.unwrap();
+        unauthenticated.write_all(&[5, 1, 0]).await.unwrap();
+        unauthenticated.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [5, 0xff]);
+
+        let mut relayed = connect_through(lan_addr, echo_port).await;
+        relayed.write_all(b"ping").await.unwrap();
+        let mut buf = [0_u8; 4];
+        relayed.read_exact(&mut buf).await.unwrap();
+        let connections = server.connections();
+        let info = connections
+            .iter()
+            .find(|info| info.listener.is_some())
+            .unwrap();
+        assert_eq!(info.to_string(), format!("{} on lan", info.id));
+
+        // lan takes one connection at a time
+        let mut second = TcpStream::connect(lan_addr).await.unwrap();
+        second.write_all(&[5, 1, 0]).await.unwrap();
+        let waiting = timeout(Duration::from_millis(200), second.read_exact(&mut choice)).await;
+        assert!(waiting.is_err(), "lan served beyond its limit");
+        drop(relayed);
+        second.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [5, 0]);
+
+        // wan's acl denies everything
+        let mut denied = TcpStream::connect(wan_addr).await.unwrap();
+        denied.write_all(&[5, 1, 0]).await.unwrap();
+        denied.write_all(&[5, 1, 0, 1, 127, 0, 0, 1]).await.unwrap();
+        denied.write_all(&echo_port.to_be_bytes()).await.unwrap();
+        let mut replies = [0_u8; 12];
+        denied.read_exact(&mut replies).await.unwrap();
+        assert_eq!(&replies[..2], [5, 0]);
+        assert_eq!(
+            replies[3],
+            crate::proto::ServerStatus::ConnectionNotAllowedByRuleset as u8
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! A periodic summary of what the server has been doing, logged for operators who read the
+//! log rather than scrape `/metrics`. Rates are averaged over the interval since the last
+//! summary. Relays gone stalled are counted, and found among `/connections` on the health
+//! listener by their throughput.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use tokio::{
+    io,
+    time::{self, Instant, MissedTickBehavior},
+};
+
+use crate::server::Server;
+
+/// how many kinds of errors a summary names
+const TOP_ERRORS: usize = 3;
+
+/// throughput in both directions below which a relay that moved data counts as stalled
+const STALLED_BYTES_PER_SEC: f64 = 1.0;
+
+/// Rejects an interval of no time, which would have summaries logged without pause.
+pub(crate) fn check_interval(secs: u64) -> io::Result<()> {
+    if secs == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "stats_interval_secs must be at least a second",
+        ));
+    }
+    Ok(())
+}
+
+/// Logs a summary every `interval`, which must not be zero, forever.
+pub async fn log_periodically(server: Arc<Server>, interval: Duration) {
+    let mut ticks = time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // the first tick completes right away
+    ticks.tick().await;
+    let mut last = Sample::take(&server);
+    loop {
+        ticks.tick().await;
+        let sample = Sample::take(&server);
+        eprintln!(
+            "stats: {}",
+            Summary {
+                active: server.active_connections(),
+                stalled: stalled(&server),
+                from: &last,
+                to: &sample,
+            }
+        );
+        last = sample;
+    }
+}
+
+/// Logs the server's counters since it started, for a last word before exiting.
+pub fn log_totals(server: &Server) {
+    eprintln!("stats: totals: {}", Totals(&Sample::take(server)));
+}
+
+/// How many connections relayed data at some point, but have all but stopped.
+fn stalled(server: &Server) -> usize {
+    server
+        .connections()
+        .iter()
+        .filter_map(|info| server.connection_progress(info.id))
+        .filter(|progress| {
+            let progress = progress.borrow();
+            progress.to_remote + progress.to_client > 0
+                && progress.rate_to_remote.bytes_per_sec() < STALLED_BYTES_PER_SEC
+                && progress.rate_to_client.bytes_per_sec() < STALLED_BYTES_PER_SEC
+        })
+        .count()
+}
+
+/// The server's counters at one point in time.
+struct Sample {
+    at: Instant,
+    accepted: u64,
+    bytes_to_remote: u64,
+    bytes_to_client: u64,
+    errors: HashMap<io::ErrorKind, u64>,
+}
+
+impl Sample {
+    fn take(server: &Server) -> Self {
+        let metrics = server.metrics();
+        Self {
+            at: Instant::now(),
+            accepted: metrics.connections_accepted.load(Ordering::Relaxed),
+            bytes_to_remote: metrics.relay.bytes_to_remote.load(Ordering::Relaxed),
+            bytes_to_client: metrics.relay.bytes_to_client.load(Ordering::Relaxed),
+            errors: metrics.connection_errors(),
+        }
+    }
+}
+
+struct Summary<'a> {
+    active: usize,
+    stalled: usize,
+    from: &'a Sample,
+    to: &'a Sample,
+}
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            active,
+            stalled,
+            from,
+            to,
+        } = self;
+        let secs = (to.at - from.at).as_secs_f64().max(f64::EPSILON);
+        let rate = |from: u64, to: u64| (to - from) as f64 / secs;
+        write!(
+            f,
+            "{active} active, {stalled} stalled, {:.1} accepted/s, \
+             {} to remote, {} to client, errors: ",
+            rate(from.accepted, to.accepted),
+            Throughput(rate(from.bytes_to_remote, to.bytes_to_remote)),
+            Throughput(rate(from.bytes_to_client, to.bytes_to_client)),
+        )?;
+        let errors = to
+            .errors
+            .iter()
+            .map(|(kind, n)| (*kind, n - from.errors.get(kind).unwrap_or(&0)))
+            .collect();
+        write_errors(f, errors)
+    }
+}
+
+struct Totals<'a>(&'a Sample);
+
+impl fmt::Display for Totals<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self(sample) = self;
+        write!(
+            f,
+            "{} accepted, {} bytes to remote, {} bytes to client, errors: ",
+            sample.accepted, sample.bytes_to_remote, sample.bytes_to_client,
+        )?;
+        write_errors(f, sample.errors.iter().map(|(k, n)| (*k, *n)).collect())
+    }
+}
+
+/// Writes the most common kinds of errors, by count.
+fn write_errors(f: &mut fmt::Formatter<'_>, errors: Vec<(io::ErrorKind, u64)>) -> fmt::Result {
+    let mut errors: Vec<_> = errors.into_iter().filter(|(_, n)| *n > 0).collect();
+    if errors.is_empty() {
+        return write!(f, "none");
+    }
+    errors.sort_by(|(a_kind, a), (b_kind, b)| b.cmp(a).then(a_kind.cmp(b_kind)));
+    for (i, (kind, n)) in errors.iter().take(TOP_ERRORS).enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{n} {kind:?}")?;
+    }
+    if errors.len() > TOP_ERRORS {
+        let rest: u64 = errors[TOP_ERRORS..].iter().map(|(_, n)| n).sum();
+        write!(f, ", {rest} other")?;
+    }
+    Ok(())
+}
+
+/// Bytes per second, in binary units.
+pub(crate) struct Throughput(pub(crate) f64);
+
+impl fmt::Display for Throughput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 4] = ["B/s", "KiB/s", "MiB/s", "GiB/s"];
+        let mut value = self.0;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        write!(f, "{value:.1} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_rates_and_the_most_common_errors_of_the_interval() {
+        let from = Sample {
+            at: Instant::now(),
+            accepted: 100,
+            bytes_to_remote: 0,
+            bytes_to_client: 1 << 20,
+            errors: HashMap::from([(io::ErrorKind::TimedOut, 7)]),
+        };
+        let to = Sample {
+            at: from.at + Duration::from_secs(10),
+            accepted: 125,
+            bytes_to_remote: 5 << 10,
+            bytes_to_client: 101 << 20,
+            errors: HashMap::from([
+                (io::ErrorKind::TimedOut, 8),
+                (io::ErrorKind::ConnectionRefused, 4),
+                (io::ErrorKind::ConnectionReset, 2),
+                (io::ErrorKind::UnexpectedEof, 1),
+            ]),
+        };
+        let summary = Summary {
+            active: 3,
+            stalled: 1,
+            from: &from,
+            to: &to,
+        };
+        assert_eq!(
+            summary.to_string(),
+            "3 active, 1 stalled, 2.5 accepted/s, 512.0 B/s to remote, 10.0 MiB/s to client, \
+             errors: 4 ConnectionRefused, 2 ConnectionReset, 1 TimedOut, 1 other"
+        );
+    }
+}
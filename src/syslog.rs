@@ -0,0 +1,206 @@
+//! Sends access records and connection errors to the local syslog daemon, configured as
+//! `syslog`.
+//!
+//! Messages follow RFC 5424, one datagram each, with the configured facility and
+//! `access` or `error` as their MSGID. Access messages carry the same fields as lines of the
+//! access log, after the time the header already has. Messages the daemon doesn't take are
+//! written to stderr instead, so they aren't lost while it restarts.
+
+use std::{ffi::CStr, net::SocketAddr, os::unix::net::UnixDatagram, path::PathBuf, process};
+
+use jiff::Timestamp;
+use tokio::io;
+
+use crate::{
+    access_log::{self, AccessRecord},
+    config::SyslogConfig,
+    server::ConnectionId,
+};
+
+const MAX_APP_NAME_LEN: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Severity {
+    Error = 3,
+    Info = 6,
+}
+
+pub struct Syslog {
+    socket: UnixDatagram,
+    path: PathBuf,
+    /// the PRI of messages less their severity
+    facility: u8,
+    hostname: String,
+    app_name: String,
+    access: bool,
+    errors: bool,
+}
+
+impl Syslog {
+    pub fn new(config: &SyslogConfig) -> io::Result<Self> {
+        check_app_name(&config.app_name)?;
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(&config.socket).map_err(|err| {
+            io::Error::new(err.kind(), format!("{}: {err}", config.socket.display()))
+        })?;
+        // a daemon that falls behind shouldn't hold up connections
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            path: config.socket.clone(),
+            facility: config.facility as u8 * 8,
+            hostname: hostname().unwrap_or_else(|| "-".to_owned()),
+            app_name: config.app_name.clone(),
+            access: config.access,
+            errors: config.errors,
+        })
+    }
+
+    /// Logs `record` like [`access_log::AccessLog::record`], if access messages are enabled.
+    pub fn record(&self, record: &AccessRecord) {
+        if self.access {
+            self.send(Severity::Info, "access", &access_log::format_fields(record));
+        }
+    }
+
+    /// Logs that connection `id` was aborted by the server terminating, if access messages
+    /// are enabled.
+    pub fn record_terminated(&self, id: ConnectionId, client: SocketAddr) {
+        if self.access {
+            self.send(
+                Severity::Info,
+                "access",
+                &access_log::terminated_fields(id, client),
+            );
+        }
+    }
+
+    /// Logs a connection that ended in an error, if error messages are enabled.
+    pub fn error(&self, msg: &str) {
+        if self.errors {
+            self.send(Severity::Error, "error", msg);
+        }
+    }
+
+    fn send(&self, severity: Severity, msgid: &str, msg: &str) {
+        let line = self.format(Timestamp::now(), severity, msgid, msg);
+        // the daemon may have restarted since, and bound a new socket at the path
+        let sent = self.socket.send(line.as_bytes()).or_else(|_| {
+            self.socket.connect(&self.path)?;
+            self.socket.send(line.as_bytes())
+        });
+        if let Err(err) = sent {
+            eprintln!(
+                "syslog: sending to {} failed: {err}: {line}",
+                self.path.display()
+            );
+        }
+    }
+
+    fn format(&self, now: Timestamp, severity: Severity, msgid: &str, msg: &str) -> String {
+        format!(
+            "<{}>1 {} {} {} {} {msgid} - {msg}",
+            self.facility + severity as u8,
+            now.strftime("%Y-%m-%dT%H:%M:%S%.6fZ"),
+            self.hostname,
+            self.app_name,
+            process::id(),
+        )
+    }
+}
+
+fn check_app_name(app_name: &str) -> io::Result<()> {
+    if app_name.is_empty()
+        || app_name.len() > MAX_APP_NAME_LEN
+        || !app_name.bytes().all(|b| b.is_ascii_graphic())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "syslog app_name {app_name:?} must be 1 to {MAX_APP_NAME_LEN} printable ascii \
+                 characters"
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The name of this host, if it fits a syslog header.
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its length, which leaves room for the terminating nul
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len() - 1) };
+    if ret != 0 {
+        return None;
+    }
+    let name = CStr::from_bytes_until_nul(&buf).ok()?.to_str().ok()?;
+    let fits = !name.is_empty() && name.len() <= 255 && name.bytes().all(|b| b.is_ascii_graphic());
+    fits.then(|| name.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+    use crate::{config::SyslogFacility, proto};
+
+    #[test]
+    fn sends_rfc5424_messages_to_the_daemon_socket() {
+        let dir = env::temp_dir().join(format!("socks5-syslog-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log.sock");
+        let _ = fs::remove_file(&path);
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        let syslog = Syslog::new(&SyslogConfig {
+            socket: path,
+            facility: SyslogFacility::Local3,
+            app_name: "proxy".to_owned(),
+            access: true,
+            errors: false,
+        })
+        .unwrap();
+
+        let request = proto::ClientConnectionRequest {
+            cmd: proto::ClientCommand::EstablishConnection,
+            dest_addr: proto::Address::DomainName("example.com".to_owned()),
+            dest_port: 443,
+        };
+        let record = AccessRecord {
+            id: ConnectionId(7),
+            client: "192.0.2.1:50000".parse().unwrap(),
+            username: None,
+            request: &request,
+            connected: None,
+            result: Ok(()),
+        };
+        syslog.error("not sent");
+        syslog.record(&record);
+        let mut buf = [0u8; 1024];
+        let len = daemon.recv(&mut buf).unwrap();
+        let msg = std::str::from_utf8(&buf[..len]).unwrap();
+        assert!(msg.starts_with("<158>1 "), "{msg}");
+        let pid = process::id();
+        assert!(
+            msg.ends_with(&format!(
+                " proxy {pid} access - conn-7 192.0.2.1:50000 - CONNECT example.com:443 - ok"
+            )),
+            "{msg}"
+        );
+
+        let now = "2024-01-03T10:00:00.5Z".parse().unwrap();
+        assert_eq!(
+            syslog.format(now, Severity::Error, "error", "conn-7: failed"),
+            format!(
+                "<155>1 2024-01-03T10:00:00.500000Z {} proxy {pid} error - conn-7: failed",
+                syslog.hostname
+            )
+        );
+
+        assert!(check_app_name("socks5").is_ok());
+        assert!(check_app_name("").is_err());
+        assert!(check_app_name("with space").is_err());
+        assert!(check_app_name(&"a".repeat(49)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
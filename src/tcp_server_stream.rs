@@ -1,36 +1,281 @@
 mod async_proto;
-mod copy;
+mod conn;
+#[cfg(target_os = "linux")]
+pub(crate) mod copy;
+#[cfg(all(unix, feature = "gssapi"))]
+mod gssapi;
+mod socks6;
+mod teardown;
+mod udp;
+#[cfg(target_os = "linux")]
+pub(crate) mod zerocopy;
+
+use std::{future::Future, sync::Arc, time::Duration};
 
 use futures::future::TryFutureExt;
 use tokio::{
-    io::{self, copy_bidirectional, AsyncWriteExt},
+    io::{self, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    sync::watch,
+    time,
 };
 
-use crate::proto;
+pub(crate) use conn::ClientConn;
+use teardown::Teardown;
+
+use crate::{
+    access_log::AccessRecord,
+    acl::RuleMetrics,
+    auth::{Authenticator, Grant},
+    config::{AclAction, DnsblAction},
+    dial,
+    faults::{self, Phase},
+    honeypot::DeniedRecord,
+    http_proxy,
+    metrics::{ConnectionRelay, Progress},
+    proto,
+    relay::{self, RelayOptions},
+    server::{ConnectionInfo, Server},
+};
 
 struct WaitingForGreeting {
-    stream: TcpStream,
+    stream: ClientConn,
     greeting: proto::ClientGreeting,
 }
 struct WaitingForConnectRequest {
-    stream: TcpStream,
+    stream: ClientConn,
+    /// the methods the client's greeting offered, for the honeypot log
+    auth_methods: Vec<proto::AuthMethod>,
+    username: Option<String>,
+    grant: Grant,
+    protection: Protection,
 }
-struct ServingConnectRequest {
-    stream: TcpStream,
+struct ServingConnectRequest<'s> {
+    stream: ClientConn,
     request: proto::ClientConnectionRequest,
+    auth_methods: Vec<proto::AuthMethod>,
+    /// the user the client authenticated as, if authentication is required
+    username: Option<String>,
+    /// what the client's credentials entitle it to
+    grant: Grant,
+    /// the counters of the acl rule that allowed the request, once it was checked
+    acl_rule: Option<&'s RuleMetrics>,
+    protection: Protection,
+}
+
+/// The request a relay serves, and what the traffic it relays counts towards.
+pub(crate) struct Requested<'a> {
+    pub(crate) request: &'a proto::ClientConnectionRequest,
+    /// the user the client authenticated as, whose byte quota the traffic uses up
+    pub(crate) username: Option<&'a str>,
+    /// the counters of the acl rule that allowed the request
+    pub(crate) acl_rule: Option<&'a RuleMetrics>,
+}
+
+/// The per-message protection negotiated along with GSSAPI authentication, which every
+/// message after the method negotiation has to pass through. Other methods have none.
+#[derive(Default)]
+struct Protection {
+    #[cfg(all(unix, feature = "gssapi"))]
+    session: Option<gssapi::Session>,
+}
+
+impl Protection {
+    fn is_active(&self) -> bool {
+        #[cfg(all(unix, feature = "gssapi"))]
+        let active = self.session.is_some();
+        #[cfg(not(all(unix, feature = "gssapi")))]
+        let active = false;
+        active
+    }
+
+    async fn read_request(
+        &self,
+        stream: &mut ClientConn,
+        max_len: Option<usize>,
+    ) -> io::Result<proto::ClientConnectionRequest> {
+        #[cfg(all(unix, feature = "gssapi"))]
+        if let Some(session) = &self.session {
+            let msg = session.read(stream).await?;
+            return proto::ClientConnectionRequest::decode(&mut msg.as_slice());
+        }
+        async_proto::read_message(stream, max_len).await
+    }
+
+    async fn reply(
+        &self,
+        server: &Server,
+        stream: &mut ClientConn,
+        resp: &proto::ServerResponse,
+    ) -> io::Result<()> {
+        let resp = resp.as_bytes();
+        let resp = faults::inject(server.config().faults.as_ref(), Phase::Request, &resp).await?;
+        #[cfg(all(unix, feature = "gssapi"))]
+        if let Some(session) = &self.session {
+            return session.write(stream, &resp).await;
+        }
+        stream.write_all(&resp).await
+    }
+}
+
+pub async fn handle(server: &Server, stream: TcpStream, info: ConnectionInfo) -> io::Result<()> {
+    let stream = match server.listener_transport() {
+        Some(transport) => {
+            within_handshake(server, info, ClientConn::wrap(stream, transport)).await?
+        }
+        None => ClientConn::Tcp(stream),
+    };
+    handle_conn(server, stream, info, None).await
+}
+
+/// Serves a client connection that is already past any transport. `certified` is the user
+/// the transport authenticated the client as, by its certificate, if it did.
+pub(crate) async fn handle_conn(
+    server: &Server,
+    stream: ClientConn,
+    info: ConnectionInfo,
+    certified: Option<String>,
+) -> io::Result<()> {
+    let http_proxy = server
+        .config()
+        .http_proxy
+        .as_ref()
+        .is_some_and(|http| http.listen_addr.is_none());
+    let mut first = [0_u8];
+    within_handshake(server, info, check_reputation(server, info)).await?;
+    // other protocols are told apart by their first byte, which transports keep hidden
+    if let ClientConn::Tcp(tcp) = &stream {
+        if server.config().socks6 || http_proxy {
+            within_handshake(server, info, tcp.peek(&mut first)).await?;
+        }
+    }
+    match first[0] {
+        socks6::VERSION if server.config().socks6 => {
+            return socks6::serve(server, stream, info).await
+        }
+        // the first letter of an http method
+        b'A'..=b'Z' if http_proxy => return http_proxy::handle(server, stream, info).await,
+        _ => {}
+    }
+    let timeouts = &server.config().handshake_timeouts;
+    let handshake = within(
+        timeouts.greeting_secs,
+        "greeting",
+        read_client_greeting(server, stream),
+    )
+    .and_then(|state| {
+        within(
+            timeouts.auth_secs,
+            "auth",
+            choose_auth_method(server, state, info, certified),
+        )
+    })
+    .and_then(|state| {
+        within(
+            timeouts.request_secs,
+            "request",
+            read_connect_request(server, state),
+        )
+    });
+    // the policies are checked against where the route script sends the request
+    within_handshake(server, info, handshake)
+        .and_then(|state| route_connect_request(server, state, info))
+        .and_then(|state| check_policy(server, state, info))
+        .and_then(|state| serve_connect_request(server, state, info))
+        .await
+}
+
+/// Turns away or holds up clients listed in the configured dnsbl zones, before anything is
+/// read from them.
+async fn check_reputation(server: &Server, info: ConnectionInfo) -> io::Result<()> {
+    let Some(dnsbl) = server.dnsbl() else {
+        return Ok(());
+    };
+    let Some(zone) = dnsbl.listing(server.resolver(), info.peer_addr.ip()).await else {
+        return Ok(());
+    };
+    match dnsbl.config().action {
+        DnsblAction::Reject => {
+            record_denied(
+                server,
+                &DeniedRecord {
+                    info,
+                    protocol: "-",
+                    auth_methods: &[],
+                    username: None,
+                    request: None,
+                    reason: &format!("listed in dnsbl {zone}"),
+                },
+            );
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("client is listed in dnsbl {zone}"),
+            ))
+        }
+        DnsblAction::Throttle => {
+            time::sleep(Duration::from_millis(dnsbl.config().throttle_ms)).await;
+            Ok(())
+        }
+    }
 }
 
-pub async fn handle(stream: TcpStream) -> io::Result<()> {
-    read_client_greeting(stream)
-        .and_then(|state| choose_auth_method(state))
-        .and_then(|state| read_connect_request(state))
-        .and_then(|state| serve_connect_request(state))
+/// Runs one phase of the handshake, failing it if it takes longer than `limit_secs`.
+pub(crate) async fn within<T>(
+    limit_secs: Option<u64>,
+    phase: &str,
+    fut: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match limit_secs {
+        Some(secs) => time::timeout(Duration::from_secs(secs), fut)
+            .await
+            .unwrap_or_else(|_| Err(timed_out(phase, secs))),
+        None => fut.await,
+    }
+}
+
+/// Runs part of the handshake, failing it once the connection was accepted longer than the
+/// `total_secs` handshake timeout ago. The listener transport, the dnsbl lookup and whatever
+/// protocol the client speaks all share that deadline.
+pub(crate) async fn within_handshake<T>(
+    server: &Server,
+    info: ConnectionInfo,
+    fut: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    let Some(secs) = server.config().handshake_timeouts.total_secs else {
+        return fut.await;
+    };
+    let deadline = time::Instant::from_std(info.accepted_at + Duration::from_secs(secs));
+    time::timeout_at(deadline, fut)
         .await
+        .unwrap_or_else(|_| Err(timed_out("handshake", secs)))
 }
 
-async fn read_client_greeting(mut stream: TcpStream) -> io::Result<WaitingForGreeting> {
-    match proto::ClientGreeting::read_from_stream(&mut stream).await {
+fn timed_out(phase: &str, secs: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("client took longer than {secs}s for the {phase}"),
+    )
+}
+
+async fn read_client_greeting(
+    server: &Server,
+    mut stream: ClientConn,
+) -> io::Result<WaitingForGreeting> {
+    let limits = &server.config().handshake_limits;
+    let greeting =
+        async_proto::read_message::<proto::ClientGreeting>(&mut stream, limits.max_pending_bytes)
+            .await
+            .and_then(|greeting| match limits.max_auth_methods {
+                Some(max) if greeting.0.len() > max.into() => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "client offered {} auth methods, more than {max}",
+                        greeting.0.len()
+                    ),
+                )),
+                _ => Ok(greeting),
+            });
+    match greeting {
         Ok(greeting) => Ok(WaitingForGreeting { stream, greeting }),
         Err(err) => {
             stream.write_all(&[proto::SOCKS_VERSION, 0xff]).await?;
@@ -40,80 +285,484 @@ async fn read_client_greeting(mut stream: TcpStream) -> io::Result<WaitingForGre
 }
 
 async fn choose_auth_method(
+    server: &Server,
     WaitingForGreeting {
         mut stream,
         greeting,
     }: WaitingForGreeting,
+    info: ConnectionInfo,
+    certified: Option<String>,
 ) -> io::Result<WaitingForConnectRequest> {
-    if greeting.0.contains(&proto::AuthMethod::NoAuth) {
-        stream
-            .write_all(&[proto::SOCKS_VERSION, proto::AuthMethod::NoAuth as u8])
-            .await?;
-        Ok(WaitingForConnectRequest { stream })
-    } else {
-        stream.write_all(&[proto::SOCKS_VERSION, 0xff]).await?;
-        Err(io::Error::new(
+    let mut accepted = Vec::new();
+    // a certificate takes the place of any auth method
+    if !server.skips_auth(&info) && certified.is_none() {
+        if server.config().gssapi.is_some() {
+            accepted.push(proto::AuthMethod::GssApi);
+        }
+        accepted.extend(
+            server
+                .private_auth_methods()
+                .keys()
+                .map(|&method| proto::AuthMethod::Private(method)),
+        );
+        if server.authenticator(&info).is_some() {
+            accepted.push(proto::AuthMethod::UserPass);
+        }
+    }
+    if accepted.is_empty() {
+        accepted.push(proto::AuthMethod::NoAuth);
+    }
+    let Some(method) = accepted
+        .into_iter()
+        .find(|method| greeting.0.contains(method))
+    else {
+        record_denied(
+            server,
+            &DeniedRecord {
+                info,
+                protocol: "socks5",
+                auth_methods: &greeting.0,
+                username: None,
+                request: None,
+                reason: "no acceptable authentication method",
+            },
+        );
+        let reply = [proto::SOCKS_VERSION, 0xff];
+        let reply =
+            faults::inject(server.config().faults.as_ref(), Phase::Greeting, &reply).await?;
+        stream.write_all(&reply).await?;
+        return Err(io::Error::new(
             io::ErrorKind::InvalidData,
-            "client does not support NoAuth authentication method",
-        ))
+            format!(
+                "client offered none of the accepted authentication methods: {:?}",
+                greeting.0
+            ),
+        ));
+    };
+
+    let reply = [proto::SOCKS_VERSION, method.into()];
+    let reply = faults::inject(server.config().faults.as_ref(), Phase::Greeting, &reply).await?;
+    stream.write_all(&reply).await?;
+
+    #[cfg(all(unix, feature = "gssapi"))]
+    if let (proto::AuthMethod::GssApi, Some(config)) = (method, &server.config().gssapi) {
+        let session = gssapi::establish(server, &mut stream, config).await?;
+        return Ok(WaitingForConnectRequest {
+            stream,
+            auth_methods: greeting.0,
+            username: Some(session.client_name()?),
+            grant: Grant::default(),
+            protection: Protection {
+                session: Some(session),
+            },
+        });
+    }
+
+    let (username, grant) = match (method, server.authenticator(&info)) {
+        (proto::AuthMethod::Private(code), _) => {
+            let handler = &server.private_auth_methods()[&code];
+            match handler.negotiate(&mut stream).await {
+                Ok(username) => (Some(username), Grant::default()),
+                Err(err) => {
+                    if err.kind() == io::ErrorKind::PermissionDenied {
+                        record_denied(
+                            server,
+                            &DeniedRecord {
+                                info,
+                                protocol: "socks5",
+                                auth_methods: &greeting.0,
+                                username: None,
+                                request: None,
+                                reason: "authentication failed",
+                            },
+                        );
+                        // the method replied itself, so the delay holds up the close instead
+                        server.tarpit().await?;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        (proto::AuthMethod::UserPass, Some(authenticator)) => {
+            let (username, grant) =
+                authenticate(server, authenticator, &mut stream, info, &greeting.0).await?;
+            (Some(username), grant)
+        }
+        _ => (certified, Grant::default()),
+    };
+    Ok(WaitingForConnectRequest {
+        stream,
+        auth_methods: greeting.0,
+        username,
+        grant,
+        protection: Protection::default(),
+    })
+}
+
+/// Returns the name of the user the client authenticated as, and what their credentials
+/// entitle them to.
+async fn authenticate(
+    server: &Server,
+    authenticator: &dyn Authenticator,
+    stream: &mut ClientConn,
+    info: ConnectionInfo,
+    auth_methods: &[proto::AuthMethod],
+) -> io::Result<(String, Grant)> {
+    let max_len = server.config().handshake_limits.max_pending_bytes;
+    let request = match async_proto::read_message::<proto::UserPassRequest>(stream, max_len).await {
+        Ok(request) => request,
+        Err(err) => {
+            stream.write_all(&[proto::USER_PASS_VERSION, 0x01]).await?;
+            return Err(err);
+        }
+    };
+    let verdict = authenticator
+        .authorize(&request.username, &request.password)
+        .await;
+
+    let ok = matches!(verdict, Ok(Some(_)));
+    if matches!(verdict, Ok(None)) {
+        record_denied(
+            server,
+            &DeniedRecord {
+                info,
+                protocol: "socks5",
+                auth_methods,
+                username: Some(&request.username),
+                request: None,
+                reason: "authentication failed",
+            },
+        );
+        server.tarpit().await?;
+    }
+    let reply = [proto::USER_PASS_VERSION, if ok { 0x00 } else { 0x01 }];
+    let reply = faults::inject(server.config().faults.as_ref(), Phase::Auth, &reply).await?;
+    stream.write_all(&reply).await?;
+    match verdict {
+        Ok(Some(grant)) => Ok((request.username, grant)),
+        Ok(None) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "authentication failed",
+        )),
+        Err(err) => Err(io::Error::new(
+            err.kind(),
+            format!("checking credentials: {err}"),
+        )),
     }
 }
 
-async fn read_connect_request(
-    WaitingForConnectRequest { mut stream }: WaitingForConnectRequest,
-) -> io::Result<ServingConnectRequest> {
-    match proto::ClientConnectionRequest::read_from_stream(&mut stream).await {
-        Ok(request) => Ok(ServingConnectRequest { stream, request }),
+async fn read_connect_request<'s>(
+    server: &'s Server,
+    WaitingForConnectRequest {
+        mut stream,
+        auth_methods,
+        username,
+        grant,
+        protection,
+    }: WaitingForConnectRequest,
+) -> io::Result<ServingConnectRequest<'s>> {
+    let max_len = server.config().handshake_limits.max_pending_bytes;
+    match protection.read_request(&mut stream, max_len).await {
+        Ok(request) => Ok(ServingConnectRequest {
+            stream,
+            request,
+            auth_methods,
+            username,
+            grant,
+            acl_rule: None,
+            protection,
+        }),
         Err(err) => {
             let resp = proto::ServerResponse {
-                status: proto::ServerStatus::GeneralFailure,
+                status: proto::Undefined::of(&err).map_or(
+                    proto::ServerStatus::GeneralFailure,
+                    proto::Undefined::status,
+                ),
                 bound_address: proto::EMPTY_ADDRESS,
                 bound_port: 0,
             };
-            stream.write_all(&resp.as_bytes()).await?;
+            protection.reply(server, &mut stream, &resp).await?;
             Err(err)
         }
     }
 }
 
+async fn check_policy<'s>(
+    server: &'s Server,
+    mut state: ServingConnectRequest<'s>,
+    info: ConnectionInfo,
+) -> io::Result<ServingConnectRequest<'s>> {
+    let client = info.peer_addr;
+    // the grant is checked against each destination an association sends datagrams to,
+    // rather than the address the client will send from
+    let grant =
+        (state.request.cmd != proto::ClientCommand::AssociateUdpPort).then_some(&state.grant);
+    let checked = check_policies(
+        server,
+        info,
+        state.username.as_deref(),
+        grant,
+        &state.request,
+    )
+    .await;
+    let rule = match checked {
+        Ok(acl_rule) => {
+            state.acl_rule = acl_rule;
+            return Ok(state);
+        }
+        Err(rule) => rule,
+    };
+
+    let ServingConnectRequest {
+        mut stream,
+        request,
+        auth_methods,
+        username,
+        protection,
+        ..
+    } = state;
+    record_denied(
+        server,
+        &DeniedRecord {
+            info,
+            protocol: "socks5",
+            auth_methods: &auth_methods,
+            username: username.as_deref(),
+            request: Some(&request),
+            reason: rule,
+        },
+    );
+    let resp = proto::ServerResponse {
+        status: proto::ServerStatus::ConnectionNotAllowedByRuleset,
+        bound_address: proto::EMPTY_ADDRESS,
+        bound_port: 0,
+    };
+    server.tarpit().await?;
+    protection.reply(server, &mut stream, &resp).await?;
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!(
+            "{rule} denied {:?} from {client} to {}:{}",
+            request.cmd, request.dest_addr, request.dest_port
+        ),
+    ))
+}
+
+/// Checks the request against the server's policies, starting with the destinations the
+/// client's credentials were granted. Fails with the name of the policy that rejects it, if
+/// any does, or else returns the counters of the acl rule allowing it. Only requests the
+/// other policies allow count towards the rate limits.
+pub(crate) async fn check_policies<'s>(
+    server: &'s Server,
+    info: ConnectionInfo,
+    username: Option<&str>,
+    grant: Option<&Grant>,
+    request: &proto::ClientConnectionRequest,
+) -> Result<Option<&'s RuleMetrics>, &'static str> {
+    if grant.is_some_and(|grant| !grant.allows(info.peer_addr, request)) {
+        return Err("granted destinations");
+    }
+    if server
+        .port_policy(&info)
+        .is_some_and(|policy| !policy.allows(username, request.dest_port))
+    {
+        return Err("destination port policy");
+    }
+    if server.blocklist().is_some_and(|blocklist| {
+        matches!(&request.dest_addr, proto::Address::DomainName(host) if blocklist.blocks(host))
+    }) {
+        return Err("blocklist");
+    }
+    let acl_rule = match server
+        .acl(&info)
+        .map(|acl| acl.check(info.peer_addr, request))
+    {
+        Some((AclAction::Deny, _)) => return Err("acl"),
+        Some((AclAction::Allow, acl_rule)) => Some(acl_rule),
+        None => None,
+    };
+    match server.rate_limiter() {
+        Some(limiter) if !limiter.admit(info.peer_addr.ip(), username).await => Err("rate limit"),
+        _ => Ok(acl_rule),
+    }
+}
+
+/// The DSCP codepoint connections for the request are marked with: that of the acl rule
+/// allowing it, or else the outbound default.
+pub(crate) fn outbound_dscp(
+    server: &Server,
+    info: ConnectionInfo,
+    request: &proto::ClientConnectionRequest,
+) -> Option<u8> {
+    server
+        .acl(&info)
+        .and_then(|acl| acl.dscp(info.peer_addr, request))
+        .or(server.config().outbound.dscp)
+}
+
+#[cfg(feature = "scripting")]
+async fn route_connect_request<'s>(
+    server: &Server,
+    mut state: ServingConnectRequest<'s>,
+    info: ConnectionInfo,
+) -> io::Result<ServingConnectRequest<'s>> {
+    use crate::script::Route;
+
+    let Some(script) = server.route_script() else {
+        return Ok(state);
+    };
+
+    let (status, rule) = match script.route(&state.request) {
+        Ok(Route::Allow) => return Ok(state),
+        Ok(Route::Redirect(dest_addr, dest_port)) => {
+            state.request.dest_addr = dest_addr;
+            state.request.dest_port = dest_port;
+            return Ok(state);
+        }
+        Ok(Route::Deny) => (
+            proto::ServerStatus::ConnectionNotAllowedByRuleset,
+            "route script",
+        ),
+        Err(err) => {
+            eprintln!("{info}: route_connect_request: {err}");
+            (proto::ServerStatus::GeneralFailure, "route script")
+        }
+    };
+    if status == proto::ServerStatus::ConnectionNotAllowedByRuleset {
+        record_denied(
+            server,
+            &DeniedRecord {
+                info,
+                protocol: "socks5",
+                auth_methods: &state.auth_methods,
+                username: state.username.as_deref(),
+                request: Some(&state.request),
+                reason: rule,
+            },
+        );
+        server.tarpit().await?;
+    }
+
+    let ServingConnectRequest {
+        mut stream,
+        request,
+        protection,
+        ..
+    } = state;
+    let resp = proto::ServerResponse {
+        status,
+        bound_address: proto::EMPTY_ADDRESS,
+        bound_port: 0,
+    };
+    protection.reply(server, &mut stream, &resp).await?;
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!(
+            "{rule} rejected {:?} to {}:{} with status: {status:?}",
+            request.cmd, request.dest_addr, request.dest_port
+        ),
+    ))
+}
+
+#[cfg(not(feature = "scripting"))]
+async fn route_connect_request<'s>(
+    _server: &Server,
+    state: ServingConnectRequest<'s>,
+    _info: ConnectionInfo,
+) -> io::Result<ServingConnectRequest<'s>> {
+    Ok(state)
+}
+
 async fn serve_connect_request(
+    server: &Server,
     ServingConnectRequest {
         mut stream,
         request,
-    }: ServingConnectRequest,
+        username,
+        grant,
+        acl_rule,
+        protection,
+        ..
+    }: ServingConnectRequest<'_>,
+    info: ConnectionInfo,
 ) -> io::Result<()> {
+    if let Some(status) = server.config().faults.as_ref().and_then(|f| f.reply_status) {
+        let resp = proto::ServerResponse {
+            status: proto::ServerStatus::try_from(status)?,
+            bound_address: proto::EMPTY_ADDRESS,
+            bound_port: 0,
+        };
+        protection.reply(server, &mut stream, &resp).await?;
+        return Err(io::Error::other(format!(
+            "fault injection: answered {:?} to {}:{} with {:?}",
+            request.cmd, request.dest_addr, request.dest_port, resp.status
+        )));
+    }
     match request.cmd {
         proto::ClientCommand::EstablishConnection => {
-            serve_establish_connection(stream, request).await
+            let requested = Requested {
+                request: &request,
+                username: username.as_deref(),
+                acl_rule,
+            };
+            serve_establish_connection(server, stream, requested, &protection, info).await
         }
         proto::ClientCommand::EstablishPortBinding => {
-            serve_establish_port_bindings(stream, request).await
-        }
-        cmd => {
-            let resp = proto::ServerResponse {
-                status: proto::ServerStatus::CommandNotSupported,
-                bound_address: proto::EMPTY_ADDRESS,
-                bound_port: 0,
+            let requested = Requested {
+                request: &request,
+                username: username.as_deref(),
+                acl_rule,
             };
-            stream.write_all(&resp.as_bytes()).await?;
-            Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!("client command {cmd:?} is not supported"),
-            ))
+            serve_establish_port_bindings(server, stream, requested, &protection, info).await
+        }
+        proto::ClientCommand::AssociateUdpPort if !server.config().udp.enabled => {
+            not_supported(server, stream, &protection, "udp associate is disabled").await
+        }
+        // relayed datagrams would have to be encapsulated too, which isn't supported
+        proto::ClientCommand::AssociateUdpPort if protection.is_active() => {
+            not_supported(
+                server,
+                stream,
+                &protection,
+                "udp associate is not supported under gssapi protection",
+            )
+            .await
+        }
+        proto::ClientCommand::AssociateUdpPort => {
+            udp::serve_associate(server, stream, request, username, grant, info).await
         }
     }
 }
 
-async fn serve_establish_port_bindings(
-    mut stream: TcpStream,
-    request: proto::ClientConnectionRequest,
+/// Tells the client the command it requested isn't supported, failing with `reason`.
+async fn not_supported(
+    server: &Server,
+    mut stream: ClientConn,
+    protection: &Protection,
+    reason: &str,
 ) -> io::Result<()> {
-    let binding = TcpListener::bind(format!(
-        "{}:{}",
-        request.dest_addr.to_string(),
-        request.dest_port,
+    let resp = proto::ServerResponse {
+        status: proto::ServerStatus::CommandNotSupported,
+        bound_address: proto::EMPTY_ADDRESS,
+        bound_port: 0,
+    };
+    protection.reply(server, &mut stream, &resp).await?;
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        reason.to_owned(),
     ))
-    .await?;
+}
+
+async fn serve_establish_port_bindings(
+    server: &Server,
+    mut stream: ClientConn,
+    requested: Requested<'_>,
+    protection: &Protection,
+    info: ConnectionInfo,
+) -> io::Result<()> {
+    let request = requested.request;
+    let binding =
+        TcpListener::bind(format!("{}:{}", request.dest_addr, request.dest_port,)).await?;
     let binding_addr = binding.local_addr()?;
 
     let resp = proto::ServerResponse {
@@ -121,49 +770,661 @@ async fn serve_establish_port_bindings(
         bound_address: binding_addr.into(),
         bound_port: binding_addr.port(),
     };
-    stream.write_all(&resp.as_bytes()).await?;
+    protection.reply(server, &mut stream, &resp).await?;
 
-    let (mut incoming_stream, incoming_addr) = binding.accept().await?;
+    let (incoming_stream, incoming_addr) = binding.accept().await?;
     let resp = proto::ServerResponse {
         status: proto::ServerStatus::RequestGranted,
         bound_address: incoming_addr.into(),
         bound_port: incoming_addr.port(),
     };
-    stream.write_all(&resp.as_bytes()).await?;
-
-    #[cfg(not(target_os = "linux"))]
-    copy_bidirectional(&mut stream, &mut incoming_stream).await?;
+    protection.reply(server, &mut stream, &resp).await?;
 
-    #[cfg(target_os = "linux")]
-    copy::splice_bidirectional(stream, incoming_stream).await?;
-
-    Ok(())
+    relay(server, stream, incoming_stream, requested, protection, info).await
 }
 
 async fn serve_establish_connection(
-    mut stream: TcpStream,
-    request: proto::ClientConnectionRequest,
+    server: &Server,
+    mut stream: ClientConn,
+    requested: Requested<'_>,
+    protection: &Protection,
+    info: ConnectionInfo,
 ) -> io::Result<()> {
-    let mut dialed_conn = TcpStream::connect(format!(
-        "{}:{}",
-        request.dest_addr.to_string(),
-        request.dest_port
-    ))
-    .await?;
+    let request = requested.request;
+    let dialed = server
+        .dialer()
+        .connect(
+            server.resolver(),
+            &request.dest_addr,
+            request.dest_port,
+            outbound_dscp(server, info, request),
+        )
+        .await;
+    record_access(server, info, requested.username, request, &dialed);
+    let dialed_conn = match dialed {
+        Ok(conn) => conn,
+        Err(err) => {
+            let resp = proto::ServerResponse {
+                status: dial_failure_status(&err),
+                bound_address: proto::EMPTY_ADDRESS,
+                bound_port: 0,
+            };
+            protection.reply(server, &mut stream, &resp).await?;
+            return Err(err);
+        }
+    };
 
     let resp = proto::ServerResponse {
         status: proto::ServerStatus::RequestGranted,
         bound_address: proto::EMPTY_ADDRESS,
         bound_port: 0,
     };
-    stream.write_all(&resp.as_bytes()).await?;
+    protection.reply(server, &mut stream, &resp).await?;
 
-    #[cfg(not(target_os = "linux"))]
-    copy_bidirectional(&mut stream, &mut dialed_conn).await?;
+    relay(server, stream, dialed_conn, requested, protection, info).await?;
+
+    eprintln!(
+        "{info}: serve_establish_connection finished {:?} after accept",
+        info.accepted_at.elapsed()
+    );
+    Ok(())
+}
+
+pub(crate) fn record_access(
+    server: &Server,
+    info: ConnectionInfo,
+    username: Option<&str>,
+    request: &proto::ClientConnectionRequest,
+    dialed: &io::Result<TcpStream>,
+) {
+    let record = AccessRecord {
+        id: info.id,
+        client: info.peer_addr,
+        username,
+        request,
+        connected: dialed.as_ref().ok().and_then(|conn| conn.peer_addr().ok()),
+        result: dialed.as_ref().map(|_| ()),
+    };
+    if let Some(access_log) = server.access_log() {
+        access_log.record(&record);
+    }
+    #[cfg(unix)]
+    if let Some(syslog) = server.syslog() {
+        syslog.record(&record);
+    }
+}
+
+/// Logs a request the server turned away to the honeypot log, if there is one.
+pub(crate) fn record_denied(server: &Server, record: &DeniedRecord) {
+    if let Some(honeypot_log) = server.honeypot_log() {
+        honeypot_log.record(record);
+    }
+}
+
+/// Tells the client why the destination couldn't be reached, as far as the error says.
+fn dial_failure_status(err: &io::Error) -> proto::ServerStatus {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => proto::ServerStatus::ConnectionRefusedByDestinationHost,
+        io::ErrorKind::NetworkUnreachable => proto::ServerStatus::NetworkUnreachable,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::NotFound => {
+            proto::ServerStatus::HostUnreachable
+        }
+        io::ErrorKind::TimedOut => proto::ServerStatus::TtlExpired,
+        _ => proto::ServerStatus::GeneralFailure,
+    }
+}
+
+/// Relays a connection that was set up by another protocol than socks5, see [`relay`].
+pub(crate) async fn relay_plain(
+    server: &Server,
+    client: ClientConn,
+    remote: TcpStream,
+    requested: Requested<'_>,
+    info: ConnectionInfo,
+) -> io::Result<()> {
+    let protection = Protection::default();
+    relay(server, client, remote, requested, &protection, info).await
+}
+
+/// Relays between the client and the remote peer, closing both legs as the server's
+/// `relay_close` settings say once the relay ends, and counts what it relayed towards the
+/// byte quotas of the requesting user and the client.
+async fn relay(
+    server: &Server,
+    client: ClientConn,
+    remote: TcpStream,
+    requested: Requested<'_>,
+    protection: &Protection,
+    info: ConnectionInfo,
+) -> io::Result<()> {
+    let close = &server.config().relay_close;
+    if close.abort_on_close {
+        if let ClientConn::Tcp(client) = &client {
+            dial::reset_on_close(client)?;
+        }
+        dial::reset_on_close(&remote)?;
+    }
+    let teardown = close
+        .drain_timeout_ms
+        .map(|timeout_ms| Teardown::new(timeout_ms, &client, &remote))
+        .transpose()?;
+    let progress = server.progress_sender(info.id);
+    let res = relay_streams(
+        server,
+        client,
+        remote,
+        &requested,
+        protection,
+        info,
+        Arc::clone(&progress),
+    )
+    .await;
+    if let (Err(_), Some(teardown)) = (&res, teardown) {
+        teardown.drain().await;
+    }
+    if let Some(limiter) = server.rate_limiter() {
+        // the relay published the last of its progress as it ended
+        let relayed = *progress.borrow();
+        let bytes = relayed.to_remote + relayed.to_client;
+        limiter
+            .add_bytes(info.peer_addr.ip(), requested.username, bytes)
+            .await;
+    }
+    res
+}
+
+/// Relays through whichever path the server's capture, priority and relay strategy settings
+/// call for.
+async fn relay_streams(
+    server: &Server,
+    client: ClientConn,
+    remote: TcpStream,
+    requested: &Requested<'_>,
+    #[cfg_attr(not(all(unix, feature = "gssapi")), allow(unused_variables))]
+    protection: &Protection,
+    info: ConnectionInfo,
+    progress: Arc<watch::Sender<Progress>>,
+) -> io::Result<()> {
+    let (request, client_addr) = (requested.request, info.peer_addr);
+    let peers = format!("{info} {client_addr} <-> {}", remote.peer_addr()?);
+    let mut relayed = ConnectionRelay::new(&server.metrics().relay, progress);
+    if let Some(top_talkers) = server.top_talkers() {
+        let destination = format!("{}:{}", request.dest_addr, request.dest_port);
+        relayed = relayed.with_top_talkers(top_talkers, client_addr.ip(), destination);
+    }
+    if let Some(acl_rule) = requested.acl_rule {
+        relayed = relayed.with_acl_rule(acl_rule);
+    }
+    let relayed = &relayed;
+    if let Some(keepalive) = &server.config().keepalive {
+        if let ClientConn::Tcp(client) = &client {
+            dial::set_keepalive(client, keepalive)?;
+        }
+        dial::set_keepalive(&remote, keepalive)?;
+    }
+
+    // the client's side of the stream is framed, so it can only be copied message by message
+    #[cfg(all(unix, feature = "gssapi"))]
+    if let Some(session) = &protection.session {
+        eprintln!("relay {peers}: gssapi encapsulation");
+        return session.relay(client, remote, relayed).await;
+    }
+    let opts = RelayOptions::from_config(server.config());
+    let client = match client {
+        ClientConn::Tcp(client) => client,
+        // there is no socket on the client's side to splice or capture from
+        ClientConn::Wrapped { stream, .. } => {
+            return relay::relay_counted(stream, remote, &opts, relayed, Some(&peers)).await;
+        }
+    };
+
+    if let Some(capture) = server
+        .capture()
+        .filter(|capture| capture.matches(client_addr, request))
+    {
+        eprintln!("relay {peers}: capturing through userspace");
+        return capture.relay(client, remote, relayed).await;
+    }
+    if let Some(class) = server
+        .priority()
+        .and_then(|priority| priority.classify(client_addr, request))
+    {
+        eprintln!("relay {peers}: priority class {}", class.name());
+        return class.relay(client, remote, relayed).await;
+    }
+    relay::relay_counted(client, remote, &opts, relayed, Some(&peers)).await
+}
 
+/// Kernel pipes kept open for the splice relay of future connections.
+pub(crate) fn pooled_splice_pipes() -> usize {
     #[cfg(target_os = "linux")]
-    copy::splice_bidirectional(stream, dialed_conn).await?;
+    return copy::pooled_pipes();
+    #[cfg(not(target_os = "linux"))]
+    0
+}
 
-    eprintln!("serve_establish_connection finished");
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use futures::future::BoxFuture;
+    use tokio::{
+        io::{AsyncReadExt, DuplexStream},
+        task::JoinHandle,
+    };
+
+    use super::*;
+    use crate::{
+        auth::PrivateAuthMethod,
+        config::{FaultsConfig, PhaseFaultsConfig, ServerConfig, TarpitConfig},
+        server::ConnectionId,
+        transport::ServerStream,
+    };
+
+    /// Accepts clients that send the right one byte token.
+    struct Token(u8);
+
+    impl PrivateAuthMethod for Token {
+        fn negotiate<'a>(
+            &'a self,
+            stream: &'a mut dyn ServerStream,
+        ) -> BoxFuture<'a, io::Result<String>> {
+            Box::pin(async move {
+                let token = stream.read_u8().await?;
+                let ok = token == self.0;
+                stream.write_u8(if ok { 0x00 } else { 0x01 }).await?;
+                if ok {
+                    Ok(format!("token-{token}"))
+                } else {
+                    Err(io::Error::new(io::ErrorKind::PermissionDenied, "bad token"))
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatches_private_auth_methods_to_their_handler() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let mut server = Server::new(ServerConfig::default()).unwrap();
+        server
+            .register_auth_method(0x80, Box::new(Token(42)))
+            .unwrap();
+        assert!(server
+            .register_auth_method(0x7f, Box::new(Token(42)))
+            .is_err());
+        let server = Arc::new(server);
+        tokio::spawn(Arc::clone(&server).serve(listener));
+
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest_port = dest.local_addr().unwrap().port();
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(&[5, 2, 0x00, 0x80]).await.unwrap();
+        let mut choice = [0_u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [5, 0x80]);
+        client.write_u8(42).await.unwrap();
+        assert_eq!(client.read_u8().await.unwrap(), 0x00);
+
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&dest_port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0_u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], proto::ServerStatus::RequestGranted as u8);
+
+        // without the private method on offer there is nothing left to choose
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(&[5, 1, 0x00]).await.unwrap();
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [5, 0xff]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cuts_off_clients_trickling_in_the_greeting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let mut config = ServerConfig::default();
+        config.handshake_timeouts.total_secs = Some(60);
+        config.handshake_timeouts.greeting_secs = Some(5);
+        let server = Arc::new(Server::new(config).unwrap());
+        tokio::spawn(Arc::clone(&server).serve(listener));
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let started = time::Instant::now();
+        client.write_all(&[5, 2]).await.unwrap();
+        // the greeting never completes, so the server gives up on it
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_secs(5) && waited < Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cuts_off_silent_clients_while_telling_protocols_apart() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let mut config = ServerConfig {
+            socks6: true,
+            ..ServerConfig::default()
+        };
+        config.handshake_timeouts.total_secs = Some(10);
+        let server = Arc::new(Server::new(config).unwrap());
+        tokio::spawn(Arc::clone(&server).serve(listener));
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let started = time::Instant::now();
+        // nothing is sent, so the server can't even peek at the first byte
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_secs(9) && waited < Duration::from_secs(60));
+    }
+
+    /// Handles a connection arriving over an in-memory pipe, returning the client's end.
+    fn serve_in_memory(server: Server) -> (DuplexStream, JoinHandle<io::Result<()>>) {
+        let (client, server_end) = io::duplex(1024);
+        let conn = ClientConn::Wrapped {
+            stream: Box::new(server_end),
+            local_addr: "127.0.0.1:1080".parse().unwrap(),
+        };
+        let info = ConnectionInfo {
+            id: ConnectionId(1),
+            peer_addr: "192.0.2.1:50000".parse().unwrap(),
+            accepted_at: std::time::Instant::now(),
+            listener: None,
+        };
+        let handling = tokio::spawn(async move { handle_conn(&server, conn, info, None).await });
+        (client, handling)
+    }
+
+    /// Sends a greeting offering no authentication and a request with the given raw
+    /// command, address type and address, returning the reply's status.
+    async fn request(client: &mut DuplexStream, cmd: u8, addr: &[u8], port: u16) -> u8 {
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut choice = [0_u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [5, 0]);
+        client.write_all(&[5, cmd, 0]).await.unwrap();
+        client.write_all(addr).await.unwrap();
+        client.write_all(&port.to_be_bytes()).await.unwrap();
+        let mut reply = [0_u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        reply[1]
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tarpits_denied_requests() {
+        let config = |max_clients| ServerConfig {
+            acl: Some(toml::from_str(r#"default = "deny""#).unwrap()),
+            tarpit: Some(TarpitConfig {
+                delay_ms: 3000,
+                jitter_ms: 2000,
+                max_clients,
+            }),
+            ..ServerConfig::default()
+        };
+        let (mut client, handling) = serve_in_memory(Server::new(config(1)).unwrap());
+        let started = time::Instant::now();
+        let status = request(&mut client, 0x01, &[1, 127, 0, 0, 1], 80).await;
+        assert_eq!(
+            status,
+            proto::ServerStatus::ConnectionNotAllowedByRuleset as u8
+        );
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_secs(3) && waited <= Duration::from_secs(5));
+        let err = handling.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        // with the tarpit full, clients are cut off right away instead
+        let (mut client, handling) = serve_in_memory(Server::new(config(0)).unwrap());
+        let started = time::Instant::now();
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut choice = [0_u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        client
+            .write_all(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(started.elapsed(), Duration::ZERO);
+        let err = handling.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    }
+
+    #[tokio::test]
+    async fn misbehaves_as_the_faults_say() {
+        let server = |faults: FaultsConfig| {
+            Server::new(ServerConfig {
+                faults: Some(faults),
+                ..ServerConfig::default()
+            })
+            .unwrap()
+        };
+
+        let (mut client, handling) = serve_in_memory(server(FaultsConfig {
+            reply_status: Some(proto::ServerStatus::HostUnreachable as u8),
+            ..FaultsConfig::default()
+        }));
+        let status = request(&mut client, 0x01, &[1, 127, 0, 0, 1], 80).await;
+        assert_eq!(status, proto::ServerStatus::HostUnreachable as u8);
+        assert!(handling.await.unwrap().is_err());
+
+        let (mut client, handling) = serve_in_memory(server(FaultsConfig {
+            greeting: PhaseFaultsConfig {
+                garble: true,
+                ..PhaseFaultsConfig::default()
+            },
+            ..FaultsConfig::default()
+        }));
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut choice = [0_u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [0xfa, 0xff]);
+        drop(client);
+        assert!(handling.await.unwrap().is_err());
+
+        let (mut client, handling) = serve_in_memory(server(FaultsConfig {
+            request: PhaseFaultsConfig {
+                close: true,
+                ..PhaseFaultsConfig::default()
+            },
+            reply_status: Some(0),
+            ..FaultsConfig::default()
+        }));
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client.read_exact(&mut choice).await.unwrap();
+        client
+            .write_all(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        let err = handling.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    }
+
+    #[tokio::test]
+    async fn rejects_messages_over_the_limits() {
+        let server = || {
+            let mut config = ServerConfig::default();
+            config.handshake_limits.max_auth_methods = Some(2);
+            config.handshake_limits.max_pending_bytes = Some(32);
+            Server::new(config).unwrap()
+        };
+
+        let (mut client, handling) = serve_in_memory(server());
+        client.write_all(&[5, 3, 0, 1, 2]).await.unwrap();
+        let mut choice = [0_u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [5, 0xff]);
+        let err = handling.await.unwrap().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "client offered 3 auth methods, more than 2"
+        );
+
+        // turned away on the length byte, without waiting for the name
+        let (mut client, handling) = serve_in_memory(server());
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client.read_exact(&mut choice).await.unwrap();
+        client.write_all(&[5, 1, 0, 3, 200]).await.unwrap();
+        let mut reply = [0_u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], proto::ServerStatus::GeneralFailure as u8);
+        let err = handling.await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "client message exceeds 32 bytes");
+
+        let (mut client, handling) = serve_in_memory(server());
+        let status = request(
+            &mut client,
+            0x01,
+            &[3, 9, b'l', b'o', b'c', b'a', b'l', b'h', b'o', b's', b't'],
+            9,
+        )
+        .await;
+        assert_ne!(status, proto::ServerStatus::GeneralFailure as u8);
+        drop(client);
+        let _ = handling.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_greetings_and_requests() {
+        let server = || Server::new(ServerConfig::default()).unwrap();
+
+        let (mut client, handling) = serve_in_memory(server());
+        client.write_all(&[4, 1, 0]).await.unwrap();
+        let mut choice = [0_u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [5, 0xff]);
+        assert!(handling.await.unwrap().is_err());
+
+        let (mut client, handling) = serve_in_memory(server());
+        let status = request(&mut client, 0x09, &[1, 127, 0, 0, 1], 80).await;
+        assert_eq!(status, proto::ServerStatus::CommandNotSupported as u8);
+        assert!(handling.await.unwrap().is_err());
+
+        let (mut client, handling) = serve_in_memory(server());
+        let status = request(&mut client, 0x01, &[0x05, 127, 0, 0, 1], 80).await;
+        assert_eq!(status, proto::ServerStatus::AddressTypeNotSupported as u8);
+        assert!(handling.await.unwrap().is_err());
+
+        // requests malformed otherwise fail in general
+        let (mut client, handling) = serve_in_memory(server());
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut choice = [0_u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        client
+            .write_all(&[5, 1, 1, 1, 127, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+        let mut reply = [0_u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], proto::ServerStatus::GeneralFailure as u8);
+        assert!(handling.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_truncated_messages() {
+        let server = || Server::new(ServerConfig::default()).unwrap();
+
+        let (mut client, handling) = serve_in_memory(server());
+        client.write_all(&[5, 3, 0]).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut choice = [0_u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [5, 0xff]);
+        let err = handling.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let (mut client, handling) = serve_in_memory(server());
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut choice = [0_u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        client.write_all(&[5, 1, 0, 3, 11]).await.unwrap();
+        client.write_all(b"example").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut reply = [0_u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], proto::ServerStatus::GeneralFailure as u8);
+        let err = handling.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn connects_to_every_address_type() {
+        let v4 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let v6 = TcpListener::bind("[::1]:0").await.unwrap();
+        let v4_port = v4.local_addr().unwrap().port();
+        let v6_port = v6.local_addr().unwrap().port();
+        for listener in [v4, v6] {
+            tokio::spawn(async move {
+                loop {
+                    let (mut conn, _) = listener.accept().await.unwrap();
+                    tokio::spawn(async move {
+                        let mut buf = [0_u8; 4];
+                        conn.read_exact(&mut buf).await.unwrap();
+                        conn.write_all(&buf).await.unwrap();
+                    });
+                }
+            });
+        }
+
+        let mut domain = vec![3, "localhost".len() as u8];
+        domain.extend_from_slice(b"localhost");
+        let mut ipv6 = vec![4];
+        ipv6.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+        for (addr, port) in [
+            (vec![1, 127, 0, 0, 1], v4_port),
+            (domain, v4_port),
+            (ipv6, v6_port),
+        ] {
+            let (mut client, _) = serve_in_memory(Server::new(ServerConfig::default()).unwrap());
+            let status = request(&mut client, 0x01, &addr, port).await;
+            assert_eq!(
+                status,
+                proto::ServerStatus::RequestGranted as u8,
+                "{addr:?}"
+            );
+            client.write_all(b"ping").await.unwrap();
+            let mut echoed = [0_u8; 4];
+            client.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(&echoed, b"ping");
+        }
+    }
+
+    #[cfg(feature = "scripting")]
+    #[tokio::test]
+    async fn checks_the_policies_against_where_the_route_script_redirects() {
+        use std::{env, fs, process};
+
+        use crate::config::BlocklistConfig;
+
+        let dir = env::temp_dir().join(format!("socks5-route-policies-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("route.rhai");
+        fs::write(&script, r#"fn route(req) { "blocked.example:80" }"#).unwrap();
+        let blocklist = dir.join("blocklist");
+        fs::write(&blocklist, "blocked.example\n").unwrap();
+        let config = ServerConfig {
+            route_script: Some(script),
+            blocklist: Some(BlocklistConfig {
+                files: vec![blocklist],
+                ..BlocklistConfig::default()
+            }),
+            ..ServerConfig::default()
+        };
+
+        let (mut client, handling) = serve_in_memory(Server::new(config).unwrap());
+        let status = request(&mut client, 0x01, &[1, 127, 0, 0, 1], 80).await;
+        assert_eq!(
+            status,
+            proto::ServerStatus::ConnectionNotAllowedByRuleset as u8
+        );
+        let err = handling.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
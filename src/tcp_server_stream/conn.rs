@@ -0,0 +1,83 @@
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::transport::{ListenerTransport, ServerStream};
+
+/// The client's side of a connection: the accepted socket itself, or what the server's
+/// listener transport made of it. The client's address is in the connection's
+/// [`ConnectionInfo`](crate::server::ConnectionInfo).
+pub(crate) enum ClientConn {
+    Tcp(TcpStream),
+    Wrapped {
+        stream: Box<dyn ServerStream>,
+        local_addr: SocketAddr,
+    },
+}
+
+impl ClientConn {
+    pub(crate) async fn wrap(
+        stream: TcpStream,
+        transport: &dyn ListenerTransport,
+    ) -> io::Result<Self> {
+        let local_addr = stream.local_addr()?;
+        Ok(Self::Wrapped {
+            stream: transport.accept(stream).await?,
+            local_addr,
+        })
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(stream) => stream.local_addr(),
+            Self::Wrapped { local_addr, .. } => Ok(*local_addr),
+        }
+    }
+}
+
+impl AsyncRead for ClientConn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Wrapped { stream, .. } => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientConn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Wrapped { stream, .. } => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Wrapped { stream, .. } => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Wrapped { stream, .. } => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -1,15 +1,20 @@
 use std::{
-    io,
+    io::{self, Write},
     os::unix::{
         self,
+        net::UnixStream as StdUnixStream,
         prelude::{AsRawFd, OwnedFd, RawFd},
     },
     pin::Pin,
     ptr,
+    sync::{Mutex, OnceLock},
     task::{Context, Poll},
 };
 
-use futures::{future::FusedFuture, ready, select, Future};
+use futures::{
+    future::{poll_fn, FusedFuture},
+    ready, Future,
+};
 use libc;
 use tokio::{
     io::AsyncWrite,
@@ -19,39 +24,174 @@ use tokio::{
     },
 };
 
-pub(crate) async fn splice_bidirectional(a: TcpStream, b: TcpStream) -> io::Result<()> {
-    let (a_read, a_write) = a.into_split();
-    let (b_read, b_write) = b.into_split();
-    let mut b_to_a = splice_one_way(b_read, a_write)?;
-    let mut a_to_b = splice_one_way(a_read, b_write)?;
-    select! {
-        res = a_to_b => {
-            if let Err(err) = res {
-                return Err(err);
-            }
-            b_to_a.await
+use crate::metrics::{ConnectionRelay, RelayCounter};
+
+/// The read and write end of a kernel pipe.
+type Pipe = (OwnedFd, OwnedFd);
+
+/// Pipes of finished connections, kept for the next ones so that servers with a lot of
+/// connection churn don't create and configure a fresh pipe per direction of each.
+static PIPE_POOL: PipePool = PipePool::new(256);
+
+#[derive(Debug)]
+struct PipePool {
+    pipes: Mutex<Vec<Pipe>>,
+    capacity: usize,
+}
+
+impl PipePool {
+    const fn new(capacity: usize) -> Self {
+        Self {
+            pipes: Mutex::new(Vec::new()),
+            capacity,
         }
-        res = b_to_a => {
-            if let Err(err) = res {
-                return Err(err);
-            }
-            a_to_b.await
+    }
+
+    fn len(&self) -> usize {
+        self.pipes.lock().unwrap().len()
+    }
+
+    fn take(&self) -> io::Result<Pipe> {
+        let pooled = self.pipes.lock().unwrap().pop();
+        pooled.map_or_else(sys_pipe, Ok)
+    }
+
+    /// Keeps `pipe` for reuse, unless the pool is full. The pipe has to be empty, or the
+    /// next connection would be handed bytes of this one.
+    fn give_back(&self, pipe: Pipe) {
+        let mut pipes = self.pipes.lock().unwrap();
+        if pipes.len() < self.capacity {
+            pipes.push(pipe);
         }
     }
 }
 
-fn splice_one_way(reader: OwnedReadHalf, writer: OwnedWriteHalf) -> io::Result<SpliceFuture> {
-    let (buf_read, buf_write) = sys_pipe()?;
-    Ok(SpliceFuture {
+/// How many pipes are kept open for reuse.
+pub(crate) fn pooled_pipes() -> usize {
+    PIPE_POOL.len()
+}
+
+/// The pipes backing both directions of a spliced connection. Created up front so a caller can
+/// still fall back to copying through userspace when no pipes can be had.
+pub(crate) struct SplicePipes {
+    pool: &'static PipePool,
+    a_to_b: Pipe,
+    b_to_a: Pipe,
+}
+
+impl SplicePipes {
+    pub(crate) fn new() -> io::Result<Self> {
+        Self::take_from(&PIPE_POOL)
+    }
+
+    fn take_from(pool: &'static PipePool) -> io::Result<Self> {
+        Ok(Self {
+            pool,
+            a_to_b: pool.take()?,
+            b_to_a: pool.take()?,
+        })
+    }
+}
+
+/// Relays between `a` and `b` until both directions reached eof. Each direction shuts down the
+/// write side of its destination once its source is done, so a half-closed connection keeps
+/// relaying the opposite direction.
+///
+/// A direction whose destination stopped taking data only ends itself, since the destination
+/// may well still be sending a response the other way. Any other error ends both directions
+/// right away, which keeps a reset connection from waiting on the other, possibly idle, side.
+/// The first error is returned once the relay is over.
+pub(crate) async fn splice_bidirectional(
+    client: TcpStream,
+    remote: TcpStream,
+    pipes: SplicePipes,
+    relayed: &ConnectionRelay<'_>,
+) -> io::Result<()> {
+    let (a_read, a_write) = client.into_split();
+    let (b_read, b_write) = remote.into_split();
+    let b_to_a = splice_one_way(
+        b_read,
+        a_write,
+        pipes.b_to_a,
+        pipes.pool,
+        relayed.to_client(),
+    );
+    let a_to_b = splice_one_way(
+        a_read,
+        b_write,
+        pipes.a_to_b,
+        pipes.pool,
+        relayed.to_remote(),
+    );
+    let mut directions = [Some(a_to_b), Some(b_to_a)];
+    let mut first_err = None;
+    poll_fn(|cx| {
+        for slot in &mut directions {
+            let Some(direction) = slot else { continue };
+            let Poll::Ready(direction_res) = Pin::new(direction).poll(cx) else {
+                continue;
+            };
+            *slot = None;
+            if let Err(err) = direction_res {
+                let fatal = err.kind() != io::ErrorKind::BrokenPipe;
+                first_err.get_or_insert(err);
+                if fatal {
+                    return Poll::Ready(());
+                }
+            }
+        }
+        if directions.iter().all(Option::is_none) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+    first_err.map_or(Ok(()), Err)
+}
+
+/// Whether splicing from a socket into a pipe works at all. Seccomp profiles of locked-down
+/// containers commonly deny it, so this is probed once and the answer reused.
+pub(crate) fn splice_supported() -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED.get_or_init(|| match probe_splice() {
+        Ok(()) => true,
+        Err(err) => {
+            eprintln!("splice is not available, relaying through userspace: {err}");
+            false
+        }
+    })
+}
+
+fn probe_splice() -> io::Result<()> {
+    let (mut tx, rx) = StdUnixStream::pair()?;
+    tx.write_all(&[0])?;
+    let (_buf_read, buf_write) = sys_pipe()?;
+    SpliceFuture::splice(rx.as_raw_fd(), buf_write.as_raw_fd(), 1).map(|_| ())
+}
+
+fn splice_one_way<'a>(
+    reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
+    pipe: Pipe,
+    pool: &'static PipePool,
+    relayed: RelayCounter<'a>,
+) -> SpliceFuture<'a> {
+    SpliceFuture {
         reader,
         writer,
-        buf_read,
-        buf_write,
-        num_buf: 0,
+        pipe: Some(pipe),
+        pool,
+        relayed,
+        buffered: 0,
         read_done: false,
-    })
+        shutdown_done: false,
+    }
 }
 
+/// upper bound on the bytes moved into the pipe by a single splice call
+const PIPE_CHUNK: usize = 64 << 10;
+
 macro_rules! try_libc {
     ($e: expr) => {{
         let ret = $e;
@@ -91,17 +231,36 @@ fn sys_pipe() -> io::Result<(OwnedFd, OwnedFd)> {
     ))
 }
 
+/// Moves bytes from `reader` to `writer` through a pipe, without copying them into userspace.
+///
+/// Data is spliced from the reader into the pipe only once the pipe has been fully drained
+/// into the writer, so `buffered` is exactly the number of bytes sitting in the pipe. Writes
+/// may drain the pipe partially, in which case the remainder stays buffered until the writer
+/// becomes writable again.
 #[derive(Debug)]
-struct SpliceFuture {
+struct SpliceFuture<'a> {
     reader: OwnedReadHalf,
     writer: OwnedWriteHalf,
-    buf_read: OwnedFd,
-    buf_write: OwnedFd,
-    num_buf: usize,
+    /// only taken when the future is dropped, to be given back to `pool`
+    pipe: Option<Pipe>,
+    pool: &'static PipePool,
+    /// counts the bytes written out
+    relayed: RelayCounter<'a>,
+    /// bytes spliced into the pipe that have not been written out yet
+    buffered: usize,
+    /// the reader returned eof, nothing more will be spliced into the pipe
     read_done: bool,
+    /// the writer has been shut down after everything was written out
+    shutdown_done: bool,
 }
 
-impl SpliceFuture {
+impl SpliceFuture<'_> {
+    /// The read and write end of the pipe.
+    fn pipe_fds(&self) -> (RawFd, RawFd) {
+        let (read, write) = self.pipe.as_ref().expect("pipe is only taken on drop");
+        (read.as_raw_fd(), write.as_raw_fd())
+    }
+
     fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
         cvt!(unsafe {
             libc::splice(
@@ -115,83 +274,391 @@ impl SpliceFuture {
         })
     }
 
-    fn do_read_op(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
-        let read_op = self
-            .reader
-            .as_ref()
-            .try_io(tokio::io::Interest::READABLE, || {
-                Self::splice(
-                    self.reader.as_ref().as_raw_fd(),
-                    self.buf_write.as_raw_fd(),
-                    64 << 10,
-                )
-            });
-        match read_op {
-            Ok(nread) => {
-                self.read_done = nread == 0;
-                Poll::Ready(read_op)
-            }
-            Err(err) => {
-                if err.kind() == io::ErrorKind::WouldBlock {
-                    ready!(self.reader.as_ref().poll_read_ready(cx))?;
-                    Poll::Ready(Ok(0))
-                } else {
-                    Poll::Ready(Err(err))
-                }
+    /// Splices whatever the reader has available into the empty pipe. Returns the number of
+    /// bytes moved, 0 meaning the reader reached eof.
+    fn poll_fill_pipe(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        loop {
+            ready!(self.reader.as_ref().poll_read_ready(cx))?;
+            let res = self
+                .reader
+                .as_ref()
+                .try_io(tokio::io::Interest::READABLE, || {
+                    Self::splice(
+                        self.reader.as_ref().as_raw_fd(),
+                        self.pipe_fds().1,
+                        PIPE_CHUNK,
+                    )
+                });
+            match res {
+                Ok(n) => return Poll::Ready(Ok(n)),
+                // try_io cleared the readiness, so the next poll_read_ready registers a wakeup
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Poll::Ready(Err(err)),
             }
         }
     }
 
-    fn do_write_op(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
-        let write_op = self
-            .writer
-            .as_ref()
-            .try_io(tokio::io::Interest::WRITABLE, || {
-                Self::splice(
-                    self.buf_read.as_raw_fd(),
-                    self.writer.as_ref().as_raw_fd(),
-                    self.num_buf,
-                )
-            });
-        match write_op {
-            Ok(n_written) => Poll::Ready(Ok(n_written)),
-            Err(err) => {
-                if err.kind() == io::ErrorKind::WouldBlock {
-                    // register to wake up when writer is ready for writes
-                    ready!(self.writer.as_ref().poll_write_ready(cx))?;
-                    Poll::Ready(Ok(0))
-                } else {
-                    Poll::Ready(Err(err))
+    /// Splices buffered bytes from the pipe into the writer. Returns the number of bytes
+    /// written, which may be less than what was buffered.
+    fn poll_drain_pipe(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        loop {
+            ready!(self.writer.as_ref().poll_write_ready(cx))?;
+            let res = self
+                .writer
+                .as_ref()
+                .try_io(tokio::io::Interest::WRITABLE, || {
+                    Self::splice(
+                        self.pipe_fds().0,
+                        self.writer.as_ref().as_raw_fd(),
+                        self.buffered,
+                    )
+                });
+            match res {
+                Ok(0) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "splice wrote zero bytes with data still buffered",
+                    )))
                 }
+                Ok(n) => return Poll::Ready(Ok(n)),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Poll::Ready(Err(err)),
             }
         }
     }
 }
 
-impl Future for SpliceFuture {
+impl Future for SpliceFuture<'_> {
     type Output = io::Result<()>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         loop {
-            while self.num_buf == 0 && !self.read_done {
-                self.num_buf += ready!(self.do_read_op(cx))?;
-            }
-
-            while self.num_buf > 0 {
-                self.num_buf -= ready!(self.do_write_op(cx))?;
+            if self.buffered > 0 {
+                let n = ready!(self.poll_drain_pipe(cx))?;
+                self.buffered -= n;
+                self.relayed.relayed(n);
+            } else if !self.read_done {
+                let n = ready!(self.poll_fill_pipe(cx))?;
+                self.buffered = n;
+                self.read_done = n == 0;
+            } else if !self.shutdown_done {
+                match ready!(Pin::new(&mut self.writer).poll_shutdown(cx)) {
+                    // the peer already closed its side entirely, there is nobody left to
+                    // signal eof to
+                    Err(err) if err.kind() == io::ErrorKind::NotConnected => {}
+                    res => res?,
+                }
+                self.shutdown_done = true;
+            } else {
+                return Poll::Ready(Ok(()));
             }
+        }
+    }
+}
 
-            if self.is_terminated() {
-                return Pin::new(&mut self.writer).poll_shutdown(cx);
+impl Drop for SpliceFuture<'_> {
+    fn drop(&mut self) {
+        // bytes left in the pipe after a failure would leak into the next connection
+        if self.buffered == 0 {
+            if let Some(pipe) = self.pipe.take() {
+                self.pool.give_back(pipe);
             }
         }
     }
 }
 
-impl FusedFuture for SpliceFuture {
+impl FusedFuture for SpliceFuture<'_> {
     fn is_terminated(&self) -> bool {
-        // we are done when the reader is closed and we have written everything we had previously
-        // buffered
-        self.num_buf == 0 && self.read_done
+        // we are done when the reader is closed, everything we buffered has been written and
+        // the writer has been shut down
+        self.read_done && self.buffered == 0 && self.shutdown_done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::AtomicU64, Arc, LazyLock},
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::watch,
+        time::{sleep, timeout},
+    };
+
+    use super::*;
+    use crate::metrics::{Progress, RelayMetrics};
+
+    const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    static TOTALS: RelayMetrics = RelayMetrics {
+        bytes_to_remote: AtomicU64::new(0),
+        bytes_to_client: AtomicU64::new(0),
+    };
+    static RELAYED: LazyLock<ConnectionRelay<'static>> = LazyLock::new(|| {
+        ConnectionRelay::new(&TOTALS, Arc::new(watch::Sender::new(Progress::default())))
+    });
+
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let lis = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (connected, accepted) =
+            tokio::join!(TcpStream::connect(lis.local_addr().unwrap()), lis.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    /// Returns the splice future relaying from `src` to `dst` through a pipe of `pool`, along
+    /// with the far ends of both socket pairs.
+    async fn splice_pair(pool: &'static PipePool) -> (SpliceFuture<'static>, TcpStream, TcpStream) {
+        let (src, src_peer) = socket_pair().await;
+        let (dst, dst_peer) = socket_pair().await;
+        let (src_read, _) = src.into_split();
+        let (_, dst_write) = dst.into_split();
+        let fut = splice_one_way(
+            src_read,
+            dst_write,
+            pool.take().unwrap(),
+            pool,
+            RELAYED.to_remote(),
+        );
+        (fut, src_peer, dst_peer)
+    }
+
+    /// Closes the stream with a RST instead of a FIN.
+    fn reset(stream: TcpStream) {
+        socket2::SockRef::from(&stream)
+            .set_linger(Some(Duration::ZERO))
+            .unwrap();
+        drop(stream);
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn relays_until_eof_and_shuts_down_writer() {
+        let (fut, mut src_peer, mut dst_peer) = splice_pair(&PIPE_POOL).await;
+        let relay = tokio::spawn(fut);
+
+        let data = pattern(1 << 20);
+        src_peer.write_all(&data).await.unwrap();
+        src_peer.shutdown().await.unwrap();
+
+        let mut received = Vec::new();
+        timeout(TEST_TIMEOUT, dst_peer.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(received == data, "relayed bytes differ from the sent ones");
+        timeout(TEST_TIMEOUT, relay)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn slow_reader_gets_everything() {
+        let (fut, mut src_peer, mut dst_peer) = splice_pair(&PIPE_POOL).await;
+        let relay = tokio::spawn(fut);
+
+        let data = pattern(4 << 20);
+        let sent = data.clone();
+        let writer = tokio::spawn(async move {
+            src_peer.write_all(&sent).await.unwrap();
+            src_peer.shutdown().await.unwrap();
+        });
+
+        // read in small chunks with pauses so the writer side keeps hitting a full socket
+        // buffer and the pipe is only ever drained partially
+        let mut received = Vec::new();
+        let mut buf = [0_u8; 4096];
+        let read_all = async {
+            loop {
+                let n = dst_peer.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buf[..n]);
+                if received.len() % (256 << 10) < n {
+                    sleep(Duration::from_millis(20)).await;
+                }
+            }
+        };
+        timeout(TEST_TIMEOUT, read_all).await.unwrap();
+        assert!(received == data, "relayed bytes differ from the sent ones");
+
+        writer.await.unwrap();
+        timeout(TEST_TIMEOUT, relay)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn half_close_keeps_relaying_the_other_direction() {
+        let (a, mut a_peer) = socket_pair().await;
+        let (b, mut b_peer) = socket_pair().await;
+        let pipes = SplicePipes::new().unwrap();
+        let relay = tokio::spawn(splice_bidirectional(a, b, pipes, &RELAYED));
+
+        a_peer.write_all(b"request").await.unwrap();
+        a_peer.shutdown().await.unwrap();
+
+        let mut request = Vec::new();
+        timeout(TEST_TIMEOUT, b_peer.read_to_end(&mut request))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(request, b"request");
+
+        // a only closed its write side, so the response must still make it back
+        b_peer.write_all(b"response").await.unwrap();
+        b_peer.shutdown().await.unwrap();
+
+        let mut response = Vec::new();
+        timeout(TEST_TIMEOUT, a_peer.read_to_end(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response, b"response");
+        timeout(TEST_TIMEOUT, relay)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn delivers_the_response_after_the_request_direction_broke() {
+        let (a, mut a_peer) = socket_pair().await;
+        let (b, mut b_peer) = socket_pair().await;
+        // writes towards b fail with a broken pipe from the start
+        socket2::SockRef::from(&b)
+            .shutdown(std::net::Shutdown::Write)
+            .unwrap();
+        let pipes = SplicePipes::new().unwrap();
+        let relay = tokio::spawn(splice_bidirectional(a, b, pipes, &RELAYED));
+
+        a_peer.write_all(b"request").await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+        b_peer.write_all(b"response").await.unwrap();
+        b_peer.shutdown().await.unwrap();
+
+        let mut response = Vec::new();
+        timeout(TEST_TIMEOUT, a_peer.read_to_end(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response, b"response");
+        let res = timeout(TEST_TIMEOUT, relay).await.unwrap().unwrap();
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn reset_ends_both_directions() {
+        let (a, a_peer) = socket_pair().await;
+        let (b, _b_peer) = socket_pair().await;
+        let pipes = SplicePipes::new().unwrap();
+        let relay = tokio::spawn(splice_bidirectional(a, b, pipes, &RELAYED));
+
+        // b stays open and quiet, so only the reset can end the relay
+        reset(a_peer);
+        let res = timeout(TEST_TIMEOUT, relay).await.unwrap().unwrap();
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn reader_reset_fails_the_relay() {
+        let (fut, src_peer, _dst_peer) = splice_pair(&PIPE_POOL).await;
+        let relay = tokio::spawn(fut);
+
+        reset(src_peer);
+
+        let res = timeout(TEST_TIMEOUT, relay).await.unwrap().unwrap();
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn writer_reset_fails_the_relay() {
+        let (fut, mut src_peer, dst_peer) = splice_pair(&PIPE_POOL).await;
+        let relay = tokio::spawn(fut);
+
+        reset(dst_peer);
+
+        // keep feeding data until the relay notices its writer is gone
+        let data = pattern(64 << 10);
+        let feed = async {
+            while src_peer.write_all(&data).await.is_ok() {
+                sleep(Duration::from_millis(5)).await;
+            }
+        };
+        let res = tokio::select! {
+            res = relay => res.unwrap(),
+            _ = feed => panic!("source closed before the relay failed"),
+            _ = sleep(TEST_TIMEOUT) => panic!("relay did not notice the reset"),
+        };
+        let kind = res.unwrap_err().kind();
+        assert!(
+            matches!(
+                kind,
+                io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe
+            ),
+            "unexpected error kind: {kind:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn reuses_only_drained_pipes() {
+        static POOL: PipePool = PipePool::new(8);
+        let pooled = || POOL.len();
+
+        let (a, mut a_peer) = socket_pair().await;
+        let (b, mut b_peer) = socket_pair().await;
+        let pipes = SplicePipes::take_from(&POOL).unwrap();
+        let mut fds = [pipes.a_to_b.0.as_raw_fd(), pipes.b_to_a.0.as_raw_fd()];
+        let relay = tokio::spawn(splice_bidirectional(a, b, pipes, &RELAYED));
+        a_peer.write_all(b"request").await.unwrap();
+        a_peer.shutdown().await.unwrap();
+        b_peer.shutdown().await.unwrap();
+        let mut buf = Vec::new();
+        b_peer.read_to_end(&mut buf).await.unwrap();
+        a_peer.read_to_end(&mut buf).await.unwrap();
+        timeout(TEST_TIMEOUT, relay)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(pooled(), 2);
+
+        let again = SplicePipes::take_from(&POOL).unwrap();
+        let mut reused = [again.a_to_b.0.as_raw_fd(), again.b_to_a.0.as_raw_fd()];
+        fds.sort();
+        reused.sort();
+        assert_eq!(reused, fds);
+        assert_eq!(pooled(), 0);
+
+        // the relay fails with the bytes it could not write out still in the pipe
+        let (fut, mut src_peer, dst_peer) = splice_pair(&POOL).await;
+        let relay = tokio::spawn(fut);
+        reset(dst_peer);
+        let data = pattern(64 << 10);
+        let feed = async {
+            while src_peer.write_all(&data).await.is_ok() {
+                sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::select! {
+            res = relay => assert!(res.unwrap().is_err()),
+            _ = feed => panic!("source closed before the relay failed"),
+            _ = sleep(TEST_TIMEOUT) => panic!("relay did not notice the reset"),
+        }
+        assert_eq!(pooled(), 0);
     }
 }
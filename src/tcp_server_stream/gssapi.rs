@@ -0,0 +1,344 @@
+//! The RFC 1961 GSSAPI subnegotiation, and the per-message protection it sets up for the
+//! rest of the connection.
+//!
+//! Accepting a context may read the keytab and replay cache, so it runs on a blocking
+//! thread. Wrapping and unwrapping messages only works on memory and stays on the runtime.
+
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    task,
+};
+
+use super::ClientConn;
+use crate::{
+    config::{GssapiConfig, GssapiProtection},
+    gssapi::{ServerContext, Step},
+    metrics::ConnectionRelay,
+    server::Server,
+};
+
+const VERSION: u8 = 0x01;
+const MTYP_AUTH: u8 = 0x01;
+const MTYP_PROTECTION: u8 = 0x02;
+const MTYP_ENCAPSULATION: u8 = 0x03;
+const MTYP_ABORT: u8 = 0xff;
+
+const LEVEL_INTEGRITY: u8 = 0x01;
+const LEVEL_CONFIDENTIALITY: u8 = 0x02;
+const LEVEL_SELECTIVE: u8 = 0x03;
+
+/// Plaintext relayed per encapsulated message, small enough for the wrapped token to fit the
+/// message's 16 bit length.
+const CHUNK_SIZE: usize = 32 * 1024;
+
+/// An established context along with the protection every following message gets.
+pub(super) struct Session {
+    context: ServerContext,
+    encrypt: bool,
+}
+
+/// Accepts the client's security context and agrees on a protection level with it. The
+/// client is sent an abort message if either fails, after the server's tarpit delay.
+pub(super) async fn establish(
+    server: &Server,
+    stream: &mut ClientConn,
+    config: &GssapiConfig,
+) -> io::Result<Session> {
+    match negotiate(stream, config).await {
+        Ok(session) => Ok(session),
+        Err(err) => {
+            server.tarpit().await?;
+            stream.write_all(&[VERSION, MTYP_ABORT]).await?;
+            Err(err)
+        }
+    }
+}
+
+async fn negotiate(stream: &mut ClientConn, config: &GssapiConfig) -> io::Result<Session> {
+    let mut context = ServerContext::new();
+    loop {
+        let token = read_message(stream, MTYP_AUTH).await?;
+        let (returned, step) = task::spawn_blocking(move || {
+            let step = context.accept(&token);
+            (context, step)
+        })
+        .await
+        .map_err(io::Error::other)?;
+        context = returned;
+        match step? {
+            Step::Continue(reply) => write_message(stream, MTYP_AUTH, &reply).await?,
+            Step::Complete(reply) => {
+                if !reply.is_empty() {
+                    write_message(stream, MTYP_AUTH, &reply).await?;
+                }
+                break;
+            }
+        }
+    }
+
+    let (offered, _) = context.unwrap(&read_message(stream, MTYP_PROTECTION).await?)?;
+    let chosen = choose_protection(&offered, config.protection)?;
+    let level = match chosen {
+        GssapiProtection::Integrity => LEVEL_INTEGRITY,
+        GssapiProtection::Confidentiality => LEVEL_CONFIDENTIALITY,
+    };
+    let reply = context.wrap(false, &[level])?;
+    write_message(stream, MTYP_PROTECTION, &reply).await?;
+
+    Ok(Session {
+        context,
+        encrypt: chosen == GssapiProtection::Confidentiality,
+    })
+}
+
+/// Picks the protection of the rest of the connection among the levels the client offered,
+/// failing if none of them is at least `required`.
+fn choose_protection(offered: &[u8], required: GssapiProtection) -> io::Result<GssapiProtection> {
+    let offered = match offered {
+        [LEVEL_INTEGRITY] => GssapiProtection::Integrity,
+        // per message selection is up to the sender, so hold the client to encrypting
+        [LEVEL_CONFIDENTIALITY | LEVEL_SELECTIVE] => GssapiProtection::Confidentiality,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("gssapi: invalid protection level {offered:?}"),
+            ))
+        }
+    };
+    if offered < required {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("gssapi: client offered {offered:?} protection, {required:?} is required"),
+        ));
+    }
+    Ok(offered)
+}
+
+impl Session {
+    /// The kerberos principal the client authenticated as.
+    pub(super) fn client_name(&self) -> io::Result<String> {
+        self.context
+            .client_name()
+            .map(str::to_owned)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "gssapi: client principal is unknown",
+                )
+            })
+    }
+
+    /// Reads and unprotects the next encapsulated message.
+    pub(super) async fn read(&self, stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+        let token = read_message(stream, MTYP_ENCAPSULATION).await?;
+        self.unwrap(&token)
+    }
+
+    /// Protects `data` and sends it as a single encapsulated message.
+    pub(super) async fn write(
+        &self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        data: &[u8],
+    ) -> io::Result<()> {
+        let token = self.context.wrap(self.encrypt, data)?;
+        write_message(stream, MTYP_ENCAPSULATION, &token).await
+    }
+
+    /// Copies between the client and the remote peer until both have finished sending,
+    /// wrapping what the remote sends and unwrapping what the client sends.
+    pub(super) async fn relay(
+        &self,
+        client: ClientConn,
+        remote: TcpStream,
+        relayed: &ConnectionRelay<'_>,
+    ) -> io::Result<()> {
+        let (mut client_read, mut client_write) = io::split(client);
+        let (mut remote_read, mut remote_write) = remote.into_split();
+
+        let upstream = async {
+            while let Some((mtyp, token)) = read_message_or_eof(&mut client_read).await? {
+                expect_type(MTYP_ENCAPSULATION, mtyp)?;
+                let data = self.unwrap(&token)?;
+                remote_write.write_all(&data).await?;
+                relayed.to_remote().relayed(data.len());
+            }
+            remote_write.shutdown().await
+        };
+        let downstream = async {
+            let mut buf = vec![0; CHUNK_SIZE];
+            loop {
+                let n = remote_read.read(&mut buf).await?;
+                if n == 0 {
+                    return client_write.shutdown().await;
+                }
+                self.write(&mut client_write, &buf[..n]).await?;
+                relayed.to_client().relayed(n);
+            }
+        };
+        tokio::try_join!(upstream, downstream).map(|_| ())
+    }
+
+    fn unwrap(&self, token: &[u8]) -> io::Result<Vec<u8>> {
+        let (data, encrypted) = self.context.unwrap(token)?;
+        if self.encrypt && !encrypted {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "gssapi: client sent an unencrypted message after agreeing to confidentiality",
+            ));
+        }
+        Ok(data)
+    }
+}
+
+async fn read_message(stream: &mut (impl AsyncRead + Unpin), mtyp: u8) -> io::Result<Vec<u8>> {
+    let (received, token) = read_message_or_eof(stream).await?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "gssapi: connection closed while waiting for a message",
+        )
+    })?;
+    expect_type(mtyp, received)?;
+    Ok(token)
+}
+
+fn expect_type(expected: u8, received: u8) -> io::Result<()> {
+    if received == expected {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("gssapi: expected message type {expected:#04x}, got {received:#04x}"),
+        ))
+    }
+}
+
+/// Reads a message of any type, returning `None` if the stream ends before it starts.
+async fn read_message_or_eof(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let version = match stream.read_u8().await {
+        Ok(version) => version,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if version != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("gssapi: unsupported message version {version:#04x}"),
+        ));
+    }
+    let mtyp = stream.read_u8().await?;
+    if mtyp == MTYP_ABORT {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "gssapi: client aborted",
+        ));
+    }
+    let len = stream.read_u16().await?;
+    let mut token = vec![0; len as usize];
+    stream.read_exact(&mut token).await?;
+    Ok(Some((mtyp, token)))
+}
+
+async fn write_message(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mtyp: u8,
+    token: &[u8],
+) -> io::Result<()> {
+    let len = u16::try_from(token.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "gssapi: token of {} bytes does not fit a message",
+                token.len()
+            ),
+        )
+    })?;
+    let mut msg = Vec::with_capacity(4 + token.len());
+    msg.extend_from_slice(&[VERSION, mtyp]);
+    msg.extend_from_slice(&len.to_be_bytes());
+    msg.extend_from_slice(token);
+    stream.write_all(&msg).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+
+    #[test]
+    fn chooses_only_offered_protection() {
+        let choose = |offered: &[u8], required| choose_protection(offered, required);
+        assert_eq!(
+            choose(&[LEVEL_INTEGRITY], GssapiProtection::Integrity).unwrap(),
+            GssapiProtection::Integrity
+        );
+        assert_eq!(
+            choose(&[LEVEL_SELECTIVE], GssapiProtection::Integrity).unwrap(),
+            GssapiProtection::Confidentiality
+        );
+        assert_eq!(
+            choose(&[LEVEL_CONFIDENTIALITY], GssapiProtection::Confidentiality).unwrap(),
+            GssapiProtection::Confidentiality
+        );
+        let err = choose(&[LEVEL_INTEGRITY], GssapiProtection::Confidentiality).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let err = choose(&[0x04], GssapiProtection::Integrity).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(choose(&[], GssapiProtection::Integrity).is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_messages_and_reports_aborts() {
+        let (mut client, mut server) = io::duplex(1024);
+        write_message(&mut client, MTYP_AUTH, b"token")
+            .await
+            .unwrap();
+        assert_eq!(
+            read_message(&mut server, MTYP_AUTH).await.unwrap(),
+            b"token"
+        );
+
+        write_message(&mut client, MTYP_PROTECTION, &[1])
+            .await
+            .unwrap();
+        let err = read_message(&mut server, MTYP_AUTH).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        client.write_all(&[VERSION, MTYP_ABORT]).await.unwrap();
+        let err = read_message(&mut server, MTYP_AUTH).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+
+        client.write_all(&[0x05]).await.unwrap();
+        let err = read_message(&mut server, MTYP_AUTH).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        drop(client);
+        assert!(read_message_or_eof(&mut server).await.unwrap().is_none());
+        assert!(write_message(&mut io::sink(), MTYP_AUTH, &[0; 65536])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn aborts_clients_whose_context_is_not_accepted() {
+        let server = Server::new(ServerConfig::default()).unwrap();
+        let (mut client, server_end) = io::duplex(1024);
+        let mut conn = ClientConn::Wrapped {
+            stream: Box::new(server_end),
+            local_addr: "127.0.0.1:1080".parse().unwrap(),
+        };
+        write_message(&mut client, MTYP_AUTH, b"not a gssapi token")
+            .await
+            .unwrap();
+        let config = GssapiConfig {
+            keytab: None,
+            protection: GssapiProtection::Integrity,
+        };
+        assert!(establish(&server, &mut conn, &config).await.is_err());
+        let mut abort = [0_u8; 2];
+        client.read_exact(&mut abort).await.unwrap();
+        assert_eq!(abort, [VERSION, MTYP_ABORT]);
+    }
+}
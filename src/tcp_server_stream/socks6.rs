@@ -0,0 +1,269 @@
+//! Experimental SOCKS6 (draft-olteanu-intarea-socks-6-11) requests, relayed through the same
+//! policy checks and relay paths as socks5 ones.
+//!
+//! Only unauthenticated NOOP and CONNECT requests are served. Request options are skipped,
+//! and any early data the client sends after its request is relayed once the destination
+//! is connected, like the rest of the stream.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use super::{
+    check_policies, dial_failure_status, outbound_dscp, record_access, record_denied, relay,
+    within, within_handshake, ClientConn, Protection, Requested,
+};
+use crate::{
+    honeypot::DeniedRecord,
+    proto,
+    server::{ConnectionInfo, Server},
+};
+
+pub(super) const VERSION: u8 = 0x06;
+
+const CMD_NOOP: u8 = 0x00;
+const CMD_CONNECT: u8 = 0x01;
+
+const AUTH_SUCCESS: u8 = 0x00;
+const AUTH_FAILURE: u8 = 0x01;
+
+struct Request {
+    cmd: u8,
+    dest_addr: proto::Address,
+    dest_port: u16,
+}
+
+pub(super) async fn serve(
+    server: &Server,
+    mut stream: ClientConn,
+    info: ConnectionInfo,
+) -> io::Result<()> {
+    let Request {
+        cmd,
+        dest_addr,
+        dest_port,
+    } = within_handshake(
+        server,
+        info,
+        within(
+            server.config().handshake_timeouts.request_secs,
+            "request",
+            read_request(&mut stream),
+        ),
+    )
+    .await?;
+
+    let requires_auth = !server.skips_auth(&info)
+        && (server.authenticator(&info).is_some()
+            || server.config().gssapi.is_some()
+            || !server.private_auth_methods().is_empty());
+    if requires_auth {
+        stream.write_all(&[VERSION, AUTH_FAILURE, 0, 0]).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "socks6 clients can't authenticate, and the server requires authentication",
+        ));
+    }
+    stream.write_all(&[VERSION, AUTH_SUCCESS, 0, 0]).await?;
+
+    let request = match cmd {
+        CMD_NOOP => return reply(&mut stream, proto::ServerStatus::RequestGranted, None).await,
+        CMD_CONNECT => proto::ClientConnectionRequest {
+            cmd: proto::ClientCommand::EstablishConnection,
+            dest_addr,
+            dest_port,
+        },
+        cmd => {
+            reply(&mut stream, proto::ServerStatus::CommandNotSupported, None).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("socks6 command {cmd:#04x} is not supported"),
+            ));
+        }
+    };
+
+    let client = info.peer_addr;
+    let acl_rule = match check_policies(server, info, None, None, &request).await {
+        Ok(acl_rule) => acl_rule,
+        Err(rule) => {
+            let status = proto::ServerStatus::ConnectionNotAllowedByRuleset;
+            record_denied(
+                server,
+                &DeniedRecord {
+                    info,
+                    protocol: "socks6",
+                    auth_methods: &[],
+                    username: None,
+                    request: Some(&request),
+                    reason: rule,
+                },
+            );
+            server.tarpit().await?;
+            reply(&mut stream, status, None).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "{rule} denied socks6 connect from {client} to {}:{}",
+                    request.dest_addr, request.dest_port
+                ),
+            ));
+        }
+    };
+
+    let dialed = server
+        .dialer()
+        .connect(
+            server.resolver(),
+            &request.dest_addr,
+            request.dest_port,
+            outbound_dscp(server, info, &request),
+        )
+        .await;
+    record_access(server, info, None, &request, &dialed);
+    let conn = match dialed {
+        Ok(conn) => conn,
+        Err(err) => {
+            reply(&mut stream, dial_failure_status(&err), None).await?;
+            return Err(err);
+        }
+    };
+    let bound = conn.local_addr()?;
+    reply(
+        &mut stream,
+        proto::ServerStatus::RequestGranted,
+        Some(bound),
+    )
+    .await?;
+
+    let requested = Requested {
+        request: &request,
+        username: None,
+        acl_rule,
+    };
+    relay(
+        server,
+        stream,
+        conn,
+        requested,
+        &Protection::default(),
+        info,
+    )
+    .await
+}
+
+async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Request> {
+    let mut head = [0_u8; 8];
+    stream.read_exact(&mut head).await?;
+    let [version, cmd, options_len @ .., port_hi, port_lo, _padding, atyp] = head;
+    if version != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected socks version: {VERSION}, got: {version}"),
+        ));
+    }
+
+    let dest_addr = match atyp {
+        0x01 => {
+            let mut buf = [0_u8; 4];
+            stream.read_exact(&mut buf).await?;
+            proto::Address::Ipv4(Ipv4Addr::from(buf))
+        }
+        0x03 => {
+            let len = stream.read_u8().await? as usize;
+            // the name is padded with nuls up to a multiple of 4 bytes, length byte included
+            let padded = (1 + len).next_multiple_of(4) - 1;
+            let mut buf = vec![0_u8; padded];
+            stream.read_exact(&mut buf).await?;
+            let name = buf.split(|&b| b == 0).next().unwrap_or_default();
+            let name = String::from_utf8(name.to_vec())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+            proto::Address::DomainName(name)
+        }
+        0x04 => {
+            let mut buf = [0_u8; 16];
+            stream.read_exact(&mut buf).await?;
+            proto::Address::Ipv6(Ipv6Addr::from(buf))
+        }
+        atyp => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown socks6 address type: {atyp}"),
+            ))
+        }
+    };
+
+    let options_len = u16::from_be_bytes(options_len);
+    io::copy(&mut stream.take(options_len.into()), &mut io::sink()).await?;
+
+    Ok(Request {
+        cmd,
+        dest_addr,
+        dest_port: u16::from_be_bytes([port_hi, port_lo]),
+    })
+}
+
+async fn reply(
+    stream: &mut ClientConn,
+    status: proto::ServerStatus,
+    bound: Option<SocketAddr>,
+) -> io::Result<()> {
+    let (bound_address, bound_port) = match bound {
+        Some(bound) => (proto::Address::from(bound), bound.port()),
+        None => (proto::EMPTY_ADDRESS, 0),
+    };
+    let mut buf = vec![VERSION, status as u8, 0, 0];
+    buf.extend_from_slice(&bound_port.to_be_bytes());
+    buf.push(0);
+    buf.extend_from_slice(&bound_address.as_bytes());
+    stream.write_all(&buf).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::config::ServerConfig;
+
+    #[tokio::test]
+    async fn connects_and_relays_early_data() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            socks6: true,
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(config).unwrap());
+        tokio::spawn(Arc::clone(&server).serve(listener));
+
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest_port = dest.local_addr().unwrap().port();
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut request = vec![VERSION, CMD_CONNECT, 0, 8];
+        request.extend_from_slice(&dest_port.to_be_bytes());
+        request.extend_from_slice(&[0, 0x03, 9]);
+        request.extend_from_slice(b"localhost\0\0");
+        // an unknown option, which is skipped
+        request.extend_from_slice(&[0xff, 0xfe, 0, 8, 1, 2, 3, 4]);
+        request.extend_from_slice(b"early");
+        client.write_all(&request).await.unwrap();
+
+        let mut auth = [0_u8; 4];
+        client.read_exact(&mut auth).await.unwrap();
+        assert_eq!(auth, [VERSION, AUTH_SUCCESS, 0, 0]);
+        let mut reply = [0_u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(
+            reply[..2],
+            [VERSION, proto::ServerStatus::RequestGranted as u8]
+        );
+
+        let (mut conn, _) = dest.accept().await.unwrap();
+        let mut early = [0_u8; 5];
+        conn.read_exact(&mut early).await.unwrap();
+        assert_eq!(&early, b"early");
+    }
+}
@@ -0,0 +1,86 @@
+use std::{net::Shutdown, time::Duration};
+
+use futures::future::join_all;
+use socket2::{SockRef, Socket};
+use tokio::{
+    io::{self, AsyncReadExt},
+    net::TcpStream,
+    time,
+};
+
+use super::ClientConn;
+
+/// Duplicates of the sockets of a relayed connection, which keep them open after the relay
+/// dropped its streams, so that they can be closed gracefully when the relay failed.
+pub(super) struct Teardown {
+    sockets: Vec<Socket>,
+    timeout: Duration,
+}
+
+impl Teardown {
+    pub(super) fn new(
+        timeout_ms: u64,
+        client: &ClientConn,
+        remote: &TcpStream,
+    ) -> io::Result<Self> {
+        let mut sockets = vec![SockRef::from(remote).try_clone()?];
+        if let ClientConn::Tcp(client) = client {
+            sockets.push(SockRef::from(client).try_clone()?);
+        }
+        Ok(Self {
+            sockets,
+            timeout: Duration::from_millis(timeout_ms),
+        })
+    }
+
+    /// Sends a FIN to both peers and discards what they send until they close their sides,
+    /// or the timeout runs out. Errors only mean that a peer is gone already.
+    pub(super) async fn drain(self) {
+        let drains = self.sockets.into_iter().map(|socket| async move {
+            let _ = socket.shutdown(Shutdown::Write);
+            // the duplicates share the non-blocking mode of the relayed sockets
+            let Ok(mut stream) = TcpStream::from_std(socket.into()) else {
+                return;
+            };
+            let mut buf = [0; 4096];
+            while let Ok(1..) = stream.read(&mut buf).await {}
+        });
+        let _ = time::timeout(self.timeout, join_all(drains)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use super::*;
+
+    async fn pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        (peer, listener.accept().await.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn closes_both_legs_gracefully() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (mut client_peer, client) = pair(&listener).await;
+        let (mut remote_peer, remote) = pair(&listener).await;
+        let client = ClientConn::Tcp(client);
+        let teardown = Teardown::new(5000, &client, &remote).unwrap();
+        drop((client, remote));
+        let draining = tokio::spawn(teardown.drain());
+
+        let mut buf = [0; 16];
+        for peer in [&mut client_peer, &mut remote_peer] {
+            peer.write_all(b"late").await.unwrap();
+            assert_eq!(peer.read(&mut buf).await.unwrap(), 0);
+            peer.shutdown().await.unwrap();
+        }
+        time::timeout(Duration::from_secs(1), draining)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,904 @@
+//! UDP ASSOCIATE: relaying datagrams between the client and any destination it names.
+//!
+//! Each association gets a socket facing the client, bound next to the tcp listener, and one
+//! facing destinations. The association lasts as long as the controlling tcp connection, and
+//! is torn down early once no datagram moved in either direction for the configured idle
+//! timeout.
+//!
+//! Each destination a client sends datagrams to is checked against the same policies as the
+//! request of a CONNECT to it would be, once per association, and datagrams to denied ones
+//! are dropped.
+//!
+//! As RFC 1928 requires, datagrams are only accepted from the address the client declared in
+//! its ASSOCIATE request. An unspecified address stands for the ip of the control connection,
+//! and an unspecified port for whichever port the client sends from first. Clients behind NATs
+//! that rebind their mappings can be allowed to move with `udp.allow_client_rebinding`.
+//!
+//! On linux, runs of datagrams the kernel coalesced on receipt are relayed with a single
+//! segmented write per destination where possible, see [`offload`].
+//!
+//! With `udp.dns` configured, DNS queries are answered from a cache where possible and sent
+//! to the upstream resolver otherwise, see [`crate::dns_relay`]. Answers arrive behind a
+//! header naming the destination the client sent its query to, wherever it went.
+
+#[cfg(target_os = "linux")]
+mod offload;
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::atomic::Ordering,
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    time::{sleep, Instant},
+};
+
+use super::ClientConn;
+use crate::{
+    auth::Grant,
+    dial::{set_dscp, Dialer},
+    dns_relay::{self, AnswerCache, DnsRelay, DNS_PORT},
+    faults::{self, Phase},
+    metrics::UdpMetrics,
+    proto,
+    server::ConnectionInfo,
+    server::Server,
+};
+
+// the largest payload of a udp datagram over ipv4
+const MAX_UDP_PAYLOAD: usize = 65507;
+// plus room for the longest header
+const MAX_DATAGRAM: usize = MAX_UDP_PAYLOAD + 262;
+// the most datagrams linux accepts in a single UDP_SEGMENT write
+const MAX_SEGMENTS: usize = 64;
+// dns queries an association waits for answers to, beyond which all of them are forgotten
+const MAX_PENDING_DNS: usize = 1024;
+// destinations an association remembers the policy verdict of, beyond which all are forgotten
+const MAX_VERDICTS: usize = 1024;
+
+pub(super) async fn serve_associate(
+    server: &Server,
+    mut stream: ClientConn,
+    request: proto::ClientConnectionRequest,
+    username: Option<String>,
+    grant: Grant,
+    info: ConnectionInfo,
+) -> io::Result<()> {
+    let expected_ip = match request.dest_addr {
+        proto::Address::Ipv4(ip) if !ip.is_unspecified() => ip.into(),
+        proto::Address::Ipv6(ip) if !ip.is_unspecified() => ip.into(),
+        _ => info.peer_addr.ip(),
+    };
+    let expected_port = (request.dest_port != 0).then_some(request.dest_port);
+
+    let config = &server.config().udp;
+    let client_socket = UdpSocket::bind((stream.local_addr()?.ip(), 0)).await?;
+    let dscp = super::outbound_dscp(server, info, &request);
+    let remote_socket = bind_outbound(server.dialer(), dscp)?;
+    let bound = client_socket.local_addr()?;
+    #[cfg(target_os = "linux")]
+    let segment_sends = config.offload
+        && match offload::enable(&client_socket).and_then(|()| offload::enable(&remote_socket)) {
+            Ok(()) => true,
+            Err(err) => {
+                eprintln!("{info}: udp segmentation offload unavailable: {err}");
+                false
+            }
+        };
+
+    let resp = proto::ServerResponse {
+        status: proto::ServerStatus::RequestGranted,
+        bound_address: bound.into(),
+        bound_port: bound.port(),
+    };
+    let resp = resp.as_bytes();
+    let resp = faults::inject(server.config().faults.as_ref(), Phase::Request, &resp).await?;
+    stream.write_all(&resp).await?;
+
+    let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+    let association = Association {
+        server,
+        info,
+        username,
+        grant,
+        verdicts: HashMap::new(),
+        client_socket,
+        remote_socket,
+        expected_ip,
+        expected_port,
+        allow_rebinding: config.allow_client_rebinding,
+        client_addr: None,
+        pending_dns: HashMap::new(),
+        dns_cache: server.dns_relay().and_then(DnsRelay::association_cache),
+        #[cfg(target_os = "linux")]
+        segment_sends,
+    };
+
+    let metrics = &server.metrics().udp;
+    metrics.associations_active.fetch_add(1, Ordering::Relaxed);
+    let res = association.run(&mut stream, idle_timeout, info).await;
+    metrics.associations_active.fetch_sub(1, Ordering::Relaxed);
+    res
+}
+
+/// Why a datagram was not relayed.
+enum Dropped {
+    Oversize,
+    BadHeader(io::Error),
+    Unauthorized,
+    /// by the named policy
+    Denied(&'static str),
+    Failed(io::Error),
+}
+
+impl Dropped {
+    fn count(&self, metrics: &UdpMetrics) {
+        let counter = match self {
+            Self::Oversize => &metrics.dropped_oversize,
+            Self::BadHeader(_) => &metrics.dropped_bad_header,
+            Self::Unauthorized => &metrics.dropped_unauthorized,
+            Self::Denied(_) => &metrics.dropped_denied,
+            Self::Failed(_) => &metrics.dropped_failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for Dropped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Oversize => write!(f, "too large to relay"),
+            Self::BadHeader(err) => write!(f, "bad header: {err}"),
+            Self::Unauthorized => write!(f, "sender is not the client of this association"),
+            Self::Denied(rule) => write!(f, "destination denied by {rule}"),
+            Self::Failed(err) => err.fmt(f),
+        }
+    }
+}
+
+/// Where a datagram from the client goes.
+enum Route<'d> {
+    /// to a destination, with its payload
+    Remote(SocketAddr, &'d [u8]),
+    /// back to the client, as a dns query answered from the cache: the header and the
+    /// answer
+    Answered(Vec<u8>, Vec<u8>),
+}
+
+/// What one read returned: `len` bytes of datagrams from `from`, all `segment_size` bytes
+/// long but the last, which may be shorter. More than one only where the kernel coalesced
+/// them.
+struct Received {
+    len: usize,
+    from: SocketAddr,
+    segment_size: usize,
+}
+
+impl Received {
+    fn datagrams<'a>(&self, buf: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+        segments(&buf[..self.len], self.segment_size)
+    }
+}
+
+/// Splits `buf` into datagrams of `segment_size` bytes, the last possibly shorter. An empty
+/// `buf` is a single empty datagram.
+fn segments(buf: &[u8], segment_size: usize) -> impl Iterator<Item = &[u8]> {
+    let empty = buf.is_empty().then_some(buf);
+    buf.chunks(segment_size.max(1)).chain(empty)
+}
+
+/// Reads a datagram, or a run of them the kernel coalesced.
+async fn recv(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<Received> {
+    #[cfg(target_os = "linux")]
+    return offload::recv_from(socket, buf).await;
+    #[cfg(not(target_os = "linux"))]
+    {
+        let (len, from) = socket.recv_from(buf).await?;
+        Ok(Received {
+            len,
+            from,
+            segment_size: len,
+        })
+    }
+}
+
+/// Datagrams to one destination, gathered while they are all of one size but the last, so
+/// they can be sent with a single write.
+struct Run {
+    dest: Option<SocketAddr>,
+    buf: Vec<u8>,
+    segment_size: usize,
+    /// payload bytes of each datagram, not counting socks headers
+    payload_lens: Vec<usize>,
+}
+
+impl Run {
+    fn new() -> Self {
+        Self {
+            dest: None,
+            buf: Vec::with_capacity(MAX_DATAGRAM),
+            segment_size: 0,
+            payload_lens: Vec::with_capacity(MAX_SEGMENTS),
+        }
+    }
+
+    /// Whether a datagram of `len` bytes to `dest` can join the run.
+    fn fits(&self, dest: SocketAddr, len: usize) -> bool {
+        self.dest == Some(dest)
+            && len > 0
+            && len <= self.segment_size
+            // a shorter datagram ends the run
+            && self.buf.len().is_multiple_of(self.segment_size)
+            && self.payload_lens.len() < MAX_SEGMENTS
+            && self.buf.len() + len <= MAX_UDP_PAYLOAD
+    }
+
+    fn push(&mut self, dest: SocketAddr, header: &[u8], payload: &[u8]) {
+        if self.dest.is_none() {
+            self.dest = Some(dest);
+            self.segment_size = header.len() + payload.len();
+        }
+        self.buf.extend_from_slice(header);
+        self.buf.extend_from_slice(payload);
+        self.payload_lens.push(payload.len());
+    }
+
+    fn clear(&mut self) {
+        self.dest = None;
+        self.buf.clear();
+        self.payload_lens.clear();
+    }
+}
+
+/// A dns query waiting for its answer.
+struct PendingDns {
+    /// what it asked, if the answer may be cached
+    question: Option<Vec<u8>>,
+    /// the header to put in front of the answer if it comes from elsewhere than the client
+    /// sent the query to
+    relabel: Option<Vec<u8>>,
+}
+
+struct Association<'a> {
+    server: &'a Server,
+    info: ConnectionInfo,
+    /// the user the client authenticated as, and what their credentials entitle them to
+    username: Option<String>,
+    grant: Grant,
+    /// the policy denying each destination the client sent to, if any does
+    verdicts: HashMap<(proto::Address, u16), Option<&'static str>>,
+    client_socket: UdpSocket,
+    remote_socket: UdpSocket,
+    expected_ip: IpAddr,
+    /// any port when the client didn't declare one
+    expected_port: Option<u16>,
+    allow_rebinding: bool,
+    /// where the client sends its datagrams from, learned from the first one
+    client_addr: Option<SocketAddr>,
+    /// dns queries relayed, by where they went and their id
+    pending_dns: HashMap<(SocketAddr, u16), PendingDns>,
+    /// this association's own dns answers, when there is no upstream to share those of
+    dns_cache: Option<AnswerCache>,
+    /// send runs of datagrams with UDP_SEGMENT. cleared if the outgoing device turns out not
+    /// to support it.
+    #[cfg(target_os = "linux")]
+    segment_sends: bool,
+}
+
+impl Association<'_> {
+    async fn run(
+        mut self,
+        control: &mut ClientConn,
+        idle_timeout: Duration,
+        info: ConnectionInfo,
+    ) -> io::Result<()> {
+        let mut control_buf = [0_u8; 64];
+        let mut client_buf = vec![0_u8; MAX_DATAGRAM];
+        let mut remote_buf = vec![0_u8; MAX_DATAGRAM];
+        let mut run = Run::new();
+        let idle = sleep(idle_timeout);
+        tokio::pin!(idle);
+
+        loop {
+            tokio::select! {
+                res = control.read(&mut control_buf) => {
+                    // clients have nothing to say on the control connection, so anything but
+                    // data means it is gone
+                    if matches!(res, Ok(0) | Err(_)) {
+                        eprintln!("{info}: udp association ended with its control connection");
+                        return Ok(());
+                    }
+                }
+                res = recv(&self.client_socket, &mut client_buf) => {
+                    let received = res?;
+                    self.forward_to_remote(&client_buf, &received, &mut run, info).await;
+                    idle.as_mut().reset(Instant::now() + idle_timeout);
+                }
+                res = recv(&self.remote_socket, &mut remote_buf) => {
+                    let received = res?;
+                    self.forward_to_client(&remote_buf, &received, &mut run, info).await;
+                    idle.as_mut().reset(Instant::now() + idle_timeout);
+                }
+                _ = &mut idle => {
+                    eprintln!("{info}: udp association idle for {idle_timeout:?}, closing");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Whether a datagram from `from` may belong to the client.
+    fn is_client(&self, from: SocketAddr) -> bool {
+        if self.allow_rebinding {
+            return true;
+        }
+        let ip_ok = from.ip().to_canonical() == self.expected_ip.to_canonical();
+        let port_ok = match (self.expected_port, self.client_addr) {
+            (Some(port), _) => from.port() == port,
+            (None, Some(client_addr)) => from.port() == client_addr.port(),
+            (None, None) => true,
+        };
+        ip_ok && port_ok
+    }
+
+    /// Relays what the client sent, in as few writes as destinations and sizes allow.
+    async fn forward_to_remote(
+        &mut self,
+        buf: &[u8],
+        received: &Received,
+        run: &mut Run,
+        info: ConnectionInfo,
+    ) {
+        let mut answered = Vec::new();
+        for datagram in received.datagrams(buf) {
+            match self.destination(datagram, received.from).await {
+                Ok(Route::Remote(dest, payload)) => {
+                    if !run.fits(dest, payload.len()) {
+                        self.flush(run, false, info).await;
+                    }
+                    run.push(dest, &[], payload);
+                }
+                Ok(Route::Answered(header, answer)) => answered.push((header, answer)),
+                Err(dropped) => {
+                    dropped.count(&self.server.metrics().udp);
+                    eprintln!(
+                        "{info}: udp datagram from {} dropped: {dropped}",
+                        received.from
+                    );
+                }
+            }
+        }
+        self.flush(run, false, info).await;
+        for (header, answer) in answered {
+            if !run.fits(received.from, header.len() + answer.len()) {
+                self.flush(run, true, info).await;
+            }
+            run.push(received.from, &header, &answer);
+        }
+        self.flush(run, true, info).await;
+    }
+
+    /// Returns where a datagram from the client goes.
+    async fn destination<'d>(
+        &mut self,
+        datagram: &'d [u8],
+        from: SocketAddr,
+    ) -> Result<Route<'d>, Dropped> {
+        if !self.is_client(from) {
+            return Err(Dropped::Unauthorized);
+        }
+        let (header, payload) = proto::UdpHeader::parse(datagram).map_err(Dropped::BadHeader)?;
+        if header.frag != 0 {
+            return Err(Dropped::BadHeader(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "fragmented datagrams are not supported",
+            )));
+        }
+        self.client_addr = Some(from);
+        if let Some(rule) = self.denying_rule(&header).await {
+            return Err(Dropped::Denied(rule));
+        }
+
+        let dns = self
+            .server
+            .dns_relay()
+            .filter(|_| header.dest_port == DNS_PORT);
+        let cached = dns
+            .and_then(|_| self.dns_cache())
+            .and_then(|cache| cache.answer(payload));
+        if let Some(answer) = cached {
+            return Ok(Route::Answered(header.as_bytes(), answer));
+        }
+        let upstream = dns.and_then(DnsRelay::upstream);
+        let relabel = upstream.map(|_| header.as_bytes());
+        let dest = match (upstream, header.dest_addr) {
+            (Some(upstream), _) => upstream,
+            (None, proto::Address::Ipv4(ip)) => SocketAddr::new(ip.into(), header.dest_port),
+            (None, proto::Address::Ipv6(ip)) => SocketAddr::new(ip.into(), header.dest_port),
+            (None, proto::Address::DomainName(host)) => self
+                .server
+                .resolver()
+                .resolve(&host, header.dest_port)
+                .await
+                .map_err(Dropped::Failed)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| {
+                    Dropped::Failed(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("{host} did not resolve to any address"),
+                    ))
+                })?,
+        };
+        if let (Some(_), Some(id)) = (dns, dns_relay::message_id(payload)) {
+            if self.pending_dns.len() >= MAX_PENDING_DNS {
+                self.pending_dns.clear();
+            }
+            let to = SocketAddr::new(dest.ip().to_canonical(), dest.port());
+            let pending = PendingDns {
+                question: dns_relay::question_key(payload),
+                relabel,
+            };
+            self.pending_dns.insert((to, id), pending);
+        }
+        let dest = outbound_addr(&self.remote_socket, dest).map_err(Dropped::Failed)?;
+        Ok(Route::Remote(dest, payload))
+    }
+
+    /// Names the policy denying datagrams to the destination of `header`, if any does,
+    /// asking the server only the first time the client sends there.
+    async fn denying_rule(&mut self, header: &proto::UdpHeader) -> Option<&'static str> {
+        let key = (header.dest_addr.clone(), header.dest_port);
+        if let Some(&verdict) = self.verdicts.get(&key) {
+            return verdict;
+        }
+        let request = proto::ClientConnectionRequest {
+            cmd: proto::ClientCommand::AssociateUdpPort,
+            dest_addr: key.0.clone(),
+            dest_port: key.1,
+        };
+        let verdict = super::check_policies(
+            self.server,
+            self.info,
+            self.username.as_deref(),
+            Some(&self.grant),
+            &request,
+        )
+        .await
+        .err();
+        if self.verdicts.len() >= MAX_VERDICTS {
+            self.verdicts.clear();
+        }
+        self.verdicts.insert(key, verdict);
+        verdict
+    }
+
+    /// Caches what a resolver answered to a query the client sent, returning the header
+    /// naming where the client sent it if the answer comes from elsewhere.
+    fn dns_answer(&mut self, from: SocketAddr, payload: &[u8]) -> Option<Vec<u8>> {
+        self.server.dns_relay()?;
+        let pending = self
+            .pending_dns
+            .remove(&(from, dns_relay::message_id(payload)?))?;
+        if let (Some(cache), Some(question)) = (self.dns_cache(), &pending.question) {
+            cache.store(payload, question);
+        }
+        pending.relabel
+    }
+
+    /// Where answers to the client's dns queries are cached: with those of every other
+    /// association if they come from the upstream, or else with this one's alone.
+    fn dns_cache(&self) -> Option<&AnswerCache> {
+        self.dns_cache
+            .as_ref()
+            .or_else(|| self.server.dns_relay()?.cache())
+    }
+
+    /// Relays what a destination sent to the client, each datagram behind a header naming
+    /// the destination.
+    async fn forward_to_client(
+        &mut self,
+        buf: &[u8],
+        received: &Received,
+        run: &mut Run,
+        info: ConnectionInfo,
+    ) {
+        let metrics = &self.server.metrics().udp;
+        let from = SocketAddr::new(received.from.ip().to_canonical(), received.from.port());
+        let Some(client_addr) = self.client_addr else {
+            let dropped = Dropped::Failed(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the client has not sent anything yet",
+            ));
+            received.datagrams(buf).for_each(|_| dropped.count(metrics));
+            eprintln!("{info}: udp datagram from {from} dropped: {dropped}");
+            return;
+        };
+        let header = proto::UdpHeader {
+            frag: 0,
+            dest_addr: from.into(),
+            dest_port: from.port(),
+        }
+        .as_bytes();
+        for payload in received.datagrams(buf) {
+            let relabeled = self.dns_answer(from, payload);
+            let header = relabeled.as_deref().unwrap_or(&header);
+            let len = header.len() + payload.len();
+            if len > MAX_UDP_PAYLOAD {
+                Dropped::Oversize.count(metrics);
+                eprintln!(
+                    "{info}: udp datagram from {from} dropped: {}",
+                    Dropped::Oversize
+                );
+                continue;
+            }
+            if !run.fits(client_addr, len) {
+                self.flush(run, true, info).await;
+            }
+            run.push(client_addr, header, payload);
+        }
+        self.flush(run, true, info).await;
+    }
+
+    /// Sends and empties `run`, through the socket facing the client or the destinations.
+    async fn flush(&mut self, run: &mut Run, to_client: bool, info: ConnectionInfo) {
+        let Some(dest) = run.dest else {
+            return;
+        };
+        let metrics = &self.server.metrics().udp;
+        match self.send(run, dest, to_client).await {
+            Ok(()) => {
+                let (datagrams, bytes) = if to_client {
+                    (&metrics.datagrams_to_client, &metrics.bytes_to_client)
+                } else {
+                    (&metrics.datagrams_to_remote, &metrics.bytes_to_remote)
+                };
+                for &len in &run.payload_lens {
+                    UdpMetrics::relayed(datagrams, bytes, len);
+                }
+            }
+            Err(err) => {
+                let dropped = Dropped::Failed(err);
+                run.payload_lens.iter().for_each(|_| dropped.count(metrics));
+                eprintln!(
+                    "{info}: {} udp datagrams to {dest} dropped: {dropped}",
+                    run.payload_lens.len()
+                );
+            }
+        }
+        run.clear();
+    }
+
+    async fn send(&mut self, run: &Run, dest: SocketAddr, to_client: bool) -> io::Result<()> {
+        let socket = if to_client {
+            &self.client_socket
+        } else {
+            &self.remote_socket
+        };
+        #[cfg(target_os = "linux")]
+        if self.segment_sends && run.payload_lens.len() > 1 {
+            match offload::send_segments(socket, &run.buf, run.segment_size, dest).await {
+                Ok(()) => return Ok(()),
+                // the outgoing device can't checksum the segments
+                Err(err) if err.raw_os_error() == Some(libc::EIO) => self.segment_sends = false,
+                // segments too large for the path mtu, which the kernel won't fragment
+                Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        for datagram in segments(&run.buf, run.segment_size) {
+            socket.send_to(datagram, dest).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Binds the destination facing socket, dual stack where the host has ipv6, marking its
+/// datagrams with `dscp` if given.
+fn bind_outbound(dialer: &Dialer, dscp: Option<u8>) -> io::Result<UdpSocket> {
+    let socket = match Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP)) {
+        Ok(socket) => {
+            socket.set_only_v6(false)?;
+            dialer.bind_datagram(&socket, Ipv6Addr::UNSPECIFIED.into())?;
+            socket
+        }
+        Err(_) => {
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+            dialer.bind_datagram(&socket, Ipv4Addr::UNSPECIFIED.into())?;
+            socket
+        }
+    };
+    if let Some(dscp) = dscp {
+        let ipv6 = socket.domain()? == Domain::IPV6;
+        set_dscp(SockRef::from(&socket), ipv6, dscp)?;
+    }
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Dual stack sockets reach ipv4 destinations through their ipv4-mapped address.
+fn outbound_addr(socket: &UdpSocket, dest: SocketAddr) -> io::Result<SocketAddr> {
+    match (socket.local_addr()?, dest.ip()) {
+        (SocketAddr::V6(_), IpAddr::V4(ip)) => {
+            Ok(SocketAddr::new(ip.to_ipv6_mapped().into(), dest.port()))
+        }
+        _ => Ok(dest),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicUsize, Arc};
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::config::ServerConfig;
+
+    /// Opens an association through the proxy, returning its control connection and the
+    /// relay's address.
+    async fn associate(proxy_addr: SocketAddr) -> (TcpStream, SocketAddr) {
+        let mut control = TcpStream::connect(proxy_addr).await.unwrap();
+        control.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0_u8; 2];
+        control.read_exact(&mut method).await.unwrap();
+        control
+            .write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let mut reply = [0_u8; 10];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], proto::ServerStatus::RequestGranted as u8);
+        let relay_addr = SocketAddr::new(
+            IpAddr::from([reply[4], reply[5], reply[6], reply[7]]),
+            u16::from_be_bytes([reply[8], reply[9]]),
+        );
+        (control, relay_addr)
+    }
+
+    #[tokio::test]
+    async fn relays_datagrams_until_the_control_connection_closes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let server = Arc::new(Server::new(ServerConfig::default()).unwrap());
+        tokio::spawn(Arc::clone(&server).serve(listener));
+
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0_u8; 1024];
+            loop {
+                let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..n], from).await.unwrap();
+            }
+        });
+
+        let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (control, relay_addr) = associate(proxy_addr).await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(relay_addr).await.unwrap();
+        let mut datagram = proto::UdpHeader {
+            frag: 0,
+            dest_addr: echo_addr.into(),
+            dest_port: echo_addr.port(),
+        }
+        .as_bytes();
+        datagram.extend_from_slice(b"ping");
+        // the first datagram pins the client's port, later ones from elsewhere are dropped
+        client.send(&datagram).await.unwrap();
+        stranger.send_to(&datagram, relay_addr).await.unwrap();
+
+        let mut buf = [0_u8; 1024];
+        let n = client.recv(&mut buf).await.unwrap();
+        let (header, payload) = proto::UdpHeader::parse(&buf[..n]).unwrap();
+        assert_eq!(header.dest_addr, proto::Address::from(echo_addr));
+        assert_eq!(header.dest_port, echo_addr.port());
+        assert_eq!(payload, b"ping");
+        let stray = tokio::time::timeout(Duration::from_millis(100), client.recv(&mut buf));
+        assert!(stray.await.is_err(), "datagram from a stranger was relayed");
+        let metrics = &server.metrics().udp;
+        assert_eq!(metrics.bytes_to_remote.load(Ordering::Relaxed), 4);
+        assert_eq!(metrics.bytes_to_client.load(Ordering::Relaxed), 4);
+        assert_eq!(metrics.dropped_unauthorized.load(Ordering::Relaxed), 1);
+
+        // with the control connection gone the relay socket is closed, which the connected
+        // client socket learns about through an icmp port unreachable
+        drop(control);
+        let closed = async {
+            loop {
+                client.send(&datagram).await.ok();
+                // datagrams still accepted by the relay get no answer once it closes
+                let recv = tokio::time::timeout(Duration::from_millis(50), client.recv(&mut buf));
+                if let Ok(Err(err)) = recv.await {
+                    if err.kind() == io::ErrorKind::ConnectionRefused {
+                        break;
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), closed)
+            .await
+            .expect("relay socket still open");
+    }
+
+    #[tokio::test]
+    async fn answers_dns_queries_from_the_cache() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut config = ServerConfig::default();
+        config.udp.dns = Some(crate::config::DnsRelayConfig {
+            upstream: Some(upstream.local_addr().unwrap().to_string()),
+            ..Default::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let server = Arc::new(Server::new(config).unwrap());
+        tokio::spawn(Arc::clone(&server).serve(listener));
+        let queries = Arc::new(AtomicUsize::new(0));
+        let answered = Arc::clone(&queries);
+        tokio::spawn(async move {
+            let mut buf = [0_u8; 512];
+            loop {
+                let (n, from) = upstream.recv_from(&mut buf).await.unwrap();
+                answered.fetch_add(1, Ordering::Relaxed);
+                // the question, answered with 192.0.2.1 for 300s
+                let mut response = buf[..n].to_vec();
+                response[2] = 0x81;
+                response[3] = 0x80;
+                response[7] = 1;
+                response
+                    .extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 1, 44, 0, 4, 192, 0, 2, 1]);
+                upstream.send_to(&response, from).await.unwrap();
+            }
+        });
+
+        let (_control, relay_addr) = associate(proxy_addr).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(relay_addr).await.unwrap();
+        // sent to a resolver that doesn't exist, which the upstream stands in for
+        let resolver = proto::UdpHeader {
+            frag: 0,
+            dest_addr: proto::Address::Ipv4([192, 0, 2, 53].into()),
+            dest_port: 53,
+        };
+        let mut buf = [0_u8; 1024];
+        for id in [1_u8, 2] {
+            let mut datagram = resolver.as_bytes();
+            datagram.extend_from_slice(&[0, id, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+            datagram.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+            client.send(&datagram).await.unwrap();
+            let n = client.recv(&mut buf).await.unwrap();
+            let (header, answer) = proto::UdpHeader::parse(&buf[..n]).unwrap();
+            assert_eq!(header.dest_addr, resolver.dest_addr);
+            assert_eq!(header.dest_port, 53);
+            assert_eq!(&answer[..4], &[0, id, 0x81, 0x80]);
+            assert_eq!(&answer[answer.len() - 4..], &[192, 0, 2, 1]);
+        }
+        assert_eq!(queries.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn drops_datagrams_to_destinations_the_policies_deny() {
+        let allowed = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let denied = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (allowed_addr, denied_addr) =
+            (allowed.local_addr().unwrap(), denied.local_addr().unwrap());
+        let config = ServerConfig {
+            port_policy: Some(
+                toml::from_str(&format!("denied = [{}]", denied_addr.port())).unwrap(),
+            ),
+            ..ServerConfig::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let server = Arc::new(Server::new(config).unwrap());
+        tokio::spawn(Arc::clone(&server).serve(listener));
+
+        let (_control, relay_addr) = associate(proxy_addr).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(relay_addr).await.unwrap();
+        for dest in [denied_addr, denied_addr, allowed_addr] {
+            let mut datagram = proto::UdpHeader {
+                frag: 0,
+                dest_addr: dest.into(),
+                dest_port: dest.port(),
+            }
+            .as_bytes();
+            datagram.extend_from_slice(b"ping");
+            client.send(&datagram).await.unwrap();
+        }
+        let mut buf = [0_u8; 1024];
+        let n = allowed.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        let stray = tokio::time::timeout(Duration::from_millis(100), denied.recv(&mut buf));
+        assert!(
+            stray.await.is_err(),
+            "datagram to a denied destination was relayed"
+        );
+        assert_eq!(
+            server.metrics().udp.dropped_denied.load(Ordering::Relaxed),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_to_associate_when_disabled() {
+        let mut config = ServerConfig::default();
+        config.udp.enabled = false;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(Arc::new(Server::new(config).unwrap()).serve(listener));
+
+        let mut control = TcpStream::connect(proxy_addr).await.unwrap();
+        control
+            .write_all(&[5, 1, 0, 5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let mut reply = [0_u8; 12];
+        control.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[3], proto::ServerStatus::CommandNotSupported as u8);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn relays_segmented_runs_as_separate_datagrams() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let server = Arc::new(Server::new(ServerConfig::default()).unwrap());
+        tokio::spawn(Arc::clone(&server).serve(listener));
+        let dest = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dest_addr = dest.local_addr().unwrap();
+        let (_control, relay_addr) = associate(proxy_addr).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // seven full datagrams and a short one, each written as a single run
+        let payloads: Vec<Vec<u8>> = (0..8_u8)
+            .map(|i| vec![i; if i == 7 { 300 } else { 1200 }])
+            .collect();
+        let header = proto::UdpHeader {
+            frag: 0,
+            dest_addr: dest_addr.into(),
+            dest_port: dest_addr.port(),
+        }
+        .as_bytes();
+        let run: Vec<u8> = payloads
+            .iter()
+            .flat_map(|payload| header.iter().chain(payload))
+            .copied()
+            .collect();
+        offload::send_segments(&client, &run, header.len() + 1200, relay_addr)
+            .await
+            .unwrap();
+
+        let mut buf = vec![0_u8; MAX_DATAGRAM];
+        let mut relay_remote_addr = None;
+        for payload in &payloads {
+            let (n, from) = dest.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], payload);
+            relay_remote_addr = Some(from);
+        }
+        let run: Vec<u8> = payloads.concat();
+        offload::send_segments(&dest, &run, 1200, relay_remote_addr.unwrap())
+            .await
+            .unwrap();
+        for payload in &payloads {
+            let n = client.recv(&mut buf).await.unwrap();
+            let (header, received) = proto::UdpHeader::parse(&buf[..n]).unwrap();
+            assert_eq!(header.dest_addr, proto::Address::from(dest_addr));
+            assert_eq!(received, payload);
+        }
+        let metrics = &server.metrics().udp;
+        assert_eq!(metrics.datagrams_to_remote.load(Ordering::Relaxed), 8);
+        assert_eq!(metrics.datagrams_to_client.load(Ordering::Relaxed), 8);
+        assert_eq!(
+            metrics.bytes_to_client.load(Ordering::Relaxed),
+            7 * 1200 + 300
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Segmentation offload for the udp relay. With UDP_GRO the kernel hands over a run of
+//! same-sized datagrams from one sender in a single read, and with UDP_SEGMENT a single write
+//! sends a run of same-sized datagrams to one destination, sparing bulk flows like QUIC
+//! downloads most of their per-datagram syscalls.
+
+use std::{
+    io, mem,
+    net::SocketAddr,
+    os::unix::io::{AsRawFd, RawFd},
+    ptr,
+};
+
+use socket2::SockAddr;
+use tokio::{io::Interest, net::UdpSocket};
+
+use super::Received;
+
+/// Turns on UDP_GRO for `socket`. Fails on kernels lacking UDP_GRO or UDP_SEGMENT, the
+/// latter of which would otherwise send a segmented write as one large datagram.
+pub(super) fn enable(socket: &UdpSocket) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+    let mut segment_size: libc::c_int = 0;
+    let mut len = mem::size_of_val(&segment_size) as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            ptr::from_mut(&mut segment_size).cast(),
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let one: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_UDP,
+            libc::UDP_GRO,
+            ptr::from_ref(&one).cast(),
+            mem::size_of_val(&one) as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Reads a datagram, or a run of them if the kernel coalesced some.
+pub(super) async fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<Received> {
+    let fd = socket.as_raw_fd();
+    socket
+        .async_io(Interest::READABLE, || recvmsg(fd, buf))
+        .await
+}
+
+/// Sends the datagrams in `buf`, all `segment_size` bytes long but the last, which may be
+/// shorter, to `dest` with a single write.
+pub(super) async fn send_segments(
+    socket: &UdpSocket,
+    buf: &[u8],
+    segment_size: usize,
+    dest: SocketAddr,
+) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+    let dest = SockAddr::from(dest);
+    socket
+        .async_io(Interest::WRITABLE, || sendmsg(fd, buf, segment_size, &dest))
+        .await
+}
+
+fn recvmsg(fd: RawFd, buf: &mut [u8]) -> io::Result<Received> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    // room for the UDP_GRO message
+    let mut control = [0_u64; 4];
+    let ((len, segment_size), from) = unsafe {
+        SockAddr::try_init(|addr, addr_len| {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_name = addr.cast();
+            msg.msg_namelen = *addr_len;
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = mem::size_of_val(&control) as _;
+            let n = libc::recvmsg(fd, &mut msg, 0);
+            if n == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            *addr_len = msg.msg_namelen;
+            Ok((n as usize, gro_segment_size(&msg)))
+        })?
+    };
+    let from = from.as_socket().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "datagram from a non-ip address")
+    })?;
+    Ok(Received {
+        len,
+        from,
+        segment_size: segment_size.unwrap_or(len),
+    })
+}
+
+/// The size of the datagrams a read coalesced, if it did.
+unsafe fn gro_segment_size(msg: &libc::msghdr) -> Option<usize> {
+    let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+    while !cmsg.is_null() {
+        if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+            let size: libc::c_int = ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast());
+            return Some(size as usize);
+        }
+        cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+    }
+    None
+}
+
+fn sendmsg(fd: RawFd, buf: &[u8], segment_size: usize, dest: &SockAddr) -> io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr().cast_mut().cast(),
+        iov_len: buf.len(),
+    };
+    let mut control = [0_u64; 4];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = dest.as_ptr().cast_mut().cast();
+    msg.msg_namelen = dest.len();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    // the segment size is a u16, as the kernel reads it
+    unsafe {
+        msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as u32) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast(), segment_size as u16);
+    }
+    if unsafe { libc::sendmsg(fd, &msg, 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,457 @@
+//! Relaying through userspace with `MSG_ZEROCOPY` sends, which hand the kernel references to
+//! the relay's buffers rather than copying them into the socket. A buffer is then in use
+//! until the kernel reports on the socket's error queue that it is done sending from it, so
+//! each direction cycles through a few buffers and only reads into one again once all of
+//! its sends completed.
+//!
+//! A relay that fails or is cancelled can't wait for that, so it parks the buffers the kernel
+//! still sends from in a task of their own, along with a duplicate of the socket whose error
+//! queue reports on them. The task frees the buffers once all their sends completed, or resets
+//! the connection after [`PARKED_TIMEOUT`] to make the kernel let go of them.
+
+use std::{
+    io, mem,
+    ops::Range,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+    time::Duration,
+};
+
+use tokio::{
+    io::{unix::AsyncFd, AsyncReadExt, AsyncWriteExt, Interest},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    runtime::Handle,
+    time,
+};
+
+use crate::metrics::{ConnectionRelay, RelayCounter};
+
+// from linux/socket.h and linux/errqueue.h, which the libc crate lacks
+const SO_ZEROCOPY: libc::c_int = 60;
+const SO_EE_ORIGIN_ZEROCOPY: u8 = 5;
+const SO_EE_CODE_ZEROCOPY_COPIED: u8 = 1;
+
+/// buffers per direction, which is how far reading can get ahead of the kernel's completions
+const BUFFERS: usize = 4;
+
+/// how long the buffers of a failed relay wait for their sends to complete before the
+/// connection is reset
+const PARKED_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Allows `MSG_ZEROCOPY` sends on `stream`. Fails on kernels without support for them.
+pub(crate) fn enable(stream: &TcpStream) -> io::Result<()> {
+    let one: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            SO_ZEROCOPY,
+            ptr::from_ref(&one).cast(),
+            mem::size_of_val(&one) as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Relays between `client` and `remote` until both directions reached eof, each shutting
+/// down the write side of its destination once its source is done. Both streams need to
+/// have been [`enable`]d.
+pub(crate) async fn relay(
+    client: TcpStream,
+    remote: TcpStream,
+    buffer_size: usize,
+    relayed: &ConnectionRelay<'_>,
+) -> io::Result<()> {
+    let (client_read, client_write) = client.into_split();
+    let (remote_read, remote_write) = remote.into_split();
+    tokio::try_join!(
+        copy(client_read, remote_write, buffer_size, relayed.to_remote()),
+        copy(remote_read, client_write, buffer_size, relayed.to_client()),
+    )
+    .map(|_| ())
+}
+
+async fn copy(
+    mut reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
+    buffer_size: usize,
+    relayed: RelayCounter<'_>,
+) -> io::Result<()> {
+    let mut sender = Sender {
+        writer,
+        buffers: (0..BUFFERS)
+            .map(|_| Buffer {
+                data: vec![0; buffer_size],
+                pending: Vec::new(),
+            })
+            .collect(),
+        next_id: 0,
+        zerocopy: true,
+    };
+    let mut current = 0;
+    loop {
+        sender.wait_until_sent(current).await?;
+        let n = reader.read(&mut sender.buffers[current].data).await?;
+        if n == 0 {
+            break;
+        }
+        let mut sent = 0;
+        while sent < n {
+            sent += sender.send(current, sent..n).await?;
+        }
+        relayed.relayed(n);
+        current = (current + 1) % BUFFERS;
+    }
+    match sender.writer.shutdown().await {
+        Err(err) if err.kind() == io::ErrorKind::NotConnected => {}
+        res => res?,
+    }
+    for index in 0..BUFFERS {
+        sender.wait_until_sent(index).await?;
+    }
+    Ok(())
+}
+
+/// The sending side of one direction, along with the buffers it sends from.
+struct Sender {
+    writer: OwnedWriteHalf,
+    buffers: Vec<Buffer>,
+    /// the id the kernel gives the next zerocopy send on the socket, counting from 0
+    next_id: u32,
+    /// cleared once the kernel reports that it copied the data anyway, as happens on
+    /// loopback or with devices lacking scatter-gather, where zerocopy only adds overhead
+    zerocopy: bool,
+}
+
+struct Buffer {
+    data: Vec<u8>,
+    /// ids of the zerocopy sends from `data` the kernel has not reported done yet
+    pending: Vec<u32>,
+}
+
+/// The kernel is done with the zerocopy sends `first` through `last`.
+struct Completion {
+    first: u32,
+    last: u32,
+    copied: bool,
+}
+
+impl Sender {
+    fn fd(&self) -> RawFd {
+        self.writer.as_ref().as_raw_fd()
+    }
+
+    /// Sends what it can of `range` of buffer `index`.
+    async fn send(&mut self, index: usize, range: Range<usize>) -> io::Result<usize> {
+        let fd = self.fd();
+        let mut zerocopy = self.zerocopy;
+        loop {
+            let stream = self.writer.as_ref();
+            stream.writable().await?;
+            let buf = &self.buffers[index].data[range.clone()];
+            let flags = libc::MSG_NOSIGNAL | if zerocopy { libc::MSG_ZEROCOPY } else { 0 };
+            let res = stream.try_io(Interest::WRITABLE, || {
+                let ret = unsafe { libc::send(fd, buf.as_ptr().cast(), buf.len(), flags) };
+                if ret == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(ret as usize)
+            });
+            match res {
+                Ok(n) => {
+                    if zerocopy {
+                        self.buffers[index].pending.push(self.next_id);
+                        self.next_id = self.next_id.wrapping_add(1);
+                    }
+                    return Ok(n);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                // the socket can't pin any more pages until earlier sends complete
+                Err(err) if zerocopy && err.raw_os_error() == Some(libc::ENOBUFS) => {
+                    zerocopy = false;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Waits until the kernel is done with every send from buffer `index`.
+    async fn wait_until_sent(&mut self, index: usize) -> io::Result<()> {
+        let fd = self.fd();
+        while !self.buffers[index].pending.is_empty() {
+            let stream = self.writer.as_ref();
+            stream.ready(Interest::ERROR).await?;
+            match stream.try_io(Interest::ERROR, || read_completion(fd)) {
+                Ok(Some(completion)) => self.complete(&completion),
+                Ok(None) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn complete(&mut self, completion: &Completion) {
+        if completion.copied {
+            self.zerocopy = false;
+        }
+        complete(&mut self.buffers, completion);
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        // the relay failed or was cancelled. whatever completed already can be freed, but
+        // the kernel may still send from the other buffers, which the allocator must not
+        // hand out again in the meantime.
+        while let Ok(Some(completion)) = read_completion(self.fd()) {
+            self.complete(&completion);
+        }
+        self.buffers.retain(|buffer| !buffer.pending.is_empty());
+        if self.buffers.is_empty() {
+            return;
+        }
+        let buffers = mem::take(&mut self.buffers);
+        match (Handle::try_current(), Parked::new(self.fd())) {
+            (Ok(runtime), Ok(fd)) => {
+                runtime.spawn(release(fd, buffers));
+            }
+            // nothing could wait for the completions, better leak than hand out the memory
+            _ => buffers
+                .into_iter()
+                .for_each(|buffer| mem::forget(buffer.data)),
+        }
+    }
+}
+
+/// A duplicate of a socket that keeps it open while parked buffers wait for their sends.
+struct Parked(OwnedFd);
+
+impl Parked {
+    fn new(fd: RawFd) -> io::Result<AsyncFd<Self>> {
+        let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if dup == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // safety: the duplicate was just created and nothing else owns it
+        let parked = Self(unsafe { OwnedFd::from_raw_fd(dup) });
+        AsyncFd::with_interest(parked, Interest::ERROR)
+    }
+}
+
+impl AsRawFd for Parked {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// Frees `buffers` once the kernel completed all their sends on `fd`.
+async fn release(fd: AsyncFd<Parked>, mut buffers: Vec<Buffer>) {
+    let drained = time::timeout(PARKED_TIMEOUT, async {
+        while buffers.iter().any(|buffer| !buffer.pending.is_empty()) {
+            let mut ready = fd.ready(Interest::ERROR).await?;
+            match ready.try_io(|fd| read_completion(fd.as_raw_fd())) {
+                Ok(Ok(Some(completion))) => complete(&mut buffers, &completion),
+                Ok(Ok(None)) | Err(_) => {}
+                Ok(Err(err)) if err.kind() == io::ErrorKind::Interrupted => {}
+                Ok(Err(err)) => return Err(err),
+            }
+        }
+        io::Result::Ok(())
+    })
+    .await;
+    if !matches!(drained, Ok(Ok(()))) {
+        // closing a socket that lingers for no time resets the connection and purges what
+        // it had left to send, pages of the buffers included
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_LINGER,
+                ptr::from_ref(&linger).cast(),
+                mem::size_of_val(&linger) as libc::socklen_t,
+            )
+        };
+        if ret == -1 {
+            buffers
+                .into_iter()
+                .for_each(|buffer| mem::forget(buffer.data));
+            return;
+        }
+    }
+    drop(fd);
+    drop(buffers);
+}
+
+/// Marks the sends of `completion` done in whichever of `buffers` they were made from.
+fn complete(buffers: &mut [Buffer], completion: &Completion) {
+    let span = completion.last.wrapping_sub(completion.first);
+    for buffer in buffers {
+        buffer
+            .pending
+            .retain(|id| id.wrapping_sub(completion.first) > span);
+    }
+}
+
+/// Reads the next message off the error queue of `fd`, which is `None` if it isn't about
+/// zerocopy sends.
+fn read_completion(fd: RawFd) -> io::Result<Option<Completion>> {
+    // room for a cmsghdr carrying a sock_extended_err and the address it is about
+    let mut control = [0_u64; 16];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    if unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+        if (level == libc::SOL_IP && kind == libc::IP_RECVERR)
+            || (level == libc::SOL_IPV6 && kind == libc::IPV6_RECVERR)
+        {
+            // safety: the data of IP_RECVERR and IPV6_RECVERR messages is a sock_extended_err
+            let err: libc::sock_extended_err =
+                unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast()) };
+            if err.ee_errno == 0 && err.ee_origin == SO_EE_ORIGIN_ZEROCOPY {
+                return Ok(Some(Completion {
+                    first: err.ee_info,
+                    last: err.ee_data,
+                    copied: err.ee_code & SO_EE_CODE_ZEROCOPY_COPIED != 0,
+                }));
+            }
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::AtomicU64, Arc},
+        time::Duration,
+    };
+
+    use tokio::{net::TcpListener, sync::watch, time::timeout};
+
+    use super::*;
+    use crate::metrics::{Progress, RelayMetrics};
+
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let lis = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (connected, accepted) =
+            tokio::join!(TcpStream::connect(lis.local_addr().unwrap()), lis.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    fn pattern(len: usize, seed: usize) -> Vec<u8> {
+        (0..len).map(|i| ((i + seed) % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn relays_both_directions_intact() {
+        let (client, mut client_peer) = socket_pair().await;
+        let (remote, mut remote_peer) = socket_pair().await;
+        enable(&client).unwrap();
+        enable(&remote).unwrap();
+        let totals = RelayMetrics {
+            bytes_to_remote: AtomicU64::new(0),
+            bytes_to_client: AtomicU64::new(0),
+        };
+        let progress = Arc::new(watch::Sender::new(Progress::default()));
+        let relayed = ConnectionRelay::new(&totals, Arc::clone(&progress));
+
+        let request = pattern(4 << 20, 0);
+        let response = pattern(4 << 20, 7);
+        let client_side = async {
+            let (mut rd, mut wr) = client_peer.split();
+            let mut received = Vec::new();
+            let write = async {
+                wr.write_all(&request).await.unwrap();
+                wr.shutdown().await.unwrap();
+            };
+            tokio::join!(write, rd.read_to_end(&mut received))
+                .1
+                .unwrap();
+            received
+        };
+        let remote_side = async {
+            let (mut rd, mut wr) = remote_peer.split();
+            let mut received = Vec::new();
+            let write = async {
+                wr.write_all(&response).await.unwrap();
+                wr.shutdown().await.unwrap();
+            };
+            tokio::join!(write, rd.read_to_end(&mut received))
+                .1
+                .unwrap();
+            received
+        };
+        let (res, at_client, at_remote) = timeout(Duration::from_secs(10), async {
+            tokio::join!(
+                relay(client, remote, 64 << 10, &relayed),
+                client_side,
+                remote_side
+            )
+        })
+        .await
+        .unwrap();
+        res.unwrap();
+        assert!(at_remote == request, "request arrived corrupted");
+        assert!(at_client == response, "response arrived corrupted");
+        drop(relayed);
+        let progress = *progress.borrow();
+        assert_eq!((progress.to_remote, progress.to_client), (4 << 20, 4 << 20));
+    }
+
+    #[tokio::test]
+    async fn cancelled_relays_deliver_what_they_sent_and_close() {
+        let (client, mut client_peer) = socket_pair().await;
+        let (remote, mut remote_peer) = socket_pair().await;
+        enable(&client).unwrap();
+        enable(&remote).unwrap();
+        let totals = RelayMetrics {
+            bytes_to_remote: AtomicU64::new(0),
+            bytes_to_client: AtomicU64::new(0),
+        };
+        let relayed = ConnectionRelay::new(&totals, Arc::new(watch::Sender::default()));
+
+        // the remote doesn't read until the relay is cancelled, so sends are outstanding
+        let request = pattern(16 << 20, 3);
+        let write = client_peer.write_all(&request);
+        let relay = relay(client, remote, 64 << 10, &relayed);
+        tokio::select! {
+            _ = time::sleep(Duration::from_millis(200)) => {}
+            res = relay => panic!("relay ended early: {res:?}"),
+            res = write => panic!("request written early: {res:?}"),
+        }
+
+        let mut received = Vec::new();
+        timeout(
+            Duration::from_secs(10),
+            remote_peer.read_to_end(&mut received),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(!received.is_empty());
+        assert!(
+            received == request[..received.len()],
+            "request arrived corrupted"
+        );
+    }
+}
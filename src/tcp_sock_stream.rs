@@ -1,54 +1,554 @@
-mod sync_proto;
+mod async_handshake;
+mod env;
+mod error;
+mod leakproof;
+pub mod sync_proto;
+mod url;
 
-use std::{io, net::TcpStream};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::Path};
 
-use crate::proto;
+use crate::{
+    proto,
+    redact::Redacted,
+    transport::{ClientStream, ClientTransport},
+};
+
+#[cfg(feature = "quic")]
+use crate::quic::{QuicClient, SyncQuicStream};
+
+pub use async_handshake::handshake_on_async;
+pub use error::ConnectError;
+pub use leakproof::{connect_leakproof, LeakproofConnectRequest};
 
 pub struct ConnectRequest {
     pub server_addr: String,
     pub dest_addr: String,
     pub dest_port: u16,
     pub supported_auth_methods: Vec<proto::AuthMethod>,
+    /// used if the proxy picks username/password authentication
+    pub credentials: Option<Credentials>,
+    /// asked for credentials if the proxy picks username/password authentication and
+    /// `credentials` is unset
+    pub credential_provider: Option<Arc<dyn CredentialProvider>>,
+    pub dns: DnsMode,
+}
+
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &Redacted)
+            .field("password", &Redacted)
+            .finish()
+    }
+}
+
+/// Fetches credentials once a proxy picked username/password authentication, for
+/// applications that look them up in a keychain, prompt for them or get them from a token
+/// service rather than holding them upfront. Closures taking the proxy address implement it.
+pub trait CredentialProvider: Send + Sync {
+    /// `proxy` is the proxy's address as the request gave it.
+    fn credentials(&self, proxy: &str) -> io::Result<Credentials>;
+}
+
+impl<F> CredentialProvider for F
+where
+    F: Fn(&str) -> io::Result<Credentials> + Send + Sync,
+{
+    fn credentials(&self, proxy: &str) -> io::Result<Credentials> {
+        self(proxy)
+    }
+}
+
+/// Where username/password authentication gets its credentials from.
+struct CredentialSource<'a> {
+    credentials: Option<&'a Credentials>,
+    provider: Option<&'a dyn CredentialProvider>,
+    proxy: &'a str,
+}
+
+impl ConnectRequest {
+    fn credential_source(&self) -> CredentialSource<'_> {
+        CredentialSource {
+            credentials: self.credentials.as_ref(),
+            provider: self.credential_provider.as_deref(),
+            proxy: &self.server_addr,
+        }
+    }
+}
+
+/// Where the destination name gets resolved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DnsMode {
+    /// resolve on the client and hand the proxy an address, like `socks5://` urls
+    Local,
+    /// hand the name to the proxy to resolve, like `socks5h://` urls
+    Remote,
+}
+
+/// What the proxy agreed to during [`connect_negotiated`].
+#[derive(Debug, Clone)]
+pub struct Negotiated {
+    pub auth_method: proto::AuthMethod,
+    /// the address the proxy connected to the destination from, as reported in its reply.
+    /// many proxies leave this unset.
+    pub bound_address: proto::Address,
+    pub bound_port: u16,
+    pub timings: ConnectTimings,
+}
+
+/// Time spent in each phase of [`connect_negotiated`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectTimings {
+    /// tcp connect to the proxy
+    pub proxy_connect: Duration,
+    /// auth method negotiation, including any sub-negotiation
+    pub auth: Duration,
+    /// from sending the connect request until the proxy replied, which is mostly the proxy
+    /// dialing the destination
+    pub dest_connect: Duration,
+}
+
+pub fn connect(req: ConnectRequest) -> Result<TcpStream, ConnectError> {
+    connect_negotiated(req).map(|(conn, _)| conn)
+}
+
+/// Like [`connect`], also reporting what was negotiated with the proxy and how long each phase
+/// of the handshake took, to tell slow proxies apart from slow destinations.
+pub fn connect_negotiated(req: ConnectRequest) -> Result<(TcpStream, Negotiated), ConnectError> {
+    let dest_addr = dest_address(&req).map_err(ConnectError::Resolve)?;
+
+    let start = Instant::now();
+    let mut conn = TcpStream::connect(&req.server_addr).map_err(ConnectError::Proxy)?;
+    let negotiated = handshake(&mut conn, &req, dest_addr, start)?;
+    Ok((conn, negotiated))
+}
+
+/// Sets up a UDP association through the proxy. The destination of `req` is where the
+/// client is going to send datagrams from, unspecified if it doesn't know yet. Returns the
+/// control connection, which the association lasts as long as, and the proxy's reply, whose
+/// bound address is where datagrams are relayed.
+pub fn associate(req: ConnectRequest) -> Result<(TcpStream, Negotiated), ConnectError> {
+    let dest_addr = dest_address(&req).map_err(ConnectError::Resolve)?;
+
+    let start = Instant::now();
+    let mut conn = TcpStream::connect(&req.server_addr).map_err(ConnectError::Proxy)?;
+    let negotiated = handshake_for(
+        &mut conn,
+        &req,
+        proto::ClientCommand::AssociateUdpPort,
+        dest_addr,
+        start,
+    )?;
+    Ok((conn, negotiated))
+}
+
+/// Like [`connect_negotiated`], running the handshake and everything after it through
+/// `transport`, which has to match the proxy's listener transport.
+pub fn connect_over(
+    req: ConnectRequest,
+    transport: &dyn ClientTransport,
+) -> Result<(Box<dyn ClientStream>, Negotiated), ConnectError> {
+    let dest_addr = dest_address(&req).map_err(ConnectError::Resolve)?;
+
+    let start = Instant::now();
+    let conn = TcpStream::connect(&req.server_addr).map_err(ConnectError::Proxy)?;
+    let mut conn = transport.wrap(conn).map_err(ConnectError::Proxy)?;
+    let negotiated = handshake(&mut conn, &req, dest_addr, start)?;
+    Ok((conn, negotiated))
+}
+
+/// Like [`connect_negotiated`], but reaches the proxy through the unix domain socket at
+/// `path`, as local helper daemons often listen on. `req.server_addr` is unused.
+#[cfg(unix)]
+pub fn connect_unix(
+    req: ConnectRequest,
+    path: impl AsRef<Path>,
+) -> Result<(UnixStream, Negotiated), ConnectError> {
+    let dest_addr = dest_address(&req).map_err(ConnectError::Resolve)?;
+
+    let start = Instant::now();
+    let mut conn = UnixStream::connect(path).map_err(ConnectError::Proxy)?;
+    let negotiated = handshake(&mut conn, &req, dest_addr, start)?;
+    Ok((conn, negotiated))
 }
 
-pub fn connect(req: ConnectRequest) -> io::Result<TcpStream> {
-    let mut conn = TcpStream::connect(&req.server_addr)?;
-    socks_handshake(&mut conn, &req)?;
-    Ok(conn)
+/// Like [`connect_negotiated`], but runs the session over a new stream of `client`'s QUIC
+/// connection. `req.server_addr` is unused, the proxy is the one `client` is connected to.
+#[cfg(feature = "quic")]
+pub fn connect_quic(
+    req: ConnectRequest,
+    client: &QuicClient,
+) -> Result<(SyncQuicStream, Negotiated), ConnectError> {
+    let dest_addr = dest_address(&req).map_err(ConnectError::Resolve)?;
+
+    let start = Instant::now();
+    let mut conn = client.open().map_err(ConnectError::Proxy)?;
+    let negotiated = handshake(&mut conn, &req, dest_addr, start)?;
+    Ok((conn, negotiated))
 }
 
-fn socks_handshake(conn: &mut TcpStream, req: &ConnectRequest) -> io::Result<()> {
+/// Runs the handshake for `req` over `stream`, a connection to the proxy the caller
+/// established itself, e.g. a TLS session or an SSH channel. `req.server_addr` only names
+/// the proxy to the credential provider, and no time is counted for connecting to it.
+pub fn handshake_on(
+    stream: &mut (impl Read + Write),
+    req: &ConnectRequest,
+) -> Result<Negotiated, ConnectError> {
+    let dest_addr = dest_address(req).map_err(ConnectError::Resolve)?;
+    handshake(stream, req, dest_addr, Instant::now())
+}
+
+/// Runs the socks handshake over a connection to the proxy that was opened at `start`.
+fn handshake(
+    conn: &mut (impl Read + Write),
+    req: &ConnectRequest,
+    dest_addr: proto::Address,
+    start: Instant,
+) -> Result<Negotiated, ConnectError> {
+    let cmd = proto::ClientCommand::EstablishConnection;
+    handshake_for(conn, req, cmd, dest_addr, start)
+}
+
+fn handshake_for(
+    conn: &mut (impl Read + Write),
+    req: &ConnectRequest,
+    cmd: proto::ClientCommand,
+    dest_addr: proto::Address,
+    start: Instant,
+) -> Result<Negotiated, ConnectError> {
+    let proxy_connected = Instant::now();
+    let auth_method = negotiate_auth(conn, &req.supported_auth_methods, req.credential_source())
+        .map_err(ConnectError::Proxy)?;
+    let authenticated = Instant::now();
+    let resp = request_connection(conn, cmd, dest_addr, req.dest_port)?;
+
+    Ok(Negotiated {
+        auth_method,
+        bound_address: resp.bound_address,
+        bound_port: resp.bound_port,
+        timings: ConnectTimings {
+            proxy_connect: proxy_connected - start,
+            auth: authenticated - proxy_connected,
+            dest_connect: authenticated.elapsed(),
+        },
+    })
+}
+
+fn negotiate_auth(
+    conn: &mut (impl Read + Write),
+    supported_auth_methods: &[proto::AuthMethod],
+    credentials: CredentialSource,
+) -> io::Result<proto::AuthMethod> {
     let resp: proto::ServerAuthChoice =
-        sync_proto::send_recv(conn, proto::ClientGreeting(vec![proto::AuthMethod::NoAuth]))?;
-    dbg!("got resp: {resp:?}");
+        sync_proto::send_recv(conn, proto::ClientGreeting(supported_auth_methods.to_vec()))?;
+
+    check_auth_choice(supported_auth_methods, resp.0)?;
+    if resp.0 == proto::AuthMethod::UserPass {
+        authenticate(conn, credentials)?;
+    }
+    Ok(resp.0)
+}
 
-    if resp.0 != proto::AuthMethod::NoAuth {
+/// Fails unless the proxy chose one of the offered methods the client implements.
+fn check_auth_choice(
+    supported_auth_methods: &[proto::AuthMethod],
+    chosen: proto::AuthMethod,
+) -> io::Result<()> {
+    if !supported_auth_methods.contains(&chosen) {
         return Err(io::Error::new(
             io::ErrorKind::PermissionDenied,
             format!(
-                "auth method negotiation failed. expected: {:?}, got: {:?}",
-                proto::AuthMethod::NoAuth,
-                resp.0
+                "auth method negotiation failed. expected one of: {supported_auth_methods:?}, \
+                 got: {chosen:?}"
             ),
         ));
     }
+    match chosen {
+        proto::AuthMethod::NoAuth | proto::AuthMethod::UserPass => Ok(()),
+        method => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("auth method {method:?} is not implemented by the client"),
+        )),
+    }
+}
 
-    let resp: proto::ServerResponse = dbg!(sync_proto::send_recv(
+fn request_connection(
+    conn: &mut (impl Read + Write),
+    cmd: proto::ClientCommand,
+    dest_addr: proto::Address,
+    dest_port: u16,
+) -> Result<proto::ServerResponse, ConnectError> {
+    let resp: proto::ServerResponse = sync_proto::send_recv(
         conn,
         proto::ClientConnectionRequest {
-            cmd: proto::ClientCommand::EstablishConnection,
-            dest_port: req.dest_port,
-            dest_addr: req.dest_addr.parse()?,
+            cmd,
+            dest_port,
+            dest_addr,
         },
-    )?);
+    )
+    .map_err(ConnectError::Proxy)?;
+    check_reply(resp)
+}
+
+fn check_reply(resp: proto::ServerResponse) -> Result<proto::ServerResponse, ConnectError> {
+    match resp.status {
+        proto::ServerStatus::RequestGranted => Ok(resp),
+        status => Err(ConnectError::Destination(status)),
+    }
+}
 
-    let status = resp.status;
-    if status == proto::ServerStatus::RequestGranted {
-        Ok(())
-    } else {
-        Err(io::Error::new(
+fn authenticate(conn: &mut (impl Read + Write), source: CredentialSource) -> io::Result<()> {
+    let resp: proto::UserPassResponse = sync_proto::send_recv(conn, source.request()?)?;
+    check_user_pass_response(&resp)
+}
+
+impl CredentialSource<'_> {
+    /// The username/password request carrying the credentials, fetched from the provider
+    /// if they weren't given.
+    fn request(&self) -> io::Result<proto::UserPassRequest> {
+        let provided;
+        let credentials = match *self {
+            CredentialSource {
+                credentials: Some(credentials),
+                ..
+            } => credentials,
+            CredentialSource {
+                provider: Some(provider),
+                proxy,
+                ..
+            } => {
+                provided = provider.credentials(proxy)?;
+                &provided
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "proxy requires username/password authentication but no credentials were \
+                     given",
+                ))
+            }
+        };
+        Ok(proto::UserPassRequest {
+            username: credentials.username.clone(),
+            password: credentials.password.clone(),
+        })
+    }
+}
+
+fn check_user_pass_response(resp: &proto::UserPassResponse) -> io::Result<()> {
+    if resp.status != 0 {
+        return Err(io::Error::new(
             io::ErrorKind::PermissionDenied,
-            format!("proxy rejected establish connection with status: {status:?}"),
-        ))
+            format!("proxy rejected credentials with status: {}", resp.status),
+        ));
+    }
+    Ok(())
+}
+
+fn dest_address(req: &ConnectRequest) -> io::Result<proto::Address> {
+    match req.dns {
+        DnsMode::Remote => req.dest_addr.parse(),
+        DnsMode::Local => match req.dest_addr.parse()? {
+            proto::Address::DomainName(host) => (host.as_str(), req.dest_port)
+                .to_socket_addrs()?
+                .next()
+                .map(proto::Address::from)
+                .ok_or_else(|| unresolved(&host)),
+            ip => Ok(ip),
+        },
+    }
+}
+
+fn unresolved(host: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{host} did not resolve to any address"),
+    )
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{env, fs, process};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, UnixListener},
+    };
+
+    use super::*;
+    use crate::{
+        config::ServerConfig,
+        server::{ConnectionId, ConnectionInfo, Server},
+        tcp_server_stream::{self, ClientConn},
+        transport::ServerStream,
+    };
+
+    /// Serves a socks session over `stream`, as a proxy would once it accepted it.
+    fn serve(stream: impl ServerStream + 'static) {
+        let server = Server::new(ServerConfig::default()).unwrap();
+        tokio::spawn(async move {
+            let conn = ClientConn::Wrapped {
+                stream: Box::new(stream),
+                local_addr: "127.0.0.1:1080".parse().unwrap(),
+            };
+            let info = ConnectionInfo {
+                id: ConnectionId(1),
+                peer_addr: "127.0.0.1:50000".parse().unwrap(),
+                accepted_at: Instant::now(),
+                listener: None,
+            };
+            tcp_server_stream::handle_conn(&server, conn, info, None).await
+        });
+    }
+
+    /// A request for a destination echoing the first four bytes it gets.
+    async fn echo_request() -> ConnectRequest {
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest_port = dest.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut conn, _) = dest.accept().await.unwrap();
+            let mut buf = [0_u8; 4];
+            conn.read_exact(&mut buf).await.unwrap();
+            conn.write_all(&buf).await.unwrap();
+        });
+        ConnectRequest {
+            server_addr: String::new(),
+            dest_addr: "127.0.0.1".to_owned(),
+            dest_port,
+            supported_auth_methods: vec![proto::AuthMethod::NoAuth],
+            credentials: None,
+            credential_provider: None,
+            dns: DnsMode::Remote,
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_through_a_proxy_on_a_unix_socket() {
+        let path = env::temp_dir().join(format!("socks5-client-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let req = echo_request().await;
+        let socket = path.clone();
+        let echoed = tokio::task::spawn_blocking(move || {
+            let (mut conn, negotiated) = connect_unix(req, socket).unwrap();
+            assert_eq!(negotiated.auth_method, proto::AuthMethod::NoAuth);
+            conn.write_all(b"ping").unwrap();
+            let mut echoed = [0_u8; 4];
+            conn.read_exact(&mut echoed).unwrap();
+            echoed
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        serve(stream);
+        assert_eq!(&echoed.await.unwrap(), b"ping");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn negotiates_over_streams_buffering_writes() {
+        struct Buffered(io::BufWriter<std::os::unix::net::UnixStream>);
+        impl Read for Buffered {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0.get_mut().read(buf)
+            }
+        }
+        impl Write for Buffered {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                self.0.flush()
+            }
+        }
+
+        let (client, proxy) = std::os::unix::net::UnixStream::pair().unwrap();
+        proxy.set_nonblocking(true).unwrap();
+        serve(tokio::net::UnixStream::from_std(proxy).unwrap());
+        let req = echo_request().await;
+        let negotiated = tokio::task::spawn_blocking(move || {
+            let mut conn = Buffered(io::BufWriter::new(client));
+            handshake_on(&mut conn, &req).unwrap()
+        });
+        let negotiated = negotiated.await.unwrap();
+        assert_eq!(negotiated.auth_method, proto::AuthMethod::NoAuth);
+    }
+
+    #[tokio::test]
+    async fn sets_up_udp_associations() {
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let req = ConnectRequest {
+            server_addr: proxy.local_addr().unwrap().to_string(),
+            dest_addr: "0.0.0.0".to_owned(),
+            dest_port: 0,
+            supported_auth_methods: vec![proto::AuthMethod::NoAuth],
+            credentials: None,
+            credential_provider: None,
+            dns: DnsMode::Remote,
+        };
+        let associating = tokio::task::spawn_blocking(move || associate(req));
+        let (stream, _) = proxy.accept().await.unwrap();
+        serve(stream);
+        let (control, negotiated) = associating.await.unwrap().unwrap();
+        assert_eq!(negotiated.auth_method, proto::AuthMethod::NoAuth);
+        assert_ne!(negotiated.bound_port, 0);
+
+        let relay =
+            std::net::SocketAddr::new(control.peer_addr().unwrap().ip(), negotiated.bound_port);
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dest_addr = dest.local_addr().unwrap();
+        let mut datagram = proto::UdpHeader {
+            frag: 0,
+            dest_addr: dest_addr.into(),
+            dest_port: dest_addr.port(),
+        }
+        .as_bytes();
+        datagram.extend_from_slice(b"ping");
+        client.send_to(&datagram, relay).await.unwrap();
+        let mut buf = [0_u8; 64];
+        let (len, _) = dest.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        drop(control);
+    }
+
+    #[tokio::test]
+    async fn handshakes_over_a_stream_of_the_callers() {
+        let (mut client, proxy) = tokio::io::duplex(1024);
+        serve(proxy);
+        let negotiated = handshake_on_async(&mut client, &echo_request().await)
+            .await
+            .unwrap();
+        assert_eq!(negotiated.auth_method, proto::AuthMethod::NoAuth);
+        client.write_all(b"ping").await.unwrap();
+        let mut echoed = [0_u8; 4];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+
+        let (mut client, proxy) = std::os::unix::net::UnixStream::pair().unwrap();
+        proxy.set_nonblocking(true).unwrap();
+        serve(tokio::net::UnixStream::from_std(proxy).unwrap());
+        let req = echo_request().await;
+        let echoed = tokio::task::spawn_blocking(move || {
+            handshake_on(&mut client, &req).unwrap();
+            client.write_all(b"pong").unwrap();
+            let mut echoed = [0_u8; 4];
+            client.read_exact(&mut echoed).unwrap();
+            echoed
+        })
+        .await
+        .unwrap();
+        assert_eq!(&echoed, b"pong");
     }
 }
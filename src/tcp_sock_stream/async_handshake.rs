@@ -0,0 +1,117 @@
+//! The client handshake over async streams, for callers that already run in a tokio runtime
+//! and have a connection to the proxy of their own.
+
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net,
+};
+
+use super::{
+    check_auth_choice, check_reply, check_user_pass_response, unresolved, ConnectError,
+    ConnectRequest, ConnectTimings, CredentialSource, DnsMode, Negotiated,
+};
+use crate::proto::{self, Framed};
+
+/// Like [`super::handshake_on`], over an async stream. Names are resolved, in
+/// [`DnsMode::Local`], without blocking the runtime, but a credential provider is called
+/// from the task running the handshake.
+pub async fn handshake_on_async(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    req: &ConnectRequest,
+) -> Result<Negotiated, ConnectError> {
+    let dest_addr = dest_address(req).await.map_err(ConnectError::Resolve)?;
+
+    let start = Instant::now();
+    let auth_method = negotiate_auth(stream, &req.supported_auth_methods, req.credential_source())
+        .await
+        .map_err(ConnectError::Proxy)?;
+    let authenticated = Instant::now();
+    let request = proto::ClientConnectionRequest {
+        cmd: proto::ClientCommand::EstablishConnection,
+        dest_addr,
+        dest_port: req.dest_port,
+    };
+    let mut buf = BytesMut::new();
+    request.encode(&mut buf);
+    let resp = send_recv(stream, &buf)
+        .await
+        .map_err(ConnectError::Proxy)
+        .and_then(check_reply)?;
+
+    Ok(Negotiated {
+        auth_method,
+        bound_address: resp.bound_address,
+        bound_port: resp.bound_port,
+        timings: ConnectTimings {
+            proxy_connect: Duration::ZERO,
+            auth: authenticated - start,
+            dest_connect: authenticated.elapsed(),
+        },
+    })
+}
+
+async fn negotiate_auth(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    supported_auth_methods: &[proto::AuthMethod],
+    credentials: CredentialSource<'_>,
+) -> io::Result<proto::AuthMethod> {
+    let mut buf = BytesMut::new();
+    proto::ClientGreeting(supported_auth_methods.to_vec()).encode(&mut buf);
+    let resp: proto::ServerAuthChoice = send_recv_fixed(stream, &buf).await?;
+
+    check_auth_choice(supported_auth_methods, resp.0)?;
+    if resp.0 == proto::AuthMethod::UserPass {
+        let mut buf = BytesMut::new();
+        credentials.request()?.encode(&mut buf)?;
+        let resp: proto::UserPassResponse = send_recv_fixed(stream, &buf).await?;
+        check_user_pass_response(&resp)?;
+    }
+    Ok(resp.0)
+}
+
+/// Sends an encoded message and reads the reply, of the two bytes the auth choice and the
+/// username/password response take, into a buffer on the stack.
+async fn send_recv_fixed<M: Framed>(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    msg: &[u8],
+) -> io::Result<M> {
+    let mut buf = [0_u8; 2];
+    debug_assert_eq!(M::MAX_LEN, buf.len());
+    stream.write_all(msg).await?;
+    stream.flush().await?;
+    stream.read_exact(&mut buf).await?;
+    M::decode_complete(&mut &buf[..])
+}
+
+/// Sends an encoded message and reads the reply, never past its end.
+async fn send_recv<M: Framed>(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    msg: &[u8],
+) -> io::Result<M> {
+    stream.write_all(msg).await?;
+    stream.flush().await?;
+    let mut buf = BytesMut::with_capacity(M::MAX_LEN);
+    while let Some(len) = proto::missing::<M>(&buf)? {
+        let start = buf.len();
+        buf.resize(start + len, 0);
+        stream.read_exact(&mut buf[start..]).await?;
+    }
+    M::decode_complete(&mut &buf[..])
+}
+
+async fn dest_address(req: &ConnectRequest) -> io::Result<proto::Address> {
+    match req.dns {
+        DnsMode::Remote => req.dest_addr.parse(),
+        DnsMode::Local => match req.dest_addr.parse()? {
+            proto::Address::DomainName(host) => net::lookup_host((host.as_str(), req.dest_port))
+                .await?
+                .next()
+                .map(proto::Address::from)
+                .ok_or_else(|| unresolved(&host)),
+            ip => Ok(ip),
+        },
+    }
+}
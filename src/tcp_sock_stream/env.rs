@@ -0,0 +1,64 @@
+use std::{env, io};
+
+use super::ConnectRequest;
+
+impl ConnectRequest {
+    /// Builds a request for `dest_addr:dest_port` through the proxy named by `ALL_PROXY` (or
+    /// `all_proxy`), in the url format accepted by [`ConnectRequest::from_url`]. Returns
+    /// `None` when no proxy is configured or the destination is excluded by `NO_PROXY` (or
+    /// `no_proxy`), in which case the destination should be connected to directly.
+    pub fn from_env(dest_addr: &str, dest_port: u16) -> io::Result<Option<Self>> {
+        let Some(url) = env_var("ALL_PROXY").filter(|url| !url.is_empty()) else {
+            return Ok(None);
+        };
+        if env_var("NO_PROXY").is_some_and(|no_proxy| excluded(&no_proxy, dest_addr)) {
+            return Ok(None);
+        }
+        Self::from_url(&url, dest_addr, dest_port).map(Some)
+    }
+}
+
+/// Prefers the upper case spelling of `name`, falling back to the lower case one.
+fn env_var(name: &str) -> Option<String> {
+    env::var(name)
+        .or_else(|_| env::var(name.to_ascii_lowercase()))
+        .ok()
+}
+
+/// `no_proxy` is a comma separated list of hosts. `*` excludes everything, and every other
+/// entry excludes that host and its subdomains, with or without a leading dot.
+fn excluded(no_proxy: &str, dest_addr: &str) -> bool {
+    let dest = dest_addr
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    no_proxy
+        .split(',')
+        .map(|entry| entry.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            entry == "*"
+                || dest == entry
+                || dest
+                    .strip_suffix(entry.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_proxy_matches_hosts_and_subdomains() {
+        let no_proxy = "localhost, .internal.lan,example.com,::1";
+        assert!(excluded(no_proxy, "localhost"));
+        assert!(excluded(no_proxy, "db.internal.lan"));
+        assert!(excluded(no_proxy, "www.Example.com"));
+        assert!(excluded(no_proxy, "[::1]"));
+        assert!(!excluded(no_proxy, "notexample.com"));
+        assert!(!excluded(no_proxy, "example.org"));
+        assert!(excluded("*", "anything"));
+    }
+}
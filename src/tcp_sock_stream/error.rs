@@ -0,0 +1,66 @@
+use std::{error, fmt, io};
+
+use crate::proto;
+
+/// Why [`super::connect`] failed, telling problems with the proxy apart from the proxy
+/// failing to reach the destination.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// the destination is malformed, or its name could not be resolved on the client in
+    /// [`super::DnsMode::Local`]
+    Resolve(io::Error),
+    /// the proxy could not be reached, authenticated with, or misbehaved. another proxy may
+    /// well succeed.
+    Proxy(io::Error),
+    /// the proxy handled the request but reported this failure for it. other proxies will
+    /// likely fail the same way, and for some statuses retrying later might help.
+    Destination(proto::ServerStatus),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Resolve(err) => write!(f, "resolving destination: {err}"),
+            Self::Proxy(err) => write!(f, "proxy: {err}"),
+            Self::Destination(status) => {
+                write!(
+                    f,
+                    "proxy rejected establish connection with status: {status:?}"
+                )
+            }
+        }
+    }
+}
+
+impl error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Resolve(err) | Self::Proxy(err) => Some(err),
+            Self::Destination(_) => None,
+        }
+    }
+}
+
+impl From<ConnectError> for io::Error {
+    fn from(err: ConnectError) -> Self {
+        use proto::ServerStatus;
+
+        let kind = match &err {
+            ConnectError::Resolve(err) | ConnectError::Proxy(err) => err.kind(),
+            ConnectError::Destination(status) => match status {
+                ServerStatus::ConnectionNotAllowedByRuleset => io::ErrorKind::PermissionDenied,
+                ServerStatus::NetworkUnreachable => io::ErrorKind::NetworkUnreachable,
+                ServerStatus::HostUnreachable => io::ErrorKind::HostUnreachable,
+                ServerStatus::ConnectionRefusedByDestinationHost => {
+                    io::ErrorKind::ConnectionRefused
+                }
+                ServerStatus::TtlExpired => io::ErrorKind::TimedOut,
+                ServerStatus::CommandNotSupported | ServerStatus::AddressTypeNotSupported => {
+                    io::ErrorKind::Unsupported
+                }
+                ServerStatus::RequestGranted | ServerStatus::GeneralFailure => io::ErrorKind::Other,
+            },
+        };
+        io::Error::new(kind, err)
+    }
+}
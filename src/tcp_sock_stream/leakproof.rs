@@ -0,0 +1,96 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+};
+
+use super::{
+    negotiate_auth, request_connection, ConnectError, ConnectRequest, CredentialProvider,
+    CredentialSource, Credentials, DnsMode,
+};
+use crate::proto;
+
+/// A [`ConnectRequest`] that cannot cause a local DNS lookup.
+///
+/// The proxy is given as a socket address rather than a name, and the destination is only
+/// ever parsed: ip literals are sent as such, anything else as a domain name (ATYP 0x03) for
+/// the proxy to resolve. There is no [`DnsMode`] to get wrong, and nothing reachable from
+/// [`connect_leakproof`] takes a name it could hand to the system resolver.
+pub struct LeakproofConnectRequest {
+    pub server_addr: SocketAddr,
+    pub dest_addr: String,
+    pub dest_port: u16,
+    pub supported_auth_methods: Vec<proto::AuthMethod>,
+    pub credentials: Option<Credentials>,
+    pub credential_provider: Option<Arc<dyn CredentialProvider>>,
+}
+
+impl TryFrom<ConnectRequest> for LeakproofConnectRequest {
+    type Error = io::Error;
+
+    /// Fails for requests that would resolve something locally: the destination in
+    /// [`DnsMode::Local`], or a proxy given by name.
+    fn try_from(req: ConnectRequest) -> io::Result<Self> {
+        if req.dns == DnsMode::Local {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "local dns mode resolves the destination on the client",
+            ));
+        }
+        let server_addr = req.server_addr.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "proxy address {:?} is not an ip address and would be resolved locally",
+                    req.server_addr
+                ),
+            )
+        })?;
+        Ok(Self {
+            server_addr,
+            dest_addr: req.dest_addr,
+            dest_port: req.dest_port,
+            supported_auth_methods: req.supported_auth_methods,
+            credentials: req.credentials,
+            credential_provider: req.credential_provider,
+        })
+    }
+}
+
+pub fn connect_leakproof(req: LeakproofConnectRequest) -> Result<TcpStream, ConnectError> {
+    let dest_addr = req.dest_addr.parse().map_err(ConnectError::Resolve)?;
+
+    let mut conn = TcpStream::connect(req.server_addr).map_err(ConnectError::Proxy)?;
+    let proxy = req.server_addr.to_string();
+    let credentials = CredentialSource {
+        credentials: req.credentials.as_ref(),
+        provider: req.credential_provider.as_deref(),
+        proxy: &proxy,
+    };
+    negotiate_auth(&mut conn, &req.supported_auth_methods, credentials)
+        .map_err(ConnectError::Proxy)?;
+    request_connection(
+        &mut conn,
+        proto::ClientCommand::EstablishConnection,
+        dest_addr,
+        req.dest_port,
+    )?;
+    Ok(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_requests_that_would_resolve_locally() {
+        let leakproof = |url| {
+            ConnectRequest::from_url(url, "example.com", 443)
+                .and_then(LeakproofConnectRequest::try_from)
+        };
+        assert!(leakproof("socks5h://127.0.0.1:1080").is_ok());
+        assert!(leakproof("socks5h://[::1]").is_ok());
+        assert!(leakproof("socks5://127.0.0.1:1080").is_err());
+        assert!(leakproof("socks5h://proxy.example.com:1080").is_err());
+    }
+}
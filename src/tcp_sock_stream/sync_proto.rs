@@ -1,128 +1,88 @@
-use std::{
-    io::{self, Read, Write},
-    net::{Ipv4Addr, Ipv6Addr, TcpStream},
-};
+use std::io::{self, Read, Write};
+
+use bytes::BytesMut;
 
 use crate::proto::*;
 
 pub trait Sendable {
-    fn write_to(&self, conn: &mut TcpStream) -> io::Result<()>;
+    fn write_to(&self, conn: &mut dyn Write) -> io::Result<()>;
 }
 
 pub trait Recievable {
-    fn read_from(conn: &mut TcpStream) -> io::Result<Self>
+    fn read_from(conn: &mut dyn Read) -> io::Result<Self>
     where
         Self: Sized;
 }
 
 pub fn send_recv<Req: Sendable, Resp: Recievable>(
-    conn: &mut TcpStream,
+    conn: &mut (impl Read + Write),
     msg_to_send: Req,
 ) -> io::Result<Resp> {
     msg_to_send.write_to(conn)?;
+    // callers' streams may buffer writes, and the reply only comes once the message is out
+    conn.flush()?;
     Resp::read_from(conn)
 }
 
 impl Sendable for ClientGreeting {
-    fn write_to(&self, conn: &mut TcpStream) -> io::Result<()> {
-        let mut buf = Vec::with_capacity(1 + 1 + self.0.len());
-        buf.push(SOCKS_VERSION);
-        buf.push(self.0.len() as u8);
-        for &auth_method in &self.0 {
-            buf.push(auth_method as u8);
-        }
-        conn.write_all(&buf)?;
-        Ok(())
+    fn write_to(&self, conn: &mut dyn Write) -> io::Result<()> {
+        let mut buf = BytesMut::with_capacity(2 + self.0.len());
+        self.encode(&mut buf);
+        conn.write_all(&buf)
     }
 }
 
 impl Recievable for ServerAuthChoice {
-    fn read_from(conn: &mut TcpStream) -> io::Result<Self> {
-        let mut buf = [0_u8; 2];
+    fn read_from(conn: &mut dyn Read) -> io::Result<Self> {
+        let mut buf = [0_u8; Self::MAX_LEN];
         conn.read_exact(&mut buf)?;
-        if buf[0] != SOCKS_VERSION {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("expected socks version: {}, got: {}", SOCKS_VERSION, buf[0]),
-            ));
-        }
+        Self::decode_complete(&mut &buf[..])
+    }
+}
 
-        Ok(Self(AuthMethod::try_from(buf[1])?))
+impl Sendable for UserPassRequest {
+    fn write_to(&self, conn: &mut dyn Write) -> io::Result<()> {
+        let mut buf = BytesMut::with_capacity(3 + self.username.len() + self.password.len());
+        self.encode(&mut buf)?;
+        conn.write_all(&buf)
+    }
+}
+
+impl Recievable for UserPassResponse {
+    fn read_from(conn: &mut dyn Read) -> io::Result<Self> {
+        let mut buf = [0_u8; Self::MAX_LEN];
+        conn.read_exact(&mut buf)?;
+        Self::decode_complete(&mut &buf[..])
     }
 }
 
 impl Recievable for Address {
-    fn read_from(conn: &mut TcpStream) -> io::Result<Self> {
-        let mut buf = [0_u8; 255];
-        conn.read_exact(&mut buf[..1])?;
-        match buf[0] {
-            0x01 => {
-                conn.read_exact(&mut buf[..4])?;
-                let addr = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
-                Ok(Self::Ipv4(addr))
-            }
-            0x03 => {
-                conn.read_exact(&mut buf[..1])?;
-                let dn_len = buf[0] as usize;
-                conn.read_exact(&mut buf[..dn_len])?;
-                let dn = String::from_utf8(buf[..dn_len].to_vec())
-                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
-                Ok(Self::DomainName(dn))
-            }
-            0x04 => {
-                let mut buf = [0_u8; 16];
-                conn.read_exact(&mut buf)?;
-                let addr = Ipv6Addr::from(buf);
-                Ok(Self::Ipv6(addr))
-            }
-            other => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("proto: failed to parse address. expected 0x01, 0x03, 0x04: got: {other}"),
-            )),
-        }
+    fn read_from(conn: &mut dyn Read) -> io::Result<Self> {
+        read_message(conn)
     }
 }
 
 impl Sendable for ClientConnectionRequest {
-    fn write_to(&self, conn: &mut TcpStream) -> io::Result<()> {
-        let mut buf = vec![];
-        buf.push(SOCKS_VERSION);
-        buf.push(self.cmd as u8);
-        buf.push(0x00); // RSV: must be always zero
-        buf.extend_from_slice(&self.dest_addr.as_bytes());
-        buf.extend_from_slice(&self.dest_port.to_be_bytes());
+    fn write_to(&self, conn: &mut dyn Write) -> io::Result<()> {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
         conn.write_all(&buf)
     }
 }
 
 impl Recievable for ServerResponse {
-    fn read_from(conn: &mut TcpStream) -> io::Result<Self> {
-        let mut buf = [0_u8; 3];
-        conn.read_exact(&mut buf)?;
-        if buf[0] != SOCKS_VERSION {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("expected socks version: {}, got: {}", SOCKS_VERSION, buf[0]),
-            ));
-        }
-        let status = ServerStatus::try_from(buf[1])?;
-        if buf[2] != 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("expected RSV byte to be zero, got: {}", buf[2]),
-            ));
-        }
-
-        let bound_address = Address::read_from(conn)?;
-
-        let mut buf = [0u8; 2];
-        conn.read_exact(&mut buf)?;
-        let bound_port = u16::from_be_bytes(buf);
+    fn read_from(conn: &mut dyn Read) -> io::Result<Self> {
+        read_message(conn)
+    }
+}
 
-        Ok(Self {
-            status,
-            bound_address,
-            bound_port,
-        })
+/// Reads a message of a variable length, never past its end, and decodes it.
+fn read_message<M: Framed>(conn: &mut dyn Read) -> io::Result<M> {
+    let mut buf = BytesMut::with_capacity(M::MAX_LEN);
+    while let Some(len) = missing::<M>(&buf)? {
+        let start = buf.len();
+        buf.resize(start + len, 0);
+        conn.read_exact(&mut buf[start..])?;
     }
+    M::decode_complete(&mut &buf[..])
 }
@@ -0,0 +1,113 @@
+use std::io;
+
+use super::{ConnectRequest, Credentials, DnsMode};
+use crate::{proto, redact};
+
+impl ConnectRequest {
+    /// Builds a request for `dest_addr:dest_port` through the proxy described by a
+    /// `socks5://[user[:password]@]host[:port]` or `socks5h://…` url. The port defaults to
+    /// 1080, and credentials are percent-decoded.
+    pub fn from_url(url: &str, dest_addr: &str, dest_port: u16) -> io::Result<Self> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid proxy url {:?}: {reason}", redact::url(url)),
+            )
+        };
+
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| invalid("missing scheme"))?;
+        let dns = match scheme.to_ascii_lowercase().as_str() {
+            "socks5" => DnsMode::Local,
+            "socks5h" => DnsMode::Remote,
+            _ => return Err(invalid("expected socks5:// or socks5h://")),
+        };
+
+        let authority = rest.trim_end_matches('/');
+        if authority.contains('/') {
+            return Err(invalid("unexpected path"));
+        }
+        let (userinfo, host_port) = match authority.rsplit_once('@') {
+            Some((userinfo, host_port)) => (Some(userinfo), host_port),
+            None => (None, authority),
+        };
+
+        let credentials = userinfo
+            .map(|userinfo| {
+                let (username, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                Ok::<_, io::Error>(Credentials {
+                    username: percent_decode(username).ok_or_else(|| invalid("bad username"))?,
+                    password: percent_decode(password).ok_or_else(|| invalid("bad password"))?,
+                })
+            })
+            .transpose()?;
+
+        let server_addr = with_default_port(host_port).ok_or_else(|| invalid("bad host"))?;
+        let supported_auth_methods = match credentials {
+            Some(_) => vec![proto::AuthMethod::NoAuth, proto::AuthMethod::UserPass],
+            None => vec![proto::AuthMethod::NoAuth],
+        };
+
+        Ok(Self {
+            server_addr,
+            dest_addr: dest_addr.to_owned(),
+            dest_port,
+            supported_auth_methods,
+            credentials,
+            credential_provider: None,
+            dns,
+        })
+    }
+}
+
+fn with_default_port(host_port: &str) -> Option<String> {
+    const DEFAULT_PORT: u16 = 1080;
+
+    match proto::split_host_port(host_port).ok()? {
+        (_, Some(_)) => Some(host_port.to_owned()),
+        (host, None) if host.contains(':') => Some(format!("[{host}]:{DEFAULT_PORT}")),
+        (host, None) => Some(format!("{host}:{DEFAULT_PORT}")),
+    }
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_proxy_urls() {
+        let req =
+            ConnectRequest::from_url("socks5h://us%3Aer:p%40ss@[::1]:9050", "a.b", 80).unwrap();
+        assert_eq!(req.server_addr, "[::1]:9050");
+        assert_eq!(req.dns, DnsMode::Remote);
+        let credentials = req.credentials.unwrap();
+        assert_eq!(credentials.username, "us:er");
+        assert_eq!(credentials.password, "p@ss");
+
+        let req = ConnectRequest::from_url("socks5://proxy.lan/", "a.b", 80).unwrap();
+        assert_eq!(req.server_addr, "proxy.lan:1080");
+        assert_eq!(req.dns, DnsMode::Local);
+        assert!(req.credentials.is_none());
+
+        assert!(ConnectRequest::from_url("http://proxy.lan", "a.b", 80).is_err());
+        assert!(ConnectRequest::from_url("socks5://", "a.b", 80).is_err());
+    }
+}
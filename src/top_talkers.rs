@@ -0,0 +1,179 @@
+//! Which clients and destinations relayed the most bytes lately, configured by the
+//! `top_talkers` section of the server config, to tell what a busy proxy is carrying.
+//!
+//! Bytes of tcp sessions are counted in both directions, into buckets a tenth of
+//! `window_secs` long, so that the report covers the last `window_secs` give or take a
+//! bucket. Each session gathers what it relays and counts it about once a second and when it
+//! ends, rather than taking the lock of the buckets for every chunk. The report is served on
+//! `/top` of the health listener, and logged when the server receives SIGUSR1.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    hash::Hash,
+    net::IpAddr,
+    sync::Mutex,
+    time::Duration,
+};
+
+use tokio::{io, time::Instant};
+
+use crate::config::TopTalkersConfig;
+
+/// buckets a window is split into
+const BUCKETS: u32 = 10;
+
+#[derive(Debug)]
+pub struct TopTalkers {
+    window: Duration,
+    top: usize,
+    /// oldest first
+    buckets: Mutex<VecDeque<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    start: Instant,
+    clients: HashMap<IpAddr, u64>,
+    /// by `host:port` as requested
+    destinations: HashMap<String, u64>,
+}
+
+impl TopTalkers {
+    pub fn new(config: &TopTalkersConfig) -> io::Result<Self> {
+        if config.window_secs == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "top_talkers window_secs must be at least 1",
+            ));
+        }
+        Ok(Self {
+            window: Duration::from_secs(config.window_secs),
+            top: config.top,
+            buckets: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    /// Counts `len` bytes relayed between `client` and `destination`.
+    pub(crate) fn record(&self, client: IpAddr, destination: &str, len: u64) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        self.expire(&mut buckets, now);
+        let bucket_len = self.window / BUCKETS;
+        if buckets
+            .back()
+            .is_none_or(|bucket| now - bucket.start >= bucket_len)
+        {
+            buckets.push_back(Bucket {
+                start: now,
+                clients: HashMap::new(),
+                destinations: HashMap::new(),
+            });
+        }
+        let bucket = buckets.back_mut().unwrap();
+        *bucket.clients.entry(client).or_default() += len;
+        // looked up before inserting, to spare an allocation per relayed chunk
+        match bucket.destinations.get_mut(destination) {
+            Some(bytes) => *bytes += len,
+            None => {
+                bucket.destinations.insert(destination.to_owned(), len);
+            }
+        }
+    }
+
+    fn expire(&self, buckets: &mut VecDeque<Bucket>, now: Instant) {
+        while buckets
+            .front()
+            .is_some_and(|bucket| now - bucket.start >= self.window)
+        {
+            buckets.pop_front();
+        }
+    }
+
+    /// The top clients and destinations by bytes relayed within the window, one per line
+    /// under a heading each.
+    pub fn report(&self) -> String {
+        let mut buckets = self.buckets.lock().unwrap();
+        self.expire(&mut buckets, Instant::now());
+        let clients = ranking(buckets.iter().map(|bucket| &bucket.clients), self.top);
+        let destinations = ranking(buckets.iter().map(|bucket| &bucket.destinations), self.top);
+        drop(buckets);
+
+        let secs = self.window.as_secs();
+        let mut report = format!("top clients, last {secs}s:\n");
+        for (client, bytes) in clients {
+            let _ = writeln!(report, "{client} {bytes} bytes");
+        }
+        let _ = writeln!(report, "top destinations, last {secs}s:");
+        for (destination, bytes) in destinations {
+            let _ = writeln!(report, "{destination} {bytes} bytes");
+        }
+        report
+    }
+}
+
+/// Sums the counts of the buckets and returns the `top` largest, largest first.
+fn ranking<'a, K: Hash + Eq + Ord + Clone + 'a>(
+    counts: impl Iterator<Item = &'a HashMap<K, u64>>,
+    top: usize,
+) -> Vec<(K, u64)> {
+    let mut totals: HashMap<&K, u64> = HashMap::new();
+    for counts in counts {
+        for (key, bytes) in counts {
+            *totals.entry(key).or_default() += bytes;
+        }
+    }
+    let mut ranking: Vec<(K, u64)> = totals
+        .into_iter()
+        .map(|(key, bytes)| (key.clone(), bytes))
+        .collect();
+    ranking.sort_by(|(a_key, a), (b_key, b)| b.cmp(a).then(a_key.cmp(b_key)));
+    ranking.truncate(top);
+    ranking
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn ranks_what_was_relayed_within_the_window() {
+        let talkers = TopTalkers::new(&TopTalkersConfig {
+            window_secs: 60,
+            top: 2,
+        })
+        .unwrap();
+        let (alice, bob, carol): (IpAddr, IpAddr, IpAddr) = (
+            [192, 0, 2, 1].into(),
+            [192, 0, 2, 2].into(),
+            [192, 0, 2, 3].into(),
+        );
+
+        talkers.record(alice, "example.com:443", 5000);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        talkers.record(bob, "example.net:22", 3000);
+        talkers.record(carol, "example.com:443", 1000);
+        talkers.record(bob, "example.org:80", 500);
+        assert_eq!(
+            talkers.report(),
+            "top clients, last 60s:\n\
+             192.0.2.1 5000 bytes\n\
+             192.0.2.2 3500 bytes\n\
+             top destinations, last 60s:\n\
+             example.com:443 6000 bytes\n\
+             example.net:22 3000 bytes\n"
+        );
+
+        // alice's bytes fall out of the window
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(
+            talkers.report(),
+            "top clients, last 60s:\n\
+             192.0.2.2 3500 bytes\n\
+             192.0.2.3 1000 bytes\n\
+             top destinations, last 60s:\n\
+             example.net:22 3000 bytes\n\
+             example.com:443 1000 bytes\n"
+        );
+    }
+}
@@ -0,0 +1,232 @@
+//! Transports wrapping the connection between client and proxy before the socks handshake,
+//! such as obfuscation layers that keep the handshake from being recognized on the wire.
+//!
+//! The server runs accepted connections through its [`ListenerTransport`], see
+//! [`Server::set_listener_transport`](crate::server::Server::set_listener_transport), and
+//! [`connect_over`](crate::tcp_sock_stream::connect_over) runs the client's connection to
+//! the proxy through a [`ClientTransport`]. Both ends have to use matching transports.
+
+use std::{
+    io::{Read, Write},
+    net,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use futures::future::BoxFuture;
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+#[cfg(feature = "websocket")]
+mod websocket;
+
+#[cfg(feature = "websocket")]
+pub use websocket::WebSocketTransport;
+
+/// A connection the server can run the socks handshake and relay over.
+pub trait ServerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ServerStream for T {}
+
+/// A connection to the proxy the client can run the socks handshake over.
+pub trait ClientStream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> ClientStream for T {}
+
+pub trait ListenerTransport: Send + Sync {
+    /// Wraps a freshly accepted connection, possibly exchanging data with the client first.
+    fn accept(&self, stream: TcpStream) -> BoxFuture<'_, io::Result<Box<dyn ServerStream>>>;
+}
+
+pub trait ClientTransport: Send + Sync {
+    /// Wraps a freshly established connection to the proxy.
+    fn wrap(&self, stream: net::TcpStream) -> io::Result<Box<dyn ClientStream>>;
+}
+
+/// XORs both directions with a repeating key. It keeps the handshake away from naive
+/// pattern matching and nothing more, so it is mostly an example of a transport.
+pub struct XorTransport {
+    key: Arc<[u8]>,
+}
+
+impl XorTransport {
+    pub fn new(key: &[u8]) -> io::Result<Self> {
+        if key.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "xor transport key must not be empty",
+            ));
+        }
+        Ok(Self { key: key.into() })
+    }
+
+    fn stream<S>(&self, inner: S) -> XorStream<S> {
+        XorStream {
+            inner,
+            key: Arc::clone(&self.key),
+            read_pos: 0,
+            write_pos: 0,
+        }
+    }
+}
+
+impl ListenerTransport for XorTransport {
+    fn accept(&self, stream: TcpStream) -> BoxFuture<'_, io::Result<Box<dyn ServerStream>>> {
+        Box::pin(async move { Ok(Box::new(self.stream(stream)) as Box<dyn ServerStream>) })
+    }
+}
+
+impl ClientTransport for XorTransport {
+    fn wrap(&self, stream: net::TcpStream) -> io::Result<Box<dyn ClientStream>> {
+        Ok(Box::new(self.stream(stream)))
+    }
+}
+
+struct XorStream<S> {
+    inner: S,
+    key: Arc<[u8]>,
+    /// offsets into the key of the next byte read and written
+    read_pos: usize,
+    write_pos: usize,
+}
+
+fn xor(key: &[u8], pos: &mut usize, buf: &mut [u8]) {
+    for b in buf {
+        *b ^= key[*pos];
+        *pos = (*pos + 1) % key.len();
+    }
+}
+
+impl<S> XorStream<S> {
+    /// The masked form of `buf`, without advancing the write position, since the inner
+    /// stream may take only part of it.
+    fn masked(&self, buf: &[u8]) -> Vec<u8> {
+        let mut masked = buf.to_vec();
+        xor(&self.key, &mut self.write_pos.clone(), &mut masked);
+        masked
+    }
+
+    fn advance_write(&mut self, n: usize) {
+        self.write_pos = (self.write_pos + n) % self.key.len();
+    }
+}
+
+impl<S: Read> Read for XorStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        xor(&self.key, &mut self.read_pos, &mut buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for XorStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(&self.masked(buf))?;
+        self.advance_write(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for XorStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        xor(
+            &this.key,
+            &mut this.read_pos,
+            &mut buf.filled_mut()[filled..],
+        );
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for XorStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let masked = this.masked(buf);
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &masked))?;
+        this.advance_write(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{
+        config::ServerConfig,
+        proto::AuthMethod,
+        server::Server,
+        tcp_sock_stream::{connect_over, ConnectRequest, DnsMode},
+    };
+
+    #[tokio::test]
+    async fn handshakes_and_relays_through_matching_transports() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let mut server = Server::new(ServerConfig::default()).unwrap();
+        server.set_listener_transport(Box::new(XorTransport::new(b"key").unwrap()));
+        tokio::spawn(Arc::new(server).serve(listener));
+
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut conn, _) = echo.accept().await.unwrap();
+            let mut buf = [0_u8; 4];
+            conn.read_exact(&mut buf).await.unwrap();
+            conn.write_all(&buf).await.unwrap();
+        });
+
+        let echoed = tokio::task::spawn_blocking(move || {
+            let req = ConnectRequest {
+                server_addr: proxy_addr.to_string(),
+                dest_addr: "127.0.0.1".to_owned(),
+                dest_port: echo_port,
+                supported_auth_methods: vec![AuthMethod::NoAuth],
+                credentials: None,
+                credential_provider: None,
+                dns: DnsMode::Remote,
+            };
+            let transport = XorTransport::new(b"key").unwrap();
+            let (mut conn, _) = connect_over(req, &transport).unwrap();
+            conn.write_all(b"ping").unwrap();
+            let mut buf = [0_u8; 4];
+            conn.read_exact(&mut buf).unwrap();
+            buf
+        })
+        .await
+        .unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+}
@@ -0,0 +1,268 @@
+//! Tunnels the socks byte stream through binary WebSocket messages, so it can pass CDNs and
+//! middleboxes that only let http through.
+
+use std::{
+    io::{Read, Write},
+    net,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures::{future::BoxFuture, Sink, Stream};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode,
+    Message, WebSocket,
+};
+
+use super::{ClientStream, ClientTransport, ListenerTransport, ServerStream};
+
+/// Speaks WebSocket on the given request path, which the client and the listener have to
+/// agree on.
+pub struct WebSocketTransport {
+    path: String,
+}
+
+impl WebSocketTransport {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_owned(),
+        }
+    }
+}
+
+impl ListenerTransport for WebSocketTransport {
+    fn accept(&self, stream: TcpStream) -> BoxFuture<'_, io::Result<Box<dyn ServerStream>>> {
+        Box::pin(async move {
+            // the error type is tungstenite's, boxing it isn't up to us
+            #[allow(clippy::result_large_err)]
+            let check_path = |req: &Request, resp: Response| {
+                if req.uri().path() == self.path {
+                    return Ok(resp);
+                }
+                let mut not_found = ErrorResponse::new(None);
+                *not_found.status_mut() = StatusCode::NOT_FOUND;
+                Err(not_found)
+            };
+            let ws = tokio_tungstenite::accept_hdr_async(stream, check_path)
+                .await
+                .map_err(ws_error)?;
+            Ok(Box::new(AsyncWebSocket {
+                ws,
+                read_buf: Vec::new(),
+                read_pos: 0,
+            }) as Box<dyn ServerStream>)
+        })
+    }
+}
+
+impl ClientTransport for WebSocketTransport {
+    fn wrap(&self, stream: net::TcpStream) -> io::Result<Box<dyn ClientStream>> {
+        let url = format!("ws://{}{}", stream.peer_addr()?, self.path);
+        let (ws, _) = tungstenite::client(url, stream).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("websocket handshake: {err}"),
+            )
+        })?;
+        Ok(Box::new(SyncWebSocket {
+            ws,
+            read_buf: Vec::new(),
+            read_pos: 0,
+        }))
+    }
+}
+
+fn ws_error(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, format!("websocket: {err}")),
+    }
+}
+
+/// Whether a received message ends the stream, carries data, or is control traffic that
+/// tungstenite already took care of.
+fn payload(msg: Message) -> Option<Option<Vec<u8>>> {
+    match msg {
+        Message::Binary(data) => Some(Some(data)),
+        Message::Close(_) => Some(None),
+        _ => None,
+    }
+}
+
+struct AsyncWebSocket {
+    ws: WebSocketStream<TcpStream>,
+    /// the rest of the last binary message, from `read_pos` on
+    read_buf: Vec<u8>,
+    read_pos: usize,
+}
+
+impl AsyncRead for AsyncWebSocket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.read_pos == this.read_buf.len() {
+            let data = match ready!(Pin::new(&mut this.ws).poll_next(cx)) {
+                None => return Poll::Ready(Ok(())),
+                Some(msg) => match payload(msg.map_err(ws_error)?) {
+                    Some(Some(data)) => data,
+                    Some(None) => return Poll::Ready(Ok(())),
+                    None => continue,
+                },
+            };
+            this.read_buf = data;
+            this.read_pos = 0;
+        }
+        let n = buf.remaining().min(this.read_buf.len() - this.read_pos);
+        buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+        this.read_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for AsyncWebSocket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let ws = &mut self.get_mut().ws;
+        ready!(Pin::new(&mut *ws).poll_ready(cx)).map_err(ws_error)?;
+        Pin::new(&mut *ws)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(ws_error)?;
+        // callers like the handshake never flush, so push the message out right away. if the
+        // socket is full, it goes out with the next write or read.
+        if let Poll::Ready(Err(err)) = Pin::new(ws).poll_flush(cx) {
+            return Poll::Ready(Err(ws_error(err)));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().ws)
+            .poll_flush(cx)
+            .map_err(ws_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().ws)
+            .poll_close(cx)
+            .map_err(ws_error)
+    }
+}
+
+struct SyncWebSocket {
+    ws: WebSocket<net::TcpStream>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+}
+
+impl Read for SyncWebSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read_pos == self.read_buf.len() {
+            let msg = match self.ws.read() {
+                Ok(msg) => msg,
+                Err(tungstenite::Error::ConnectionClosed) => return Ok(0),
+                Err(err) => return Err(ws_error(err)),
+            };
+            match payload(msg) {
+                Some(Some(data)) => {
+                    self.read_buf = data;
+                    self.read_pos = 0;
+                }
+                Some(None) => return Ok(0),
+                None => {}
+            }
+        }
+        let n = buf.len().min(self.read_buf.len() - self.read_pos);
+        buf[..n].copy_from_slice(&self.read_buf[self.read_pos..self.read_pos + n]);
+        self.read_pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for SyncWebSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.ws
+            .send(Message::Binary(buf.to_vec()))
+            .map_err(ws_error)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.ws.flush().map_err(ws_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{
+        config::{ServerConfig, WebSocketConfig},
+        proto::AuthMethod,
+        server::Server,
+        tcp_sock_stream::{connect_over, ConnectRequest, DnsMode},
+    };
+
+    #[tokio::test]
+    async fn relays_through_a_websocket_on_the_configured_path() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let server = Server::new(ServerConfig {
+            websocket: Some(WebSocketConfig {
+                path: "/tunnel".to_owned(),
+            }),
+            ..ServerConfig::default()
+        })
+        .unwrap();
+        tokio::spawn(Arc::new(server).serve(listener));
+
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut conn, _) = echo.accept().await.unwrap();
+            let mut buf = [0_u8; 4];
+            conn.read_exact(&mut buf).await.unwrap();
+            conn.write_all(&buf).await.unwrap();
+        });
+
+        let (echoed, wrong_path) = tokio::task::spawn_blocking(move || {
+            let req = |dest_port| ConnectRequest {
+                server_addr: proxy_addr.to_string(),
+                dest_addr: "127.0.0.1".to_owned(),
+                dest_port,
+                supported_auth_methods: vec![AuthMethod::NoAuth],
+                credentials: None,
+                credential_provider: None,
+                dns: DnsMode::Remote,
+            };
+            let (mut conn, _) =
+                connect_over(req(echo_port), &WebSocketTransport::new("/tunnel")).unwrap();
+            conn.write_all(b"ping").unwrap();
+            let mut buf = [0_u8; 4];
+            conn.read_exact(&mut buf).unwrap();
+            let wrong_path = connect_over(req(echo_port), &WebSocketTransport::new("/"));
+            (buf, wrong_path.is_err())
+        })
+        .await
+        .unwrap();
+        assert_eq!(&echoed, b"ping");
+        assert!(wrong_path);
+    }
+}
@@ -0,0 +1,384 @@
+//! Binary upgrades without closing the listen sockets.
+//!
+//! The running process execs a new copy of the current binary with `SOCKS5_UPGRADE_SOCKET`
+//! pointing at a unix socket, and sends its listening sockets over it using `SCM_RIGHTS`.
+//! The socket is in a directory only the running user can enter, and the listeners are only
+//! sent to the new process itself, as told by the peer credentials of its connection.
+//! The new process picks them up with [`inherit_listeners`] instead of binding its own, and
+//! acknowledges once it has them, after which the old process stops accepting and drains
+//! its remaining connections.
+
+use std::{
+    collections::HashMap,
+    env,
+    fs::DirBuilder,
+    mem,
+    net::{TcpListener as StdTcpListener, UdpSocket as StdUdpSocket},
+    os::unix::{
+        fs::DirBuilderExt,
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        net::UnixStream as StdUnixStream,
+    },
+    path::PathBuf,
+    process::{self, Command},
+    ptr,
+    time::Duration,
+};
+
+use tokio::{
+    io::{self, AsyncReadExt, Interest},
+    net::{TcpListener, UnixListener},
+    time::timeout,
+};
+
+use crate::random;
+
+pub const UPGRADE_SOCKET_ENV: &str = "SOCKS5_UPGRADE_SOCKET";
+
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_LISTENERS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListenerKind {
+    Socks,
+    Health,
+    HttpProxy,
+    Redirect,
+    /// the udp socket of the QUIC endpoint
+    Quic,
+    /// the `[[listeners]]` entry at this index of the config
+    Named(u8),
+}
+
+impl From<ListenerKind> for u8 {
+    fn from(kind: ListenerKind) -> u8 {
+        match kind {
+            ListenerKind::Socks => b'S',
+            ListenerKind::Health => b'H',
+            ListenerKind::HttpProxy => b'P',
+            ListenerKind::Redirect => b'R',
+            ListenerKind::Quic => b'Q',
+            ListenerKind::Named(index) => 0x80 | index,
+        }
+    }
+}
+
+impl TryFrom<u8> for ListenerKind {
+    type Error = io::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            b'S' => Ok(Self::Socks),
+            b'H' => Ok(Self::Health),
+            b'P' => Ok(Self::HttpProxy),
+            b'R' => Ok(Self::Redirect),
+            b'Q' => Ok(Self::Quic),
+            0x80.. => Ok(Self::Named(value & 0x7f)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected listener kind: {value}"),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InheritedListeners {
+    pub socks: Option<TcpListener>,
+    pub health: Option<TcpListener>,
+    pub http_proxy: Option<TcpListener>,
+    pub redirect: Option<TcpListener>,
+    pub quic: Option<StdUdpSocket>,
+    /// by their index in `[[listeners]]`
+    pub named: HashMap<u8, TcpListener>,
+}
+
+/// Execs a new copy of the running binary with the same arguments and passes it the given
+/// listeners. Returns once the new process has acknowledged receiving them.
+pub async fn hand_off(listeners: &[(ListenerKind, RawFd)]) -> io::Result<()> {
+    if listeners.len() > MAX_LISTENERS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("can't hand off more than {MAX_LISTENERS} listeners"),
+        ));
+    }
+    let dir = private_dir()?;
+    let _cleanup = RemoveOnDrop(dir.clone());
+    let path = dir.join("upgrade.sock");
+    let unix_lis = UnixListener::bind(&path)?;
+
+    let mut child = Command::new(env::current_exe()?)
+        .args(env::args_os().skip(1))
+        .env(UPGRADE_SOCKET_ENV, &path)
+        .spawn()?;
+    let child_pid = child.id();
+    eprintln!("upgrade: started new process {child_pid}");
+
+    let handoff = async {
+        let (mut conn, _) = unix_lis.accept().await?;
+        check_peer(&conn.peer_cred()?, child_pid)?;
+        let kinds: Vec<u8> = listeners.iter().map(|&(kind, _)| kind.into()).collect();
+        let fds: Vec<RawFd> = listeners.iter().map(|&(_, fd)| fd).collect();
+        loop {
+            conn.writable().await?;
+            match conn.try_io(Interest::WRITABLE, || {
+                send_fds(conn.as_raw_fd(), &kinds, &fds)
+            }) {
+                Ok(()) => break,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        let mut ack = [0_u8; 1];
+        conn.read_exact(&mut ack).await?;
+        Ok(())
+    };
+    let res = match timeout(HANDOFF_TIMEOUT, handoff).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "new process did not pick up the listeners in time",
+        )),
+    };
+    if res.is_err() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+    res
+}
+
+/// Creates a directory only the running user can enter for the hand-off socket, under
+/// `$XDG_RUNTIME_DIR` if it is set, or else the temp dir. Creating it fails rather than
+/// reuse whatever another user may have put in its place.
+fn private_dir() -> io::Result<PathBuf> {
+    let parent = env::var_os("XDG_RUNTIME_DIR").map_or_else(env::temp_dir, PathBuf::from);
+    let name = format!(
+        "socks5-upgrade-{}-{:016x}",
+        process::id(),
+        random::below(u64::MAX)
+    );
+    let dir = parent.join(name);
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+/// Refuses to hand the listeners to anyone but the new process, or at least, where the
+/// platform doesn't tell the peer's pid, to a process of the running user.
+fn check_peer(peer: &tokio::net::unix::UCred, child_pid: u32) -> io::Result<()> {
+    // safety: geteuid can't fail and has no preconditions
+    let uid = unsafe { libc::geteuid() };
+    let pid_matches = peer
+        .pid()
+        .is_none_or(|pid| u32::try_from(pid) == Ok(child_pid));
+    if peer.uid() != uid || !pid_matches {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "upgrade: refusing listeners to uid {} pid {:?} rather than new process \
+                 {child_pid}",
+                peer.uid(),
+                peer.pid()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Receives the listeners of the process being upgraded, if this process was started by
+/// [`hand_off`]. Must be called from within a tokio runtime.
+pub fn inherit_listeners() -> io::Result<Option<InheritedListeners>> {
+    let Some(path) = env::var_os(UPGRADE_SOCKET_ENV).map(PathBuf::from) else {
+        return Ok(None);
+    };
+    let mut conn = StdUnixStream::connect(&path)?;
+    conn.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+    let (kinds, fds) = recv_fds(conn.as_raw_fd())?;
+
+    let mut inherited = InheritedListeners::default();
+    for (kind, fd) in kinds.into_iter().zip(fds) {
+        match ListenerKind::try_from(kind)? {
+            ListenerKind::Socks => inherited.socks = Some(inherit_tcp(fd)?),
+            ListenerKind::Health => inherited.health = Some(inherit_tcp(fd)?),
+            ListenerKind::HttpProxy => inherited.http_proxy = Some(inherit_tcp(fd)?),
+            ListenerKind::Redirect => inherited.redirect = Some(inherit_tcp(fd)?),
+            ListenerKind::Quic => inherited.quic = Some(StdUdpSocket::from(fd)),
+            ListenerKind::Named(index) => {
+                inherited.named.insert(index, inherit_tcp(fd)?);
+            }
+        }
+    }
+
+    std::io::Write::write_all(&mut conn, &[1])?;
+    Ok(Some(inherited))
+}
+
+fn inherit_tcp(fd: OwnedFd) -> io::Result<TcpListener> {
+    let lis = StdTcpListener::from(fd);
+    lis.set_nonblocking(true)?;
+    TcpListener::from_std(lis)
+}
+
+fn send_fds(sock: RawFd, payload: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let fds_len = mem::size_of_val(fds);
+    let mut cmsg_buf = vec![0_u8; unsafe { libc::CMSG_SPACE(fds_len as u32) } as usize];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = cmsg_buf.len() as _;
+
+    // safety: cmsg_buf is sized with CMSG_SPACE for exactly fds.len() descriptors
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len as u32) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+    }
+
+    let ret = unsafe { libc::sendmsg(sock, &msg, 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receives the listener kinds and their fds, which are owned right away so that they are
+/// closed rather than leaked if the message turns out to be malformed.
+fn recv_fds(sock: RawFd) -> io::Result<(Vec<u8>, Vec<OwnedFd>)> {
+    let mut payload = [0_u8; MAX_LISTENERS];
+    let fds_len = MAX_LISTENERS * mem::size_of::<RawFd>();
+    let mut cmsg_buf = vec![0_u8; unsafe { libc::CMSG_SPACE(fds_len as u32) } as usize];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = cmsg_buf.len() as _;
+
+    let nread = unsafe { libc::recvmsg(sock, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if nread < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut fds = Vec::new();
+    // safety: the kernel filled in msg and its control buffer, which we walk with the cmsg
+    // macros without going past msg_controllen. the fds it passed are new to this process
+    // and owned by nobody else
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data_len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..data_len / mem::size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    let kinds = payload[..nread as usize].to_vec();
+    if kinds.len() != fds.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "upgrade: received {} listener kinds but {} fds",
+                kinds.len(),
+                fds.len()
+            ),
+        ));
+    }
+    Ok((kinds, fds))
+}
+
+/// Removes the hand-off directory, and the socket in it.
+struct RemoveOnDrop(PathBuf);
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+
+    #[test]
+    fn passes_listeners_and_their_kinds_over_a_unix_socket() {
+        let (old, new) = StdUnixStream::pair().unwrap();
+        let lis = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = lis.local_addr().unwrap();
+        let kinds = [ListenerKind::Socks.into(), ListenerKind::Named(3).into()];
+        let named = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        send_fds(
+            old.as_raw_fd(),
+            &kinds,
+            &[lis.as_raw_fd(), named.as_raw_fd()],
+        )
+        .unwrap();
+
+        let (received, fds) = recv_fds(new.as_raw_fd()).unwrap();
+        assert_eq!(received, kinds);
+        assert_eq!(
+            ListenerKind::try_from(received[1]).unwrap(),
+            ListenerKind::Named(3)
+        );
+        let inherited = StdTcpListener::from(fds.into_iter().next().unwrap());
+        assert_eq!(inherited.local_addr().unwrap(), addr);
+        drop(lis);
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        let (mut accepted, _) = inherited.accept().unwrap();
+        client.write_all(b"x").unwrap();
+        let mut buf = [0_u8; 1];
+        accepted.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"x");
+    }
+
+    #[test]
+    fn creates_a_directory_only_the_user_can_enter() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = private_dir().unwrap();
+        let _cleanup = RemoveOnDrop(dir.clone());
+        let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        assert_ne!(private_dir().map(RemoveOnDrop).unwrap().0, dir);
+    }
+
+    #[tokio::test]
+    async fn hands_listeners_only_to_the_new_process() {
+        let (old, _new) = tokio::net::UnixStream::pair().unwrap();
+        // both ends of a pair belong to the process that made it
+        let peer = old.peer_cred().unwrap();
+        check_peer(&peer, process::id()).unwrap();
+        if peer.pid().is_some() {
+            let err = check_peer(&peer, process::id() + 1).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        }
+    }
+
+    #[test]
+    fn rejects_kinds_without_as_many_fds() {
+        let (old, new) = StdUnixStream::pair().unwrap();
+        let lis = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let kinds = [ListenerKind::Socks.into(), ListenerKind::Health.into()];
+        send_fds(old.as_raw_fd(), &kinds, &[lis.as_raw_fd()]).unwrap();
+        let err = recv_fds(new.as_raw_fd()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}